@@ -0,0 +1,61 @@
+-- Reserved "auth" service: permissions that protect the auth API's own management endpoints.
+-- Required in every deployment (not demo data). Safe to run repeatedly, so it also upgrades
+-- existing databases:
+--   psql -U postgres -d api_auth -f db/auth_admin.sql
+-- The service, its permissions and the auth-admin role are shared platform rows (tenant_id NULL):
+-- every tenant assigns auth-admin to its own people, and admin rights stay inside their tenant.
+
+\set ON_ERROR_STOP on
+
+INSERT INTO auth.services (name, description, tenant_id)
+VALUES ('auth', 'Auth API management plane (reserved)', NULL)
+ON CONFLICT (tenant_id, name) DO NOTHING;
+
+INSERT INTO auth.permission (name, tenant_id)
+VALUES
+  ('users.write', NULL),
+  ('roles.write', NULL),
+  ('permissions.write', NULL),
+  ('relations.write', NULL),
+  ('services.write', NULL),
+  ('tenants.write', NULL),
+  ('groups.write', NULL),
+  ('relationships.write', NULL)
+ON CONFLICT (tenant_id, service_id, name) DO NOTHING;
+
+INSERT INTO auth.role (name, tenant_id)
+VALUES ('auth-admin', NULL)
+ON CONFLICT (tenant_id, name) DO NOTHING;
+
+INSERT INTO auth.service_roles (service_id, role_id)
+SELECT s.id, r.id
+FROM auth.services s
+JOIN auth.role r ON r.name = 'auth-admin' AND r.tenant_id IS NULL
+WHERE s.name = 'auth' AND s.tenant_id IS NULL
+ON CONFLICT (service_id, role_id) DO NOTHING;
+
+-- tenants.write only takes effect for admins of the default tenant.
+INSERT INTO auth.role_permission (role_id, permission_id)
+SELECT r.id, p.id
+FROM auth.role r
+JOIN auth.permission p ON p.tenant_id IS NULL AND p.name IN (
+  'users.write',
+  'roles.write',
+  'permissions.write',
+  'relations.write',
+  'services.write',
+  'tenants.write',
+  'groups.write',
+  'relationships.write'
+)
+WHERE r.name = 'auth-admin' AND r.tenant_id IS NULL
+ON CONFLICT (role_id, permission_id) DO NOTHING;
+
+-- Bootstrap: people allowed to register services become auth admins.
+INSERT INTO auth.person_service_role (person_id, service_id, role_id)
+SELECT pe.id, s.id, r.id
+FROM auth.person pe
+JOIN auth.services s ON s.name = 'auth' AND s.tenant_id IS NULL
+JOIN auth.role r ON r.name = 'auth-admin' AND r.tenant_id IS NULL
+WHERE pe.can_register_services AND pe.removed_at IS NULL
+ON CONFLICT (person_id, service_id, role_id) DO NOTHING;
@@ -11,7 +11,7 @@ VALUES
   ('Service C', 'Customer support portal'),
   ('UI Store', 'Frontend store surface'),
   ('ui-store', 'Frontend store surface')
-ON CONFLICT (name) DO NOTHING;
+ON CONFLICT (tenant_id, name) DO NOTHING;
 
 -- Roles
 INSERT INTO auth.role (name)
@@ -20,7 +20,7 @@ VALUES
   ('User'),
   ('Editor'),
   ('Viewer')
-ON CONFLICT (name) DO NOTHING;
+ON CONFLICT (tenant_id, name) DO NOTHING;
 
 -- Permissions
 INSERT INTO auth.permission (name)
@@ -30,7 +30,7 @@ VALUES
   ('update'),
   ('delete'),
   ('share')
-ON CONFLICT (name) DO NOTHING;
+ON CONFLICT (tenant_id, service_id, name) DO NOTHING;
 
 -- People
 INSERT INTO auth.person (
@@ -105,6 +105,19 @@ JOIN auth.role r ON r.name = rpp.role_name
 JOIN auth.permission p ON p.name = rpp.permission_name
 ON CONFLICT (role_id, permission_id) DO NOTHING;
 
+-- Role hierarchy (child inherits the permissions of its parents)
+WITH role_parent_pairs (role_name, parent_name) AS (
+  VALUES
+    ('Admin', 'Editor'),
+    ('Editor', 'Viewer')
+)
+INSERT INTO auth.role_parent (role_id, parent_role_id)
+SELECT r.id, parent.id
+FROM role_parent_pairs rpp
+JOIN auth.role r ON r.name = rpp.role_name
+JOIN auth.role parent ON parent.name = rpp.parent_name
+ON CONFLICT (role_id, parent_role_id) DO NOTHING;
+
 -- Person assignments to service roles
 WITH person_service_role_pairs (username, service_name, role_name) AS (
   VALUES
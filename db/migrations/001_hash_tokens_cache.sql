@@ -0,0 +1,29 @@
+-- One-time migration for databases created before tokens were stored hashed.
+-- Replaces every plaintext token in auth.tokens_cache with HMAC-SHA256(JWT_SECRET, token),
+-- which is what the API now stores and looks up. Existing user and service tokens keep working.
+--
+-- Run it with the same secret the API uses (the API falls back to 'local_secret' when JWT_SECRET is unset):
+--   psql -U postgres -d api_auth -v jwt_secret="$JWT_SECRET" -f db/migrations/001_hash_tokens_cache.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+-- Permission snapshots are a cache; dropping them avoids rewriting the foreign key values.
+DELETE FROM auth.permissions_cache;
+
+ALTER TABLE auth.permissions_cache DROP CONSTRAINT permissions_cache_token_fkey;
+
+ALTER TABLE auth.tokens_cache RENAME COLUMN token TO token_hash;
+ALTER TABLE auth.permissions_cache RENAME COLUMN token TO token_hash;
+
+UPDATE auth.tokens_cache
+SET token_hash = encode(hmac(token_hash, :'jwt_secret', 'sha256'), 'hex');
+
+ALTER TABLE auth.permissions_cache
+  ADD CONSTRAINT permissions_cache_token_hash_fkey
+  FOREIGN KEY (token_hash) REFERENCES auth.tokens_cache(token_hash) ON DELETE CASCADE;
+
+COMMIT;
@@ -0,0 +1,51 @@
+-- One-time migration for databases created before refresh tokens and per-device sessions existed.
+-- Adds auth.sessions, the single-use refresh token store, and the session every user token
+-- belongs to. Existing user tokens keep working without a session; people get a session and a
+-- refresh token at their next login.
+--
+--   psql -U postgres -d api_auth -f db/migrations/002_sessions.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- One row per signed-in device; user and refresh tokens hang off it.
+CREATE TABLE auth.sessions (
+  id SERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  user_agent TEXT,
+  ip TEXT,
+  last_seen BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+ALTER TABLE auth.tokens_cache
+  ADD COLUMN session_id INTEGER REFERENCES auth.sessions(id) ON DELETE CASCADE;
+
+-- Single-use refresh tokens; every rotation stays in the session started at login.
+CREATE TABLE auth.refresh_tokens (
+  token_hash TEXT PRIMARY KEY,
+  session_id INTEGER REFERENCES auth.sessions(id) ON DELETE CASCADE NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  payload JSONB NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TRIGGER trg_auth_sessions_audit
+BEFORE INSERT OR UPDATE ON auth.sessions
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_refresh_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.refresh_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.sessions, auth.refresh_tokens TO admin;
+GRANT USAGE, SELECT, UPDATE ON SEQUENCE auth.sessions_id_seq TO admin;
+
+COMMIT;
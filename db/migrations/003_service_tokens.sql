@@ -0,0 +1,35 @@
+-- One-time migration for databases created before service tokens could be listed and rotated.
+-- Registers every existing service token in auth.service_tokens so it shows up in
+-- GET /services/{id}/tokens and can be revoked or rotated out. Raw values are not stored,
+-- so migrated tokens have an empty hint; they keep their original (non-expiring) expires_at.
+--
+--   psql -U postgres -d api_auth -f db/migrations/003_service_tokens.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+CREATE TABLE auth.service_tokens (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  token_hash TEXT UNIQUE REFERENCES auth.tokens_cache(token_hash) ON DELETE CASCADE NOT NULL,
+  token_hint TEXT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TRIGGER trg_auth_service_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.service_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+INSERT INTO auth.service_tokens (service_id, token_hash, token_hint)
+SELECT (tc.payload ->> 'service_id')::INT, tc.token_hash, ''
+FROM auth.tokens_cache tc
+JOIN auth.services s ON s.id = (tc.payload ->> 'service_id')::INT
+WHERE tc.payload ->> 'token_type' = 'service';
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.service_tokens TO admin;
+GRANT USAGE, SELECT, UPDATE ON SEQUENCE auth.service_tokens_id_seq TO admin;
+
+COMMIT;
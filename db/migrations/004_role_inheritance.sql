@@ -0,0 +1,35 @@
+-- One-time migration for databases created before role inheritance existed.
+-- Adds the role hierarchy table; no links are created, so every role keeps exactly its own
+-- permissions until parents are added. Drops auth.list_role_permissions, whose result gained
+-- columns in later features, so reloading db/procedures.sql after this or any later migration
+-- recreates it.
+--
+--   psql -U postgres -d api_auth -f db/migrations/004_role_inheritance.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Role hierarchy: role_id inherits every permission of parent_role_id (and of its ancestors)
+CREATE TABLE auth.role_parent (
+  id SERIAL PRIMARY KEY,
+  role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  parent_role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (role_id, parent_role_id),
+  CHECK (role_id <> parent_role_id)
+);
+
+CREATE TRIGGER trg_auth_role_parent_audit
+BEFORE INSERT OR UPDATE ON auth.role_parent
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.role_parent TO admin;
+GRANT USAGE, SELECT, UPDATE ON SEQUENCE auth.role_parent_id_seq TO admin;
+
+DROP FUNCTION IF EXISTS auth.list_role_permissions(INT);
+
+COMMIT;
@@ -0,0 +1,50 @@
+-- One-time migration for databases created before deny rules existed.
+-- Adds per-role and per-person deny tables; both start empty, so no decision changes until
+-- rules are added. Reload db/procedures.sql afterwards.
+--
+--   psql -U postgres -d api_auth -f db/migrations/005_deny_rules.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Deny rules always win over grants: per role (inherited through role_parent like grants)...
+CREATE TABLE auth.role_permission_deny (
+  id SERIAL PRIMARY KEY,
+  role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  permission_id INTEGER REFERENCES auth.permission(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (role_id, permission_id)
+);
+
+-- ...or for one person inside one service.
+CREATE TABLE auth.person_service_permission_deny (
+  id SERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  permission_id INTEGER REFERENCES auth.permission(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (person_id, service_id, permission_id)
+);
+
+CREATE TRIGGER trg_auth_role_permission_deny_audit
+BEFORE INSERT OR UPDATE ON auth.role_permission_deny
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_person_service_permission_deny_audit
+BEFORE INSERT OR UPDATE ON auth.person_service_permission_deny
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE
+  ON auth.role_permission_deny, auth.person_service_permission_deny
+  TO admin;
+GRANT USAGE, SELECT, UPDATE
+  ON SEQUENCE auth.role_permission_deny_id_seq, auth.person_service_permission_deny_id_seq
+  TO admin;
+
+COMMIT;
@@ -0,0 +1,28 @@
+-- One-time migration for databases created before role assignments had a validity window.
+-- Adds valid_from/valid_until to auth.person_service_role (existing rows stay permanent) and
+-- the view permission queries read. Reload db/procedures.sql afterwards.
+--
+--   psql -U postgres -d api_auth -f db/migrations/006_role_assignment_windows.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+ALTER TABLE auth.person_service_role
+  ADD COLUMN valid_from BIGINT,
+  ADD COLUMN valid_until BIGINT,
+  ADD CHECK (valid_from IS NULL OR valid_until IS NULL OR valid_until > valid_from);
+
+CREATE VIEW auth.active_person_service_role AS
+SELECT *
+FROM auth.person_service_role
+WHERE (valid_from IS NULL OR valid_from <= EXTRACT(EPOCH FROM NOW())::BIGINT)
+  AND (valid_until IS NULL OR valid_until > EXTRACT(EPOCH FROM NOW())::BIGINT);
+
+-- The procedure gains two optional parameters; drop the old signature so calls stay unambiguous.
+DROP PROCEDURE IF EXISTS auth.assign_role_to_person_in_service(INT, INT, INT);
+
+GRANT SELECT ON auth.active_person_service_role TO admin;
+
+COMMIT;
@@ -0,0 +1,20 @@
+-- One-time migration for databases created before services could require linked roles.
+-- Adds auth.services.strict_roles (off for existing services) and drops the procedure and
+-- function signatures that gained parameters or columns. Reload db/procedures.sql afterwards.
+--
+--   psql -U postgres -d api_auth -f db/migrations/007_strict_service_roles.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+ALTER TABLE auth.services
+  ADD COLUMN strict_roles BOOLEAN NOT NULL DEFAULT FALSE;
+
+DROP FUNCTION IF EXISTS auth.create_service(TEXT, TEXT);
+DROP FUNCTION IF EXISTS auth.list_services();
+DROP PROCEDURE IF EXISTS auth.update_service(INT, TEXT, TEXT);
+DROP PROCEDURE IF EXISTS auth.remove_role_from_service(INT, INT);
+
+COMMIT;
@@ -0,0 +1,27 @@
+-- One-time migration for databases created before permissions could belong to a service.
+-- Adds auth.permission.service_id (existing permissions stay global), makes names unique per
+-- service and drops the function signatures that gained parameters or columns, including the
+-- deny listings from 005_deny_rules.sql. Reload db/procedures.sql afterwards.
+--
+--   psql -U postgres -d api_auth -f db/migrations/008_service_permissions.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+ALTER TABLE auth.permission
+  ADD COLUMN service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE;
+
+ALTER TABLE auth.permission DROP CONSTRAINT permission_name_key;
+ALTER TABLE auth.permission
+  ADD CONSTRAINT permission_name_key UNIQUE NULLS NOT DISTINCT (service_id, name);
+
+DROP FUNCTION IF EXISTS auth.create_permission(TEXT);
+DROP FUNCTION IF EXISTS auth.list_permissions();
+DROP FUNCTION IF EXISTS auth.list_role_permissions(INT);
+DROP FUNCTION IF EXISTS auth.list_role_effective_permissions(INT);
+DROP FUNCTION IF EXISTS auth.list_role_denied_permissions(INT);
+DROP FUNCTION IF EXISTS auth.list_person_denied_permissions_in_service(INT, INT);
+
+COMMIT;
@@ -0,0 +1,79 @@
+-- One-time migration for databases created before tenants existed.
+-- Creates auth.tenant with the default tenant (id 1), moves every existing person, service, role
+-- and permission into it, turns the reserved auth service, its permissions and auth-admin into
+-- shared rows (tenant_id NULL), makes names unique per tenant and drops the function signatures
+-- that gained parameters. Reload db/procedures.sql and db/auth_admin.sql afterwards (the latter
+-- adds tenants.write). Tokens issued before the upgrade count as default-tenant tokens.
+--
+--   psql -U postgres -d api_auth -f db/migrations/009_tenants.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+--   psql -U postgres -d api_auth -f db/auth_admin.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+CREATE TABLE auth.tenant (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  owner_person_id INTEGER REFERENCES auth.person(id) ON DELETE SET NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TRIGGER trg_auth_tenant_audit
+BEFORE INSERT OR UPDATE ON auth.tenant
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+INSERT INTO auth.tenant (name) VALUES ('default');
+
+ALTER TABLE auth.person
+  ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES auth.tenant(id);
+ALTER TABLE auth.services
+  ADD COLUMN tenant_id INTEGER DEFAULT 1 REFERENCES auth.tenant(id);
+ALTER TABLE auth.role
+  ADD COLUMN tenant_id INTEGER DEFAULT 1 REFERENCES auth.tenant(id);
+ALTER TABLE auth.permission
+  ADD COLUMN tenant_id INTEGER DEFAULT 1 REFERENCES auth.tenant(id);
+
+UPDATE auth.services SET tenant_id = NULL WHERE name = 'auth';
+UPDATE auth.role SET tenant_id = NULL WHERE name = 'auth-admin';
+UPDATE auth.permission
+SET tenant_id = NULL
+WHERE service_id IS NULL
+  AND name IN (
+    'users.write',
+    'roles.write',
+    'permissions.write',
+    'relations.write',
+    'services.write'
+  );
+
+ALTER TABLE auth.services DROP CONSTRAINT services_name_key;
+ALTER TABLE auth.services
+  ADD CONSTRAINT services_name_key UNIQUE NULLS NOT DISTINCT (tenant_id, name);
+ALTER TABLE auth.role DROP CONSTRAINT role_name_key;
+ALTER TABLE auth.role
+  ADD CONSTRAINT role_name_key UNIQUE NULLS NOT DISTINCT (tenant_id, name);
+ALTER TABLE auth.permission DROP CONSTRAINT permission_name_key;
+ALTER TABLE auth.permission
+  ADD CONSTRAINT permission_name_key UNIQUE NULLS NOT DISTINCT (tenant_id, service_id, name);
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.tenant TO admin;
+GRANT USAGE, SELECT, UPDATE ON SEQUENCE auth.tenant_id_seq TO admin;
+
+DROP FUNCTION IF EXISTS auth.create_person(
+  TEXT, TEXT, TEXT, auth.person_type, auth.document_type, TEXT
+);
+DROP FUNCTION IF EXISTS auth.list_people();
+DROP FUNCTION IF EXISTS auth.create_role(TEXT);
+DROP FUNCTION IF EXISTS auth.list_roles();
+DROP FUNCTION IF EXISTS auth.create_service(TEXT, TEXT, BOOLEAN);
+DROP FUNCTION IF EXISTS auth.list_services();
+DROP FUNCTION IF EXISTS auth.create_permission(TEXT, INT);
+DROP FUNCTION IF EXISTS auth.list_permissions(INT);
+DROP FUNCTION IF EXISTS auth.list_service_roles(INT);
+DROP FUNCTION IF EXISTS auth.list_persons_with_role_in_service(INT, INT);
+
+COMMIT;
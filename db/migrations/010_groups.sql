@@ -0,0 +1,77 @@
+-- One-time migration for databases created before groups existed.
+-- Adds groups, their members and their per-service roles, plus the effective_person_service_role
+-- view that permission queries now read, and drops the explain function whose result gained the
+-- group columns. Reload db/procedures.sql and db/auth_admin.sql afterwards (the latter adds
+-- groups.write).
+--
+--   psql -U postgres -d api_auth -f db/migrations/010_groups.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+--   psql -U postgres -d api_auth -f db/auth_admin.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+CREATE TABLE auth.groups (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  description TEXT,
+  tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES auth.tenant(id),
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  CONSTRAINT groups_name_key UNIQUE (tenant_id, name)
+);
+
+CREATE TABLE auth.group_member (
+  id SERIAL PRIMARY KEY,
+  group_id INTEGER REFERENCES auth.groups(id) ON DELETE CASCADE NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (group_id, person_id)
+);
+
+CREATE TABLE auth.group_service_role (
+  id SERIAL PRIMARY KEY,
+  group_id INTEGER REFERENCES auth.groups(id) ON DELETE CASCADE NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (group_id, service_id, role_id)
+);
+
+CREATE VIEW auth.effective_person_service_role AS
+SELECT psr.person_id, psr.service_id, psr.role_id, NULL::INTEGER AS group_id
+FROM auth.active_person_service_role psr
+UNION ALL
+SELECT gm.person_id, gsr.service_id, gsr.role_id, gsr.group_id
+FROM auth.group_member gm
+JOIN auth.group_service_role gsr ON gsr.group_id = gm.group_id;
+
+CREATE TRIGGER trg_auth_groups_audit
+BEFORE INSERT OR UPDATE ON auth.groups
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_group_member_audit
+BEFORE INSERT OR UPDATE ON auth.group_member
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_group_service_role_audit
+BEFORE INSERT OR UPDATE ON auth.group_service_role
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE
+  ON auth.groups, auth.group_member, auth.group_service_role
+  TO admin;
+GRANT SELECT ON auth.effective_person_service_role TO admin;
+GRANT USAGE, SELECT, UPDATE
+  ON SEQUENCE auth.groups_id_seq, auth.group_member_id_seq, auth.group_service_role_id_seq
+  TO admin;
+
+DROP FUNCTION IF EXISTS auth.explain_person_permission_grants(INT, INT, TEXT);
+
+COMMIT;
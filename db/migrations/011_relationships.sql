@@ -0,0 +1,80 @@
+-- One-time migration for databases created before relationship-based authorization existed.
+-- Adds per-service relation definitions with their inheritance rules and the relationship tuple
+-- store. Nothing existing changes; reload db/procedures.sql afterwards for the new functions and
+-- db/auth_admin.sql for relationships.write.
+--
+--   psql -U postgres -d api_auth -f db/migrations/011_relationships.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+--   psql -U postgres -d api_auth -f db/auth_admin.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Relationship-based authorization. Each service defines relations per object type...
+CREATE TABLE auth.relation_definition (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  object_type TEXT NOT NULL,
+  relation TEXT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (service_id, object_type, relation)
+);
+
+-- ...and how they are inherited: holders of implied_by on the same object (via_relation NULL),
+-- or on an object the via_relation of this object points to, also hold the defined relation...
+CREATE TABLE auth.relation_rule (
+  id SERIAL PRIMARY KEY,
+  definition_id INTEGER REFERENCES auth.relation_definition(id) ON DELETE CASCADE NOT NULL,
+  implied_by TEXT NOT NULL,
+  via_relation TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE NULLS NOT DISTINCT (definition_id, implied_by, via_relation)
+);
+
+-- ...while tuples record that a subject (type:id, or everyone holding subject_relation on it)
+-- holds a relation on an object.
+CREATE TABLE auth.relation_tuple (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  object_type TEXT NOT NULL,
+  object_id TEXT NOT NULL,
+  relation TEXT NOT NULL,
+  subject_type TEXT NOT NULL,
+  subject_id TEXT NOT NULL,
+  subject_relation TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  CONSTRAINT relation_tuple_key UNIQUE NULLS NOT DISTINCT (
+    service_id, object_type, object_id, relation, subject_type, subject_id, subject_relation
+  )
+);
+
+CREATE TRIGGER trg_auth_relation_definition_audit
+BEFORE INSERT OR UPDATE ON auth.relation_definition
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_relation_rule_audit
+BEFORE INSERT OR UPDATE ON auth.relation_rule
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_relation_tuple_audit
+BEFORE INSERT OR UPDATE ON auth.relation_tuple
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE
+  ON auth.relation_definition, auth.relation_rule, auth.relation_tuple
+  TO admin;
+GRANT USAGE, SELECT, UPDATE
+  ON SEQUENCE
+    auth.relation_definition_id_seq,
+    auth.relation_rule_id_seq,
+    auth.relation_tuple_id_seq
+  TO admin;
+
+COMMIT;
@@ -0,0 +1,34 @@
+-- One-time migration for databases created before condition expressions existed.
+-- Adds the optional condition column to role-permission links and person role assignments,
+-- carries it through the assignment views, and drops the procedures and functions whose
+-- signatures gained it. Reload db/procedures.sql afterwards.
+--
+--   psql -U postgres -d api_auth -f db/migrations/012_conditions.sql
+--   psql -U postgres -d api_auth -f db/procedures.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+ALTER TABLE auth.role_permission ADD COLUMN condition TEXT;
+ALTER TABLE auth.person_service_role ADD COLUMN condition TEXT;
+
+CREATE OR REPLACE VIEW auth.active_person_service_role AS
+SELECT *
+FROM auth.person_service_role
+WHERE (valid_from IS NULL OR valid_from <= EXTRACT(EPOCH FROM NOW())::BIGINT)
+  AND (valid_until IS NULL OR valid_until > EXTRACT(EPOCH FROM NOW())::BIGINT);
+
+CREATE OR REPLACE VIEW auth.effective_person_service_role AS
+SELECT psr.person_id, psr.service_id, psr.role_id, NULL::INTEGER AS group_id, psr.condition
+FROM auth.active_person_service_role psr
+UNION ALL
+SELECT gm.person_id, gsr.service_id, gsr.role_id, gsr.group_id, NULL::TEXT AS condition
+FROM auth.group_member gm
+JOIN auth.group_service_role gsr ON gsr.group_id = gm.group_id;
+
+DROP PROCEDURE IF EXISTS auth.assign_permission_to_role(INT, INT);
+DROP PROCEDURE IF EXISTS auth.assign_role_to_person_in_service(INT, INT, INT, BIGINT, BIGINT);
+DROP FUNCTION IF EXISTS auth.list_role_permissions(INT);
+
+COMMIT;
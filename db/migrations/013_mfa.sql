@@ -0,0 +1,31 @@
+-- One-time migration for databases created before TOTP multi-factor authentication existed.
+-- Adds the MFA state columns to people and the table holding second-step login challenges.
+--
+--   psql -U postgres -d api_auth -f db/migrations/013_mfa.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+ALTER TABLE auth.person ADD COLUMN mfa_secret TEXT;
+ALTER TABLE auth.person ADD COLUMN mfa_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE auth.person ADD COLUMN mfa_last_step BIGINT;
+
+CREATE TABLE auth.mfa_challenges (
+  token_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  payload JSONB NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  expires_at BIGINT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TRIGGER trg_auth_mfa_challenges_audit
+BEFORE INSERT OR UPDATE ON auth.mfa_challenges
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.mfa_challenges TO admin;
+
+COMMIT;
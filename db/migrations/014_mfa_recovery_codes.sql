@@ -0,0 +1,28 @@
+-- One-time migration for databases created before MFA recovery codes existed.
+-- Adds the table holding each person's single-use recovery codes.
+--
+--   psql -U postgres -d api_auth -f db/migrations/014_mfa_recovery_codes.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Single-use MFA recovery codes, bcrypt-hashed like passwords; used_at marks spent ones.
+CREATE TABLE auth.mfa_recovery_codes (
+  id SERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  code_hash TEXT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TRIGGER trg_auth_mfa_recovery_codes_audit
+BEFORE INSERT OR UPDATE ON auth.mfa_recovery_codes
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.mfa_recovery_codes TO admin;
+GRANT USAGE, SELECT, UPDATE ON SEQUENCE auth.mfa_recovery_codes_id_seq TO admin;
+
+COMMIT;
@@ -0,0 +1,29 @@
+-- One-time migration for databases created before login lockout existed.
+-- Adds the table tracking failed logins per username and per client IP.
+--
+--   psql -U postgres -d api_auth -f db/migrations/015_login_attempts.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Failed logins per username and per client IP; locked_until blocks logins while in the future.
+CREATE TABLE auth.login_attempts (
+  scope TEXT NOT NULL CHECK (scope IN ('username', 'ip')),
+  key TEXT NOT NULL,
+  failures INTEGER NOT NULL DEFAULT 0,
+  last_failure_at BIGINT NOT NULL,
+  locked_until BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  PRIMARY KEY (scope, key)
+);
+
+CREATE TRIGGER trg_auth_login_attempts_audit
+BEFORE INSERT OR UPDATE ON auth.login_attempts
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.login_attempts TO admin;
+
+COMMIT;
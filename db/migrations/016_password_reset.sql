@@ -0,0 +1,27 @@
+-- One-time migration for databases created before password resets existed.
+-- Adds the table holding single-use password reset tokens.
+--
+--   psql -U postgres -d api_auth -f db/migrations/016_password_reset.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Single-use password reset tokens, stored hashed like user tokens.
+CREATE TABLE auth.password_reset_tokens (
+  token_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+CREATE TRIGGER trg_auth_password_reset_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.password_reset_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.password_reset_tokens TO admin;
+
+COMMIT;
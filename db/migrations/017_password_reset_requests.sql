@@ -0,0 +1,28 @@
+-- One-time migration for databases created before password reset requests were rate limited.
+-- Adds the table counting reset requests per username and per client IP.
+--
+--   psql -U postgres -d api_auth -f db/migrations/017_password_reset_requests.sql
+
+\set ON_ERROR_STOP on
+
+BEGIN;
+
+-- Password reset requests per username and per client IP within the current window.
+CREATE TABLE auth.password_reset_requests (
+  scope TEXT NOT NULL CHECK (scope IN ('username', 'ip')),
+  key TEXT NOT NULL,
+  requests INTEGER NOT NULL DEFAULT 0,
+  window_start BIGINT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  PRIMARY KEY (scope, key)
+);
+
+CREATE TRIGGER trg_auth_password_reset_requests_audit
+BEFORE INSERT OR UPDATE ON auth.password_reset_requests
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON auth.password_reset_requests TO admin;
+
+COMMIT;
@@ -1,5 +1,60 @@
 -- Procedures and functions for the auth schema
 
+-- Tenant management
+-- Creates a tenant owned by a new legal person, who becomes auth-admin of the tenant.
+CREATE OR REPLACE FUNCTION auth.create_tenant(
+    p_name TEXT,
+    p_owner_username TEXT,
+    p_owner_password_hash TEXT,
+    p_owner_name TEXT,
+    p_owner_document_type auth.document_type,
+    p_owner_document_number TEXT
+)
+RETURNS TABLE(id INT, name TEXT, owner_person_id INT) AS $$
+DECLARE
+    v_tenant_id INT;
+    v_owner_id INT;
+BEGIN
+    INSERT INTO auth.tenant (name)
+    VALUES (p_name)
+    RETURNING auth.tenant.id INTO v_tenant_id;
+
+    INSERT INTO auth.person (
+        username, password_hash, name, person_type, document_type, document_number, tenant_id
+    )
+    VALUES (
+        p_owner_username, p_owner_password_hash, p_owner_name, 'J',
+        p_owner_document_type, p_owner_document_number, v_tenant_id
+    )
+    RETURNING auth.person.id INTO v_owner_id;
+
+    UPDATE auth.tenant
+    SET owner_person_id = v_owner_id
+    WHERE auth.tenant.id = v_tenant_id;
+
+    INSERT INTO auth.person_service_role (person_id, service_id, role_id)
+    SELECT v_owner_id, s.id, r.id
+    FROM auth.services s
+    JOIN auth.role r ON r.name = 'auth-admin' AND r.tenant_id IS NULL
+    WHERE s.name = 'auth' AND s.tenant_id IS NULL;
+
+    RETURN QUERY
+    SELECT t.id, t.name, t.owner_person_id
+    FROM auth.tenant t
+    WHERE t.id = v_tenant_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION auth.list_tenants()
+RETURNS TABLE(id INT, name TEXT, owner_person_id INT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT t.id, t.name, t.owner_person_id
+    FROM auth.tenant t
+    ORDER BY t.id;
+END;
+$$ LANGUAGE plpgsql;
+
 -- Person management
 CREATE OR REPLACE FUNCTION auth.create_person(
     p_username TEXT,
@@ -7,24 +62,32 @@ CREATE OR REPLACE FUNCTION auth.create_person(
     p_name TEXT,
     p_person_type auth.person_type,
     p_document_type auth.document_type,
-    p_document_number TEXT
+    p_document_number TEXT,
+    p_tenant_id INT DEFAULT 1
 )
 RETURNS TABLE(id INT, username TEXT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
-    INSERT INTO auth.person (username, password_hash, name, person_type, document_type, document_number)
-    VALUES (p_username, p_password_hash, p_name, p_person_type, p_document_type, p_document_number)
+    INSERT INTO auth.person (
+        username, password_hash, name, person_type, document_type, document_number, tenant_id
+    )
+    VALUES (
+        p_username, p_password_hash, p_name, p_person_type, p_document_type, p_document_number,
+        p_tenant_id
+    )
     RETURNING auth.person.id, auth.person.username, auth.person.name;
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE FUNCTION auth.list_people()
+-- p_tenant_id NULL lists the people of every tenant.
+CREATE OR REPLACE FUNCTION auth.list_people(p_tenant_id INT DEFAULT NULL)
 RETURNS TABLE(id INT, username TEXT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
     SELECT p.id, p.username, p.name
     FROM auth.person p
-    WHERE p.removed_at IS NULL;
+    WHERE p.removed_at IS NULL
+      AND (p_tenant_id IS NULL OR p.tenant_id = p_tenant_id);
 END;
 $$ LANGUAGE plpgsql;
 
@@ -64,26 +127,32 @@ END;
 $$ LANGUAGE plpgsql;
 
 -- Role management
-CREATE OR REPLACE FUNCTION auth.create_role(p_name TEXT)
+-- Role names are unique per tenant.
+CREATE OR REPLACE FUNCTION auth.create_role(p_name TEXT, p_tenant_id INT DEFAULT 1)
 RETURNS TABLE(id INT, name TEXT) AS $$
 BEGIN
-    INSERT INTO auth.role (name)
-    VALUES (p_name)
+    INSERT INTO auth.role (name, tenant_id)
+    VALUES (p_name, p_tenant_id)
     ON CONFLICT ON CONSTRAINT role_name_key DO NOTHING;
 
     RETURN QUERY
     SELECT r.id, r.name
     FROM auth.role r
-    WHERE r.name = p_name;
+    WHERE r.name = p_name
+      AND r.tenant_id IS NOT DISTINCT FROM p_tenant_id;
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE FUNCTION auth.list_roles()
+-- With a tenant, its own roles plus the shared ones; p_tenant_id NULL lists every role.
+CREATE OR REPLACE FUNCTION auth.list_roles(p_tenant_id INT DEFAULT NULL)
 RETURNS TABLE(id INT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
     SELECT r.id, r.name
-    FROM auth.role r;
+    FROM auth.role r
+    WHERE p_tenant_id IS NULL
+       OR r.tenant_id IS NULL
+       OR r.tenant_id = p_tenant_id;
 END;
 $$ LANGUAGE plpgsql;
 
@@ -107,6 +176,7 @@ BEGIN
         OR NOT EXISTS (
             SELECT 1 FROM auth.role r2
             WHERE r2.name = p_name
+              AND r2.tenant_id IS NOT DISTINCT FROM auth.role.tenant_id
               AND r2.id <> p_id
         )
       );
@@ -120,26 +190,41 @@ END;
 $$ LANGUAGE plpgsql;
 
 -- Permission management
-CREATE OR REPLACE FUNCTION auth.create_permission(p_name TEXT)
-RETURNS TABLE(id INT, name TEXT) AS $$
+-- Names are unique per tenant and service; p_service_id NULL creates a global permission.
+CREATE OR REPLACE FUNCTION auth.create_permission(
+    p_name TEXT,
+    p_service_id INT DEFAULT NULL,
+    p_tenant_id INT DEFAULT 1
+)
+RETURNS TABLE(id INT, name TEXT, service_id INT) AS $$
 BEGIN
-    INSERT INTO auth.permission (name)
-    VALUES (p_name)
+    INSERT INTO auth.permission (name, service_id, tenant_id)
+    VALUES (p_name, p_service_id, p_tenant_id)
     ON CONFLICT ON CONSTRAINT permission_name_key DO NOTHING;
 
     RETURN QUERY
-    SELECT p.id, p.name
+    SELECT p.id, p.name, p.service_id
     FROM auth.permission p
-    WHERE p.name = p_name;
+    WHERE p.name = p_name
+      AND p.service_id IS NOT DISTINCT FROM p_service_id
+      AND p.tenant_id IS NOT DISTINCT FROM p_tenant_id;
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE FUNCTION auth.list_permissions()
-RETURNS TABLE(id INT, name TEXT) AS $$
+-- Without a service lists every permission; with one, its own catalog plus the global permissions.
+-- With a tenant, only its own permissions and the shared ones.
+CREATE OR REPLACE FUNCTION auth.list_permissions(
+    p_service_id INT DEFAULT NULL,
+    p_tenant_id INT DEFAULT NULL
+)
+RETURNS TABLE(id INT, name TEXT, service_id INT) AS $$
 BEGIN
     RETURN QUERY
-    SELECT p.id, p.name
-    FROM auth.permission p;
+    SELECT p.id, p.name, p.service_id
+    FROM auth.permission p
+    WHERE (p_service_id IS NULL OR p.service_id IS NULL OR p.service_id = p_service_id)
+      AND (p_tenant_id IS NULL OR p.tenant_id IS NULL OR p.tenant_id = p_tenant_id)
+    ORDER BY p.id;
 END;
 $$ LANGUAGE plpgsql;
 
@@ -153,6 +238,8 @@ BEGIN
         OR NOT EXISTS (
             SELECT 1 FROM auth.permission p2
             WHERE p2.name = p_name
+              AND p2.service_id IS NOT DISTINCT FROM auth.permission.service_id
+              AND p2.tenant_id IS NOT DISTINCT FROM auth.permission.tenant_id
               AND p2.id <> p_id
         )
       );
@@ -166,46 +253,63 @@ END;
 $$ LANGUAGE plpgsql;
 
 -- Service management
-CREATE OR REPLACE FUNCTION auth.create_service(p_name TEXT, p_description TEXT)
-RETURNS TABLE(id INT, name TEXT, description TEXT) AS $$
+-- Service names are unique per tenant; creating an existing name updates it.
+CREATE OR REPLACE FUNCTION auth.create_service(
+    p_name TEXT,
+    p_description TEXT,
+    p_strict_roles BOOLEAN DEFAULT NULL,
+    p_tenant_id INT DEFAULT 1
+)
+RETURNS TABLE(id INT, name TEXT, description TEXT, strict_roles BOOLEAN) AS $$
 BEGIN
     RETURN QUERY
     WITH upsert AS (
-        INSERT INTO auth.services (name, description)
-        VALUES (p_name, p_description)
+        INSERT INTO auth.services (name, description, strict_roles, tenant_id)
+        VALUES (p_name, p_description, COALESCE(p_strict_roles, FALSE), p_tenant_id)
         ON CONFLICT ON CONSTRAINT services_name_key DO UPDATE
         SET
             description = EXCLUDED.description,
+            strict_roles = COALESCE(p_strict_roles, auth.services.strict_roles),
             updated_at = EXTRACT(EPOCH FROM NOW())::BIGINT
         RETURNING auth.services.id AS id,
                   auth.services.name AS name,
-                  auth.services.description AS description
+                  auth.services.description AS description,
+                  auth.services.strict_roles AS strict_roles
     )
-    SELECT upsert.id, upsert.name, upsert.description FROM upsert
+    SELECT upsert.id, upsert.name, upsert.description, upsert.strict_roles FROM upsert
     UNION ALL
-    SELECT s.id, s.name, s.description
+    SELECT s.id, s.name, s.description, s.strict_roles
     FROM auth.services s
     WHERE s.name = p_name
+      AND s.tenant_id IS NOT DISTINCT FROM p_tenant_id
       AND NOT EXISTS (SELECT 1 FROM upsert);
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE FUNCTION auth.list_services()
-RETURNS TABLE(id INT, name TEXT, description TEXT) AS $$
+-- With a tenant, its own services plus the shared ones; p_tenant_id NULL lists every service.
+CREATE OR REPLACE FUNCTION auth.list_services(p_tenant_id INT DEFAULT NULL)
+RETURNS TABLE(id INT, name TEXT, description TEXT, strict_roles BOOLEAN) AS $$
 BEGIN
     RETURN QUERY
-    SELECT s.id, s.name, s.description
+    SELECT s.id, s.name, s.description, s.strict_roles
     FROM auth.services s
-    WHERE s.status = TRUE;
+    WHERE s.status = TRUE
+      AND (p_tenant_id IS NULL OR s.tenant_id IS NULL OR s.tenant_id = p_tenant_id);
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE PROCEDURE auth.update_service(p_id INT, p_name TEXT, p_description TEXT) AS $$
+CREATE OR REPLACE PROCEDURE auth.update_service(
+    p_id INT,
+    p_name TEXT,
+    p_description TEXT,
+    p_strict_roles BOOLEAN DEFAULT NULL
+) AS $$
 BEGIN
     UPDATE auth.services
     SET
         name = COALESCE(p_name, name),
-        description = COALESCE(p_description, description)
+        description = COALESCE(p_description, description),
+        strict_roles = COALESCE(p_strict_roles, strict_roles)
     WHERE id = p_id;
 END;
 $$ LANGUAGE plpgsql;
@@ -219,14 +323,17 @@ END;
 $$ LANGUAGE plpgsql;
 
 -- Global role-permission relationships
+-- Re-assigning an existing grant replaces its condition.
 CREATE OR REPLACE PROCEDURE auth.assign_permission_to_role(
     p_role_id INT,
-    p_permission_id INT
+    p_permission_id INT,
+    p_condition TEXT DEFAULT NULL
 ) AS $$
 BEGIN
-    INSERT INTO auth.role_permission (role_id, permission_id)
-    VALUES (p_role_id, p_permission_id)
-    ON CONFLICT (role_id, permission_id) DO NOTHING;
+    INSERT INTO auth.role_permission (role_id, permission_id, condition)
+    VALUES (p_role_id, p_permission_id, p_condition)
+    ON CONFLICT (role_id, permission_id)
+    DO UPDATE SET condition = EXCLUDED.condition;
 END;
 $$ LANGUAGE plpgsql;
 
@@ -242,16 +349,190 @@ END;
 $$ LANGUAGE plpgsql;
 
 CREATE OR REPLACE FUNCTION auth.list_role_permissions(p_role_id INT)
-RETURNS TABLE(id INT, name TEXT) AS $$
+RETURNS TABLE(id INT, name TEXT, service_id INT, condition TEXT) AS $$
 BEGIN
     RETURN QUERY
-    SELECT p.id, p.name
+    SELECT p.id, p.name, p.service_id, rp.condition
     FROM auth.permission p
     JOIN auth.role_permission rp ON p.id = rp.permission_id
     WHERE rp.role_id = p_role_id;
 END;
 $$ LANGUAGE plpgsql;
 
+-- Permission names are dot-namespaced; a grant of 'prefix.*' covers every name below 'prefix.'
+-- and '*' covers everything.
+CREATE OR REPLACE FUNCTION auth.permission_matches(p_grant TEXT, p_name TEXT)
+RETURNS BOOLEAN AS $$
+    SELECT p_grant = p_name
+        OR p_grant = '*'
+        OR (
+            right(p_grant, 2) = '.*'
+            AND length(p_name) > length(p_grant) - 1
+            AND starts_with(p_name, left(p_grant, -1))
+        );
+$$ LANGUAGE sql IMMUTABLE;
+
+-- Role hierarchy
+-- Roles in p_role_ids plus every ancestor reachable through role_parent; UNION stops on cycles.
+CREATE OR REPLACE FUNCTION auth.role_ancestors(p_role_ids INT[])
+RETURNS TABLE(role_id INT) AS $$
+    WITH RECURSIVE lineage(role_id) AS (
+        SELECT unnest(p_role_ids)
+        UNION
+        SELECT rp.parent_role_id
+        FROM lineage l
+        JOIN auth.role_parent rp ON rp.role_id = l.role_id
+    )
+    SELECT lineage.role_id FROM lineage;
+$$ LANGUAGE sql STABLE;
+
+-- Returns 'linked', 'role_not_found' or 'role_hierarchy_cycle'.
+CREATE OR REPLACE FUNCTION auth.add_role_parent(p_role_id INT, p_parent_role_id INT)
+RETURNS TEXT AS $$
+BEGIN
+    -- Serialize hierarchy edits so two concurrent links cannot close a cycle together.
+    LOCK TABLE auth.role_parent IN SHARE ROW EXCLUSIVE MODE;
+    IF NOT EXISTS (SELECT 1 FROM auth.role WHERE id = p_role_id)
+        OR NOT EXISTS (SELECT 1 FROM auth.role WHERE id = p_parent_role_id) THEN
+        RETURN 'role_not_found';
+    END IF;
+    IF p_role_id IN (SELECT ra.role_id FROM auth.role_ancestors(ARRAY[p_parent_role_id]) ra) THEN
+        RETURN 'role_hierarchy_cycle';
+    END IF;
+    INSERT INTO auth.role_parent (role_id, parent_role_id)
+    VALUES (p_role_id, p_parent_role_id)
+    ON CONFLICT (role_id, parent_role_id) DO NOTHING;
+    RETURN 'linked';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.remove_role_parent(p_role_id INT, p_parent_role_id INT) AS $$
+BEGIN
+    DELETE FROM auth.role_parent
+    WHERE role_id = p_role_id
+      AND parent_role_id = p_parent_role_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION auth.list_role_parents(p_role_id INT)
+RETURNS TABLE(id INT, name TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT r.id, r.name
+    FROM auth.role r
+    JOIN auth.role_parent rp ON r.id = rp.parent_role_id
+    WHERE rp.role_id = p_role_id
+    ORDER BY r.id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Direct and inherited permissions of a role, with wildcard grants expanded to the names they cover
+-- and anything the role or its ancestors deny left out. A service-scoped grant or deny only reaches
+-- permissions of its own service (and, for grants, global ones).
+CREATE OR REPLACE FUNCTION auth.list_role_effective_permissions(p_role_id INT)
+RETURNS TABLE(id INT, name TEXT, service_id INT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT DISTINCT p.id, p.name, p.service_id
+    FROM auth.role_ancestors(ARRAY[p_role_id]) ra
+    JOIN auth.role_permission rp ON rp.role_id = ra.role_id
+    JOIN auth.permission g ON g.id = rp.permission_id
+    JOIN auth.permission p ON auth.permission_matches(g.name, p.name)
+        AND (g.service_id IS NULL OR p.service_id IS NULL OR p.service_id = g.service_id)
+    WHERE NOT EXISTS (
+        SELECT 1
+        FROM auth.role_ancestors(ARRAY[p_role_id]) da
+        JOIN auth.role_permission_deny rd ON rd.role_id = da.role_id
+        JOIN auth.permission d ON d.id = rd.permission_id
+        WHERE auth.permission_matches(d.name, p.name)
+          AND (d.service_id IS NULL OR d.service_id = p.service_id)
+    )
+    ORDER BY p.id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Deny rules
+CREATE OR REPLACE PROCEDURE auth.deny_permission_to_role(p_role_id INT, p_permission_id INT) AS $$
+BEGIN
+    INSERT INTO auth.role_permission_deny (role_id, permission_id)
+    VALUES (p_role_id, p_permission_id)
+    ON CONFLICT (role_id, permission_id) DO NOTHING;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.remove_permission_deny_from_role(p_role_id INT, p_permission_id INT) AS $$
+BEGIN
+    DELETE FROM auth.role_permission_deny
+    WHERE role_id = p_role_id
+      AND permission_id = p_permission_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION auth.list_role_denied_permissions(p_role_id INT)
+RETURNS TABLE(id INT, name TEXT, service_id INT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT p.id, p.name, p.service_id
+    FROM auth.permission p
+    JOIN auth.role_permission_deny rd ON p.id = rd.permission_id
+    WHERE rd.role_id = p_role_id
+    ORDER BY p.id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.deny_permission_to_person_in_service(
+    p_person_id INT,
+    p_service_id INT,
+    p_permission_id INT
+) AS $$
+BEGIN
+    INSERT INTO auth.person_service_permission_deny (person_id, service_id, permission_id)
+    VALUES (p_person_id, p_service_id, p_permission_id)
+    ON CONFLICT (person_id, service_id, permission_id) DO NOTHING;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.remove_permission_deny_from_person_in_service(
+    p_person_id INT,
+    p_service_id INT,
+    p_permission_id INT
+) AS $$
+BEGIN
+    DELETE FROM auth.person_service_permission_deny
+    WHERE person_id = p_person_id
+      AND service_id = p_service_id
+      AND permission_id = p_permission_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Every deny that applies to a person in a service: their own plus those of their roles (own or
+-- through groups) and ancestors, limited to the service's catalog and the global permissions.
+CREATE OR REPLACE FUNCTION auth.list_person_denied_permissions_in_service(p_person_id INT, p_service_id INT)
+RETURNS TABLE(id INT, name TEXT, service_id INT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT DISTINCT p.id, p.name, p.service_id
+    FROM auth.permission p
+    WHERE (p.service_id IS NULL OR p.service_id = p_service_id)
+      AND p.id IN (
+        SELECT rd.permission_id
+        FROM auth.role_ancestors(ARRAY(
+            SELECT psr.role_id
+            FROM auth.effective_person_service_role psr
+            WHERE psr.person_id = p_person_id
+              AND psr.service_id = p_service_id
+        )) ra
+        JOIN auth.role_permission_deny rd ON rd.role_id = ra.role_id
+        UNION
+        SELECT pd.permission_id
+        FROM auth.person_service_permission_deny pd
+        WHERE pd.person_id = p_person_id
+          AND pd.service_id = p_service_id
+    )
+    ORDER BY p.id;
+END;
+$$ LANGUAGE plpgsql;
+
 -- Service-role relationships
 CREATE OR REPLACE PROCEDURE auth.assign_role_to_service(p_service_id INT, p_role_id INT) AS $$
 BEGIN
@@ -261,8 +542,21 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE PROCEDURE auth.remove_role_from_service(p_service_id INT, p_role_id INT) AS $$
+-- With p_cascade the person and group assignments of that role in the service go too.
+CREATE OR REPLACE PROCEDURE auth.remove_role_from_service(
+    p_service_id INT,
+    p_role_id INT,
+    p_cascade BOOLEAN DEFAULT FALSE
+) AS $$
 BEGIN
+    IF p_cascade THEN
+        DELETE FROM auth.person_service_role
+        WHERE service_id = p_service_id
+          AND role_id = p_role_id;
+        DELETE FROM auth.group_service_role
+        WHERE service_id = p_service_id
+          AND role_id = p_role_id;
+    END IF;
     DELETE FROM auth.service_roles
     WHERE service_id = p_service_id
       AND role_id = p_role_id;
@@ -285,23 +579,38 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE FUNCTION auth.list_service_roles(p_service_id INT)
+-- With a tenant, only roles it can see (its own and the shared ones).
+CREATE OR REPLACE FUNCTION auth.list_service_roles(p_service_id INT, p_tenant_id INT DEFAULT NULL)
 RETURNS TABLE(id INT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
     SELECT r.id, r.name
     FROM auth.role r
     JOIN auth.service_roles sr ON r.id = sr.role_id
-    WHERE sr.service_id = p_service_id;
+    WHERE sr.service_id = p_service_id
+      AND (p_tenant_id IS NULL OR r.tenant_id IS NULL OR r.tenant_id = p_tenant_id);
 END;
 $$ LANGUAGE plpgsql;
 
 -- Person assignments to service roles
-CREATE OR REPLACE PROCEDURE auth.assign_role_to_person_in_service(p_person_id INT, p_service_id INT, p_role_id INT) AS $$
+-- Re-assigning an existing role replaces its validity window and condition.
+CREATE OR REPLACE PROCEDURE auth.assign_role_to_person_in_service(
+    p_person_id INT,
+    p_service_id INT,
+    p_role_id INT,
+    p_valid_from BIGINT DEFAULT NULL,
+    p_valid_until BIGINT DEFAULT NULL,
+    p_condition TEXT DEFAULT NULL
+) AS $$
 BEGIN
-    INSERT INTO auth.person_service_role (person_id, service_id, role_id)
-    VALUES (p_person_id, p_service_id, p_role_id)
-    ON CONFLICT (person_id, service_id, role_id) DO NOTHING;
+    INSERT INTO auth.person_service_role (
+        person_id, service_id, role_id, valid_from, valid_until, condition
+    )
+    VALUES (p_person_id, p_service_id, p_role_id, p_valid_from, p_valid_until, p_condition)
+    ON CONFLICT (person_id, service_id, role_id)
+    DO UPDATE SET valid_from = EXCLUDED.valid_from,
+        valid_until = EXCLUDED.valid_until,
+        condition = EXCLUDED.condition;
 END;
 $$ LANGUAGE plpgsql;
 
@@ -320,36 +629,180 @@ BEGIN
     RETURN QUERY
     SELECT r.id, r.name
     FROM auth.role r
-    JOIN auth.person_service_role psr ON r.id = psr.role_id
+    JOIN auth.active_person_service_role psr ON r.id = psr.role_id
     WHERE psr.person_id = p_person_id
       AND psr.service_id = p_service_id;
 END;
 $$ LANGUAGE plpgsql;
 
-CREATE OR REPLACE FUNCTION auth.list_persons_with_role_in_service(p_service_id INT, p_role_id INT)
+-- With a tenant, only its own people (shared roles are held across tenants).
+CREATE OR REPLACE FUNCTION auth.list_persons_with_role_in_service(
+    p_service_id INT,
+    p_role_id INT,
+    p_tenant_id INT DEFAULT NULL
+)
 RETURNS TABLE(id INT, username TEXT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
     SELECT p.id, p.username, p.name
     FROM auth.person p
-    JOIN auth.person_service_role psr ON p.id = psr.person_id
+    JOIN auth.active_person_service_role psr ON p.id = psr.person_id
     WHERE psr.service_id = p_service_id
       AND psr.role_id = p_role_id
-      AND p.removed_at IS NULL;
+      AND p.removed_at IS NULL
+      AND (p_tenant_id IS NULL OR p.tenant_id = p_tenant_id);
+END;
+$$ LANGUAGE plpgsql;
+
+-- Groups
+-- Group names are unique per tenant; creating an existing name returns that group.
+CREATE OR REPLACE FUNCTION auth.create_group(
+    p_name TEXT,
+    p_description TEXT DEFAULT NULL,
+    p_tenant_id INT DEFAULT 1
+)
+RETURNS TABLE(id INT, name TEXT, description TEXT) AS $$
+BEGIN
+    INSERT INTO auth.groups (name, description, tenant_id)
+    VALUES (p_name, p_description, p_tenant_id)
+    ON CONFLICT ON CONSTRAINT groups_name_key DO NOTHING;
+
+    RETURN QUERY
+    SELECT g.id, g.name, g.description
+    FROM auth.groups g
+    WHERE g.name = p_name
+      AND g.tenant_id = p_tenant_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- p_tenant_id NULL lists the groups of every tenant.
+CREATE OR REPLACE FUNCTION auth.list_groups(p_tenant_id INT DEFAULT NULL)
+RETURNS TABLE(id INT, name TEXT, description TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT g.id, g.name, g.description
+    FROM auth.groups g
+    WHERE p_tenant_id IS NULL OR g.tenant_id = p_tenant_id
+    ORDER BY g.id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION auth.get_group(p_id INT)
+RETURNS TABLE(id INT, name TEXT, description TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT g.id, g.name, g.description
+    FROM auth.groups g
+    WHERE g.id = p_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.update_group(p_id INT, p_name TEXT, p_description TEXT) AS $$
+BEGIN
+    UPDATE auth.groups
+    SET
+        name = COALESCE(p_name, name),
+        description = COALESCE(p_description, description)
+    WHERE id = p_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.delete_group(p_id INT) AS $$
+BEGIN
+    DELETE FROM auth.groups WHERE id = p_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.add_group_member(p_group_id INT, p_person_id INT) AS $$
+BEGIN
+    INSERT INTO auth.group_member (group_id, person_id)
+    VALUES (p_group_id, p_person_id)
+    ON CONFLICT (group_id, person_id) DO NOTHING;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.remove_group_member(p_group_id INT, p_person_id INT) AS $$
+BEGIN
+    DELETE FROM auth.group_member
+    WHERE group_id = p_group_id
+      AND person_id = p_person_id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION auth.list_group_members(p_group_id INT)
+RETURNS TABLE(id INT, username TEXT, name TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT p.id, p.username, p.name
+    FROM auth.person p
+    JOIN auth.group_member gm ON gm.person_id = p.id
+    WHERE gm.group_id = p_group_id
+      AND p.removed_at IS NULL
+    ORDER BY p.id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.assign_role_to_group_in_service(
+    p_group_id INT,
+    p_service_id INT,
+    p_role_id INT
+) AS $$
+BEGIN
+    INSERT INTO auth.group_service_role (group_id, service_id, role_id)
+    VALUES (p_group_id, p_service_id, p_role_id)
+    ON CONFLICT (group_id, service_id, role_id) DO NOTHING;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.remove_role_from_group_in_service(
+    p_group_id INT,
+    p_service_id INT,
+    p_role_id INT
+) AS $$
+BEGIN
+    DELETE FROM auth.group_service_role
+    WHERE group_id = p_group_id
+      AND service_id = p_service_id
+      AND role_id = p_role_id;
 END;
 $$ LANGUAGE plpgsql;
 
+CREATE OR REPLACE FUNCTION auth.list_group_service_roles(p_group_id INT)
+RETURNS TABLE(service_id INT, service_name TEXT, role_id INT, role_name TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT s.id, s.name, r.id, r.name
+    FROM auth.group_service_role gsr
+    JOIN auth.services s ON s.id = gsr.service_id
+    JOIN auth.role r ON r.id = gsr.role_id
+    WHERE gsr.group_id = p_group_id
+    ORDER BY s.id, r.id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Conditions need request context this check does not have, so conditional assignments and
+-- grants never count here.
 CREATE OR REPLACE FUNCTION auth.check_person_permission_in_service(p_person_id INT, p_service_id INT, p_permission_name TEXT)
 RETURNS BOOLEAN AS $$
 BEGIN
     RETURN EXISTS (
         SELECT 1
-        FROM auth.person_service_role psr
-        JOIN auth.role_permission rp ON rp.role_id = psr.role_id
+        FROM auth.role_ancestors(ARRAY(
+            SELECT psr.role_id
+            FROM auth.effective_person_service_role psr
+            WHERE psr.person_id = p_person_id
+              AND psr.service_id = p_service_id
+              AND psr.condition IS NULL
+        )) ra
+        JOIN auth.role_permission rp ON rp.role_id = ra.role_id
         JOIN auth.permission p ON rp.permission_id = p.id
-        WHERE psr.person_id = p_person_id
-          AND psr.service_id = p_service_id
-          AND p.name = p_permission_name
+        WHERE auth.permission_matches(p.name, p_permission_name)
+          AND rp.condition IS NULL
+          AND (p.service_id IS NULL OR p.service_id = p_service_id)
+    ) AND NOT EXISTS (
+        SELECT 1
+        FROM auth.list_person_denied_permissions_in_service(p_person_id, p_service_id) d
+        WHERE auth.permission_matches(d.name, p_permission_name)
     );
 END;
 $$ LANGUAGE plpgsql;
@@ -358,10 +811,379 @@ CREATE OR REPLACE FUNCTION auth.list_services_of_person(p_person_id INT)
 RETURNS TABLE(id INT, name TEXT) AS $$
 BEGIN
     RETURN QUERY
-    SELECT s.id, s.name
+    SELECT DISTINCT s.id, s.name
     FROM auth.services s
-    JOIN auth.person_service_role psr ON s.id = psr.service_id
+    JOIN auth.effective_person_service_role psr ON s.id = psr.service_id
     WHERE psr.person_id = p_person_id
       AND s.status = TRUE;
 END;
 $$ LANGUAGE plpgsql;
+
+-- Relationships
+-- Defines (or redefines) a relation of an object type in a service. p_implied_by and
+-- p_via_relations are parallel arrays: a NULL via means holders of the implied relation on the
+-- same object hold this one, a via relation means holders of the implied relation on the objects
+-- this object points to through via.
+CREATE OR REPLACE FUNCTION auth.define_relation(
+    p_service_id INT,
+    p_object_type TEXT,
+    p_relation TEXT,
+    p_implied_by TEXT[] DEFAULT '{}',
+    p_via_relations TEXT[] DEFAULT '{}'
+)
+RETURNS INT AS $$
+DECLARE
+    v_id INT;
+BEGIN
+    INSERT INTO auth.relation_definition (service_id, object_type, relation)
+    VALUES (p_service_id, p_object_type, p_relation)
+    ON CONFLICT (service_id, object_type, relation) DO UPDATE SET relation = EXCLUDED.relation
+    RETURNING id INTO v_id;
+
+    DELETE FROM auth.relation_rule WHERE definition_id = v_id;
+
+    INSERT INTO auth.relation_rule (definition_id, implied_by, via_relation)
+    SELECT v_id, r.implied_by, r.via_relation
+    FROM unnest(p_implied_by, p_via_relations) AS r(implied_by, via_relation)
+    WHERE r.implied_by IS NOT NULL
+    ON CONFLICT DO NOTHING;
+
+    RETURN v_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- One row per inheritance rule; relations without rules appear once with NULL rule columns.
+CREATE OR REPLACE FUNCTION auth.list_relation_definitions(p_service_id INT)
+RETURNS TABLE(object_type TEXT, relation TEXT, implied_by TEXT, via_relation TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT d.object_type, d.relation, r.implied_by, r.via_relation
+    FROM auth.relation_definition d
+    LEFT JOIN auth.relation_rule r ON r.definition_id = d.id
+    WHERE d.service_id = p_service_id
+    ORDER BY d.object_type, d.relation, r.id;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION auth.relation_defined(
+    p_service_id INT,
+    p_object_type TEXT,
+    p_relation TEXT
+)
+RETURNS BOOLEAN AS $$
+BEGIN
+    RETURN EXISTS (
+        SELECT 1
+        FROM auth.relation_definition d
+        WHERE d.service_id = p_service_id
+          AND d.object_type = p_object_type
+          AND d.relation = p_relation
+    );
+END;
+$$ LANGUAGE plpgsql;
+
+-- Tuples of the deleted relation go with it. Returns FALSE when the relation was not defined.
+CREATE OR REPLACE FUNCTION auth.delete_relation_definition(
+    p_service_id INT,
+    p_object_type TEXT,
+    p_relation TEXT
+)
+RETURNS BOOLEAN AS $$
+BEGIN
+    DELETE FROM auth.relation_tuple
+    WHERE service_id = p_service_id
+      AND object_type = p_object_type
+      AND relation = p_relation;
+
+    DELETE FROM auth.relation_definition
+    WHERE service_id = p_service_id
+      AND object_type = p_object_type
+      AND relation = p_relation;
+
+    RETURN FOUND;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.write_relation_tuple(
+    p_service_id INT,
+    p_object_type TEXT,
+    p_object_id TEXT,
+    p_relation TEXT,
+    p_subject_type TEXT,
+    p_subject_id TEXT,
+    p_subject_relation TEXT DEFAULT NULL
+) AS $$
+BEGIN
+    INSERT INTO auth.relation_tuple (
+        service_id, object_type, object_id, relation, subject_type, subject_id, subject_relation
+    )
+    VALUES (
+        p_service_id,
+        p_object_type,
+        p_object_id,
+        p_relation,
+        p_subject_type,
+        p_subject_id,
+        p_subject_relation
+    )
+    ON CONFLICT ON CONSTRAINT relation_tuple_key DO NOTHING;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE PROCEDURE auth.delete_relation_tuple(
+    p_service_id INT,
+    p_object_type TEXT,
+    p_object_id TEXT,
+    p_relation TEXT,
+    p_subject_type TEXT,
+    p_subject_id TEXT,
+    p_subject_relation TEXT DEFAULT NULL
+) AS $$
+BEGIN
+    DELETE FROM auth.relation_tuple
+    WHERE service_id = p_service_id
+      AND object_type = p_object_type
+      AND object_id = p_object_id
+      AND relation = p_relation
+      AND subject_type = p_subject_type
+      AND subject_id = p_subject_id
+      AND subject_relation IS NOT DISTINCT FROM p_subject_relation;
+END;
+$$ LANGUAGE plpgsql;
+
+-- p_object_type and p_object_id narrow the listing when given.
+CREATE OR REPLACE FUNCTION auth.list_relation_tuples(
+    p_service_id INT,
+    p_object_type TEXT DEFAULT NULL,
+    p_object_id TEXT DEFAULT NULL
+)
+RETURNS TABLE(
+    object_type TEXT,
+    object_id TEXT,
+    relation TEXT,
+    subject_type TEXT,
+    subject_id TEXT,
+    subject_relation TEXT
+) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT
+        t.object_type, t.object_id, t.relation, t.subject_type, t.subject_id, t.subject_relation
+    FROM auth.relation_tuple t
+    WHERE t.service_id = p_service_id
+      AND (p_object_type IS NULL OR t.object_type = p_object_type)
+      AND (p_object_id IS NULL OR t.object_id = p_object_id)
+    ORDER BY t.id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Whether the subject holds the relation on the object: through a direct tuple, through a
+-- userset tuple (everyone holding a relation on another object) or through the relation's
+-- inheritance rules. Each step costs one unit of p_depth; a relation already on the current path
+-- (p_visited) is not followed again, so cyclic definitions or tuples end in FALSE, not a loop.
+CREATE OR REPLACE FUNCTION auth.check_relation(
+    p_service_id INT,
+    p_object_type TEXT,
+    p_object_id TEXT,
+    p_relation TEXT,
+    p_subject_type TEXT,
+    p_subject_id TEXT,
+    p_depth INT DEFAULT 8,
+    p_visited TEXT[] DEFAULT '{}'
+)
+RETURNS BOOLEAN AS $$
+DECLARE
+    v_key TEXT := p_object_type || ':' || p_object_id || '#' || p_relation;
+    v_visited TEXT[];
+    v_tuple RECORD;
+    v_rule RECORD;
+BEGIN
+    IF p_depth <= 0 OR v_key = ANY(p_visited) THEN
+        RETURN FALSE;
+    END IF;
+    v_visited := p_visited || v_key;
+
+    IF EXISTS (
+        SELECT 1
+        FROM auth.relation_tuple t
+        WHERE t.service_id = p_service_id
+          AND t.object_type = p_object_type
+          AND t.object_id = p_object_id
+          AND t.relation = p_relation
+          AND t.subject_type = p_subject_type
+          AND t.subject_id = p_subject_id
+          AND t.subject_relation IS NULL
+    ) THEN
+        RETURN TRUE;
+    END IF;
+
+    FOR v_tuple IN
+        SELECT t.subject_type, t.subject_id, t.subject_relation
+        FROM auth.relation_tuple t
+        WHERE t.service_id = p_service_id
+          AND t.object_type = p_object_type
+          AND t.object_id = p_object_id
+          AND t.relation = p_relation
+          AND t.subject_relation IS NOT NULL
+    LOOP
+        IF auth.check_relation(
+            p_service_id,
+            v_tuple.subject_type,
+            v_tuple.subject_id,
+            v_tuple.subject_relation,
+            p_subject_type,
+            p_subject_id,
+            p_depth - 1,
+            v_visited
+        ) THEN
+            RETURN TRUE;
+        END IF;
+    END LOOP;
+
+    FOR v_rule IN
+        SELECT r.implied_by, r.via_relation
+        FROM auth.relation_definition d
+        JOIN auth.relation_rule r ON r.definition_id = d.id
+        WHERE d.service_id = p_service_id
+          AND d.object_type = p_object_type
+          AND d.relation = p_relation
+        ORDER BY r.id
+    LOOP
+        IF v_rule.via_relation IS NULL THEN
+            IF auth.check_relation(
+                p_service_id,
+                p_object_type,
+                p_object_id,
+                v_rule.implied_by,
+                p_subject_type,
+                p_subject_id,
+                p_depth - 1,
+                v_visited
+            ) THEN
+                RETURN TRUE;
+            END IF;
+        ELSE
+            FOR v_tuple IN
+                SELECT t.subject_type, t.subject_id
+                FROM auth.relation_tuple t
+                WHERE t.service_id = p_service_id
+                  AND t.object_type = p_object_type
+                  AND t.object_id = p_object_id
+                  AND t.relation = v_rule.via_relation
+                  AND t.subject_relation IS NULL
+            LOOP
+                IF auth.check_relation(
+                    p_service_id,
+                    v_tuple.subject_type,
+                    v_tuple.subject_id,
+                    v_rule.implied_by,
+                    p_subject_type,
+                    p_subject_id,
+                    p_depth - 1,
+                    v_visited
+                ) THEN
+                    RETURN TRUE;
+                END IF;
+            END LOOP;
+        END IF;
+    END LOOP;
+
+    RETURN FALSE;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Access explanation
+-- Every assignment of the person in the service (inside its window or not), own or through a group
+-- (group_id set), with the grants matching p_permission_name held by the assigned role or its
+-- ancestors. Assignments without a matching grant appear once with NULL granting columns.
+CREATE OR REPLACE FUNCTION auth.explain_person_permission_grants(p_person_id INT, p_service_id INT, p_permission_name TEXT)
+RETURNS TABLE(
+    assigned_role_id INT,
+    assigned_role_name TEXT,
+    valid_from BIGINT,
+    valid_until BIGINT,
+    assignment_active BOOLEAN,
+    service_linked BOOLEAN,
+    granting_role_id INT,
+    granting_role_name TEXT,
+    granted_permission TEXT,
+    group_id INT,
+    group_name TEXT
+) AS $$
+BEGIN
+    RETURN QUERY
+    WITH assignments AS (
+        SELECT psr.role_id AS role_id,
+            psr.valid_from AS window_from,
+            psr.valid_until AS window_until,
+            EXISTS (SELECT 1 FROM auth.active_person_service_role a WHERE a.id = psr.id) AS active,
+            NULL::INT AS via_group_id
+        FROM auth.person_service_role psr
+        WHERE psr.person_id = p_person_id
+          AND psr.service_id = p_service_id
+        UNION ALL
+        SELECT gsr.role_id, NULL::BIGINT, NULL::BIGINT, TRUE, gsr.group_id
+        FROM auth.group_member gm
+        JOIN auth.group_service_role gsr ON gsr.group_id = gm.group_id
+        WHERE gm.person_id = p_person_id
+          AND gsr.service_id = p_service_id
+    )
+    SELECT asg.role_id,
+        r.name,
+        asg.window_from,
+        asg.window_until,
+        asg.active,
+        EXISTS (
+            SELECT 1
+            FROM auth.service_roles sr
+            WHERE sr.service_id = p_service_id
+              AND sr.role_id = asg.role_id
+        ),
+        g.role_id,
+        g.role_name,
+        g.permission_name,
+        asg.via_group_id,
+        grp.name
+    FROM assignments asg
+    JOIN auth.role r ON r.id = asg.role_id
+    LEFT JOIN auth.groups grp ON grp.id = asg.via_group_id
+    LEFT JOIN LATERAL (
+        SELECT ra.role_id, gr.name AS role_name, p.name AS permission_name
+        FROM auth.role_ancestors(ARRAY[asg.role_id]) ra
+        JOIN auth.role gr ON gr.id = ra.role_id
+        JOIN auth.role_permission rp ON rp.role_id = ra.role_id
+        JOIN auth.permission p ON p.id = rp.permission_id
+        WHERE auth.permission_matches(p.name, p_permission_name)
+          AND (p.service_id IS NULL OR p.service_id = p_service_id)
+    ) g ON TRUE
+    ORDER BY asg.via_group_id NULLS FIRST, asg.role_id, g.role_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Deny rules matching p_permission_name for the person in the service, with where each comes from.
+CREATE OR REPLACE FUNCTION auth.explain_person_permission_denies(p_person_id INT, p_service_id INT, p_permission_name TEXT)
+RETURNS TABLE(source TEXT, role_id INT, role_name TEXT, denied_permission TEXT) AS $$
+BEGIN
+    RETURN QUERY
+    SELECT 'role'::TEXT, r.id, r.name, p.name
+    FROM auth.role_ancestors(ARRAY(
+        SELECT psr.role_id
+        FROM auth.effective_person_service_role psr
+        WHERE psr.person_id = p_person_id
+          AND psr.service_id = p_service_id
+    )) ra
+    JOIN auth.role r ON r.id = ra.role_id
+    JOIN auth.role_permission_deny rd ON rd.role_id = ra.role_id
+    JOIN auth.permission p ON p.id = rd.permission_id
+    WHERE auth.permission_matches(p.name, p_permission_name)
+      AND (p.service_id IS NULL OR p.service_id = p_service_id)
+    UNION ALL
+    SELECT 'person'::TEXT, NULL::INT, NULL::TEXT, p.name
+    FROM auth.person_service_permission_deny pd
+    JOIN auth.permission p ON p.id = pd.permission_id
+    WHERE pd.person_id = p_person_id
+      AND pd.service_id = p_service_id
+      AND auth.permission_matches(p.name, p_permission_name)
+      AND (p.service_id IS NULL OR p.service_id = p_service_id);
+END;
+$$ LANGUAGE plpgsql;
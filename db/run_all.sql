@@ -17,4 +17,7 @@ CREATE DATABASE api_auth;
 \echo 'Loading demo data...'
 \ir demo_data.sql
 
+\echo 'Loading reserved auth service...'
+\ir auth_admin.sql
+
 \echo 'Database setup completed successfully.'
@@ -45,6 +45,19 @@ CREATE TYPE auth.document_type AS ENUM ('DNI', 'CE', 'RUC');
 CREATE TYPE auth.person_type AS ENUM ('N', 'J');
 
 -- Tables
+
+-- Client organizations. Row 1 is the default tenant; its admins also create the other tenants.
+CREATE TABLE auth.tenant (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  -- Legal person (person_type 'J') owning the tenant; NULL for the default tenant.
+  owner_person_id INTEGER,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+INSERT INTO auth.tenant (name) VALUES ('default');
+
 CREATE TABLE auth.person (
   id SERIAL PRIMARY KEY,
   username TEXT NOT NULL UNIQUE,
@@ -57,30 +70,53 @@ CREATE TABLE auth.person (
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   removed_at BIGINT,
+  tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES auth.tenant(id),
+  -- TOTP secret encrypted by the API (AES-256-GCM); pending until mfa_enabled is set.
+  mfa_secret TEXT,
+  mfa_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+  -- Last accepted time step, so a code is never accepted twice.
+  mfa_last_step BIGINT,
   UNIQUE (document_type, document_number)
 );
 
+ALTER TABLE auth.tenant
+  ADD CONSTRAINT tenant_owner_person_id_fkey
+  FOREIGN KEY (owner_person_id) REFERENCES auth.person(id) ON DELETE SET NULL;
+
 CREATE TABLE auth.role (
   id SERIAL PRIMARY KEY,
-  name TEXT NOT NULL UNIQUE,
+  name TEXT NOT NULL,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
-  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  -- NULL marks a shared platform role (auth-admin), visible to every tenant but owned by none.
+  tenant_id INTEGER DEFAULT 1 REFERENCES auth.tenant(id),
+  CONSTRAINT role_name_key UNIQUE NULLS NOT DISTINCT (tenant_id, name)
 );
 
-CREATE TABLE auth.permission (
+CREATE TABLE auth.services (
   id SERIAL PRIMARY KEY,
-  name TEXT NOT NULL UNIQUE,
+  name TEXT NOT NULL,
+  description TEXT,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
-  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  status BOOLEAN NOT NULL DEFAULT TRUE,
+  -- Only roles linked through auth.service_roles may be assigned to people (also forced by STRICT_SERVICE_ROLES).
+  strict_roles BOOLEAN NOT NULL DEFAULT FALSE,
+  -- NULL marks a shared platform service (the reserved auth service).
+  tenant_id INTEGER DEFAULT 1 REFERENCES auth.tenant(id),
+  CONSTRAINT services_name_key UNIQUE NULLS NOT DISTINCT (tenant_id, name)
 );
 
-CREATE TABLE auth.services (
+CREATE TABLE auth.permission (
   id SERIAL PRIMARY KEY,
-  name TEXT NOT NULL UNIQUE,
-  description TEXT,
+  name TEXT NOT NULL,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
-  status BOOLEAN NOT NULL DEFAULT TRUE
+  -- Owning service; NULL keeps the permission global (usable in every service).
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE,
+  -- NULL marks a shared platform permission (the auth API's own *.write permissions).
+  tenant_id INTEGER DEFAULT 1 REFERENCES auth.tenant(id),
+  CONSTRAINT permission_name_key UNIQUE NULLS NOT DISTINCT (tenant_id, service_id, name)
 );
 
 -- Linking Tables
@@ -100,38 +136,255 @@ CREATE TABLE auth.role_permission (
   id SERIAL PRIMARY KEY,
   role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
   permission_id INTEGER REFERENCES auth.permission(id) ON DELETE CASCADE NOT NULL,
+  -- Optional condition expression; the grant only applies while it holds.
+  condition TEXT,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   UNIQUE (role_id, permission_id)
 );
 
+-- Role hierarchy: role_id inherits every permission of parent_role_id (and of its ancestors)
+CREATE TABLE auth.role_parent (
+  id SERIAL PRIMARY KEY,
+  role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  parent_role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (role_id, parent_role_id),
+  CHECK (role_id <> parent_role_id)
+);
+
 -- Person-Service-Roles (as required by API, replaces user's person_role)
 CREATE TABLE auth.person_service_role (
   id SERIAL PRIMARY KEY,
   person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
   service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
   role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  -- Optional validity window (epoch seconds); NULL leaves that side open.
+  valid_from BIGINT,
+  valid_until BIGINT,
+  -- Optional condition expression; the role only applies while it holds.
+  condition TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (person_id, service_id, role_id),
+  CHECK (valid_from IS NULL OR valid_until IS NULL OR valid_until > valid_from)
+);
+
+-- Assignments whose window contains the current time.
+CREATE VIEW auth.active_person_service_role AS
+SELECT *
+FROM auth.person_service_role
+WHERE (valid_from IS NULL OR valid_from <= EXTRACT(EPOCH FROM NOW())::BIGINT)
+  AND (valid_until IS NULL OR valid_until > EXTRACT(EPOCH FROM NOW())::BIGINT);
+
+-- Groups of people in one tenant; members hold every role the group holds in a service.
+CREATE TABLE auth.groups (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  description TEXT,
+  tenant_id INTEGER NOT NULL DEFAULT 1 REFERENCES auth.tenant(id),
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  CONSTRAINT groups_name_key UNIQUE (tenant_id, name)
+);
+
+CREATE TABLE auth.group_member (
+  id SERIAL PRIMARY KEY,
+  group_id INTEGER REFERENCES auth.groups(id) ON DELETE CASCADE NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
-  UNIQUE (person_id, service_id, role_id)
+  UNIQUE (group_id, person_id)
 );
 
+CREATE TABLE auth.group_service_role (
+  id SERIAL PRIMARY KEY,
+  group_id INTEGER REFERENCES auth.groups(id) ON DELETE CASCADE NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (group_id, service_id, role_id)
+);
+
+-- Roles a person holds right now: active own assignments (with their condition) plus those of
+-- their groups (group_id set).
+CREATE VIEW auth.effective_person_service_role AS
+SELECT psr.person_id, psr.service_id, psr.role_id, NULL::INTEGER AS group_id, psr.condition
+FROM auth.active_person_service_role psr
+UNION ALL
+SELECT gm.person_id, gsr.service_id, gsr.role_id, gsr.group_id, NULL::TEXT AS condition
+FROM auth.group_member gm
+JOIN auth.group_service_role gsr ON gsr.group_id = gm.group_id;
+
+-- Deny rules always win over grants: per role (inherited through role_parent like grants)...
+CREATE TABLE auth.role_permission_deny (
+  id SERIAL PRIMARY KEY,
+  role_id INTEGER REFERENCES auth.role(id) ON DELETE CASCADE NOT NULL,
+  permission_id INTEGER REFERENCES auth.permission(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (role_id, permission_id)
+);
+
+-- ...or for one person inside one service.
+CREATE TABLE auth.person_service_permission_deny (
+  id SERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  permission_id INTEGER REFERENCES auth.permission(id) ON DELETE CASCADE NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (person_id, service_id, permission_id)
+);
+
+-- Relationship-based authorization. Each service defines relations per object type...
+CREATE TABLE auth.relation_definition (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  object_type TEXT NOT NULL,
+  relation TEXT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE (service_id, object_type, relation)
+);
+
+-- ...and how they are inherited: holders of implied_by on the same object (via_relation NULL),
+-- or on an object the via_relation of this object points to, also hold the defined relation...
+CREATE TABLE auth.relation_rule (
+  id SERIAL PRIMARY KEY,
+  definition_id INTEGER REFERENCES auth.relation_definition(id) ON DELETE CASCADE NOT NULL,
+  implied_by TEXT NOT NULL,
+  via_relation TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  UNIQUE NULLS NOT DISTINCT (definition_id, implied_by, via_relation)
+);
+
+-- ...while tuples record that a subject (type:id, or everyone holding subject_relation on it)
+-- holds a relation on an object.
+CREATE TABLE auth.relation_tuple (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  object_type TEXT NOT NULL,
+  object_id TEXT NOT NULL,
+  relation TEXT NOT NULL,
+  subject_type TEXT NOT NULL,
+  subject_id TEXT NOT NULL,
+  subject_relation TEXT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  CONSTRAINT relation_tuple_key UNIQUE NULLS NOT DISTINCT (
+    service_id, object_type, object_id, relation, subject_type, subject_id, subject_relation
+  )
+);
+
+-- One row per signed-in device; user and refresh tokens hang off it.
+CREATE TABLE auth.sessions (
+  id SERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  user_agent TEXT,
+  ip TEXT,
+  last_seen BIGINT NOT NULL DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Tokens are stored as HMAC-SHA256(JWT_SECRET, token); the raw value only leaves the API at issue time.
 CREATE TABLE auth.tokens_cache (
-  token TEXT PRIMARY KEY,
+  token_hash TEXT PRIMARY KEY,
+  payload JSONB NOT NULL,
+  expires_at BIGINT NOT NULL,
+  session_id INTEGER REFERENCES auth.sessions(id) ON DELETE CASCADE,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Service tokens by id so they can be listed, revoked and rotated; validation goes through tokens_cache.
+CREATE TABLE auth.service_tokens (
+  id SERIAL PRIMARY KEY,
+  service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
+  token_hash TEXT UNIQUE REFERENCES auth.tokens_cache(token_hash) ON DELETE CASCADE NOT NULL,
+  token_hint TEXT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Single-use refresh tokens; every rotation stays in the session started at login.
+CREATE TABLE auth.refresh_tokens (
+  token_hash TEXT PRIMARY KEY,
+  session_id INTEGER REFERENCES auth.sessions(id) ON DELETE CASCADE NOT NULL,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
   payload JSONB NOT NULL,
   expires_at BIGINT NOT NULL,
+  used_at BIGINT,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
 );
 
+-- Short-lived second-step tokens handed out by a password login when MFA is enabled.
+CREATE TABLE auth.mfa_challenges (
+  token_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  payload JSONB NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  expires_at BIGINT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Single-use MFA recovery codes, bcrypt-hashed like passwords; used_at marks spent ones.
+CREATE TABLE auth.mfa_recovery_codes (
+  id SERIAL PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  code_hash TEXT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Failed logins per username and per client IP; locked_until blocks logins while in the future.
+CREATE TABLE auth.login_attempts (
+  scope TEXT NOT NULL CHECK (scope IN ('username', 'ip')),
+  key TEXT NOT NULL,
+  failures INTEGER NOT NULL DEFAULT 0,
+  last_failure_at BIGINT NOT NULL,
+  locked_until BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  PRIMARY KEY (scope, key)
+);
+
+-- Single-use password reset tokens, stored hashed like user tokens.
+CREATE TABLE auth.password_reset_tokens (
+  token_hash TEXT PRIMARY KEY,
+  person_id INTEGER REFERENCES auth.person(id) ON DELETE CASCADE NOT NULL,
+  expires_at BIGINT NOT NULL,
+  used_at BIGINT,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT
+);
+
+-- Password reset requests per username and per client IP within the current window.
+CREATE TABLE auth.password_reset_requests (
+  scope TEXT NOT NULL CHECK (scope IN ('username', 'ip')),
+  key TEXT NOT NULL,
+  requests INTEGER NOT NULL DEFAULT 0,
+  window_start BIGINT NOT NULL,
+  created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
+  PRIMARY KEY (scope, key)
+);
+
 CREATE TABLE auth.permissions_cache (
-  token TEXT REFERENCES auth.tokens_cache(token) ON DELETE CASCADE NOT NULL,
+  token_hash TEXT REFERENCES auth.tokens_cache(token_hash) ON DELETE CASCADE NOT NULL,
   service_id INTEGER REFERENCES auth.services(id) ON DELETE CASCADE NOT NULL,
   permissions JSONB NOT NULL,
   expires_at BIGINT NOT NULL,
   created_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
   updated_at BIGINT DEFAULT EXTRACT(EPOCH FROM NOW())::BIGINT,
-  PRIMARY KEY (token, service_id)
+  PRIMARY KEY (token_hash, service_id)
 );
 
 CREATE OR REPLACE FUNCTION auth.set_epoch_audit_fields()
@@ -156,6 +409,11 @@ BEGIN
 END;
 $$ LANGUAGE plpgsql;
 
+CREATE TRIGGER trg_auth_tenant_audit
+BEFORE INSERT OR UPDATE ON auth.tenant
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
 CREATE TRIGGER trg_auth_person_audit
 BEFORE INSERT OR UPDATE ON auth.person
 FOR EACH ROW
@@ -166,13 +424,13 @@ BEFORE INSERT OR UPDATE ON auth.role
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
 
-CREATE TRIGGER trg_auth_permission_audit
-BEFORE INSERT OR UPDATE ON auth.permission
+CREATE TRIGGER trg_auth_services_audit
+BEFORE INSERT OR UPDATE ON auth.services
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
 
-CREATE TRIGGER trg_auth_services_audit
-BEFORE INSERT OR UPDATE ON auth.services
+CREATE TRIGGER trg_auth_permission_audit
+BEFORE INSERT OR UPDATE ON auth.permission
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
 
@@ -186,16 +444,101 @@ BEFORE INSERT OR UPDATE ON auth.role_permission
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
 
+CREATE TRIGGER trg_auth_role_parent_audit
+BEFORE INSERT OR UPDATE ON auth.role_parent
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
 CREATE TRIGGER trg_auth_person_service_role_audit
 BEFORE INSERT OR UPDATE ON auth.person_service_role
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
 
+CREATE TRIGGER trg_auth_groups_audit
+BEFORE INSERT OR UPDATE ON auth.groups
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_group_member_audit
+BEFORE INSERT OR UPDATE ON auth.group_member
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_group_service_role_audit
+BEFORE INSERT OR UPDATE ON auth.group_service_role
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_role_permission_deny_audit
+BEFORE INSERT OR UPDATE ON auth.role_permission_deny
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_person_service_permission_deny_audit
+BEFORE INSERT OR UPDATE ON auth.person_service_permission_deny
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_relation_definition_audit
+BEFORE INSERT OR UPDATE ON auth.relation_definition
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_relation_rule_audit
+BEFORE INSERT OR UPDATE ON auth.relation_rule
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_relation_tuple_audit
+BEFORE INSERT OR UPDATE ON auth.relation_tuple
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_sessions_audit
+BEFORE INSERT OR UPDATE ON auth.sessions
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
 CREATE TRIGGER trg_auth_tokens_cache_audit
 BEFORE INSERT OR UPDATE ON auth.tokens_cache
 FOR EACH ROW
 EXECUTE FUNCTION auth.set_epoch_audit_fields();
 
+CREATE TRIGGER trg_auth_service_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.service_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_refresh_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.refresh_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_mfa_challenges_audit
+BEFORE INSERT OR UPDATE ON auth.mfa_challenges
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_mfa_recovery_codes_audit
+BEFORE INSERT OR UPDATE ON auth.mfa_recovery_codes
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_login_attempts_audit
+BEFORE INSERT OR UPDATE ON auth.login_attempts
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_password_reset_tokens_audit
+BEFORE INSERT OR UPDATE ON auth.password_reset_tokens
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
+CREATE TRIGGER trg_auth_password_reset_requests_audit
+BEFORE INSERT OR UPDATE ON auth.password_reset_requests
+FOR EACH ROW
+EXECUTE FUNCTION auth.set_epoch_audit_fields();
+
 CREATE TRIGGER trg_auth_permissions_cache_audit
 BEFORE INSERT OR UPDATE ON auth.permissions_cache
 FOR EACH ROW
@@ -1,28 +1,63 @@
+use hmac::{Hmac, Mac};
 use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::Serialize;
 use serde_json::{Value, json};
 use sha2::{Digest, Sha256};
-use sqlx::{Pool, Postgres};
+use sqlx::{PgConnection, Pool, Postgres, Transaction};
 use std::env;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Wrong codes a login challenge tolerates before it stops working.
+pub const MAX_MFA_ATTEMPTS: i32 = 5;
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct TokenRecord {
-  pub token: String,
+  pub token_hash: String,
   pub payload: Value,
   pub expires_at: i64,
+  pub session_id: Option<i32>,
+  pub created_at: i64,
+  pub session_created_at: Option<i64>,
+}
+
+impl TokenRecord {
+  /// Start of the absolute lifetime. Refreshing issues new rows, so session tokens count from
+  /// when the session began rather than from their own creation.
+  pub fn lifetime_start(&self) -> i64 {
+    self.session_created_at.unwrap_or(self.created_at)
+  }
+
+  pub fn is_service_token(&self) -> bool {
+    self.payload.get("token_type").and_then(Value::as_str) == Some("service")
+  }
 }
 
 #[derive(Debug, Clone)]
 pub struct TokenConfig {
   pub ttl_seconds: i64,
   pub renew_threshold_seconds: i64,
+  pub refresh_ttl_seconds: i64,
+  pub max_lifetime_seconds: i64,
+  pub service_ttl_seconds: i64,
+  pub service_rotation_grace_seconds: i64,
+  pub mfa_challenge_ttl_seconds: i64,
+  pub password_reset_ttl_seconds: i64,
+  pub password_reset_resend_seconds: i64,
 }
 
 impl TokenConfig {
   const DEFAULT_USER_TTL_SECONDS: i64 = 300;
   const DEFAULT_RENEW_THRESHOLD_SECONDS: i64 = 30;
+  const DEFAULT_REFRESH_TTL_SECONDS: i64 = 30 * 24 * 60 * 60;
+  const DEFAULT_MAX_LIFETIME_SECONDS: i64 = 12 * 60 * 60;
+  const DEFAULT_SERVICE_TTL_SECONDS: i64 = 90 * 24 * 60 * 60;
+  const DEFAULT_SERVICE_ROTATION_GRACE_SECONDS: i64 = 60 * 60;
+  const DEFAULT_MFA_CHALLENGE_TTL_SECONDS: i64 = 5 * 60;
+  const DEFAULT_PASSWORD_RESET_TTL_SECONDS: i64 = 15 * 60;
+  const DEFAULT_PASSWORD_RESET_RESEND_SECONDS: i64 = 60;
 
   fn load_env_seconds(key: &str, fallback: i64) -> i64 {
     env::var(key)
@@ -38,9 +73,44 @@ impl TokenConfig {
       "TOKEN_RENEW_THRESHOLD_SECONDS",
       Self::DEFAULT_RENEW_THRESHOLD_SECONDS,
     );
+    let refresh_ttl_seconds = Self::load_env_seconds(
+      "REFRESH_TOKEN_TTL_SECONDS",
+      Self::DEFAULT_REFRESH_TTL_SECONDS,
+    );
+    let max_lifetime_seconds = Self::load_env_seconds(
+      "USER_TOKEN_MAX_LIFETIME_SECONDS",
+      Self::DEFAULT_MAX_LIFETIME_SECONDS,
+    );
+    let service_ttl_seconds = Self::load_env_seconds(
+      "SERVICE_TOKEN_TTL_SECONDS",
+      Self::DEFAULT_SERVICE_TTL_SECONDS,
+    );
+    let service_rotation_grace_seconds = Self::load_env_seconds(
+      "SERVICE_TOKEN_ROTATION_GRACE_SECONDS",
+      Self::DEFAULT_SERVICE_ROTATION_GRACE_SECONDS,
+    );
+    let mfa_challenge_ttl_seconds = Self::load_env_seconds(
+      "MFA_CHALLENGE_TTL_SECONDS",
+      Self::DEFAULT_MFA_CHALLENGE_TTL_SECONDS,
+    );
+    let password_reset_ttl_seconds = Self::load_env_seconds(
+      "PASSWORD_RESET_TTL_SECONDS",
+      Self::DEFAULT_PASSWORD_RESET_TTL_SECONDS,
+    );
+    let password_reset_resend_seconds = Self::load_env_seconds(
+      "PASSWORD_RESET_RESEND_SECONDS",
+      Self::DEFAULT_PASSWORD_RESET_RESEND_SECONDS,
+    );
     Self {
       ttl_seconds,
       renew_threshold_seconds,
+      refresh_ttl_seconds,
+      max_lifetime_seconds,
+      service_ttl_seconds,
+      service_rotation_grace_seconds,
+      mfa_challenge_ttl_seconds,
+      password_reset_ttl_seconds,
+      password_reset_resend_seconds,
     }
   }
 }
@@ -64,17 +134,106 @@ impl From<sqlx::Error> for TokenError {
   }
 }
 
+#[derive(Debug)]
+pub enum RefreshError {
+  NotFound,
+  Expired,
+  /// A rotated-out refresh token was presented again; its session has been revoked.
+  Reused,
+  Database(sqlx::Error),
+}
+
+impl From<sqlx::Error> for RefreshError {
+  fn from(err: sqlx::Error) -> Self {
+    RefreshError::Database(err)
+  }
+}
+
 #[derive(Debug, Serialize)]
 pub struct TokenIssue {
   pub token: String,
   pub expires_at: i64,
 }
 
+/// User token plus the refresh token that can replace it once it expires.
+#[derive(Debug)]
+pub struct SessionIssue {
+  pub session_id: i32,
+  pub token: TokenIssue,
+  pub refresh: TokenIssue,
+  pub payload: Value,
+}
+
+/// Device metadata recorded when a session starts.
+#[derive(Debug, Clone, Default)]
+pub struct SessionMetadata {
+  pub user_agent: Option<String>,
+  pub ip: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SessionRecord {
+  pub id: i32,
+  pub user_agent: Option<String>,
+  pub ip: Option<String>,
+  pub created_at: i64,
+  pub last_seen: i64,
+}
+
+/// Service token as handed out once; `id` is what list, revoke and rotate refer to.
+#[derive(Debug)]
+pub struct ServiceTokenIssue {
+  pub id: i32,
+  pub token: TokenIssue,
+}
+
+/// Redacted view of an active service token: only the last characters of the raw value.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ServiceTokenRecord {
+  pub id: i32,
+  pub token_hint: String,
+  pub created_at: i64,
+  pub expires_at: i64,
+}
+
+/// Outcome of a rotation: the new token plus when the previous ones stop being accepted.
+#[derive(Debug)]
+pub struct ServiceTokenRotation {
+  pub issued: ServiceTokenIssue,
+  pub retired_tokens: u64,
+  pub grace_expires_at: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RefreshTokenRecord {
+  session_id: i32,
+  person_id: i32,
+  payload: Value,
+  session_created_at: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct RefreshTokenState {
+  session_id: i32,
+  person_id: i32,
+  used_at: Option<i64>,
+}
+
+/// A pending second login step: who passed the password check and the payload to issue.
+#[derive(Debug, sqlx::FromRow)]
+pub struct MfaChallenge {
+  pub person_id: i32,
+  pub payload: Value,
+  pub expires_at: i64,
+}
+
 #[derive(Debug)]
 pub struct TokenValidation {
   pub record: TokenRecord,
   pub renewed: bool,
   pub expires_at: i64,
+  /// Hard limit renewals cannot extend past; `None` when no maximum lifetime applies.
+  pub absolute_expires_at: Option<i64>,
 }
 
 impl<'a> TokenManager<'a> {
@@ -94,6 +253,18 @@ impl<'a> TokenManager<'a> {
       .as_secs() as i64
   }
 
+  fn token_secret() -> String {
+    env::var("JWT_SECRET").unwrap_or_else(|_| "local_secret".to_string())
+  }
+
+  /// Keyed digest stored in place of the raw token; only the client keeps the raw value.
+  fn hash_token(token: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(Self::token_secret().as_bytes())
+      .expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+  }
+
   fn generate_token_value(secret: &str, now: i64) -> String {
     let mut random = [0u8; 32];
     OsRng.fill_bytes(&mut random);
@@ -109,45 +280,74 @@ impl<'a> TokenManager<'a> {
 
   async fn insert_token(
     &self,
+    conn: &mut PgConnection,
     token: &str,
     payload: &Value,
     expires_at: i64,
+    session_id: Option<i32>,
   ) -> Result<(), sqlx::Error> {
     sqlx::query(
-      "INSERT INTO auth.tokens_cache (token, payload, expires_at)
-        VALUES ($1, $2, $3)",
+      "INSERT INTO auth.tokens_cache (token_hash, payload, expires_at, session_id)
+        VALUES ($1, $2, $3, $4)",
     )
-    .bind(token)
+    .bind(Self::hash_token(token))
     .bind(payload)
     .bind(expires_at)
-    .execute(self.pool)
+    .bind(session_id)
+    .execute(conn)
     .await?;
     Ok(())
   }
 
-  async fn fetch_token(&self, token: &str) -> Result<Option<TokenRecord>, sqlx::Error> {
+  async fn insert_refresh_token(
+    &self,
+    conn: &mut PgConnection,
+    token: &str,
+    session_id: i32,
+    person_id: i32,
+    payload: &Value,
+    expires_at: i64,
+  ) -> Result<(), sqlx::Error> {
+    sqlx::query(
+      "INSERT INTO auth.refresh_tokens (token_hash, session_id, person_id, payload, expires_at)
+        VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(Self::hash_token(token))
+    .bind(session_id)
+    .bind(person_id)
+    .bind(payload)
+    .bind(expires_at)
+    .execute(conn)
+    .await?;
+    Ok(())
+  }
+
+  async fn fetch_token(&self, token_hash: &str) -> Result<Option<TokenRecord>, sqlx::Error> {
     sqlx::query_as::<_, TokenRecord>(
-      "SELECT token, payload, expires_at FROM auth.tokens_cache WHERE token = $1",
+      "SELECT token_hash, payload, expires_at, session_id, created_at,
+          (SELECT s.created_at FROM auth.sessions s WHERE s.id = session_id) AS session_created_at
+        FROM auth.tokens_cache WHERE token_hash = $1",
     )
-    .bind(token)
+    .bind(token_hash)
     .fetch_optional(self.pool)
     .await
   }
 
   async fn touch_token(
     &self,
-    token: &str,
+    token_hash: &str,
     previous_expires_at: i64,
     new_expires_at: i64,
   ) -> Result<Option<TokenRecord>, sqlx::Error> {
     let updated = sqlx::query_as::<_, TokenRecord>(
       "UPDATE auth.tokens_cache
         SET expires_at = $1
-        WHERE token = $2 AND expires_at = $3
-        RETURNING token, payload, expires_at",
+        WHERE token_hash = $2 AND expires_at = $3
+        RETURNING token_hash, payload, expires_at, session_id, created_at,
+          (SELECT s.created_at FROM auth.sessions s WHERE s.id = session_id) AS session_created_at",
     )
     .bind(new_expires_at)
-    .bind(token)
+    .bind(token_hash)
     .bind(previous_expires_at)
     .fetch_optional(self.pool)
     .await?;
@@ -155,11 +355,43 @@ impl<'a> TokenManager<'a> {
   }
 
   fn compute_expires_at(&self, modified_at: i64) -> i64 {
-    modified_at + self.config.ttl_seconds
+    let absolute_expires_at =
+      Self::absolute_expires_at(modified_at, self.config.max_lifetime_seconds);
+    Self::cap_expires_at(modified_at + self.config.ttl_seconds, absolute_expires_at)
+  }
+
+  fn absolute_expires_at(created_at: i64, max_lifetime_seconds: i64) -> Option<i64> {
+    if max_lifetime_seconds <= 0 {
+      return None;
+    }
+    Some(created_at + max_lifetime_seconds)
+  }
+
+  fn cap_expires_at(expires_at: i64, absolute_expires_at: Option<i64>) -> i64 {
+    match absolute_expires_at {
+      Some(limit) => expires_at.min(limit),
+      None => expires_at,
+    }
+  }
+
+  fn compute_refresh_expires_at(&self, modified_at: i64) -> i64 {
+    modified_at + self.config.refresh_ttl_seconds
   }
 
-  fn compute_service_expires_at(&self, _modified_at: i64) -> i64 {
-    Self::non_expiring_expires_at()
+  fn compute_service_expires_at(&self, modified_at: i64) -> i64 {
+    if self.config.service_ttl_seconds <= 0 {
+      return Self::non_expiring_expires_at();
+    }
+    modified_at + self.config.service_ttl_seconds
+  }
+
+  /// How long a fresh service token lives; `None` when service tokens do not expire.
+  pub fn service_ttl(&self) -> Option<i64> {
+    (self.config.service_ttl_seconds > 0).then_some(self.config.service_ttl_seconds)
+  }
+
+  pub fn service_rotation_grace(&self) -> i64 {
+    self.config.service_rotation_grace_seconds
   }
 
   fn non_expiring_expires_at() -> i64 {
@@ -168,40 +400,584 @@ impl<'a> TokenManager<'a> {
 
   pub async fn issue_token(&self, payload: Value) -> Result<TokenIssue, sqlx::Error> {
     let now = Self::now_epoch();
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "local_secret".to_string());
-    let token = Self::generate_token_value(&secret, now);
+    let token = Self::generate_token_value(&Self::token_secret(), now);
     let expires_at = self.compute_expires_at(now);
-    self.insert_token(&token, &payload, expires_at).await?;
+    let mut conn = self.pool.acquire().await?;
+    self
+      .insert_token(&mut conn, &token, &payload, expires_at, None)
+      .await?;
     Ok(TokenIssue {
       token,
       expires_at,
     })
   }
 
+  /// Issues a narrower token in the same session as `parent`. It keeps the parent's creation
+  /// time, so exchanging can never push a token past its absolute lifetime.
+  pub async fn issue_derived_token(
+    &self,
+    parent: &TokenRecord,
+    payload: Value,
+  ) -> Result<TokenIssue, sqlx::Error> {
+    let now = Self::now_epoch();
+    let token = Self::generate_token_value(&Self::token_secret(), now);
+    let absolute_expires_at =
+      Self::absolute_expires_at(parent.lifetime_start(), self.config.max_lifetime_seconds);
+    let expires_at = Self::cap_expires_at(now + self.config.ttl_seconds, absolute_expires_at);
+    sqlx::query(
+      "INSERT INTO auth.tokens_cache (token_hash, payload, expires_at, session_id, created_at)
+        VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(Self::hash_token(&token))
+    .bind(&payload)
+    .bind(expires_at)
+    .bind(parent.session_id)
+    .bind(parent.created_at)
+    .execute(self.pool)
+    .await?;
+    Ok(TokenIssue {
+      token,
+      expires_at,
+    })
+  }
+
+  async fn create_session(
+    &self,
+    conn: &mut PgConnection,
+    person_id: i32,
+    metadata: &SessionMetadata,
+    now: i64,
+  ) -> Result<(i32, i64), sqlx::Error> {
+    sqlx::query_as::<_, (i32, i64)>(
+      "INSERT INTO auth.sessions (person_id, user_agent, ip, last_seen)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id, created_at",
+    )
+    .bind(person_id)
+    .bind(&metadata.user_agent)
+    .bind(&metadata.ip)
+    .bind(now)
+    .fetch_one(conn)
+    .await
+  }
+
+  async fn touch_session(&self, session_id: i32, now: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE auth.sessions SET last_seen = $2 WHERE id = $1")
+      .bind(session_id)
+      .bind(now)
+      .execute(self.pool)
+      .await?;
+    Ok(())
+  }
+
+  /// Issues a user token and a refresh token for a session that began at `session_created_at`;
+  /// neither outlives the session's absolute lifetime.
+  async fn issue_session_tokens(
+    &self,
+    conn: &mut PgConnection,
+    session_id: i32,
+    session_created_at: i64,
+    person_id: i32,
+    payload: Value,
+  ) -> Result<SessionIssue, sqlx::Error> {
+    let now = Self::now_epoch();
+    let secret = Self::token_secret();
+    let token = Self::generate_token_value(&secret, now);
+    let absolute_expires_at =
+      Self::absolute_expires_at(session_created_at, self.config.max_lifetime_seconds);
+    let expires_at = Self::cap_expires_at(now + self.config.ttl_seconds, absolute_expires_at);
+    self
+      .insert_token(conn, &token, &payload, expires_at, Some(session_id))
+      .await?;
+    let refresh_token = Self::generate_token_value(&secret, now);
+    let refresh_expires_at =
+      Self::cap_expires_at(self.compute_refresh_expires_at(now), absolute_expires_at);
+    self
+      .insert_refresh_token(
+        conn,
+        &refresh_token,
+        session_id,
+        person_id,
+        &payload,
+        refresh_expires_at,
+      )
+      .await?;
+    Ok(SessionIssue {
+      session_id,
+      token: TokenIssue {
+        token,
+        expires_at,
+      },
+      refresh: TokenIssue {
+        token: refresh_token,
+        expires_at: refresh_expires_at,
+      },
+      payload,
+    })
+  }
+
+  /// Starts a new session (one per device) with a user token and its first refresh token.
+  pub async fn issue_user_session(
+    &self,
+    person_id: i32,
+    payload: Value,
+    metadata: &SessionMetadata,
+  ) -> Result<SessionIssue, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+    let (session_id, session_created_at) = self
+      .create_session(&mut tx, person_id, metadata, Self::now_epoch())
+      .await?;
+    let issued = self
+      .issue_session_tokens(&mut tx, session_id, session_created_at, person_id, payload)
+      .await?;
+    tx.commit().await?;
+    Ok(issued)
+  }
+
+  /// Exchanges a refresh token for a new user token and a new refresh token in the same session.
+  /// Each refresh token works once; presenting a used one revokes the whole session. Sessions
+  /// past their absolute lifetime cannot be refreshed.
+  pub async fn refresh_user_session(
+    &self,
+    refresh_token: &str,
+  ) -> Result<SessionIssue, RefreshError> {
+    let token_hash = Self::hash_token(refresh_token);
+    let now = Self::now_epoch();
+    let rotated = sqlx::query_as::<_, RefreshTokenRecord>(
+      "UPDATE auth.refresh_tokens
+        SET used_at = $2
+        WHERE token_hash = $1
+          AND used_at IS NULL
+          AND expires_at > $2
+        RETURNING session_id, person_id, payload,
+          (SELECT s.created_at FROM auth.sessions s WHERE s.id = session_id) AS session_created_at",
+    )
+    .bind(&token_hash)
+    .bind(now)
+    .fetch_optional(self.pool)
+    .await?;
+
+    let record = match rotated {
+      Some(record) => record,
+      None => {
+        let state = sqlx::query_as::<_, RefreshTokenState>(
+          "SELECT session_id, person_id, used_at
+            FROM auth.refresh_tokens WHERE token_hash = $1",
+        )
+        .bind(&token_hash)
+        .fetch_optional(self.pool)
+        .await?;
+        return Err(match state {
+          None => RefreshError::NotFound,
+          Some(state) if state.used_at.is_some() => {
+            self
+              .revoke_session(state.person_id, state.session_id)
+              .await?;
+            RefreshError::Reused
+          }
+          Some(_) => RefreshError::Expired,
+        });
+      }
+    };
+
+    let absolute_expires_at =
+      Self::absolute_expires_at(record.session_created_at, self.config.max_lifetime_seconds);
+    if absolute_expires_at.is_some_and(|limit| self.has_expired(limit, now)) {
+      return Err(RefreshError::Expired);
+    }
+    self.touch_session(record.session_id, now).await?;
+    let mut conn = self.pool.acquire().await?;
+    Ok(
+      self
+        .issue_session_tokens(
+          &mut conn,
+          record.session_id,
+          record.session_created_at,
+          record.person_id,
+          record.payload,
+        )
+        .await?,
+    )
+  }
+
+  pub async fn list_sessions(&self, person_id: i32) -> Result<Vec<SessionRecord>, sqlx::Error> {
+    sqlx::query_as::<_, SessionRecord>(
+      "SELECT id, user_agent, ip, created_at, last_seen
+        FROM auth.sessions
+        WHERE person_id = $1
+        ORDER BY last_seen DESC",
+    )
+    .bind(person_id)
+    .fetch_all(self.pool)
+    .await
+  }
+
+  /// Deletes a session; its user tokens, refresh tokens and access snapshots cascade with it.
+  pub async fn revoke_session(&self, person_id: i32, session_id: i32) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.sessions WHERE id = $1 AND person_id = $2")
+      .bind(session_id)
+      .bind(person_id)
+      .execute(self.pool)
+      .await?
+      .rows_affected();
+    Ok(rows > 0)
+  }
+
+  pub async fn revoke_sessions_for_user(
+    &self,
+    person_id: i32,
+    keep_session_id: Option<i32>,
+  ) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query(
+      "DELETE FROM auth.sessions
+        WHERE person_id = $1 AND ($2::INTEGER IS NULL OR id <> $2)",
+    )
+    .bind(person_id)
+    .bind(keep_session_id)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows)
+  }
+
+  /// Keeps one session after a password change: every other session and sessionless token of
+  /// the person is revoked, and the kept session gets a new user token and refresh token in
+  /// place of its old ones. Tokens from before sessions existed have no session to keep, so a
+  /// new one is started. Runs on the caller's transaction, next to the password update.
+  pub async fn rotate_session(
+    &self,
+    tx: &mut Transaction<'_, Postgres>,
+    person_id: i32,
+    session_id: Option<i32>,
+    payload: Value,
+    metadata: &SessionMetadata,
+  ) -> Result<SessionIssue, sqlx::Error> {
+    let now = Self::now_epoch();
+    let (session_id, session_created_at) = match session_id {
+      Some(session_id) => sqlx::query_as::<_, (i32, i64)>(
+        "UPDATE auth.sessions SET last_seen = $2 WHERE id = $1 RETURNING id, created_at",
+      )
+      .bind(session_id)
+      .bind(now)
+      .fetch_one(&mut **tx)
+      .await?,
+      None => self.create_session(tx, person_id, metadata, now).await?,
+    };
+    sqlx::query("DELETE FROM auth.sessions WHERE person_id = $1 AND id <> $2")
+      .bind(person_id)
+      .bind(session_id)
+      .execute(&mut **tx)
+      .await?;
+    sqlx::query(
+      "DELETE FROM auth.tokens_cache
+        WHERE session_id = $1 OR (session_id IS NULL AND payload ->> 'user_id' = $2)",
+    )
+    .bind(session_id)
+    .bind(person_id.to_string())
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query("DELETE FROM auth.refresh_tokens WHERE session_id = $1")
+      .bind(session_id)
+      .execute(&mut **tx)
+      .await?;
+    self
+      .issue_session_tokens(tx, session_id, session_created_at, person_id, payload)
+      .await
+  }
+
+  /// Issues the short-lived token a password login returns when the person has MFA enabled.
+  pub async fn issue_mfa_challenge(
+    &self,
+    person_id: i32,
+    payload: Value,
+  ) -> Result<TokenIssue, sqlx::Error> {
+    let now = Self::now_epoch();
+    let token = Self::generate_token_value(&Self::token_secret(), now);
+    let expires_at = now + self.config.mfa_challenge_ttl_seconds;
+    sqlx::query(
+      "INSERT INTO auth.mfa_challenges (token_hash, person_id, payload, expires_at)
+        VALUES ($1, $2, $3, $4)",
+    )
+    .bind(Self::hash_token(&token))
+    .bind(person_id)
+    .bind(&payload)
+    .bind(expires_at)
+    .execute(self.pool)
+    .await?;
+    Ok(TokenIssue { token, expires_at })
+  }
+
+  /// Looks a challenge up without consuming it; spent challenges read as not found.
+  pub async fn load_mfa_challenge(&self, token: &str) -> Result<MfaChallenge, TokenError> {
+    let challenge = sqlx::query_as::<_, MfaChallenge>(
+      "SELECT person_id, payload, expires_at
+        FROM auth.mfa_challenges
+        WHERE token_hash = $1 AND attempts < $2",
+    )
+    .bind(Self::hash_token(token))
+    .bind(MAX_MFA_ATTEMPTS)
+    .fetch_optional(self.pool)
+    .await?
+    .ok_or(TokenError::NotFound)?;
+    if self.has_expired(challenge.expires_at, Self::now_epoch()) {
+      return Err(TokenError::Expired);
+    }
+    Ok(challenge)
+  }
+
+  /// Counts a wrong code against the challenge; it stops working after `MAX_MFA_ATTEMPTS`.
+  pub async fn fail_mfa_challenge(&self, token: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+      "UPDATE auth.mfa_challenges
+        SET attempts = attempts + 1
+        WHERE token_hash = $1",
+    )
+    .bind(Self::hash_token(token))
+    .execute(self.pool)
+    .await?;
+    Ok(())
+  }
+
+  /// Deletes the challenge once its code is accepted; false if another request got there first.
+  pub async fn consume_mfa_challenge(&self, token: &str) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query(
+      "DELETE FROM auth.mfa_challenges
+        WHERE token_hash = $1 AND attempts < $2",
+    )
+    .bind(Self::hash_token(token))
+    .bind(MAX_MFA_ATTEMPTS)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows > 0)
+  }
+
+  /// Issues a single-use password reset token; earlier unused ones of the person stop working.
+  /// `None` while a live token is younger than the resend interval: that one stays valid, so
+  /// repeated requests cannot keep invalidating the token the person is about to use.
+  pub async fn issue_password_reset(
+    &self,
+    person_id: i32,
+  ) -> Result<Option<TokenIssue>, sqlx::Error> {
+    let now = Self::now_epoch();
+    let token = Self::generate_token_value(&Self::token_secret(), now);
+    let expires_at = now + self.config.password_reset_ttl_seconds;
+    let mut tx = self.pool.begin().await?;
+    let recent = sqlx::query_scalar::<_, bool>(
+      "SELECT EXISTS (
+        SELECT 1 FROM auth.password_reset_tokens
+          WHERE person_id = $1 AND used_at IS NULL AND expires_at > $2 AND created_at > $2 - $3
+      )",
+    )
+    .bind(person_id)
+    .bind(now)
+    .bind(self.config.password_reset_resend_seconds)
+    .fetch_one(&mut *tx)
+    .await?;
+    if recent {
+      return Ok(None);
+    }
+    sqlx::query("DELETE FROM auth.password_reset_tokens WHERE person_id = $1")
+      .bind(person_id)
+      .execute(&mut *tx)
+      .await?;
+    sqlx::query(
+      "INSERT INTO auth.password_reset_tokens (token_hash, person_id, expires_at)
+        VALUES ($1, $2, $3)",
+    )
+    .bind(Self::hash_token(&token))
+    .bind(person_id)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(TokenIssue { token, expires_at }))
+  }
+
+  /// Person a reset token belongs to, without spending it; used and unknown tokens are
+  /// `NotFound`.
+  pub async fn find_password_reset(&self, token: &str) -> Result<i32, TokenError> {
+    let (person_id, expires_at) = sqlx::query_as::<_, (i32, i64)>(
+      "SELECT person_id, expires_at FROM auth.password_reset_tokens
+        WHERE token_hash = $1 AND used_at IS NULL",
+    )
+    .bind(Self::hash_token(token))
+    .fetch_optional(self.pool)
+    .await?
+    .ok_or(TokenError::NotFound)?;
+    if self.has_expired(expires_at, Self::now_epoch()) {
+      return Err(TokenError::Expired);
+    }
+    Ok(person_id)
+  }
+
+  /// Marks a reset token used and sets its person's new password in one transaction, so the
+  /// token is only spent when the password actually changes. `NotFound` if another request
+  /// used it first or it expired meanwhile.
+  pub async fn consume_password_reset(
+    &self,
+    token: &str,
+    password_hash: &str,
+  ) -> Result<i32, TokenError> {
+    let mut tx = self.pool.begin().await?;
+    let person_id = sqlx::query_scalar::<_, i32>(
+      "UPDATE auth.password_reset_tokens
+        SET used_at = $2
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > $2
+        RETURNING person_id",
+    )
+    .bind(Self::hash_token(token))
+    .bind(Self::now_epoch())
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(TokenError::NotFound)?;
+    sqlx::query("UPDATE auth.person SET password_hash = $2 WHERE id = $1")
+      .bind(person_id)
+      .bind(password_hash)
+      .execute(&mut *tx)
+      .await?;
+    tx.commit().await?;
+    Ok(person_id)
+  }
+
   pub async fn issue_service_token(
     &self,
     service_id: i32,
     service_name: &str,
-  ) -> Result<TokenIssue, sqlx::Error> {
+  ) -> Result<ServiceTokenIssue, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+    let issued = self
+      .insert_service_token(&mut tx, service_id, service_name)
+      .await?;
+    tx.commit().await?;
+    Ok(issued)
+  }
+
+  /// Writes a service token to `tokens_cache` and `service_tokens` on the caller's transaction,
+  /// so neither row can exist without the other.
+  async fn insert_service_token(
+    &self,
+    conn: &mut PgConnection,
+    service_id: i32,
+    service_name: &str,
+  ) -> Result<ServiceTokenIssue, sqlx::Error> {
     let now = Self::now_epoch();
-    let secret = env::var("JWT_SECRET").unwrap_or_else(|_| "local_secret".to_string());
-    let token = Self::generate_token_value(&secret, now);
+    let token = Self::generate_token_value(&Self::token_secret(), now);
     let payload = json!({
       "service_id": service_id,
       "service_name": service_name,
       "token_type": "service",
     });
     let expires_at = self.compute_service_expires_at(now);
-    self.insert_token(&token, &payload, expires_at).await?;
-    Ok(TokenIssue {
-      token,
-      expires_at,
+    sqlx::query(
+      "INSERT INTO auth.tokens_cache (token_hash, payload, expires_at)
+        VALUES ($1, $2, $3)",
+    )
+    .bind(Self::hash_token(&token))
+    .bind(&payload)
+    .bind(expires_at)
+    .execute(&mut *conn)
+    .await?;
+    let token_hint = token[token.len().saturating_sub(4)..].to_string();
+    let id = sqlx::query_scalar::<_, i32>(
+      "INSERT INTO auth.service_tokens (service_id, token_hash, token_hint)
+        VALUES ($1, $2, $3)
+        RETURNING id",
+    )
+    .bind(service_id)
+    .bind(Self::hash_token(&token))
+    .bind(token_hint)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(ServiceTokenIssue {
+      id,
+      token: TokenIssue {
+        token,
+        expires_at,
+      },
+    })
+  }
+
+  pub async fn list_service_tokens(
+    &self,
+    service_id: i32,
+  ) -> Result<Vec<ServiceTokenRecord>, sqlx::Error> {
+    sqlx::query_as::<_, ServiceTokenRecord>(
+      "SELECT st.id, st.token_hint, st.created_at, tc.expires_at
+        FROM auth.service_tokens st
+        JOIN auth.tokens_cache tc ON tc.token_hash = st.token_hash
+        WHERE st.service_id = $1 AND tc.expires_at > $2
+        ORDER BY st.id",
+    )
+    .bind(service_id)
+    .bind(Self::now_epoch())
+    .fetch_all(self.pool)
+    .await
+  }
+
+  /// Deleting the cached token cascades to its `service_tokens` row and permission snapshots.
+  pub async fn revoke_service_token(
+    &self,
+    service_id: i32,
+    token_id: i32,
+  ) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query(
+      "DELETE FROM auth.tokens_cache
+        WHERE token_hash = (
+          SELECT token_hash FROM auth.service_tokens WHERE id = $1 AND service_id = $2
+        )",
+    )
+    .bind(token_id)
+    .bind(service_id)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows > 0)
+  }
+
+  /// Issues a new token and shortens every other token of the service to `now + grace_seconds`,
+  /// so callers can switch over without downtime. Both happen in one transaction: a failed
+  /// rotation leaves the old tokens as they were and no new one behind.
+  pub async fn rotate_service_token(
+    &self,
+    service_id: i32,
+    service_name: &str,
+    grace_seconds: i64,
+  ) -> Result<ServiceTokenRotation, sqlx::Error> {
+    let mut tx = self.pool.begin().await?;
+    let issued = self
+      .insert_service_token(&mut tx, service_id, service_name)
+      .await?;
+    let grace_expires_at = Self::now_epoch().saturating_add(grace_seconds);
+    let retired_tokens = sqlx::query(
+      "UPDATE auth.tokens_cache
+        SET expires_at = $3
+        WHERE expires_at > $3
+          AND token_hash IN (
+            SELECT token_hash FROM auth.service_tokens WHERE service_id = $1 AND id <> $2
+          )",
+    )
+    .bind(service_id)
+    .bind(issued.id)
+    .bind(grace_expires_at)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    tx.commit().await?;
+    Ok(ServiceTokenRotation {
+      issued,
+      retired_tokens,
+      grace_expires_at,
     })
   }
 
   pub async fn delete_token(&self, token: &str) -> Result<bool, sqlx::Error> {
-    let rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE token = $1")
-      .bind(token)
+    self.delete_token_hash(&Self::hash_token(token)).await
+  }
+
+  async fn delete_token_hash(&self, token_hash: &str) -> Result<bool, sqlx::Error> {
+    let rows = sqlx::query("DELETE FROM auth.tokens_cache WHERE token_hash = $1")
+      .bind(token_hash)
       .execute(self.pool)
       .await?
       .rows_affected();
@@ -214,6 +990,7 @@ impl<'a> TokenManager<'a> {
       .execute(self.pool)
       .await?
       .rows_affected();
+    self.revoke_sessions_for_user(user_id, None).await?;
     Ok(rows)
   }
 
@@ -224,8 +1001,8 @@ impl<'a> TokenManager<'a> {
   ) -> Result<u64, sqlx::Error> {
     let rows = sqlx::query(
       "DELETE FROM auth.permissions_cache
-        WHERE token IN (
-          SELECT token FROM auth.tokens_cache WHERE payload ->> 'user_id' = $1
+        WHERE token_hash IN (
+          SELECT token_hash FROM auth.tokens_cache WHERE payload ->> 'user_id' = $1
         ) AND service_id = $2",
     )
     .bind(user_id.to_string())
@@ -239,8 +1016,8 @@ impl<'a> TokenManager<'a> {
   pub async fn delete_access_cache_for_user(&self, user_id: i32) -> Result<u64, sqlx::Error> {
     let rows = sqlx::query(
       "DELETE FROM auth.permissions_cache
-        WHERE token IN (
-          SELECT token FROM auth.tokens_cache WHERE payload ->> 'user_id' = $1
+        WHERE token_hash IN (
+          SELECT token_hash FROM auth.tokens_cache WHERE payload ->> 'user_id' = $1
         )",
     )
       .bind(user_id.to_string())
@@ -250,6 +1027,29 @@ impl<'a> TokenManager<'a> {
     Ok(rows)
   }
 
+  /// Drops the snapshots of every member of a group, in one service or (`None`) in all of them.
+  pub async fn delete_access_cache_for_group(
+    &self,
+    group_id: i32,
+    service_id: Option<i32>,
+  ) -> Result<u64, sqlx::Error> {
+    let rows = sqlx::query(
+      "DELETE FROM auth.permissions_cache
+        WHERE token_hash IN (
+          SELECT tc.token_hash
+          FROM auth.tokens_cache tc
+          JOIN auth.group_member gm ON tc.payload ->> 'user_id' = gm.person_id::TEXT
+          WHERE gm.group_id = $1
+        ) AND ($2::INT IS NULL OR service_id = $2)",
+    )
+    .bind(group_id)
+    .bind(service_id)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows)
+  }
+
   pub async fn delete_access_cache_for_service(
     &self,
     service_id: i32,
@@ -278,9 +1078,9 @@ impl<'a> TokenManager<'a> {
     sqlx::query_as::<_, AccessCacheRecord>(
       "SELECT permissions AS access_json, expires_at
         FROM auth.permissions_cache
-        WHERE token = $1 AND service_id = $2",
+        WHERE token_hash = $1 AND service_id = $2",
     )
-    .bind(token)
+    .bind(Self::hash_token(token))
     .bind(service_id)
     .fetch_optional(self.pool)
     .await
@@ -295,13 +1095,13 @@ impl<'a> TokenManager<'a> {
   ) -> Result<(), sqlx::Error> {
     sqlx::query(
       "INSERT INTO auth.permissions_cache
-        (token, service_id, permissions, expires_at)
+        (token_hash, service_id, permissions, expires_at)
         VALUES ($1, $2, $3, $4)
-        ON CONFLICT (token, service_id)
+        ON CONFLICT (token_hash, service_id)
         DO UPDATE SET permissions = EXCLUDED.permissions,
           expires_at = EXCLUDED.expires_at",
     )
-    .bind(token)
+    .bind(Self::hash_token(token))
     .bind(service_id)
     .bind(access_json)
     .bind(expires_at)
@@ -310,6 +1110,47 @@ impl<'a> TokenManager<'a> {
     Ok(())
   }
 
+  /// Deletes role assignments whose window has closed and drops every snapshot built before a
+  /// window opened or closed, so the next check rebuilds it. Returns the removed assignments.
+  pub async fn expire_role_assignments(&self) -> Result<u64, sqlx::Error> {
+    let now = Self::now_epoch();
+    let mut tx = self.pool.begin().await?;
+    let expired: Vec<(i32, i32)> = sqlx::query_as(
+      "DELETE FROM auth.person_service_role
+        WHERE valid_until <= $1
+        RETURNING person_id, service_id",
+    )
+    .bind(now)
+    .fetch_all(&mut *tx)
+    .await?;
+    let (person_ids, service_ids): (Vec<i32>, Vec<i32>) = expired.iter().copied().unzip();
+    sqlx::query(
+      "DELETE FROM auth.permissions_cache pc
+        USING auth.tokens_cache tc, unnest($1::INT[], $2::INT[]) AS closed(person_id, service_id)
+        WHERE pc.token_hash = tc.token_hash
+          AND tc.payload ->> 'user_id' = closed.person_id::TEXT
+          AND pc.service_id = closed.service_id",
+    )
+    .bind(&person_ids)
+    .bind(&service_ids)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+      "DELETE FROM auth.permissions_cache pc
+        USING auth.tokens_cache tc, auth.person_service_role psr
+        WHERE pc.token_hash = tc.token_hash
+          AND tc.payload ->> 'user_id' = psr.person_id::TEXT
+          AND pc.service_id = psr.service_id
+          AND psr.valid_from <= $1
+          AND pc.updated_at < psr.valid_from",
+    )
+    .bind(now)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(expired.len() as u64)
+  }
+
   pub async fn cleanup_expired(&self) -> Result<u64, sqlx::Error> {
     let now = Self::now_epoch();
     let rows = sqlx::query(
@@ -328,7 +1169,42 @@ impl<'a> TokenManager<'a> {
     .execute(self.pool)
     .await?
     .rows_affected();
-    Ok(rows + permissions_rows)
+    let refresh_rows = sqlx::query(
+      "DELETE FROM auth.refresh_tokens
+        WHERE expires_at < $1",
+    )
+    .bind(now)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    let challenge_rows = sqlx::query(
+      "DELETE FROM auth.mfa_challenges
+        WHERE expires_at < $1 OR attempts >= $2",
+    )
+    .bind(now)
+    .bind(MAX_MFA_ATTEMPTS)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    let reset_rows = sqlx::query(
+      "DELETE FROM auth.password_reset_tokens
+        WHERE expires_at < $1",
+    )
+    .bind(now)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    // Sessions untouched for longer than any token they could hold are dead.
+    let idle_limit = now - self.config.refresh_ttl_seconds.max(self.config.ttl_seconds);
+    let session_rows = sqlx::query(
+      "DELETE FROM auth.sessions
+        WHERE last_seen < $1",
+    )
+    .bind(idle_limit)
+    .execute(self.pool)
+    .await?
+    .rows_affected();
+    Ok(rows + permissions_rows + refresh_rows + challenge_rows + reset_rows + session_rows)
   }
 
   fn has_expired(&self, expires_at: i64, now: i64) -> bool {
@@ -342,37 +1218,55 @@ impl<'a> TokenManager<'a> {
     expires_at - now <= self.config.renew_threshold_seconds
   }
 
+  /// `user_token` is set on the user path, where service tokens (stored alongside user tokens)
+  /// read as not found instead of being held to the user token lifetime.
   async fn validate_token_with_ttl(
     &self,
     token: &str,
+    user_token: bool,
     renew_if_needed: bool,
     ttl_seconds: i64,
+    max_lifetime_seconds: i64,
   ) -> Result<TokenValidation, TokenError> {
-    let mut record = match self.fetch_token(token).await? {
+    let token_hash = Self::hash_token(token);
+    let mut record = match self.fetch_token(&token_hash).await? {
       Some(rec) => rec,
       None => return Err(TokenError::NotFound),
     };
+    if user_token && record.is_service_token() {
+      return Err(TokenError::NotFound);
+    }
     let now = Self::now_epoch();
-    if self.has_expired(record.expires_at, now) {
-      let _ = self.delete_token(token).await;
+    let absolute_expires_at =
+      Self::absolute_expires_at(record.lifetime_start(), max_lifetime_seconds);
+    let past_absolute_limit =
+      absolute_expires_at.is_some_and(|limit| self.has_expired(limit, now));
+    if self.has_expired(record.expires_at, now) || past_absolute_limit {
+      let _ = self.delete_token_hash(&token_hash).await;
       return Err(TokenError::Expired);
     }
 
     let mut renewed = false;
-    if renew_if_needed && self.should_renew(record.expires_at, now) {
-      let new_expires_at = now + ttl_seconds;
+    let new_expires_at = Self::cap_expires_at(now + ttl_seconds, absolute_expires_at);
+    if renew_if_needed
+      && self.should_renew(record.expires_at, now)
+      && new_expires_at > record.expires_at
+    {
       match self
-        .touch_token(token, record.expires_at, new_expires_at)
+        .touch_token(&token_hash, record.expires_at, new_expires_at)
         .await?
       {
         Some(updated) => {
           record = updated;
           renewed = true;
+          if let Some(session_id) = record.session_id {
+            self.touch_session(session_id, now).await?;
+          }
         }
         None => {
-          if let Some(updated) = self.fetch_token(token).await? {
+          if let Some(updated) = self.fetch_token(&token_hash).await? {
             if self.has_expired(updated.expires_at, now) {
-              let _ = self.delete_token(token).await;
+              let _ = self.delete_token_hash(&token_hash).await;
               return Err(TokenError::Expired);
             }
             record = updated;
@@ -388,6 +1282,7 @@ impl<'a> TokenManager<'a> {
       record,
       renewed,
       expires_at,
+      absolute_expires_at,
     })
   }
 
@@ -397,7 +1292,13 @@ impl<'a> TokenManager<'a> {
     renew_if_needed: bool,
   ) -> Result<TokenValidation, TokenError> {
     self
-      .validate_token_with_ttl(token, renew_if_needed, self.config.ttl_seconds)
+      .validate_token_with_ttl(
+        token,
+        true,
+        renew_if_needed,
+        self.config.ttl_seconds,
+        self.config.max_lifetime_seconds,
+      )
       .await
   }
 
@@ -405,7 +1306,7 @@ impl<'a> TokenManager<'a> {
     &self,
     token: &str,
   ) -> Result<TokenValidation, TokenError> {
-    self.validate_token_with_ttl(token, false, 0).await
+    self.validate_token_with_ttl(token, false, false, 0, 0).await
   }
 }
 
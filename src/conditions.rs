@@ -0,0 +1,538 @@
+use serde_json::{Map, Value as JsonValue};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Longest accepted source, in bytes.
+pub const MAX_CONDITION_LENGTH: usize = 512;
+/// Deepest accepted nesting of `!`, parentheses and lists.
+const MAX_NESTING: usize = 16;
+
+const TIME_FIELDS: [&str; 4] = ["hour", "minute", "weekday", "epoch"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConditionError(String);
+
+impl fmt::Display for ConditionError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for ConditionError {}
+
+fn error(message: impl Into<String>) -> ConditionError {
+  ConditionError(message.into())
+}
+
+/// What a condition is evaluated against.
+#[derive(Debug, Clone, Default)]
+pub struct ConditionContext {
+  /// Evaluation time, epoch seconds.
+  pub now: i64,
+  pub ip: Option<IpAddr>,
+  /// Header values keyed by lowercase name.
+  pub headers: HashMap<String, String>,
+  pub user: Map<String, JsonValue>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+  Str(String),
+  Int(i64),
+  Bool(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+  Var(String),
+  Literal(Literal),
+  List(Vec<Literal>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  In,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+  Or(Box<Expr>, Box<Expr>),
+  And(Box<Expr>, Box<Expr>),
+  Not(Box<Expr>),
+  Compare(Operand, CompareOp, Operand),
+  /// A lone operand: true only for `true` or a variable holding `true`.
+  Truthy(Operand),
+}
+
+/// A parsed condition, ready to be evaluated any number of times. Conditions are boolean
+/// expressions over the request context, such as
+/// `time.hour >= 9 && time.hour < 18 && ip in ["10.0.0.0/8"]`:
+///
+/// - variables: `time.hour`, `time.minute`, `time.weekday` (1 = Monday … 7 = Sunday) and
+///   `time.epoch`, all UTC; `ip`; `header.<name>` (lowercase); `user.<key>`;
+/// - literals: strings, integers, `true`, `false` and lists of those;
+/// - operators: `==`, `!=`, `<`, `<=`, `>`, `>=` (integers only), `in` (list membership, or an IP
+///   inside a CIDR range), `!`, `&&`, `||` and parentheses.
+///
+/// There are no functions or loops, and a comparison with a missing value or mismatched types is
+/// false, so conditions fail closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+  expr: Expr,
+}
+
+impl Condition {
+  pub fn parse(source: &str) -> Result<Self, ConditionError> {
+    if source.len() > MAX_CONDITION_LENGTH {
+      return Err(error(format!(
+        "condition longer than {} bytes",
+        MAX_CONDITION_LENGTH
+      )));
+    }
+    let tokens = tokenize(source)?;
+    if tokens.is_empty() {
+      return Err(error("empty condition"));
+    }
+    let mut parser = Parser {
+      tokens,
+      position: 0,
+      depth: 0,
+    };
+    let expr = parser.or_expr()?;
+    if let Some(token) = parser.peek() {
+      return Err(error(format!("unexpected {}", token)));
+    }
+    Ok(Self { expr })
+  }
+
+  pub fn evaluate(&self, context: &ConditionContext) -> bool {
+    evaluate(&self.expr, context)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Ident(String),
+  Str(String),
+  Int(i64),
+  True,
+  False,
+  In,
+  Op(CompareOp),
+  And,
+  Or,
+  Not,
+  LParen,
+  RParen,
+  LBracket,
+  RBracket,
+  Comma,
+}
+
+impl fmt::Display for Token {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Token::Ident(name) => write!(f, "`{}`", name),
+      Token::Str(value) => write!(f, "\"{}\"", value),
+      Token::Int(value) => write!(f, "`{}`", value),
+      Token::True => f.write_str("`true`"),
+      Token::False => f.write_str("`false`"),
+      Token::In => f.write_str("`in`"),
+      Token::Op(_) => f.write_str("comparison operator"),
+      Token::And => f.write_str("`&&`"),
+      Token::Or => f.write_str("`||`"),
+      Token::Not => f.write_str("`!`"),
+      Token::LParen => f.write_str("`(`"),
+      Token::RParen => f.write_str("`)`"),
+      Token::LBracket => f.write_str("`[`"),
+      Token::RBracket => f.write_str("`]`"),
+      Token::Comma => f.write_str("`,`"),
+    }
+  }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, ConditionError> {
+  let chars: Vec<char> = source.chars().collect();
+  let mut tokens = Vec::new();
+  let mut i = 0;
+  while i < chars.len() {
+    let c = chars[i];
+    let next = chars.get(i + 1).copied();
+    match c {
+      c if c.is_whitespace() => i += 1,
+      '(' | ')' | '[' | ']' | ',' => {
+        tokens.push(match c {
+          '(' => Token::LParen,
+          ')' => Token::RParen,
+          '[' => Token::LBracket,
+          ']' => Token::RBracket,
+          _ => Token::Comma,
+        });
+        i += 1;
+      }
+      '&' if next == Some('&') => {
+        tokens.push(Token::And);
+        i += 2;
+      }
+      '|' if next == Some('|') => {
+        tokens.push(Token::Or);
+        i += 2;
+      }
+      '=' if next == Some('=') => {
+        tokens.push(Token::Op(CompareOp::Eq));
+        i += 2;
+      }
+      '!' if next == Some('=') => {
+        tokens.push(Token::Op(CompareOp::Ne));
+        i += 2;
+      }
+      '!' => {
+        tokens.push(Token::Not);
+        i += 1;
+      }
+      '<' | '>' => {
+        let or_equal = next == Some('=');
+        tokens.push(Token::Op(match (c, or_equal) {
+          ('<', false) => CompareOp::Lt,
+          ('<', true) => CompareOp::Le,
+          ('>', false) => CompareOp::Gt,
+          _ => CompareOp::Ge,
+        }));
+        i += if or_equal { 2 } else { 1 };
+      }
+      '"' => {
+        let mut value = String::new();
+        i += 1;
+        loop {
+          match chars.get(i) {
+            None => return Err(error("unterminated string")),
+            Some('"') => break,
+            Some('\\') => {
+              match chars.get(i + 1) {
+                Some(escaped @ ('"' | '\\')) => value.push(*escaped),
+                _ => return Err(error("invalid escape in string")),
+              }
+              i += 2;
+            }
+            Some(other) => {
+              value.push(*other);
+              i += 1;
+            }
+          }
+        }
+        tokens.push(Token::Str(value));
+        i += 1;
+      }
+      c if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+        let start = i;
+        i += 1;
+        while chars.get(i).is_some_and(|d| d.is_ascii_digit()) {
+          i += 1;
+        }
+        let text: String = chars[start..i].iter().collect();
+        let value = text
+          .parse::<i64>()
+          .map_err(|_| error(format!("integer out of range: {}", text)))?;
+        tokens.push(Token::Int(value));
+      }
+      c if c.is_ascii_alphabetic() => {
+        let start = i;
+        while chars
+          .get(i)
+          .is_some_and(|d| d.is_ascii_alphanumeric() || matches!(d, '_' | '.' | '-'))
+        {
+          i += 1;
+        }
+        let word: String = chars[start..i].iter().collect();
+        tokens.push(match word.as_str() {
+          "true" => Token::True,
+          "false" => Token::False,
+          "in" => Token::In,
+          _ => Token::Ident(validate_variable(&word)?),
+        });
+      }
+      other => return Err(error(format!("unexpected character `{}`", other))),
+    }
+  }
+  Ok(tokens)
+}
+
+/// Only known context paths are accepted, so typos fail when the condition is saved.
+fn validate_variable(name: &str) -> Result<String, ConditionError> {
+  let lowered = name.to_ascii_lowercase();
+  let valid = match lowered.split_once('.') {
+    None => lowered == "ip",
+    Some(("time", field)) => TIME_FIELDS.contains(&field),
+    Some(("header", header)) => !header.is_empty() && !header.contains('.'),
+    Some(("user", key)) => !key.is_empty() && !key.contains('.'),
+    Some(_) => false,
+  };
+  if valid {
+    Ok(lowered)
+  } else {
+    Err(error(format!("unknown variable `{}`", name)))
+  }
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  position: usize,
+  depth: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.position)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.position).cloned();
+    self.position += 1;
+    token
+  }
+
+  fn expect(&mut self, expected: Token) -> Result<(), ConditionError> {
+    match self.next() {
+      Some(token) if token == expected => Ok(()),
+      Some(token) => Err(error(format!("expected {}, found {}", expected, token))),
+      None => Err(error(format!("expected {}, found end of condition", expected))),
+    }
+  }
+
+  fn nest(&mut self) -> Result<(), ConditionError> {
+    self.depth += 1;
+    if self.depth > MAX_NESTING {
+      return Err(error("condition nested too deeply"));
+    }
+    Ok(())
+  }
+
+  fn or_expr(&mut self) -> Result<Expr, ConditionError> {
+    let mut expr = self.and_expr()?;
+    while self.peek() == Some(&Token::Or) {
+      self.position += 1;
+      expr = Expr::Or(Box::new(expr), Box::new(self.and_expr()?));
+    }
+    Ok(expr)
+  }
+
+  fn and_expr(&mut self) -> Result<Expr, ConditionError> {
+    let mut expr = self.unary()?;
+    while self.peek() == Some(&Token::And) {
+      self.position += 1;
+      expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+    }
+    Ok(expr)
+  }
+
+  fn unary(&mut self) -> Result<Expr, ConditionError> {
+    match self.peek() {
+      Some(Token::Not) => {
+        self.position += 1;
+        self.nest()?;
+        let expr = Expr::Not(Box::new(self.unary()?));
+        self.depth -= 1;
+        Ok(expr)
+      }
+      Some(Token::LParen) => {
+        self.position += 1;
+        self.nest()?;
+        let expr = self.or_expr()?;
+        self.expect(Token::RParen)?;
+        self.depth -= 1;
+        Ok(expr)
+      }
+      _ => self.comparison(),
+    }
+  }
+
+  fn comparison(&mut self) -> Result<Expr, ConditionError> {
+    let left = self.operand()?;
+    let op = match self.peek() {
+      Some(Token::Op(op)) => *op,
+      Some(Token::In) => CompareOp::In,
+      _ => return Ok(Expr::Truthy(left)),
+    };
+    self.position += 1;
+    let right = self.operand()?;
+    Ok(Expr::Compare(left, op, right))
+  }
+
+  fn operand(&mut self) -> Result<Operand, ConditionError> {
+    match self.next() {
+      Some(Token::Ident(name)) => Ok(Operand::Var(name)),
+      Some(Token::LBracket) => {
+        self.nest()?;
+        let mut items = Vec::new();
+        if self.peek() != Some(&Token::RBracket) {
+          loop {
+            items.push(self.literal()?);
+            if self.peek() != Some(&Token::Comma) {
+              break;
+            }
+            self.position += 1;
+          }
+        }
+        self.expect(Token::RBracket)?;
+        self.depth -= 1;
+        Ok(Operand::List(items))
+      }
+      Some(token) => literal_of(token).map(Operand::Literal),
+      None => Err(error("expected a value, found end of condition")),
+    }
+  }
+
+  fn literal(&mut self) -> Result<Literal, ConditionError> {
+    match self.next() {
+      Some(token) => literal_of(token),
+      None => Err(error("expected a value, found end of condition")),
+    }
+  }
+}
+
+fn literal_of(token: Token) -> Result<Literal, ConditionError> {
+  match token {
+    Token::Str(value) => Ok(Literal::Str(value)),
+    Token::Int(value) => Ok(Literal::Int(value)),
+    Token::True => Ok(Literal::Bool(true)),
+    Token::False => Ok(Literal::Bool(false)),
+    other => Err(error(format!("expected a value, found {}", other))),
+  }
+}
+
+fn evaluate(expr: &Expr, context: &ConditionContext) -> bool {
+  match expr {
+    Expr::Or(left, right) => evaluate(left, context) || evaluate(right, context),
+    Expr::And(left, right) => evaluate(left, context) && evaluate(right, context),
+    Expr::Not(inner) => !evaluate(inner, context),
+    Expr::Truthy(operand) => {
+      matches!(resolve(operand, context), Resolved::One(Literal::Bool(true)))
+    }
+    Expr::Compare(left, op, right) => {
+      compare(&resolve(left, context), *op, &resolve(right, context))
+    }
+  }
+}
+
+enum Resolved {
+  Missing,
+  One(Literal),
+  Many(Vec<Literal>),
+}
+
+fn resolve(operand: &Operand, context: &ConditionContext) -> Resolved {
+  match operand {
+    Operand::Literal(literal) => Resolved::One(literal.clone()),
+    Operand::List(items) => Resolved::Many(items.clone()),
+    Operand::Var(name) => variable(name, context).map_or(Resolved::Missing, Resolved::One),
+  }
+}
+
+fn variable(name: &str, context: &ConditionContext) -> Option<Literal> {
+  if name == "ip" {
+    return context.ip.map(|ip| Literal::Str(ip.to_string()));
+  }
+  let (root, key) = name.split_once('.')?;
+  match root {
+    "time" => {
+      let seconds_of_day = context.now.rem_euclid(86_400);
+      let days = context.now.div_euclid(86_400);
+      Some(Literal::Int(match key {
+        "hour" => seconds_of_day / 3_600,
+        "minute" => seconds_of_day % 3_600 / 60,
+        // 1970-01-01 was a Thursday.
+        "weekday" => (days + 3).rem_euclid(7) + 1,
+        _ => context.now,
+      }))
+    }
+    "header" => context.headers.get(key).cloned().map(Literal::Str),
+    _ => match context.user.get(key)? {
+      JsonValue::String(value) => Some(Literal::Str(value.clone())),
+      JsonValue::Number(value) => value.as_i64().map(Literal::Int),
+      JsonValue::Bool(value) => Some(Literal::Bool(*value)),
+      _ => None,
+    },
+  }
+}
+
+fn compare(left: &Resolved, op: CompareOp, right: &Resolved) -> bool {
+  match (left, op, right) {
+    (Resolved::One(value), CompareOp::In, Resolved::Many(items)) => {
+      items.iter().any(|item| item == value || in_range(value, item))
+    }
+    (Resolved::One(value), CompareOp::In, Resolved::One(range)) => in_range(value, range),
+    (Resolved::One(left), op, Resolved::One(right)) => match (left, right) {
+      (Literal::Int(a), Literal::Int(b)) => match op {
+        CompareOp::Eq => a == b,
+        CompareOp::Ne => a != b,
+        CompareOp::Lt => a < b,
+        CompareOp::Le => a <= b,
+        CompareOp::Gt => a > b,
+        CompareOp::Ge => a >= b,
+        CompareOp::In => false,
+      },
+      (Literal::Str(a), Literal::Str(b)) => match op {
+        CompareOp::Eq => a == b,
+        CompareOp::Ne => a != b,
+        _ => false,
+      },
+      (Literal::Bool(a), Literal::Bool(b)) => match op {
+        CompareOp::Eq => a == b,
+        CompareOp::Ne => a != b,
+        _ => false,
+      },
+      _ => false,
+    },
+    _ => false,
+  }
+}
+
+/// Whether `value` is an IP address inside `range` (`10.0.0.0/8`, or a single address).
+fn in_range(value: &Literal, range: &Literal) -> bool {
+  let (Literal::Str(address), Literal::Str(range)) = (value, range) else {
+    return false;
+  };
+  address
+    .parse::<IpAddr>()
+    .is_ok_and(|address| ip_in_range(address, range))
+}
+
+/// Whether `address` lies inside `range`, a CIDR range such as `10.0.0.0/8` or a single address.
+pub fn ip_in_range(address: IpAddr, range: &str) -> bool {
+  let (network, prefix) = match range.split_once('/') {
+    Some((network, prefix)) => match prefix.parse::<u32>() {
+      Ok(prefix) => (network, Some(prefix)),
+      Err(_) => return false,
+    },
+    None => (range, None),
+  };
+  match (address, network.parse::<IpAddr>()) {
+    (IpAddr::V4(address), Ok(IpAddr::V4(network))) => {
+      let prefix = prefix.unwrap_or(32);
+      prefix <= 32
+        && masked(u32::from(address).into(), prefix, 32)
+          == masked(u32::from(network).into(), prefix, 32)
+    }
+    (IpAddr::V6(address), Ok(IpAddr::V6(network))) => {
+      let prefix = prefix.unwrap_or(128);
+      prefix <= 128
+        && masked(u128::from(address), prefix, 128) == masked(u128::from(network), prefix, 128)
+    }
+    _ => false,
+  }
+}
+
+fn masked(bits: u128, prefix: u32, width: u32) -> u128 {
+  if prefix == 0 {
+    0
+  } else {
+    bits >> (width - prefix)
+  }
+}
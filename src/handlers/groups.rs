@@ -0,0 +1,334 @@
+use crate::auth::TokenManager;
+use httpageboy::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::users::User;
+use super::{
+  error_response, require_admin_permission, require_tenant_access, require_token_with_renew,
+  resolve_person_id, token_tenant_id, FlexibleId, TenantResource, GROUPS_WRITE,
+};
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Group {
+  id: i32,
+  name: String,
+  description: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroupPayload {
+  name: String,
+  description: Option<String>,
+}
+
+/// Reads the `{id}` path parameter and checks the group belongs to the caller's tenant.
+async fn group_from_path(
+  req: &Request,
+  db: &crate::database::DB,
+  tenant_id: i32,
+) -> Result<i32, Response> {
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return Err(error_response(StatusCode::BadRequest, "invalid_group_id")),
+  };
+  require_tenant_access(db, tenant_id, TenantResource::Group, id, true).await?;
+  Ok(id)
+}
+
+pub async fn create_group(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, GROUPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let payload: CreateGroupPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  if payload.name.trim().is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+  match sqlx::query_as::<_, Group>("SELECT * FROM auth.create_group($1, $2, $3)")
+    .bind(payload.name.trim())
+    .bind(payload.description)
+    .bind(token_tenant_id(&validation))
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(group) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&group).unwrap(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "create_group_failed"),
+  }
+}
+
+pub async fn list_groups(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, Group>("SELECT * FROM auth.list_groups($1)")
+    .bind(token_tenant_id(&validation))
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(groups) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&groups).unwrap(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "list_groups_failed"),
+  }
+}
+
+pub async fn get_group(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id = match group_from_path(req, &db, token_tenant_id(&validation)).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, Group>("SELECT * FROM auth.get_group($1)")
+    .bind(id)
+    .fetch_optional(db.pool())
+    .await
+  {
+    Ok(Some(group)) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&group).unwrap(),
+    },
+    Ok(None) => error_response(StatusCode::NotFound, "group_not_found"),
+    Err(_) => error_response(StatusCode::InternalServerError, "get_group_failed"),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct UpdateGroupPayload {
+  name: Option<String>,
+  description: Option<String>,
+}
+
+pub async fn update_group(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, GROUPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id = match group_from_path(req, &db, token_tenant_id(&validation)).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let payload: UpdateGroupPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  match sqlx::query("CALL auth.update_group($1, $2, $3)")
+    .bind(id)
+    .bind(payload.name)
+    .bind(payload.description)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "status": "success" }).to_string().into_bytes(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] update_group: {}", err);
+      error_response(StatusCode::InternalServerError, "update_group_failed")
+    }
+  }
+}
+
+pub async fn delete_group(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, GROUPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id = match group_from_path(req, &db, token_tenant_id(&validation)).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  // Members are only known while the group exists, so their snapshots go first.
+  let manager = TokenManager::new(db.pool());
+  if manager.delete_access_cache_for_group(id, None).await.is_err() {
+    return error_response(
+      StatusCode::InternalServerError,
+      "invalidate_access_cache_failed",
+    );
+  }
+  match sqlx::query("CALL auth.delete_group($1)")
+    .bind(id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "status": "group_deleted", "group_id": id })
+        .to_string()
+        .into_bytes(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "delete_group_failed"),
+  }
+}
+
+pub async fn list_group_members(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id = match group_from_path(req, &db, token_tenant_id(&validation)).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, User>("SELECT id, username, name FROM auth.list_group_members($1)")
+    .bind(id)
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(users) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&users).unwrap(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "list_group_members_failed"),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct GroupMemberPayload {
+  person_id: FlexibleId,
+}
+
+pub async fn add_group_member(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, GROUPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let id = match group_from_path(req, &db, tenant_id).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let payload: GroupMemberPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let person_id = match resolve_person_id(&db, tenant_id, &payload.person_id).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query("CALL auth.add_group_member($1, $2)")
+    .bind(id)
+    .bind(person_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.delete_access_cache_for_user(person_id).await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "group_member_added",
+          "group_id": id,
+          "person_id": person_id,
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "add_group_member_failed"),
+  }
+}
+
+pub async fn remove_group_member(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, GROUPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let id = match group_from_path(req, &db, tenant_id).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let person_identifier = match req.params.get("person_id") {
+    Some(value) => FlexibleId::from(value.clone()),
+    None => return error_response(StatusCode::BadRequest, "invalid_person_id"),
+  };
+  let person_id = match resolve_person_id(&db, tenant_id, &person_identifier).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query("CALL auth.remove_group_member($1, $2)")
+    .bind(id)
+    .bind(person_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.delete_access_cache_for_user(person_id).await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "group_member_removed",
+          "group_id": id,
+          "person_id": person_id,
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "remove_group_member_failed"),
+  }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct GroupServiceRole {
+  service_id: i32,
+  service_name: String,
+  role_id: i32,
+  role_name: String,
+}
+
+pub async fn list_group_roles(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id = match group_from_path(req, &db, token_tenant_id(&validation)).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, GroupServiceRole>("SELECT * FROM auth.list_group_service_roles($1)")
+    .bind(id)
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(roles) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&roles).unwrap(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "list_group_roles_failed"),
+  }
+}
@@ -0,0 +1,207 @@
+use httpageboy::{Response, StatusCode};
+use serde_json::json;
+use std::env;
+
+use super::{current_epoch, error_response};
+
+const USERNAME_SCOPE: &str = "username";
+const IP_SCOPE: &str = "ip";
+
+/// Lockout thresholds, read from the environment on each login like the token settings.
+#[derive(Debug, Clone)]
+struct LockoutConfig {
+  /// Failures per username before it is locked.
+  username_threshold: i32,
+  /// Failures per client IP before it is locked; higher, since one IP may serve many people.
+  ip_threshold: i32,
+  /// First lock length; every further failure doubles it.
+  base_lock_seconds: i64,
+  max_lock_seconds: i64,
+  /// Quiet period after which failures are forgotten.
+  window_seconds: i64,
+}
+
+impl LockoutConfig {
+  const DEFAULT_USERNAME_THRESHOLD: i64 = 5;
+  const DEFAULT_IP_THRESHOLD: i64 = 20;
+  const DEFAULT_BASE_LOCK_SECONDS: i64 = 30;
+  const DEFAULT_MAX_LOCK_SECONDS: i64 = 60 * 60;
+  const DEFAULT_WINDOW_SECONDS: i64 = 15 * 60;
+
+  fn load_env(key: &str, fallback: i64) -> i64 {
+    env::var(key)
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(fallback)
+  }
+
+  fn load() -> Self {
+    Self {
+      username_threshold: Self::load_env(
+        "LOGIN_LOCKOUT_THRESHOLD",
+        Self::DEFAULT_USERNAME_THRESHOLD,
+      ) as i32,
+      ip_threshold: Self::load_env("LOGIN_IP_LOCKOUT_THRESHOLD", Self::DEFAULT_IP_THRESHOLD) as i32,
+      base_lock_seconds: Self::load_env(
+        "LOGIN_LOCKOUT_BASE_SECONDS",
+        Self::DEFAULT_BASE_LOCK_SECONDS,
+      ),
+      max_lock_seconds: Self::load_env("LOGIN_LOCKOUT_MAX_SECONDS", Self::DEFAULT_MAX_LOCK_SECONDS),
+      window_seconds: Self::load_env("LOGIN_ATTEMPT_WINDOW_SECONDS", Self::DEFAULT_WINDOW_SECONDS),
+    }
+  }
+
+  /// Lock length after `failures` consecutive failures: none below the threshold, then the
+  /// base length doubling with each further failure, capped at the maximum.
+  fn lock_seconds(&self, failures: i32, threshold: i32) -> Option<i64> {
+    if failures < threshold {
+      return None;
+    }
+    let doublings = (failures - threshold).min(30) as u32;
+    Some(
+      self
+        .base_lock_seconds
+        .saturating_mul(1 << doublings)
+        .min(self.max_lock_seconds),
+    )
+  }
+}
+
+/// `429 account_locked` with the seconds left, if the username or client IP is locked.
+pub(super) async fn check_login_lock(
+  db: &crate::database::DB,
+  username: &str,
+  ip: Option<&str>,
+) -> Result<(), Response> {
+  let now = current_epoch();
+  let locked_until = match sqlx::query_scalar::<_, Option<i64>>(
+    "SELECT MAX(locked_until) FROM auth.login_attempts
+      WHERE ((scope = $1 AND key = $2) OR (scope = $3 AND key = $4))
+        AND locked_until > $5",
+  )
+  .bind(USERNAME_SCOPE)
+  .bind(username)
+  .bind(IP_SCOPE)
+  .bind(ip)
+  .bind(now)
+  .fetch_one(db.pool())
+  .await
+  {
+    Ok(locked_until) => locked_until,
+    Err(_) => {
+      return Err(error_response(
+        StatusCode::InternalServerError,
+        "login_lookup_failed",
+      ));
+    }
+  };
+  match locked_until {
+    Some(locked_until) => Err(Response {
+      status: StatusCode::TooManyRequests.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "error": "account_locked",
+        "retry_after": locked_until - now,
+        "locked_until": locked_until,
+      })
+      .to_string()
+      .into_bytes(),
+    }),
+    None => Ok(()),
+  }
+}
+
+async fn record_failure(
+  db: &crate::database::DB,
+  config: &LockoutConfig,
+  scope: &str,
+  key: &str,
+  threshold: i32,
+  now: i64,
+) -> Result<(), sqlx::Error> {
+  // Failures older than the window start a new count, unless a lock ended within it, so
+  // repeated offenders keep escalating.
+  let failures = sqlx::query_scalar::<_, i32>(
+    "INSERT INTO auth.login_attempts AS a (scope, key, failures, last_failure_at)
+      VALUES ($1, $2, 1, $3)
+      ON CONFLICT (scope, key) DO UPDATE
+        SET failures = CASE
+            WHEN a.last_failure_at < $3 - $4 AND COALESCE(a.locked_until, 0) < $3 - $4 THEN 1
+            ELSE a.failures + 1
+          END,
+          last_failure_at = $3
+      RETURNING failures",
+  )
+  .bind(scope)
+  .bind(key)
+  .bind(now)
+  .bind(config.window_seconds)
+  .fetch_one(db.pool())
+  .await?;
+  if let Some(seconds) = config.lock_seconds(failures, threshold) {
+    sqlx::query(
+      "UPDATE auth.login_attempts SET locked_until = $3
+        WHERE scope = $1 AND key = $2",
+    )
+    .bind(scope)
+    .bind(key)
+    .bind(now + seconds)
+    .execute(db.pool())
+    .await?;
+  }
+  Ok(())
+}
+
+/// Counts a failed login against the username (known or not, so lockout reveals nothing) and
+/// the client IP when one is known.
+pub(super) async fn record_login_failure(
+  db: &crate::database::DB,
+  username: &str,
+  ip: Option<&str>,
+) -> Result<(), sqlx::Error> {
+  let config = LockoutConfig::load();
+  let now = current_epoch();
+  record_failure(
+    db,
+    &config,
+    USERNAME_SCOPE,
+    username,
+    config.username_threshold,
+    now,
+  )
+  .await?;
+  if let Some(ip) = ip {
+    record_failure(db, &config, IP_SCOPE, ip, config.ip_threshold, now).await?;
+  }
+  Ok(())
+}
+
+/// Forgets the failures and lock of a username, after a successful login or an admin unlock.
+pub(super) async fn clear_login_failures(
+  db: &crate::database::DB,
+  username: &str,
+) -> Result<bool, sqlx::Error> {
+  let rows = sqlx::query("DELETE FROM auth.login_attempts WHERE scope = $1 AND key = $2")
+    .bind(USERNAME_SCOPE)
+    .bind(username)
+    .execute(db.pool())
+    .await?
+    .rows_affected();
+  Ok(rows > 0)
+}
+
+/// Drops counters nobody has failed against for a full window and whose lock is over.
+pub async fn prune_login_attempts(db: &crate::database::DB) -> Result<u64, sqlx::Error> {
+  let config = LockoutConfig::load();
+  let cutoff = current_epoch() - config.window_seconds;
+  let rows = sqlx::query(
+    "DELETE FROM auth.login_attempts
+      WHERE last_failure_at < $1 AND COALESCE(locked_until, 0) < $1",
+  )
+  .bind(cutoff)
+  .execute(db.pool())
+  .await?
+  .rows_affected();
+  Ok(rows)
+}
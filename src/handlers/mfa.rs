@@ -0,0 +1,500 @@
+use crate::auth::{TokenError, TokenManager};
+use crate::mfa;
+use bcrypt::{hash, verify};
+use httpageboy::{Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use std::env;
+
+use super::login_attempts::{check_login_lock, clear_login_failures, record_login_failure};
+use super::users::session_response;
+use super::{
+  current_epoch, error_response, get_db_connection, log_access, require_token_with_renew,
+  session_metadata, token_user_id, unauthorized_response,
+};
+
+/// Recovery codes carry 50 random bits, so a lower bcrypt cost than for passwords keeps
+/// checking a full set fast without making them guessable.
+const RECOVERY_CODE_COST: u32 = 8;
+
+#[derive(sqlx::FromRow)]
+struct MfaState {
+  username: String,
+  mfa_secret: Option<String>,
+  mfa_enabled: bool,
+  mfa_last_step: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct MfaCodePayload {
+  code: String,
+}
+
+#[derive(Deserialize)]
+pub struct MfaVerifyPayload {
+  mfa_token: String,
+  code: String,
+}
+
+fn mfa_issuer() -> String {
+  env::var("MFA_ISSUER").unwrap_or_else(|_| "eqeqo-auth".to_string())
+}
+
+async fn load_mfa_state(db: &crate::database::DB, person_id: i32) -> Result<MfaState, Response> {
+  match sqlx::query_as::<_, MfaState>(
+    "SELECT username, mfa_secret, mfa_enabled, mfa_last_step
+      FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(state)) => Ok(state),
+    Ok(None) => Err(unauthorized_response("invalid_token")),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "mfa_lookup_failed",
+    )),
+  }
+}
+
+/// Checks a code against the person's secret and records its time step, so each code is
+/// accepted once. `Ok(false)` means the code is wrong or already used.
+async fn accept_code(
+  db: &crate::database::DB,
+  person_id: i32,
+  state: &MfaState,
+  code: &str,
+) -> Result<bool, Response> {
+  let secret = match state
+    .mfa_secret
+    .as_deref()
+    .map(|stored| mfa::decrypt_secret(stored, person_id))
+  {
+    Some(Ok(secret)) => secret,
+    Some(Err(err)) => {
+      eprintln!(
+        "[handler-error] mfa secret for person {}: {}",
+        person_id, err
+      );
+      return Err(error_response(
+        StatusCode::InternalServerError,
+        "mfa_secret_unreadable",
+      ));
+    }
+    None => return Ok(false),
+  };
+  let step = match mfa::verify_code(&secret, code, current_epoch(), state.mfa_last_step) {
+    Some(step) => step,
+    None => return Ok(false),
+  };
+  // Guarded on the previous step so two requests racing with one code cannot both pass.
+  match sqlx::query(
+    "UPDATE auth.person SET mfa_last_step = $2
+      WHERE id = $1 AND (mfa_last_step IS NULL OR mfa_last_step < $2)",
+  )
+  .bind(person_id)
+  .bind(step)
+  .execute(db.pool())
+  .await
+  {
+    Ok(result) => Ok(result.rows_affected() > 0),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "mfa_update_failed",
+    )),
+  }
+}
+
+/// TOTP codes are all digits; anything else is tried as a recovery code.
+async fn accept_code_or_recovery(
+  db: &crate::database::DB,
+  person_id: i32,
+  state: &MfaState,
+  code: &str,
+) -> Result<bool, Response> {
+  if code.trim().bytes().all(|b| b.is_ascii_digit()) {
+    accept_code(db, person_id, state, code).await
+  } else {
+    accept_recovery_code(db, person_id, code).await
+  }
+}
+
+/// Spends the unused recovery code matching `code`. `Ok(false)` when none matches.
+async fn accept_recovery_code(
+  db: &crate::database::DB,
+  person_id: i32,
+  code: &str,
+) -> Result<bool, Response> {
+  let normalized = match mfa::normalize_recovery_code(code) {
+    Some(normalized) => normalized,
+    None => return Ok(false),
+  };
+  let unused = match sqlx::query_as::<_, (i32, String)>(
+    "SELECT id, code_hash FROM auth.mfa_recovery_codes
+      WHERE person_id = $1 AND used_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(unused) => unused,
+    Err(_) => {
+      return Err(error_response(
+        StatusCode::InternalServerError,
+        "mfa_lookup_failed",
+      ));
+    }
+  };
+  let id = match unused
+    .into_iter()
+    .find(|(_, code_hash)| verify(&normalized, code_hash).unwrap_or(false))
+  {
+    Some((id, _)) => id,
+    None => return Ok(false),
+  };
+  match sqlx::query(
+    "UPDATE auth.mfa_recovery_codes SET used_at = $2
+      WHERE id = $1 AND used_at IS NULL",
+  )
+  .bind(id)
+  .bind(current_epoch())
+  .execute(db.pool())
+  .await
+  {
+    Ok(result) => Ok(result.rows_affected() > 0),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "mfa_update_failed",
+    )),
+  }
+}
+
+/// Replaces the person's recovery codes with a fresh set; the clear values are only returned here.
+async fn issue_recovery_codes(
+  db: &crate::database::DB,
+  person_id: i32,
+) -> Result<Vec<String>, Response> {
+  let codes = mfa::generate_recovery_codes();
+  let mut hashes = Vec::with_capacity(codes.len());
+  for code in &codes {
+    let normalized = mfa::normalize_recovery_code(code).unwrap_or_default();
+    match hash(normalized, RECOVERY_CODE_COST) {
+      Ok(code_hash) => hashes.push(code_hash),
+      Err(_) => {
+        return Err(error_response(
+          StatusCode::InternalServerError,
+          "recovery_codes_failed",
+        ));
+      }
+    }
+  }
+  let mut tx = match db.pool().begin().await {
+    Ok(tx) => tx,
+    Err(_) => {
+      return Err(error_response(
+        StatusCode::InternalServerError,
+        "recovery_codes_failed",
+      ));
+    }
+  };
+  let removed = sqlx::query("DELETE FROM auth.mfa_recovery_codes WHERE person_id = $1")
+    .bind(person_id)
+    .execute(&mut *tx)
+    .await;
+  let inserted = sqlx::query(
+    "INSERT INTO auth.mfa_recovery_codes (person_id, code_hash)
+      SELECT $1, UNNEST($2::TEXT[])",
+  )
+  .bind(person_id)
+  .bind(&hashes)
+  .execute(&mut *tx)
+  .await;
+  if removed.is_err() || inserted.is_err() || tx.commit().await.is_err() {
+    return Err(error_response(
+      StatusCode::InternalServerError,
+      "recovery_codes_failed",
+    ));
+  }
+  Ok(codes)
+}
+
+/// Unused recovery codes of a person with MFA enabled; 0 otherwise.
+pub(super) async fn remaining_recovery_codes(
+  db: &crate::database::DB,
+  person_id: i32,
+) -> Result<i64, sqlx::Error> {
+  sqlx::query_scalar::<_, i64>(
+    "SELECT COUNT(*) FROM auth.mfa_recovery_codes c
+      JOIN auth.person p ON p.id = c.person_id
+      WHERE c.person_id = $1 AND c.used_at IS NULL AND p.mfa_enabled",
+  )
+  .bind(person_id)
+  .fetch_one(db.pool())
+  .await
+}
+
+/// Starts (or restarts) enrollment with a fresh secret; MFA stays off until it is confirmed.
+pub async fn enroll_mfa(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id = match token_user_id(&validation) {
+    Some(id) => id,
+    None => return unauthorized_response("invalid_token"),
+  };
+  let state = match load_mfa_state(&db, person_id).await {
+    Ok(state) => state,
+    Err(response) => return response,
+  };
+  if state.mfa_enabled {
+    return error_response(StatusCode::Conflict, "mfa_already_enabled");
+  }
+  let secret = mfa::generate_secret();
+  let encrypted = match mfa::encrypt_secret(&secret, person_id) {
+    Ok(encrypted) => encrypted,
+    Err(_) => return error_response(StatusCode::InternalServerError, "mfa_enroll_failed"),
+  };
+  match sqlx::query(
+    "UPDATE auth.person SET mfa_secret = $2, mfa_last_step = NULL
+      WHERE id = $1 AND NOT mfa_enabled",
+  )
+  .bind(person_id)
+  .bind(encrypted)
+  .execute(db.pool())
+  .await
+  {
+    Ok(result) if result.rows_affected() > 0 => {}
+    Ok(_) => return error_response(StatusCode::Conflict, "mfa_already_enabled"),
+    Err(_) => return error_response(StatusCode::InternalServerError, "mfa_enroll_failed"),
+  }
+  let recovery_codes = match issue_recovery_codes(&db, person_id).await {
+    Ok(codes) => codes,
+    Err(response) => return response,
+  };
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "secret": mfa::encode_secret(&secret),
+      "otpauth_uri": mfa::otpauth_uri(&mfa_issuer(), &state.username, &secret),
+      "recovery_codes": recovery_codes,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+/// Turns MFA on once the first code from the enrolled secret checks out.
+pub async fn confirm_mfa(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id = match token_user_id(&validation) {
+    Some(id) => id,
+    None => return unauthorized_response("invalid_token"),
+  };
+  let payload: MfaCodePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let state = match load_mfa_state(&db, person_id).await {
+    Ok(state) => state,
+    Err(response) => return response,
+  };
+  if state.mfa_enabled {
+    return error_response(StatusCode::Conflict, "mfa_already_enabled");
+  }
+  if state.mfa_secret.is_none() {
+    return error_response(StatusCode::Conflict, "mfa_not_enrolled");
+  }
+  match accept_code(&db, person_id, &state, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => return error_response(StatusCode::BadRequest, "invalid_mfa_code"),
+    Err(response) => return response,
+  }
+  match sqlx::query("UPDATE auth.person SET mfa_enabled = TRUE WHERE id = $1")
+    .bind(person_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "status": "mfa_enabled" }).to_string().into_bytes(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "mfa_confirm_failed"),
+  }
+}
+
+/// Turns MFA off; a current code is required so a stolen session alone cannot do it.
+pub async fn disable_mfa(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id = match token_user_id(&validation) {
+    Some(id) => id,
+    None => return unauthorized_response("invalid_token"),
+  };
+  let payload: MfaCodePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let state = match load_mfa_state(&db, person_id).await {
+    Ok(state) => state,
+    Err(response) => return response,
+  };
+  if !state.mfa_enabled {
+    return error_response(StatusCode::Conflict, "mfa_not_enabled");
+  }
+  match accept_code_or_recovery(&db, person_id, &state, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => return error_response(StatusCode::BadRequest, "invalid_mfa_code"),
+    Err(response) => return response,
+  }
+  // Pending login challenges and recovery codes are dropped with the secret.
+  let mut tx = match db.pool().begin().await {
+    Ok(tx) => tx,
+    Err(_) => return error_response(StatusCode::InternalServerError, "mfa_disable_failed"),
+  };
+  let disabled = sqlx::query(
+    "UPDATE auth.person
+      SET mfa_secret = NULL, mfa_enabled = FALSE, mfa_last_step = NULL
+      WHERE id = $1",
+  )
+  .bind(person_id)
+  .execute(&mut *tx)
+  .await;
+  let cleared = sqlx::query("DELETE FROM auth.mfa_challenges WHERE person_id = $1")
+    .bind(person_id)
+    .execute(&mut *tx)
+    .await;
+  let recovery_cleared = sqlx::query("DELETE FROM auth.mfa_recovery_codes WHERE person_id = $1")
+    .bind(person_id)
+    .execute(&mut *tx)
+    .await;
+  if disabled.is_err()
+    || cleared.is_err()
+    || recovery_cleared.is_err()
+    || tx.commit().await.is_err()
+  {
+    return error_response(StatusCode::InternalServerError, "mfa_disable_failed");
+  }
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "status": "mfa_disabled" }).to_string().into_bytes(),
+  }
+}
+
+/// Replaces the recovery codes; like disabling, it takes a current code or a recovery code.
+pub async fn regenerate_recovery_codes(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let person_id = match token_user_id(&validation) {
+    Some(id) => id,
+    None => return unauthorized_response("invalid_token"),
+  };
+  let payload: MfaCodePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let state = match load_mfa_state(&db, person_id).await {
+    Ok(state) => state,
+    Err(response) => return response,
+  };
+  if !state.mfa_enabled {
+    return error_response(StatusCode::Conflict, "mfa_not_enabled");
+  }
+  match accept_code_or_recovery(&db, person_id, &state, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => return error_response(StatusCode::BadRequest, "invalid_mfa_code"),
+    Err(response) => return response,
+  }
+  match issue_recovery_codes(&db, person_id).await {
+    Ok(codes) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "recovery_codes": codes }).to_string().into_bytes(),
+    },
+    Err(response) => response,
+  }
+}
+
+/// Second login step: exchanges the challenge from `/auth/login` and a code for a session.
+pub async fn verify_mfa(req: &Request) -> Response {
+  let payload: MfaVerifyPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  if payload.mfa_token.trim().is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let manager = TokenManager::new(db.pool());
+  let challenge = match manager.load_mfa_challenge(&payload.mfa_token).await {
+    Ok(challenge) => challenge,
+    Err(TokenError::NotFound) => return unauthorized_response("invalid_mfa_token"),
+    Err(TokenError::Expired) => return unauthorized_response("expired_mfa_token"),
+    Err(TokenError::Database(_)) => {
+      return error_response(StatusCode::InternalServerError, "mfa_verify_failed");
+    }
+  };
+  let state = match load_mfa_state(&db, challenge.person_id).await {
+    Ok(state) if state.mfa_enabled => state,
+    Ok(_) => return unauthorized_response("invalid_mfa_token"),
+    Err(response) => return response,
+  };
+  let metadata = session_metadata(req);
+  if let Err(response) = check_login_lock(&db, &state.username, metadata.ip.as_deref()).await {
+    return response;
+  }
+  match accept_code_or_recovery(&db, challenge.person_id, &state, &payload.code).await {
+    Ok(true) => {}
+    Ok(false) => {
+      if manager
+        .fail_mfa_challenge(&payload.mfa_token)
+        .await
+        .is_err()
+      {
+        return error_response(StatusCode::InternalServerError, "mfa_verify_failed");
+      }
+      // Wrong codes count towards the login lockout too, so fresh challenges do not reset them.
+      if let Err(err) = record_login_failure(&db, &state.username, metadata.ip.as_deref()).await {
+        eprintln!("[handler-error] record_login_failure: {}", err);
+      }
+      return unauthorized_response("invalid_mfa_code");
+    }
+    Err(response) => return response,
+  }
+  match manager.consume_mfa_challenge(&payload.mfa_token).await {
+    Ok(true) => {}
+    Ok(false) => return unauthorized_response("invalid_mfa_token"),
+    Err(_) => return error_response(StatusCode::InternalServerError, "mfa_verify_failed"),
+  }
+  if clear_login_failures(&db, &state.username).await.is_err() {
+    return error_response(StatusCode::InternalServerError, "mfa_verify_failed");
+  }
+
+  let issued = match manager
+    .issue_user_session(challenge.person_id, challenge.payload, &metadata)
+    .await
+  {
+    Ok(issue) => issue,
+    Err(_) => {
+      return error_response(StatusCode::InternalServerError, "login_issue_failed");
+    }
+  };
+
+  log_access(req, false);
+
+  session_response(issued)
+}
@@ -1,9 +1,11 @@
-use crate::auth::{TokenError, TokenManager, TokenValidation};
+use crate::auth::{SessionMetadata, TokenError, TokenManager, TokenValidation};
+use crate::conditions::{ip_in_range, Condition, ConditionContext};
 use crate::database::DB;
 use httpageboy::{Request, Response, StatusCode};
 use serde::Deserialize;
-use serde_json::json;
+use serde_json::{json, Value};
 use std::future::Future;
+use std::net::IpAddr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // Generic response for errors
@@ -38,6 +40,46 @@ fn extract_header(req: &Request, name: &str) -> Option<String> {
     .filter(|value| !value.is_empty())
 }
 
+/// Device metadata for a new session.
+pub(super) fn session_metadata(req: &Request) -> SessionMetadata {
+  SessionMetadata {
+    user_agent: extract_header(req, "user-agent"),
+    ip: client_ip(req),
+  }
+}
+
+/// Client IP of a request. The server hands handlers no peer address, so it has to come from
+/// `x-forwarded-for`, read from the right with the hops listed in `TRUSTED_PROXIES` skipped.
+/// Nothing here can tell a proxy's header from one a client wrote, so the result is only
+/// trustworthy when the API is reachable solely through a proxy that overwrites the header.
+/// Without `TRUSTED_PROXIES` or without the header no IP is known and per-IP limits do not apply.
+pub(super) fn client_ip(req: &Request) -> Option<String> {
+  let proxies = trusted_proxies();
+  if proxies.is_empty() {
+    return None;
+  }
+  let forwarded = extract_header(req, "x-forwarded-for")?;
+  let mut innermost_proxy = None;
+  for hop in forwarded.rsplit(',') {
+    let address = hop.trim().parse::<IpAddr>().ok()?;
+    if !proxies.iter().any(|range| ip_in_range(address, range)) {
+      return Some(address.to_string());
+    }
+    innermost_proxy = Some(address.to_string());
+  }
+  innermost_proxy
+}
+
+/// `TRUSTED_PROXIES`: comma-separated addresses or CIDR ranges of the proxies in front.
+fn trusted_proxies() -> Vec<String> {
+  std::env::var("TRUSTED_PROXIES")
+    .unwrap_or_default()
+    .split(',')
+    .map(|range| range.trim().to_string())
+    .filter(|range| !range.is_empty())
+    .collect()
+}
+
 fn extract_token(req: &Request) -> Option<String> {
   extract_header(req, "user-token")
 }
@@ -57,14 +99,32 @@ pub(super) fn unauthorized_response(message: &str) -> Response {
     "invalid_token" => "token inválido o revocado; realiza login para obtener uno nuevo",
     "invalid_service_token" => "token de servicio inválido o revocado; solicita uno nuevo",
     "expired_token" => "token expirado; solicita un token nuevo iniciando sesión",
+    "expired_service_token" => "token de servicio expirado; rota o emite uno nuevo",
     "invalid_credentials" => "usuario o contraseña incorrectos",
+    "invalid_current_password" => "contraseña actual incorrecta",
+    "invalid_refresh_token" => "refresh token inválido o revocado; realiza login nuevamente",
+    "expired_refresh_token" => "refresh token expirado; realiza login nuevamente",
+    "refresh_token_reused" => {
+      "refresh token ya utilizado; la sesión fue revocada por seguridad, realiza login nuevamente"
+    }
+    "invalid_mfa_token" => {
+      "token MFA inválido o ya utilizado; inicia sesión nuevamente con tu contraseña"
+    }
+    "expired_mfa_token" => "token MFA expirado; inicia sesión nuevamente con tu contraseña",
+    "invalid_mfa_code" => "código MFA o de recuperación incorrecto",
+    "invalid_reset_token" => {
+      "token de recuperación inválido o ya utilizado; solicita uno nuevo con /auth/password/forgot"
+    }
+    "expired_reset_token" => {
+      "token de recuperación expirado; solicita uno nuevo con /auth/password/forgot"
+    }
     "service_inactive" => "servicio desactivado; contacta al administrador",
     _ => "solicitud no autorizada",
   };
   error_response_with_detail(StatusCode::Unauthorized, message, detail)
 }
 
-fn current_epoch() -> i64 {
+pub(super) fn current_epoch() -> i64 {
   SystemTime::now()
     .duration_since(UNIX_EPOCH)
     .unwrap_or_default()
@@ -78,6 +138,91 @@ pub(super) fn log_access(req: &Request, cached: bool) {
   println!("- ts={}, endpoint={}{}", timestamp, endpoint, cached_suffix);
 }
 
+pub(super) fn token_user_id(validation: &TokenValidation) -> Option<i32> {
+  validation
+    .record
+    .payload
+    .get("user_id")
+    .and_then(|value| value.as_i64())
+    .map(|value| value as i32)
+}
+
+/// Tenant every pre-tenant row was moved into; its admins also create the other tenants.
+pub(super) const DEFAULT_TENANT_ID: i32 = 1;
+
+/// Tenant the token was issued for. Tokens from before tenants existed belong to the default one.
+pub(super) fn token_tenant_id(validation: &TokenValidation) -> i32 {
+  validation
+    .record
+    .payload
+    .get("tenant_id")
+    .and_then(|value| value.as_i64())
+    .map(|value| value as i32)
+    .unwrap_or(DEFAULT_TENANT_ID)
+}
+
+/// Tenant-owned tables. Rows with `tenant_id` NULL (never people) are shared platform rows: every
+/// tenant may read and reference them, none may change them through the API.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum TenantResource {
+  User,
+  Person,
+  Service,
+  Role,
+  Permission,
+  Group,
+}
+
+impl TenantResource {
+  fn table(&self) -> &'static str {
+    match self {
+      TenantResource::User | TenantResource::Person => "auth.person",
+      TenantResource::Service => "auth.services",
+      TenantResource::Role => "auth.role",
+      TenantResource::Permission => "auth.permission",
+      TenantResource::Group => "auth.groups",
+    }
+  }
+
+  fn not_found(&self) -> &'static str {
+    match self {
+      TenantResource::User => "user_not_found",
+      TenantResource::Person => "person_not_found",
+      TenantResource::Service => "service_not_found",
+      TenantResource::Role => "role_not_found",
+      TenantResource::Permission => "permission_not_found",
+      TenantResource::Group => "group_not_found",
+    }
+  }
+}
+
+/// Answers rows of another tenant as if they did not exist and refuses changes to shared rows
+/// when `write` is set. Missing rows pass, so each handler keeps its own not-found behaviour.
+pub(super) async fn require_tenant_access(
+  db: &DB,
+  tenant_id: i32,
+  resource: TenantResource,
+  id: i32,
+  write: bool,
+) -> Result<(), Response> {
+  let query = format!("SELECT tenant_id FROM {} WHERE id = $1", resource.table());
+  match sqlx::query_scalar::<_, Option<i32>>(&query)
+    .bind(id)
+    .fetch_optional(db.pool())
+    .await
+  {
+    Ok(None) => Ok(()),
+    Ok(Some(Some(owner))) if owner == tenant_id => Ok(()),
+    Ok(Some(Some(_))) => Err(error_response(StatusCode::NotFound, resource.not_found())),
+    Ok(Some(None)) if !write => Ok(()),
+    Ok(Some(None)) => Err(error_response(StatusCode::Forbidden, "shared_resource")),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "tenant_check_failed",
+    )),
+  }
+}
+
 async fn require_token(
   req: &Request,
   renew: bool,
@@ -125,6 +270,55 @@ pub(super) async fn require_token_with_renew_no_log(
   require_token(req, true, false).await
 }
 
+/// Reserved service whose permissions guard the auth API's own management endpoints.
+pub(super) const AUTH_SERVICE_NAME: &str = "auth";
+pub(super) const USERS_WRITE: &str = "users.write";
+pub(super) const ROLES_WRITE: &str = "roles.write";
+pub(super) const PERMISSIONS_WRITE: &str = "permissions.write";
+pub(super) const RELATIONS_WRITE: &str = "relations.write";
+/// Relation definitions and tuples, kept apart from role and permission assignments.
+pub(super) const RELATIONSHIPS_WRITE: &str = "relationships.write";
+pub(super) const SERVICES_WRITE: &str = "services.write";
+/// Only honoured for admins of the default tenant.
+pub(super) const TENANTS_WRITE: &str = "tenants.write";
+pub(super) const GROUPS_WRITE: &str = "groups.write";
+
+/// Like `require_token_with_renew`, but the caller must also hold `permission` in the reserved
+/// `auth` service.
+pub(super) async fn require_admin_permission(
+  req: &Request,
+  permission: &str,
+) -> Result<(DB, TokenValidation, String), Response> {
+  let (db, validation, token) = require_token_with_renew(req).await?;
+  let user_id = match token_user_id(&validation) {
+    Some(id) => id,
+    None => return Err(error_response(StatusCode::Unauthorized, "invalid_token")),
+  };
+  match sqlx::query_scalar::<_, bool>(
+    "SELECT auth.check_person_permission_in_service(
+      pe.id,
+      (SELECT s.id FROM auth.services s WHERE s.name = $2 AND s.tenant_id IS NULL),
+      $3
+    )
+    FROM auth.person pe
+    WHERE pe.id = $1 AND pe.removed_at IS NULL",
+  )
+  .bind(user_id)
+  .bind(AUTH_SERVICE_NAME)
+  .bind(permission)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(true)) => Ok((db, validation, token)),
+    Ok(Some(false)) => Err(error_response(StatusCode::Forbidden, "insufficient_permissions")),
+    Ok(None) => Err(error_response(StatusCode::Unauthorized, "invalid_token")),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "admin_permission_check_failed",
+    )),
+  }
+}
+
 pub(super) async fn get_db_connection() -> Result<DB, Response> {
   match DB::new().await {
     Ok(db) => Ok(db),
@@ -202,12 +396,15 @@ impl From<String> for FlexibleId {
   }
 }
 
+/// Resolves a service id or name among the services `tenant_id` can see (its own, then shared).
 pub(super) async fn resolve_service_id(
   db: &DB,
+  tenant_id: i32,
   identifier: &FlexibleId,
   create_if_missing: bool,
 ) -> Result<i32, Response> {
   if let Some(id) = identifier.parse_int() {
+    require_tenant_access(db, tenant_id, TenantResource::Service, id, false).await?;
     return Ok(id);
   }
   let name = identifier
@@ -216,24 +413,31 @@ pub(super) async fn resolve_service_id(
     .filter(|s| !s.is_empty())
     .ok_or_else(|| error_response(StatusCode::BadRequest, "invalid_service_id"))?;
 
-  match sqlx::query_scalar::<_, i32>("SELECT id FROM auth.services WHERE name = $1")
+  let lookup = "SELECT id FROM auth.services
+    WHERE name = $1 AND (tenant_id = $2 OR tenant_id IS NULL)
+    ORDER BY tenant_id NULLS LAST
+    LIMIT 1";
+  match sqlx::query_scalar::<_, i32>(lookup)
     .bind(name)
+    .bind(tenant_id)
     .fetch_optional(db.pool())
     .await
   {
     Ok(Some(id)) => Ok(id),
     Ok(None) if create_if_missing => match sqlx::query_scalar::<_, i32>(
-      "INSERT INTO auth.services (name) VALUES ($1)
-        ON CONFLICT (name) DO NOTHING
+      "INSERT INTO auth.services (name, tenant_id) VALUES ($1, $2)
+        ON CONFLICT (tenant_id, name) DO NOTHING
         RETURNING id",
     )
     .bind(name)
+    .bind(tenant_id)
     .fetch_optional(db.pool())
     .await
     {
       Ok(Some(id)) => Ok(id),
-      Ok(None) => sqlx::query_scalar::<_, i32>("SELECT id FROM auth.services WHERE name = $1")
+      Ok(None) => sqlx::query_scalar::<_, i32>(lookup)
         .bind(name)
+        .bind(tenant_id)
         .fetch_one(db.pool())
         .await
         .map_err(|_| error_response(StatusCode::InternalServerError, "resolve_service_failed")),
@@ -250,14 +454,21 @@ pub(super) async fn resolve_service_id(
   }
 }
 
-pub(super) async fn resolve_person_id(db: &DB, identifier: &FlexibleId) -> Result<i32, Response> {
+/// Resolves a person id, `person-<id>` or username among the people of `tenant_id`.
+pub(super) async fn resolve_person_id(
+  db: &DB,
+  tenant_id: i32,
+  identifier: &FlexibleId,
+) -> Result<i32, Response> {
   if let Some(id) = identifier.parse_int() {
+    require_tenant_access(db, tenant_id, TenantResource::Person, id, false).await?;
     return Ok(id);
   }
   if let Some(str_value) = identifier.as_str() {
     let trimmed = str_value.trim();
     if trimmed.starts_with("person-") {
       if let Some(id) = extract_digits(trimmed) {
+        require_tenant_access(db, tenant_id, TenantResource::Person, id, false).await?;
         return Ok(id);
       }
     }
@@ -266,9 +477,10 @@ pub(super) async fn resolve_person_id(db: &DB, identifier: &FlexibleId) -> Resul
       return Err(error_response(StatusCode::BadRequest, "invalid_person_id"));
     }
     return match sqlx::query_scalar::<_, i32>(
-      "SELECT id FROM auth.person WHERE username = $1 AND removed_at IS NULL",
+      "SELECT id FROM auth.person WHERE username = $1 AND tenant_id = $2 AND removed_at IS NULL",
     )
     .bind(username)
+    .bind(tenant_id)
     .fetch_optional(db.pool())
     .await
     {
@@ -283,27 +495,44 @@ pub(super) async fn resolve_person_id(db: &DB, identifier: &FlexibleId) -> Resul
   Err(error_response(StatusCode::BadRequest, "invalid_person_id"))
 }
 
+/// Roles (own and through groups), effective permissions and deny rules of a person in a service,
+/// plus the grants that only hold under a condition (as `(permission, condition)` pairs).
+/// Permissions matched by a deny rule are already removed; the deny names are returned so snapshots
+/// can show them.
 pub(super) async fn load_roles_and_permissions(
   db: &DB,
   person_id: i32,
   service_id: i32,
-) -> Result<(Vec<String>, Vec<String>), Response> {
-  let permissions = match sqlx::query_scalar::<_, String>(
-    "SELECT name FROM (
-      SELECT DISTINCT p.id, p.name
-      FROM auth.person_service_role psr
-      JOIN auth.role_permission rp ON rp.role_id = psr.role_id
+) -> Result<AccessGrants, Response> {
+  // Each grant carries the conditions of the assignment and of the role-permission link behind it.
+  let rows = match sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+    "WITH granted AS (
+      SELECT DISTINCT p.id, p.name, psr.condition AS assignment_condition,
+        rp.condition AS grant_condition
+      FROM auth.effective_person_service_role psr
+      CROSS JOIN LATERAL auth.role_ancestors(ARRAY[psr.role_id]) ra
+      JOIN auth.role_permission rp ON rp.role_id = ra.role_id
       JOIN auth.permission p ON p.id = rp.permission_id
       WHERE psr.person_id = $1 AND psr.service_id = $2
+        AND (p.service_id IS NULL OR p.service_id = $2)
+    )
+    SELECT name, assignment_condition, grant_condition FROM (
+      SELECT id, name, assignment_condition, grant_condition FROM granted
+      UNION
+      SELECT p.id, p.name, g.assignment_condition, g.grant_condition
+      FROM auth.permission p
+      JOIN granted g ON auth.permission_matches(g.name, p.name)
+      WHERE p.service_id IS NULL OR p.service_id = $2
     ) perms
-    ORDER BY id",
+    GROUP BY name, assignment_condition, grant_condition
+    ORDER BY MIN(id)",
   )
   .bind(person_id)
   .bind(service_id)
   .fetch_all(db.pool())
   .await
   {
-    Ok(perms) => perms,
+    Ok(rows) => rows,
     Err(_) => {
       return Err(error_response(
         StatusCode::InternalServerError,
@@ -311,11 +540,32 @@ pub(super) async fn load_roles_and_permissions(
       ));
     }
   };
+  let mut permissions: Vec<String> = Vec::new();
+  for (name, _, _) in rows.iter().filter(|(_, a, g)| a.is_none() && g.is_none()) {
+    if !permissions.contains(name) {
+      permissions.push(name.clone());
+    }
+  }
+  let mut conditional: Vec<(String, String)> = Vec::new();
+  for (name, assignment_condition, grant_condition) in rows {
+    let condition = match (assignment_condition, grant_condition) {
+      (None, None) => continue,
+      (Some(condition), None) | (None, Some(condition)) => condition,
+      (Some(assignment), Some(grant)) => format!("({}) && ({})", assignment, grant),
+    };
+    let entry = (name, condition);
+    if !permissions.contains(&entry.0) && !conditional.contains(&entry) {
+      conditional.push(entry);
+    }
+  }
 
   let roles = match sqlx::query_scalar::<_, String>(
-    "SELECT r.name FROM auth.person_service_role psr
-      JOIN auth.role r ON r.id = psr.role_id
-      WHERE psr.person_id = $1 AND psr.service_id = $2",
+    "SELECT r.name FROM auth.role r
+      WHERE r.id IN (
+        SELECT psr.role_id FROM auth.effective_person_service_role psr
+        WHERE psr.person_id = $1 AND psr.service_id = $2
+      )
+      ORDER BY r.id",
   )
   .bind(person_id)
   .bind(service_id)
@@ -331,27 +581,409 @@ pub(super) async fn load_roles_and_permissions(
     }
   };
 
-  Ok((roles, permissions))
+  let denied = match sqlx::query_scalar::<_, String>(
+    "SELECT name FROM auth.list_person_denied_permissions_in_service($1, $2)",
+  )
+  .bind(person_id)
+  .bind(service_id)
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(list) => list,
+    Err(_) => {
+      return Err(error_response(
+        StatusCode::InternalServerError,
+        "load_denied_permissions_failed",
+      ));
+    }
+  };
+  permissions.retain(|permission| !denied.iter().any(|deny| permission_matches(deny, permission)));
+  conditional
+    .retain(|(permission, _)| !denied.iter().any(|deny| permission_matches(deny, permission)));
+
+  Ok(AccessGrants {
+    roles,
+    permissions,
+    denied,
+    conditional,
+  })
+}
+
+/// What `load_roles_and_permissions` found for a person in a service.
+pub(super) struct AccessGrants {
+  pub(super) roles: Vec<String>,
+  pub(super) permissions: Vec<String>,
+  pub(super) denied: Vec<String>,
+  pub(super) conditional: Vec<(String, String)>,
+}
+
+/// Scopes a token was limited to at login or exchange; empty means the token is unrestricted.
+pub(super) fn token_scopes(payload: &Value) -> Vec<String> {
+  payload
+    .get("scopes")
+    .and_then(|value| value.as_array())
+    .map(|scopes| {
+      scopes
+        .iter()
+        .filter_map(|scope| scope.as_str().map(str::to_string))
+        .collect()
+    })
+    .unwrap_or_default()
 }
 
+/// Trims and dedupes requested scopes. Scopes follow permission naming (wildcards included); an
+/// explicit empty list is rejected because an empty scope set means "unrestricted".
+pub(super) fn normalize_scopes(requested: &[String]) -> Result<Vec<String>, Response> {
+  let mut scopes: Vec<String> = Vec::new();
+  for scope in requested.iter().map(|scope| scope.trim()) {
+    if !is_valid_permission_name(scope) {
+      return Err(error_response(StatusCode::BadRequest, "invalid_scope"));
+    }
+    if !scopes.iter().any(|known| known == scope) {
+      scopes.push(scope.to_string());
+    }
+  }
+  if scopes.is_empty() {
+    return Err(error_response(StatusCode::BadRequest, "invalid_scope"));
+  }
+  Ok(scopes)
+}
+
+fn scopes_allow(scopes: &[String], permission: &str) -> bool {
+  scopes.is_empty() || scopes.iter().any(|scope| permission_matches(scope, permission))
+}
+
+/// Access snapshot of `user_id` in `service_id`, served from `permissions_cache` while fresh and
+/// rebuilt (then cached) otherwise. The flag tells whether the cache answered. Role permissions
+/// are narrowed to the token `scopes`; grants that only partly overlap a scope (`stock.*` under
+/// `stock.items.read`) stay listed and are cut down at decision time.
+pub(super) async fn load_access_snapshot(
+  db: &DB,
+  token: &str,
+  user_id: i32,
+  service_id: i32,
+  scopes: &[String],
+) -> Result<(Value, bool), Response> {
+  let now = current_epoch();
+  let manager = TokenManager::new(db.pool());
+  match manager.load_access_cache(token, service_id).await {
+    Ok(Some(cache)) if cache.expires_at > now => return Ok((cache.access_json, true)),
+    Ok(_) => {}
+    Err(_) => {
+      return Err(error_response(
+        StatusCode::InternalServerError,
+        "load_access_cache_failed",
+      ));
+    }
+  }
+
+  let AccessGrants {
+    roles,
+    mut permissions,
+    denied,
+    mut conditional,
+  } = load_roles_and_permissions(db, user_id, service_id).await?;
+  if !scopes.is_empty() {
+    let overlaps_scopes = |permission: &String| {
+      scopes.iter().any(|scope| {
+        permission_matches(scope, permission) || permission_matches(permission, scope)
+      })
+    };
+    permissions.retain(overlaps_scopes);
+    conditional.retain(|(permission, _)| overlaps_scopes(permission));
+  }
+  let conditional: Vec<Value> = conditional
+    .into_iter()
+    .map(|(permission, condition)| json!({ "permission": permission, "condition": condition }))
+    .collect();
+  let expires_at = now + manager.ttl();
+  let access = json!({
+    "user_id": user_id,
+    "service_id": service_id,
+    "roles": roles,
+    "permissions": permissions,
+    "conditional": conditional,
+    "denied": denied,
+    "scopes": scopes,
+    "expires_at": expires_at,
+  });
+  if manager
+    .store_access_cache(token, service_id, &access, expires_at)
+    .await
+    .is_err()
+  {
+    return Err(error_response(
+      StatusCode::InternalServerError,
+      "store_access_cache_failed",
+    ));
+  }
+  Ok((access, false))
+}
+
+/// How several requested permissions combine: every one (`all`) or at least one (`any`).
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(super) enum PermissionMode {
+  #[default]
+  All,
+  Any,
+}
+
+impl PermissionMode {
+  pub(super) fn as_str(&self) -> &'static str {
+    match self {
+      PermissionMode::All => "all",
+      PermissionMode::Any => "any",
+    }
+  }
+}
+
+/// Whether granting `grant` covers `name`: exact match, `*` for everything, or `prefix.*` for
+/// every name below `prefix.` (but not `prefix` itself).
+pub(super) fn permission_matches(grant: &str, name: &str) -> bool {
+  if grant == name || grant == "*" {
+    return true;
+  }
+  match grant.strip_suffix('*') {
+    Some(prefix) if prefix.ends_with('.') => name.len() > prefix.len() && name.starts_with(prefix),
+    _ => false,
+  }
+}
+
+/// Dot-separated, non-empty segments; `*` may only appear as the whole last segment.
+pub(super) fn is_valid_permission_name(name: &str) -> bool {
+  let mut segments = name.split('.').peekable();
+  while let Some(segment) = segments.next() {
+    let is_last = segments.peek().is_none();
+    let valid = (is_last && segment == "*")
+      || (!segment.trim().is_empty() && !segment.contains('*'));
+    if !valid {
+      return false;
+    }
+  }
+  true
+}
+
+fn snapshot_lists(access: &Value, key: &str, permission: &str) -> bool {
+  access
+    .get(key)
+    .and_then(|value| value.as_array())
+    .is_some_and(|names| {
+      names
+        .iter()
+        .filter_map(|name| name.as_str())
+        .any(|name| permission_matches(name, permission))
+    })
+}
+
+/// Conditional grants of the snapshot covering `permission`, as `(grant, condition)` pairs.
+fn snapshot_conditions<'a>(
+  access: &'a Value,
+  permission: &'a str,
+) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+  access
+    .get("conditional")
+    .and_then(|value| value.as_array())
+    .into_iter()
+    .flatten()
+    .filter_map(|entry| {
+      Some((
+        entry.get("permission")?.as_str()?,
+        entry.get("condition")?.as_str()?,
+      ))
+    })
+    .filter(move |(grant, _)| permission_matches(grant, permission))
+}
+
+/// Stored conditions were validated when saved; one that no longer parses never holds.
+fn condition_holds(condition: &str, context: &ConditionContext) -> bool {
+  Condition::parse(condition).is_ok_and(|condition| condition.evaluate(context))
+}
+
+/// Deny rules win over grants (a wildcard grant may still be listed next to a narrower deny).
+/// Conditional grants count when their condition holds in `context`.
+fn snapshot_grants(access: &Value, permission: &str, context: &ConditionContext) -> bool {
+  let granted = snapshot_lists(access, "permissions", permission)
+    || snapshot_conditions(access, permission)
+      .any(|(_, condition)| condition_holds(condition, context));
+  granted
+    && !snapshot_lists(access, "denied", permission)
+    && scopes_allow(&token_scopes(access), permission)
+}
+
+/// Outcome of `decide_permissions`.
+pub(super) struct PermissionDecision {
+  pub(super) allowed: bool,
+  /// Requested permissions the snapshot does not grant in this context.
+  pub(super) missing: Vec<String>,
+  /// For missing permissions, the conditional grants that would have covered them.
+  pub(super) unmet_conditions: Vec<Value>,
+}
+
+/// Evaluates `requested` against an access snapshot as stored in `permissions_cache`, with
+/// conditional grants evaluated against `context`.
+pub(super) fn decide_permissions(
+  access: &Value,
+  requested: &[String],
+  mode: PermissionMode,
+  context: &ConditionContext,
+) -> PermissionDecision {
+  let missing: Vec<String> = requested
+    .iter()
+    .filter(|permission| !snapshot_grants(access, permission, context))
+    .cloned()
+    .collect();
+  let unmet_conditions = missing
+    .iter()
+    .filter(|permission| !snapshot_lists(access, "denied", permission))
+    .flat_map(|permission| {
+      snapshot_conditions(access, permission).map(move |(grant, condition)| {
+        json!({ "permission": permission, "grant": grant, "condition": condition })
+      })
+    })
+    .collect();
+  let allowed = match mode {
+    PermissionMode::All => missing.is_empty(),
+    PermissionMode::Any => missing.len() < requested.len(),
+  };
+  PermissionDecision {
+    allowed,
+    missing,
+    unmet_conditions,
+  }
+}
+
+/// Context a backend relays for the end-user request it is checking (service-token calls only).
+/// Given headers replace the ones of the backend's own request.
+#[derive(Deserialize)]
+pub(super) struct ConditionContextOverride {
+  ip: Option<String>,
+  headers: Option<std::collections::HashMap<String, String>>,
+}
+
+impl ConditionContextOverride {
+  pub(super) fn apply(self, context: &mut ConditionContext) -> Result<(), Response> {
+    if let Some(ip) = self.ip {
+      match ip.trim().parse() {
+        Ok(ip) => context.ip = Some(ip),
+        Err(_) => return Err(error_response(StatusCode::BadRequest, "invalid_context_ip")),
+      }
+    }
+    if let Some(headers) = self.headers {
+      context.headers = headers
+        .into_iter()
+        .map(|(key, value)| (key.to_ascii_lowercase(), value.trim().to_string()))
+        .filter(|(key, _)| !HIDDEN_CONDITION_HEADERS.contains(&key.as_str()))
+        .collect();
+    }
+    Ok(())
+  }
+}
+
+/// Checks a condition sent with a grant or assignment; blank means unconditional.
+pub(super) fn validate_condition(condition: Option<String>) -> Result<Option<String>, Response> {
+  let Some(condition) = condition.map(|c| c.trim().to_string()).filter(|c| !c.is_empty()) else {
+    return Ok(None);
+  };
+  match Condition::parse(&condition) {
+    Ok(_) => Ok(Some(condition)),
+    Err(err) => Err(error_response_with_detail(
+      StatusCode::BadRequest,
+      "invalid_condition",
+      &err.to_string(),
+    )),
+  }
+}
+
+/// Headers conditions never see, since they carry credentials.
+const HIDDEN_CONDITION_HEADERS: [&str; 4] =
+  ["user-token", "service-token", "authorization", "cookie"];
+
+/// Request context for condition expressions: current time, client IP (see `client_ip`), request
+/// headers and the user attributes of the token payload.
+pub(super) fn condition_context(req: &Request, payload: &Value) -> ConditionContext {
+  let headers = req
+    .headers
+    .iter()
+    .map(|(key, value)| (key.to_ascii_lowercase(), value.trim().to_string()))
+    .filter(|(key, _)| !HIDDEN_CONDITION_HEADERS.contains(&key.as_str()))
+    .collect();
+  let mut user = serde_json::Map::new();
+  for (attribute, key) in [
+    ("id", "user_id"),
+    ("username", "username"),
+    ("name", "name"),
+    ("tenant_id", "tenant_id"),
+  ] {
+    if let Some(value) = payload.get(key) {
+      user.insert(attribute.to_string(), value.clone());
+    }
+  }
+  ConditionContext {
+    now: current_epoch(),
+    ip: client_ip(req).and_then(|ip| ip.parse().ok()),
+    headers,
+    user,
+  }
+}
+
+mod groups;
+mod login_attempts;
+mod mfa;
+mod passwords;
 mod permissions;
 mod relations;
+mod relationships;
+mod reset_requests;
 mod roles;
 mod services;
+mod tenants;
 mod users;
 
+pub use groups::*;
+pub use login_attempts::*;
+pub use mfa::*;
+pub use passwords::*;
 pub use permissions::*;
 pub use relations::*;
+pub use relationships::*;
+pub use reset_requests::*;
 pub use roles::*;
 pub use services::*;
+pub use tenants::*;
 pub use users::*;
 
+/// Resolves a permission id or name among the permissions `tenant_id` can see. With a service,
+/// names are looked up in that service's catalog first and then among the global permissions, and
+/// ids of another service's permissions are rejected; without one only global names resolve.
 pub(super) async fn resolve_permission_id(
   db: &DB,
+  tenant_id: i32,
   identifier: &FlexibleId,
+  service_id: Option<i32>,
 ) -> Result<i32, Response> {
   if let Some(id) = identifier.parse_int() {
-    return Ok(id);
+    require_tenant_access(db, tenant_id, TenantResource::Permission, id, false).await?;
+    let Some(service_id) = service_id else {
+      return Ok(id);
+    };
+    return match sqlx::query_scalar::<_, Option<i32>>(
+      "SELECT service_id FROM auth.permission WHERE id = $1",
+    )
+    .bind(id)
+    .fetch_optional(db.pool())
+    .await
+    {
+      Ok(Some(Some(owner))) if owner != service_id => Err(error_response(
+        StatusCode::BadRequest,
+        "permission_not_in_service",
+      )),
+      Ok(_) => Ok(id),
+      Err(_) => Err(error_response(
+        StatusCode::InternalServerError,
+        "resolve_permission_failed",
+      )),
+    };
   }
 
   let name = identifier
@@ -360,10 +992,19 @@ pub(super) async fn resolve_permission_id(
     .filter(|s| !s.is_empty())
     .ok_or_else(|| error_response(StatusCode::BadRequest, "invalid_permission_id"))?;
 
-  match sqlx::query_scalar::<_, i32>("SELECT id FROM auth.permission WHERE name = $1")
-    .bind(name)
-    .fetch_optional(db.pool())
-    .await
+  match sqlx::query_scalar::<_, i32>(
+    "SELECT id FROM auth.permission
+      WHERE name = $1
+        AND (service_id IS NULL OR service_id = $2)
+        AND (tenant_id IS NULL OR tenant_id = $3)
+      ORDER BY service_id NULLS LAST, tenant_id NULLS LAST
+      LIMIT 1",
+  )
+  .bind(name)
+  .bind(service_id)
+  .bind(tenant_id)
+  .fetch_optional(db.pool())
+  .await
   {
     Ok(Some(id)) => Ok(id),
     Ok(None) => Err(error_response(
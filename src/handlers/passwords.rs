@@ -0,0 +1,304 @@
+use crate::auth::{TokenError, TokenManager};
+use crate::notifier::{self, Notification};
+use bcrypt::verify;
+use httpageboy::{Request, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+
+use super::login_attempts::{check_login_lock, clear_login_failures, record_login_failure};
+use super::reset_requests::throttle_reset_request;
+use super::users::{hash_password, session_response};
+use super::{
+  error_response, error_response_with_detail, get_db_connection, session_metadata, token_user_id,
+  unauthorized_response, with_auth_no_renew,
+};
+
+const MIN_PASSWORD_CHARS: usize = 8;
+/// bcrypt ignores everything past 72 bytes, so longer passwords would only look stronger.
+const MAX_PASSWORD_BYTES: usize = 72;
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordPayload {
+  username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordPayload {
+  token: String,
+  password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordPayload {
+  current_password: String,
+  new_password: String,
+}
+
+/// Rules for passwords people choose themselves: `400 weak_password` naming the broken rule.
+fn check_password_policy(password: &str, username: &str) -> Result<(), Response> {
+  let weak = |detail: &str| {
+    Err(error_response_with_detail(
+      StatusCode::BadRequest,
+      "weak_password",
+      detail,
+    ))
+  };
+  if password.chars().count() < MIN_PASSWORD_CHARS {
+    return weak("password must have at least 8 characters");
+  }
+  if password.len() > MAX_PASSWORD_BYTES {
+    return weak("password must not exceed 72 bytes");
+  }
+  if !password.chars().any(char::is_alphabetic) || password.chars().all(char::is_alphabetic) {
+    return weak("password must mix letters with digits or symbols");
+  }
+  if password.to_lowercase().contains(&username.to_lowercase()) {
+    return weak("password must not contain the username");
+  }
+  Ok(())
+}
+
+/// Sends a reset token to the person. The answer is the same whether or not the username
+/// exists, so this cannot be used to discover accounts. Requests are limited per username and
+/// per client IP.
+pub async fn forgot_password(req: &Request) -> Response {
+  let payload: ForgotPasswordPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  if payload.username.trim().is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let ip = session_metadata(req).ip;
+  if let Err(response) = throttle_reset_request(&db, &payload.username, ip.as_deref()).await {
+    return response;
+  }
+  let person_id = match sqlx::query_scalar::<_, i32>(
+    "SELECT id FROM auth.person WHERE username = $1 AND removed_at IS NULL",
+  )
+  .bind(&payload.username)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(person_id) => person_id,
+    Err(_) => return error_response(StatusCode::InternalServerError, "password_reset_failed"),
+  };
+
+  if let Some(person_id) = person_id {
+    let manager = TokenManager::new(db.pool());
+    let issued = match manager.issue_password_reset(person_id).await {
+      Ok(Some(issued)) => issued,
+      // The token sent moments ago is still on its way; sending nothing keeps it valid.
+      Ok(None) => return reset_requested_response(),
+      Err(_) => return error_response(StatusCode::InternalServerError, "password_reset_failed"),
+    };
+    let notification = Notification {
+      kind: "password_reset".to_string(),
+      person_id,
+      recipient: payload.username.clone(),
+      subject: "Password reset".to_string(),
+      body: format!(
+        "Use this token with POST /auth/password/reset to choose a new password: {}",
+        issued.token
+      ),
+      data: json!({ "token": issued.token, "expires_at": issued.expires_at }),
+    };
+    // Delivery problems stay in the log; the caller gets the same answer either way.
+    if let Err(err) = notifier::deliver(notification).await {
+      eprintln!("[handler-error] password reset notification: {}", err);
+    }
+  }
+
+  reset_requested_response()
+}
+
+fn reset_requested_response() -> Response {
+  Response {
+    status: StatusCode::Accepted.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({ "status": "password_reset_requested" })
+      .to_string()
+      .into_bytes(),
+  }
+}
+
+/// Sets a new password with a reset token, then signs the person out everywhere.
+pub async fn reset_password(req: &Request) -> Response {
+  let payload: ResetPasswordPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  if payload.token.trim().is_empty() || payload.password.trim().is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let manager = TokenManager::new(db.pool());
+  let person_id = match manager.find_password_reset(&payload.token).await {
+    Ok(person_id) => person_id,
+    Err(TokenError::NotFound) => return unauthorized_response("invalid_reset_token"),
+    Err(TokenError::Expired) => return unauthorized_response("expired_reset_token"),
+    Err(TokenError::Database(_)) => {
+      return error_response(StatusCode::InternalServerError, "password_reset_failed");
+    }
+  };
+  let username = match sqlx::query_scalar::<_, String>(
+    "SELECT username FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(username)) => username,
+    Ok(None) => return unauthorized_response("invalid_reset_token"),
+    Err(_) => return error_response(StatusCode::InternalServerError, "password_reset_failed"),
+  };
+  if let Err(response) = check_password_policy(&payload.password, &username) {
+    return response;
+  }
+  let password_hash = match hash_password(&payload.password) {
+    Ok(hash) => hash,
+    Err(response) => return response,
+  };
+  match manager
+    .consume_password_reset(&payload.token, &password_hash)
+    .await
+  {
+    Ok(_) => {}
+    Err(TokenError::NotFound | TokenError::Expired) => {
+      return unauthorized_response("invalid_reset_token");
+    }
+    Err(TokenError::Database(_)) => {
+      return error_response(StatusCode::InternalServerError, "password_reset_failed");
+    }
+  }
+
+  // Whoever held the old password loses every session, refresh token and pending MFA step.
+  let revoked = match manager.delete_tokens_for_user(person_id).await {
+    Ok(revoked) => revoked,
+    Err(_) => return error_response(StatusCode::InternalServerError, "user_token_cleanup_failed"),
+  };
+  let challenges = sqlx::query("DELETE FROM auth.mfa_challenges WHERE person_id = $1")
+    .bind(person_id)
+    .execute(db.pool())
+    .await;
+  if challenges.is_err() || clear_login_failures(&db, &username).await.is_err() {
+    return error_response(StatusCode::InternalServerError, "user_token_cleanup_failed");
+  }
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "status": "password_reset",
+      "user_id": person_id,
+      "revoked_tokens": revoked,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+/// Changes the caller's password after checking the current one. Other sessions are signed out
+/// and the calling session continues with new tokens.
+pub async fn change_password(req: &Request) -> Response {
+  let payload: Option<ChangePasswordPayload> = serde_json::from_slice(req.body.as_bytes()).ok();
+  let metadata = session_metadata(req);
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let person_id = match token_user_id(&validation) {
+      Some(id) => id,
+      None => return unauthorized_response("invalid_token"),
+    };
+    let payload = match payload {
+      Some(p) if !p.current_password.is_empty() && !p.new_password.trim().is_empty() => p,
+      _ => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+    };
+    let (username, password_hash) = match sqlx::query_as::<_, (String, String)>(
+      "SELECT username, password_hash FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+    )
+    .bind(person_id)
+    .fetch_optional(db.pool())
+    .await
+    {
+      Ok(Some(person)) => person,
+      Ok(None) => return unauthorized_response("invalid_token"),
+      Err(_) => return error_response(StatusCode::InternalServerError, "password_change_failed"),
+    };
+
+    // Guessing the current password with a stolen token counts towards lockout like a login.
+    let ip = metadata.ip.as_deref();
+    if let Err(response) = check_login_lock(&db, &username, ip).await {
+      return response;
+    }
+    if !matches!(verify(&payload.current_password, &password_hash), Ok(true)) {
+      if let Err(err) = record_login_failure(&db, &username, ip).await {
+        eprintln!("[handler-error] record_login_failure: {}", err);
+      }
+      return unauthorized_response("invalid_current_password");
+    }
+    if payload.new_password == payload.current_password {
+      return error_response_with_detail(
+        StatusCode::BadRequest,
+        "weak_password",
+        "new password must differ from the current one",
+      );
+    }
+    if let Err(response) = check_password_policy(&payload.new_password, &username) {
+      return response;
+    }
+    let new_hash = match hash_password(&payload.new_password) {
+      Ok(hash) => hash,
+      Err(response) => return response,
+    };
+    // The current password was right, so earlier failures stop counting like after a login.
+    if clear_login_failures(&db, &username).await.is_err() {
+      return error_response(StatusCode::InternalServerError, "password_change_failed");
+    }
+
+    // The new password, the cleanup and the new tokens land together or not at all, so a
+    // changed password never leaves the other sessions signed in.
+    let mut tx = match db.pool().begin().await {
+      Ok(tx) => tx,
+      Err(_) => return error_response(StatusCode::InternalServerError, "password_change_failed"),
+    };
+    let updated = sqlx::query("UPDATE auth.person SET password_hash = $2 WHERE id = $1")
+      .bind(person_id)
+      .bind(new_hash)
+      .execute(&mut *tx)
+      .await;
+    // Pending MFA steps and reset tokens were earned with the old password.
+    let challenges = sqlx::query("DELETE FROM auth.mfa_challenges WHERE person_id = $1")
+      .bind(person_id)
+      .execute(&mut *tx)
+      .await;
+    let reset_tokens = sqlx::query("DELETE FROM auth.password_reset_tokens WHERE person_id = $1")
+      .bind(person_id)
+      .execute(&mut *tx)
+      .await;
+    if updated.is_err() || challenges.is_err() || reset_tokens.is_err() {
+      return error_response(StatusCode::InternalServerError, "password_change_failed");
+    }
+
+    let manager = TokenManager::new(db.pool());
+    let payload = validation.record.payload.clone();
+    let session_id = validation.record.session_id;
+    let issued = match manager
+      .rotate_session(&mut tx, person_id, session_id, payload, &metadata)
+      .await
+    {
+      Ok(issued) => issued,
+      Err(_) => return error_response(StatusCode::InternalServerError, "password_change_failed"),
+    };
+    if tx.commit().await.is_err() {
+      return error_response(StatusCode::InternalServerError, "password_change_failed");
+    }
+    session_response(issued)
+  })
+  .await
+}
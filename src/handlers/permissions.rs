@@ -3,22 +3,30 @@ use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{error_response, require_token_with_renew};
+use super::{
+  error_response, is_valid_permission_name, require_admin_permission, require_tenant_access,
+  require_token_with_renew, resolve_service_id, token_tenant_id, validate_condition, FlexibleId,
+  TenantResource, PERMISSIONS_WRITE, ROLES_WRITE,
+};
 
 #[derive(Serialize, sqlx::FromRow)]
 pub struct Permission {
   id: i32,
   name: String,
+  /// Owning service; `None` for global permissions.
+  service_id: Option<i32>,
 }
 
 #[derive(Deserialize)]
 pub struct CreatePermissionPayload {
   #[serde(alias = "code")]
   name: String,
+  /// Creates the permission in this service's catalog instead of the global one.
+  service_id: Option<FlexibleId>,
 }
 
 pub async fn create_permission(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, PERMISSIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -26,8 +34,21 @@ pub async fn create_permission(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.create_permission($1)")
+  if !is_valid_permission_name(&payload.name) {
+    return error_response(StatusCode::BadRequest, "invalid_permission_name");
+  }
+  let tenant_id = token_tenant_id(&validation);
+  let service_id = match &payload.service_id {
+    Some(identifier) => match resolve_service_id(&db, tenant_id, identifier, false).await {
+      Ok(id) => Some(id),
+      Err(response) => return response,
+    },
+    None => None,
+  };
+  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.create_permission($1, $2, $3)")
     .bind(payload.name)
+    .bind(service_id)
+    .bind(tenant_id)
     .fetch_one(db.pool())
     .await
   {
@@ -44,11 +65,25 @@ pub async fn create_permission(req: &Request) -> Response {
 }
 
 pub async fn list_permissions(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
-  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.list_permissions()")
+  let tenant_id = token_tenant_id(&validation);
+  // `?service_id=` narrows the list to that service's catalog plus the global permissions.
+  let service_id = match req.params.get("service_id") {
+    Some(raw) => {
+      let identifier = FlexibleId::from(raw.as_str());
+      match resolve_service_id(&db, tenant_id, &identifier, false).await {
+        Ok(id) => Some(id),
+        Err(response) => return response,
+      }
+    }
+    None => None,
+  };
+  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.list_permissions($1, $2)")
+    .bind(service_id)
+    .bind(tenant_id)
     .fetch_all(db.pool())
     .await
   {
@@ -68,7 +103,7 @@ pub struct UpdatePermissionPayload {
 }
 
 pub async fn update_permission(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, PERMISSIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -76,10 +111,19 @@ pub async fn update_permission(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_permission_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Permission, id, true).await
+  {
+    return response;
+  }
   let payload: UpdatePermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
+  if !is_valid_permission_name(&payload.name) {
+    return error_response(StatusCode::BadRequest, "invalid_permission_name");
+  }
   match sqlx::query("CALL auth.update_permission($1, $2)")
     .bind(id)
     .bind(payload.name)
@@ -99,7 +143,7 @@ pub async fn update_permission(req: &Request) -> Response {
 }
 
 pub async fn delete_permission(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, PERMISSIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -107,6 +151,12 @@ pub async fn delete_permission(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_permission_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Permission, id, true).await
+  {
+    return response;
+  }
   match sqlx::query("CALL auth.delete_permission($1)")
     .bind(id)
     .execute(db.pool())
@@ -127,10 +177,29 @@ pub async fn delete_permission(req: &Request) -> Response {
 pub struct RolePermissionPayload {
   role_id: i32,
   permission_id: i32,
+  /// Optional condition expression the grant only applies under; ignored on removal.
+  condition: Option<String>,
+}
+
+/// The role must belong to the caller's tenant; the permission may also be a shared one.
+async fn require_role_permission_access(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  payload: &RolePermissionPayload,
+) -> Result<(), Response> {
+  require_tenant_access(db, tenant_id, TenantResource::Role, payload.role_id, true).await?;
+  require_tenant_access(
+    db,
+    tenant_id,
+    TenantResource::Permission,
+    payload.permission_id,
+    false,
+  )
+  .await
 }
 
 pub async fn assign_permission_to_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -138,9 +207,18 @@ pub async fn assign_permission_to_role(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  match sqlx::query("CALL auth.assign_permission_to_role($1, $2)")
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) = require_role_permission_access(&db, tenant_id, &payload).await {
+    return response;
+  }
+  let condition = match validate_condition(payload.condition) {
+    Ok(condition) => condition,
+    Err(response) => return response,
+  };
+  match sqlx::query("CALL auth.assign_permission_to_role($1, $2, $3)")
     .bind(payload.role_id)
     .bind(payload.permission_id)
+    .bind(condition)
     .execute(db.pool())
     .await
   {
@@ -163,7 +241,7 @@ pub async fn assign_permission_to_role(req: &Request) -> Response {
 }
 
 pub async fn remove_permission_from_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -171,6 +249,10 @@ pub async fn remove_permission_from_role(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) = require_role_permission_access(&db, tenant_id, &payload).await {
+    return response;
+  }
   match sqlx::query("CALL auth.remove_permission_from_role($1, $2)")
     .bind(payload.role_id)
     .bind(payload.permission_id)
@@ -201,8 +283,135 @@ pub async fn remove_permission_from_role(req: &Request) -> Response {
   }
 }
 
+pub async fn deny_permission_to_role(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let payload: RolePermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) = require_role_permission_access(&db, tenant_id, &payload).await {
+    return response;
+  }
+  match sqlx::query("CALL auth.deny_permission_to_role($1, $2)")
+    .bind(payload.role_id)
+    .bind(payload.permission_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.clear_access_cache().await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "permission_denied_to_role",
+          "role_id": payload.role_id,
+          "permission_id": payload.permission_id
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "deny_permission_failed"),
+  }
+}
+
+pub async fn remove_permission_deny_from_role(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let payload: RolePermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) = require_role_permission_access(&db, tenant_id, &payload).await {
+    return response;
+  }
+  match sqlx::query("CALL auth.remove_permission_deny_from_role($1, $2)")
+    .bind(payload.role_id)
+    .bind(payload.permission_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.clear_access_cache().await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "permission_deny_removed",
+          "role_id": payload.role_id,
+          "permission_id": payload.permission_id
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "remove_permission_deny_failed"),
+  }
+}
+
+pub async fn list_role_denied_permissions(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, false).await
+  {
+    return response;
+  }
+  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.list_role_denied_permissions($1)")
+    .bind(id)
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(permissions) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&permissions).unwrap(),
+    },
+    Err(_) => error_response(
+      StatusCode::InternalServerError,
+      "list_denied_permissions_failed",
+    ),
+  }
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct RolePermission {
+  id: i32,
+  name: String,
+  service_id: Option<i32>,
+  /// Condition the grant only applies under; `None` when unconditional.
+  condition: Option<String>,
+}
+
 pub async fn list_role_permissions(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -210,7 +419,13 @@ pub async fn list_role_permissions(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
   };
-  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.list_role_permissions($1)")
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, false).await
+  {
+    return response;
+  }
+  match sqlx::query_as::<_, RolePermission>("SELECT * FROM auth.list_role_permissions($1)")
     .bind(id)
     .fetch_all(db.pool())
     .await
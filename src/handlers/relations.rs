@@ -4,33 +4,120 @@ use serde_json::json;
 use crate::auth::TokenManager;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use super::permissions::Permission;
 use super::roles::Role;
 use super::users::User;
 use super::{
-  FlexibleId, error_response, load_roles_and_permissions, log_access,
-  require_token_with_renew, require_token_with_renew_no_log, resolve_permission_id,
-  resolve_person_id, resolve_service_id,
+  FlexibleId, TenantResource, error_response, load_access_snapshot, log_access,
+  require_admin_permission, require_tenant_access, require_token_with_renew,
+  require_token_with_renew_no_log, resolve_permission_id, resolve_person_id, resolve_service_id,
+  token_scopes, token_tenant_id, validate_condition, RELATIONS_WRITE,
 };
 
 #[derive(Deserialize)]
 pub struct ServiceRolePayload {
   service_id: FlexibleId,
   role_id: i32,
+  /// On removal, also drop person assignments of the role in the service.
+  #[serde(default)]
+  cascade: bool,
+}
+
+/// `STRICT_SERVICE_ROLES=true` makes every service strict, whatever its own `strict_roles` flag.
+fn strict_service_roles_globally() -> bool {
+  std::env::var("STRICT_SERVICE_ROLES").is_ok_and(|value| {
+    matches!(
+      value.trim().to_ascii_lowercase().as_str(),
+      "1" | "true" | "yes"
+    )
+  })
+}
+
+async fn service_is_strict(db: &crate::database::DB, service_id: i32) -> Result<bool, Response> {
+  if strict_service_roles_globally() {
+    return Ok(true);
+  }
+  match sqlx::query_scalar::<_, bool>("SELECT strict_roles FROM auth.services WHERE id = $1")
+    .bind(service_id)
+    .fetch_optional(db.pool())
+    .await
+  {
+    Ok(strict) => Ok(strict.unwrap_or(false)),
+    Err(_) => Err(error_response(StatusCode::InternalServerError, "load_service_failed")),
+  }
+}
+
+/// In strict services a role must be linked through `auth.service_roles` before people get it.
+async fn require_service_role_link(
+  db: &crate::database::DB,
+  service_id: i32,
+  role_id: i32,
+) -> Result<(), Response> {
+  if !service_is_strict(db, service_id).await? {
+    return Ok(());
+  }
+  match sqlx::query_scalar::<_, bool>("CALL auth.check_service_role_link($1, $2, NULL)")
+    .bind(service_id)
+    .bind(role_id)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(true) => Ok(()),
+    Ok(false) => Err(error_response(StatusCode::Conflict, "role_not_linked_to_service")),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "check_service_role_link_failed",
+    )),
+  }
+}
+
+/// Tenants may link their own rows to shared ones, but links between two shared rows belong to
+/// the platform itself.
+async fn require_service_role_access(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  service_id: i32,
+  role_id: i32,
+) -> Result<(), Response> {
+  require_tenant_access(db, tenant_id, TenantResource::Role, role_id, false).await?;
+  match sqlx::query_scalar::<_, bool>(
+    "SELECT s.tenant_id IS NULL AND r.tenant_id IS NULL
+      FROM auth.services s, auth.role r
+      WHERE s.id = $1 AND r.id = $2",
+  )
+  .bind(service_id)
+  .bind(role_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(true)) => Err(error_response(StatusCode::Forbidden, "shared_resource")),
+    Ok(_) => Ok(()),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "tenant_check_failed",
+    )),
+  }
 }
 
 pub async fn assign_role_to_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let payload: ServiceRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  let service_id = match resolve_service_id(&db, &payload.service_id, true).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &payload.service_id, true).await {
     Ok(id) => id,
     Err(response) => return response,
   };
+  if let Err(response) =
+    require_service_role_access(&db, tenant_id, service_id, payload.role_id).await
+  {
+    return response;
+  }
   match sqlx::query("CALL auth.assign_role_to_service($1, $2)")
     .bind(service_id)
     .bind(payload.role_id)
@@ -59,21 +146,61 @@ pub async fn assign_role_to_service(req: &Request) -> Response {
 }
 
 pub async fn remove_role_from_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let payload: ServiceRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  let service_id = match resolve_service_id(&db, &payload.service_id, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &payload.service_id, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
-  match sqlx::query("CALL auth.remove_role_from_service($1, $2)")
+  if let Err(response) =
+    require_service_role_access(&db, tenant_id, service_id, payload.role_id).await
+  {
+    return response;
+  }
+  if !payload.cascade {
+    let strict = match service_is_strict(&db, service_id).await {
+      Ok(strict) => strict,
+      Err(response) => return response,
+    };
+    let assignments = match sqlx::query_scalar::<_, i64>(
+      "SELECT
+        (SELECT COUNT(*) FROM auth.person_service_role WHERE service_id = $1 AND role_id = $2)
+        + (SELECT COUNT(*) FROM auth.group_service_role WHERE service_id = $1 AND role_id = $2)",
+    )
     .bind(service_id)
     .bind(payload.role_id)
+    .fetch_one(db.pool())
+    .await
+    {
+      Ok(count) => count,
+      Err(_) => {
+        return error_response(StatusCode::InternalServerError, "remove_role_service_failed");
+      }
+    };
+    if strict && assignments > 0 {
+      return Response {
+        status: StatusCode::Conflict.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "error": "service_role_in_use",
+          "assignments": assignments,
+        })
+        .to_string()
+        .into_bytes(),
+      };
+    }
+  }
+  match sqlx::query("CALL auth.remove_role_from_service($1, $2, $3)")
+    .bind(service_id)
+    .bind(payload.role_id)
+    .bind(payload.cascade)
     .execute(db.pool())
     .await
   {
@@ -101,20 +228,22 @@ pub async fn remove_role_from_service(req: &Request) -> Response {
 }
 
 pub async fn list_service_roles(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let identifier = match req.params.get("id") {
     Some(id) => FlexibleId::from(id.clone()),
     None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
   };
-  let id = match resolve_service_id(&db, &identifier, false).await {
+  let id = match resolve_service_id(&db, tenant_id, &identifier, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
-  match sqlx::query_as::<_, Role>("SELECT * FROM auth.list_service_roles($1)")
+  match sqlx::query_as::<_, Role>("SELECT * FROM auth.list_service_roles($1, $2)")
     .bind(id)
+    .bind(tenant_id)
     .fetch_all(db.pool())
     .await
   {
@@ -132,29 +261,63 @@ pub struct PersonServiceRolePayload {
   person_id: FlexibleId,
   service_id: FlexibleId,
   role_id: i32,
+  /// Optional validity window in epoch seconds; ignored on removal.
+  valid_from: Option<i64>,
+  valid_until: Option<i64>,
+  /// Optional condition expression the assignment only applies under; ignored on removal.
+  condition: Option<String>,
 }
 
 pub async fn assign_role_to_person_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let payload: PersonServiceRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  let person_id = match resolve_person_id(&db, &payload.person_id).await {
+  let person_id = match resolve_person_id(&db, tenant_id, &payload.person_id).await {
     Ok(id) => id,
     Err(response) => return response,
   };
-  let service_id = match resolve_service_id(&db, &payload.service_id, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &payload.service_id, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
-  match sqlx::query("CALL auth.assign_role_to_person_in_service($1, $2, $3)")
+  let role_id = payload.role_id;
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, role_id, false).await
+  {
+    return response;
+  }
+  let now = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_secs() as i64;
+  let window_closed = match (payload.valid_from, payload.valid_until) {
+    (Some(from), Some(until)) => until <= from || until <= now,
+    (None, Some(until)) => until <= now,
+    _ => false,
+  };
+  if window_closed {
+    return error_response(StatusCode::BadRequest, "invalid_validity_window");
+  }
+  let condition = match validate_condition(payload.condition) {
+    Ok(condition) => condition,
+    Err(response) => return response,
+  };
+  if let Err(response) = require_service_role_link(&db, service_id, payload.role_id).await {
+    return response;
+  }
+  match sqlx::query("CALL auth.assign_role_to_person_in_service($1, $2, $3, $4, $5, $6)")
     .bind(person_id)
     .bind(service_id)
     .bind(payload.role_id)
+    .bind(payload.valid_from)
+    .bind(payload.valid_until)
+    .bind(condition)
     .execute(db.pool())
     .await
   {
@@ -177,22 +340,29 @@ pub async fn assign_role_to_person_in_service(req: &Request) -> Response {
 }
 
 pub async fn remove_role_from_person_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let payload: PersonServiceRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  let person_id = match resolve_person_id(&db, &payload.person_id).await {
+  let person_id = match resolve_person_id(&db, tenant_id, &payload.person_id).await {
     Ok(id) => id,
     Err(response) => return response,
   };
-  let service_id = match resolve_service_id(&db, &payload.service_id, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &payload.service_id, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
+  let role_id = payload.role_id;
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, role_id, false).await
+  {
+    return response;
+  }
   match sqlx::query("CALL auth.remove_role_from_person_in_service($1, $2, $3)")
     .bind(person_id)
     .bind(service_id)
@@ -221,10 +391,11 @@ pub async fn remove_role_from_person_in_service(req: &Request) -> Response {
 }
 
 pub async fn list_person_roles_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let person_identifier = match req.params.get("person_id") {
     Some(value) => FlexibleId::from(value.clone()),
     None => return error_response(StatusCode::BadRequest, "invalid_person_id"),
@@ -233,11 +404,11 @@ pub async fn list_person_roles_in_service(req: &Request) -> Response {
     Some(value) => FlexibleId::from(value.clone()),
     None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
   };
-  let person_id = match resolve_person_id(&db, &person_identifier).await {
+  let person_id = match resolve_person_id(&db, tenant_id, &person_identifier).await {
     Ok(id) => id,
     Err(response) => return response,
   };
-  let service_id = match resolve_service_id(&db, &service_identifier, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &service_identifier, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
@@ -257,10 +428,11 @@ pub async fn list_person_roles_in_service(req: &Request) -> Response {
 }
 
 pub async fn list_persons_with_role_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
   let service_identifier = match req.params.get("service_id") {
     Some(value) => FlexibleId::from(value.clone()),
     None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
@@ -269,15 +441,16 @@ pub async fn list_persons_with_role_in_service(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
   };
-  let service_id = match resolve_service_id(&db, &service_identifier, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &service_identifier, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
   match sqlx::query_as::<_, User>(
-    "SELECT id, username, name FROM auth.list_persons_with_role_in_service($1, $2)",
+    "SELECT id, username, name FROM auth.list_persons_with_role_in_service($1, $2, $3)",
   )
   .bind(service_id)
   .bind(role_id)
+  .bind(tenant_id)
   .fetch_all(db.pool())
   .await
   {
@@ -293,6 +466,115 @@ pub async fn list_persons_with_role_in_service(req: &Request) -> Response {
   }
 }
 
+#[derive(Deserialize)]
+pub struct GroupServiceRolePayload {
+  group_id: i32,
+  service_id: FlexibleId,
+  role_id: i32,
+}
+
+/// Resolves the service of a group role payload after checking the group and role are visible.
+async fn resolve_group_service_role(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  payload: &GroupServiceRolePayload,
+) -> Result<i32, Response> {
+  require_tenant_access(db, tenant_id, TenantResource::Group, payload.group_id, true).await?;
+  require_tenant_access(db, tenant_id, TenantResource::Role, payload.role_id, false).await?;
+  resolve_service_id(db, tenant_id, &payload.service_id, false).await
+}
+
+pub async fn assign_role_to_group_in_service(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let payload: GroupServiceRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let service_id = match resolve_group_service_role(&db, tenant_id, &payload).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  if let Err(response) = require_service_role_link(&db, service_id, payload.role_id).await {
+    return response;
+  }
+  match sqlx::query("CALL auth.assign_role_to_group_in_service($1, $2, $3)")
+    .bind(payload.group_id)
+    .bind(service_id)
+    .bind(payload.role_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager
+        .delete_access_cache_for_group(payload.group_id, Some(service_id))
+        .await
+        .is_err()
+      {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({ "status": "success" }).to_string().into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "assign_role_group_failed"),
+  }
+}
+
+pub async fn remove_role_from_group_in_service(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let payload: GroupServiceRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let service_id = match resolve_group_service_role(&db, tenant_id, &payload).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query("CALL auth.remove_role_from_group_in_service($1, $2, $3)")
+    .bind(payload.group_id)
+    .bind(service_id)
+    .bind(payload.role_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager
+        .delete_access_cache_for_group(payload.group_id, Some(service_id))
+        .await
+        .is_err()
+      {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({ "status": "role_removed_from_group" })
+          .to_string()
+          .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "remove_role_group_failed"),
+  }
+}
+
 #[derive(Deserialize)]
 pub struct PersonServicePermissionPayload {
   person_id: FlexibleId,
@@ -301,15 +583,23 @@ pub struct PersonServicePermissionPayload {
   permission_name: Option<String>,
 }
 
+/// Name of the per-person role `grant_permission_to_person_in_service` hangs direct grants on.
+fn direct_role_name(person_id: i32, service_id: i32) -> String {
+  format!("direct:{}:{}", person_id, service_id)
+}
+
 async fn ensure_direct_role(
   db: &crate::database::DB,
+  tenant_id: i32,
   person_id: i32,
   service_id: i32,
 ) -> Result<i32, Response> {
-  let role_name = format!("direct:{}:{}", person_id, service_id);
+  let role_name = direct_role_name(person_id, service_id);
 
-  let existing = sqlx::query_scalar::<_, i32>("SELECT id FROM auth.role WHERE name = $1")
+  let lookup = "SELECT id FROM auth.role WHERE name = $1 AND tenant_id = $2";
+  let existing = sqlx::query_scalar::<_, i32>(lookup)
     .bind(&role_name)
+    .bind(tenant_id)
     .fetch_optional(db.pool())
     .await
     .map_err(|_| {
@@ -323,11 +613,12 @@ async fn ensure_direct_role(
   }
 
   let inserted = sqlx::query_scalar::<_, i32>(
-    "INSERT INTO auth.role (name) VALUES ($1)
-      ON CONFLICT (name) DO NOTHING
+    "INSERT INTO auth.role (name, tenant_id) VALUES ($1, $2)
+      ON CONFLICT (tenant_id, name) DO NOTHING
       RETURNING id",
   )
   .bind(&role_name)
+  .bind(tenant_id)
   .fetch_optional(db.pool())
   .await
   .map_err(|_| error_response(StatusCode::InternalServerError, "create_direct_role_failed"))?;
@@ -336,8 +627,9 @@ async fn ensure_direct_role(
     return Ok(id);
   }
 
-  sqlx::query_scalar::<_, i32>("SELECT id FROM auth.role WHERE name = $1")
+  sqlx::query_scalar::<_, i32>(lookup)
     .bind(&role_name)
+    .bind(tenant_id)
     .fetch_one(db.pool())
     .await
     .map_err(|_| {
@@ -349,22 +641,23 @@ async fn ensure_direct_role(
 }
 
 pub async fn grant_permission_to_person_in_service(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
 
   let payload: PersonServicePermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
 
-  let person_id = match resolve_person_id(&db, &payload.person_id).await {
+  let person_id = match resolve_person_id(&db, tenant_id, &payload.person_id).await {
     Ok(id) => id,
     Err(response) => return response,
   };
 
-  let service_id = match resolve_service_id(&db, &payload.service_id, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &payload.service_id, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
@@ -375,12 +668,13 @@ pub async fn grant_permission_to_person_in_service(req: &Request) -> Response {
     _ => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
 
-  let permission_id = match resolve_permission_id(&db, &permission_identifier).await {
-    Ok(id) => id,
-    Err(response) => return response,
-  };
+  let permission_id =
+    match resolve_permission_id(&db, tenant_id, &permission_identifier, Some(service_id)).await {
+      Ok(id) => id,
+      Err(response) => return response,
+    };
 
-  let role_id = match ensure_direct_role(&db, person_id, service_id).await {
+  let role_id = match ensure_direct_role(&db, tenant_id, person_id, service_id).await {
     Ok(id) => id,
     Err(response) => return response,
   };
@@ -445,6 +739,159 @@ pub async fn grant_permission_to_person_in_service(req: &Request) -> Response {
   }
 }
 
+async fn resolve_person_service_permission(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  payload: PersonServicePermissionPayload,
+) -> Result<(i32, i32, i32), Response> {
+  let person_id = resolve_person_id(db, tenant_id, &payload.person_id).await?;
+  let service_id = resolve_service_id(db, tenant_id, &payload.service_id, false).await?;
+  let permission_identifier = match (payload.permission_id, payload.permission_name) {
+    (Some(id), _) => id,
+    (None, Some(name)) => FlexibleId::from(name),
+    _ => return Err(error_response(StatusCode::BadRequest, "invalid_request_body")),
+  };
+  let permission_id =
+    resolve_permission_id(db, tenant_id, &permission_identifier, Some(service_id)).await?;
+  Ok((person_id, service_id, permission_id))
+}
+
+pub async fn deny_permission_to_person_in_service(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let payload: PersonServicePermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let (person_id, service_id, permission_id) =
+    match resolve_person_service_permission(&db, tenant_id, payload).await {
+      Ok(ids) => ids,
+      Err(response) => return response,
+    };
+  match sqlx::query("CALL auth.deny_permission_to_person_in_service($1, $2, $3)")
+    .bind(person_id)
+    .bind(service_id)
+    .bind(permission_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.delete_access_cache(person_id, service_id).await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "permission_denied_to_person",
+          "person_id": person_id,
+          "service_id": service_id,
+          "permission_id": permission_id,
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "deny_permission_failed"),
+  }
+}
+
+pub async fn remove_permission_deny_from_person_in_service(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let payload: PersonServicePermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let (person_id, service_id, permission_id) =
+    match resolve_person_service_permission(&db, tenant_id, payload).await {
+      Ok(ids) => ids,
+      Err(response) => return response,
+    };
+  match sqlx::query("CALL auth.remove_permission_deny_from_person_in_service($1, $2, $3)")
+    .bind(person_id)
+    .bind(service_id)
+    .bind(permission_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.delete_access_cache(person_id, service_id).await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "permission_deny_removed",
+          "person_id": person_id,
+          "service_id": service_id,
+          "permission_id": permission_id,
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "remove_permission_deny_failed"),
+  }
+}
+
+pub async fn list_person_denied_permissions_in_service(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let person_identifier = match req.params.get("person_id") {
+    Some(value) => FlexibleId::from(value.clone()),
+    None => return error_response(StatusCode::BadRequest, "invalid_person_id"),
+  };
+  let service_identifier = match req.params.get("service_id") {
+    Some(value) => FlexibleId::from(value.clone()),
+    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
+  };
+  let person_id = match resolve_person_id(&db, tenant_id, &person_identifier).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let service_id = match resolve_service_id(&db, tenant_id, &service_identifier, false).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, Permission>(
+    "SELECT * FROM auth.list_person_denied_permissions_in_service($1, $2)",
+  )
+  .bind(person_id)
+  .bind(service_id)
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(permissions) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&permissions).unwrap(),
+    },
+    Err(_) => error_response(
+      StatusCode::InternalServerError,
+      "list_denied_permissions_failed",
+    ),
+  }
+}
+
 #[derive(sqlx::FromRow, serde::Serialize)]
 struct PersonData {
   id: i32,
@@ -457,6 +904,7 @@ pub async fn get_person_service_info(req: &Request) -> Response {
     Ok(result) => result,
     Err(response) => return response,
   };
+  let tenant_id = token_tenant_id(&validation);
 
   let person_identifier = match req.params.get("person_id") {
     Some(value) => FlexibleId::from(value.clone()),
@@ -468,7 +916,7 @@ pub async fn get_person_service_info(req: &Request) -> Response {
     None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
   };
 
-  let person_id = match resolve_person_id(&db, &person_identifier).await {
+  let person_id = match resolve_person_id(&db, tenant_id, &person_identifier).await {
     Ok(id) => id,
     Err(response) => return response,
   };
@@ -484,7 +932,7 @@ pub async fn get_person_service_info(req: &Request) -> Response {
     }
   }
 
-  let service_id = match resolve_service_id(&db, &service_identifier, false).await {
+  let service_id = match resolve_service_id(&db, tenant_id, &service_identifier, false).await {
     Ok(id) => id,
     Err(response) => return response,
   };
@@ -501,56 +949,17 @@ pub async fn get_person_service_info(req: &Request) -> Response {
     Err(_) => return error_response(StatusCode::InternalServerError, "load_person_failed"),
   };
 
-  let now = SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .unwrap_or_default()
-    .as_secs() as i64;
-
-  let manager = TokenManager::new(db.pool());
-  let cached_access = match manager.load_access_cache(&token, service_id).await {
-    Ok(Some(cache)) if cache.expires_at > now => Some(cache.access_json),
-    Ok(_) => None,
-    Err(_) => {
-      return error_response(
-        StatusCode::InternalServerError,
-        "load_access_cache_failed",
-      );
-    }
-  };
-  let used_cache = cached_access.is_some();
-
-  let (roles, permissions) = if let Some(access) = cached_access {
-    let roles = access.get("roles").cloned().unwrap_or_else(|| json!([]));
-    let permissions = access
-      .get("permissions")
-      .cloned()
-      .unwrap_or_else(|| json!([]));
-    (roles, permissions)
-  } else {
-    let (roles, permissions) = match load_roles_and_permissions(&db, person_id, service_id).await {
+  let scopes = token_scopes(&validation.record.payload);
+  let (access, used_cache) =
+    match load_access_snapshot(&db, &token, person_id, service_id, &scopes).await {
       Ok(result) => result,
       Err(response) => return response,
     };
-    let expires_at = now + manager.ttl();
-    let access = json!({
-      "user_id": person_id,
-      "service_id": service_id,
-      "roles": roles,
-      "permissions": permissions,
-      "scopes": [],
-      "expires_at": expires_at,
-    });
-    if let Err(_) = manager
-      .store_access_cache(&token, service_id, &access, expires_at)
-      .await
-    {
-      return error_response(
-        StatusCode::InternalServerError,
-        "store_access_cache_failed",
-      );
-    }
-    (json!(roles), json!(permissions))
-  };
+  let roles = access.get("roles").cloned().unwrap_or_else(|| json!([]));
+  let permissions = access
+    .get("permissions")
+    .cloned()
+    .unwrap_or_else(|| json!([]));
 
   log_access(req, used_cache);
 
@@ -567,3 +976,206 @@ pub async fn get_person_service_info(req: &Request) -> Response {
     .into_bytes(),
   }
 }
+
+#[derive(Deserialize)]
+pub struct ExplainPermissionPayload {
+  person_id: FlexibleId,
+  service_id: FlexibleId,
+  permission: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct ExplainServiceData {
+  id: i32,
+  name: String,
+  status: bool,
+}
+
+#[derive(sqlx::FromRow)]
+struct ExplainGrantRow {
+  assigned_role_id: i32,
+  assigned_role_name: String,
+  valid_from: Option<i64>,
+  valid_until: Option<i64>,
+  assignment_active: bool,
+  service_linked: bool,
+  granting_role_id: Option<i32>,
+  granting_role_name: Option<String>,
+  granted_permission: Option<String>,
+  group_id: Option<i32>,
+  group_name: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct ExplainDenyRow {
+  source: String,
+  role_id: Option<i32>,
+  role_name: Option<String>,
+  denied_permission: String,
+}
+
+/// Decision for one person, service and permission plus the chain behind it: every assignment of
+/// the person in the service, own or through a group (with its window, whether it is a direct role
+/// and whether the role is linked to the service), the grants reaching the permission through it,
+/// and matching denies.
+pub async fn explain_permission(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let payload: ExplainPermissionPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let permission = payload.permission.trim().to_string();
+  if permission.is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_permission");
+  }
+  let person_id = match resolve_person_id(&db, tenant_id, &payload.person_id).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let service_id = match resolve_service_id(&db, tenant_id, &payload.service_id, false).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+
+  let person = match sqlx::query_as::<_, PersonData>(
+    "SELECT id, username, name FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(person_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(person)) => person,
+    Ok(None) => return error_response(StatusCode::NotFound, "person_not_found"),
+    Err(_) => return error_response(StatusCode::InternalServerError, "load_person_failed"),
+  };
+  let service = match sqlx::query_as::<_, ExplainServiceData>(
+    "SELECT id, name, status FROM auth.services WHERE id = $1",
+  )
+  .bind(service_id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(service)) => service,
+    Ok(None) => return error_response(StatusCode::NotFound, "service_not_found"),
+    Err(_) => return error_response(StatusCode::InternalServerError, "load_service_failed"),
+  };
+
+  let grant_rows = match sqlx::query_as::<_, ExplainGrantRow>(
+    "SELECT * FROM auth.explain_person_permission_grants($1, $2, $3)",
+  )
+  .bind(person_id)
+  .bind(service_id)
+  .bind(&permission)
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(rows) => rows,
+    Err(_) => return error_response(StatusCode::InternalServerError, "explain_permission_failed"),
+  };
+  let deny_rows = match sqlx::query_as::<_, ExplainDenyRow>(
+    "SELECT * FROM auth.explain_person_permission_denies($1, $2, $3)",
+  )
+  .bind(person_id)
+  .bind(service_id)
+  .bind(&permission)
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(rows) => rows,
+    Err(_) => return error_response(StatusCode::InternalServerError, "explain_permission_failed"),
+  };
+
+  // Rows come ordered by group and assigned role; fold them into one entry per assignment.
+  let direct_role = direct_role_name(person_id, service_id);
+  let mut assignments: Vec<serde_json::Value> = Vec::new();
+  let mut granted_by_active = false;
+  let mut granted_by_inactive = false;
+  for row in &grant_rows {
+    let is_new = assignments.last().is_none_or(|entry| {
+      entry["role_id"] != json!(row.assigned_role_id) || entry["group_id"] != json!(row.group_id)
+    });
+    if is_new {
+      assignments.push(json!({
+        "role_id": row.assigned_role_id,
+        "role_name": row.assigned_role_name,
+        "direct": row.assigned_role_name == direct_role,
+        "group_id": row.group_id,
+        "group_name": row.group_name,
+        "service_linked": row.service_linked,
+        "active": row.assignment_active,
+        "valid_from": row.valid_from,
+        "valid_until": row.valid_until,
+        "grants": [],
+      }));
+    }
+    if let (Some(role_id), Some(role_name), Some(granted)) = (
+      row.granting_role_id,
+      row.granting_role_name.as_ref(),
+      row.granted_permission.as_ref(),
+    ) {
+      if row.assignment_active {
+        granted_by_active = true;
+      } else {
+        granted_by_inactive = true;
+      }
+      if let Some(grants) = assignments
+        .last_mut()
+        .and_then(|entry| entry["grants"].as_array_mut())
+      {
+        grants.push(json!({
+          "role_id": role_id,
+          "role_name": role_name,
+          "permission": granted,
+          "inherited": role_id != row.assigned_role_id,
+        }));
+      }
+    }
+  }
+  let denies: Vec<serde_json::Value> = deny_rows
+    .iter()
+    .map(|row| {
+      json!({
+        "source": row.source,
+        "role_id": row.role_id,
+        "role_name": row.role_name,
+        "permission": row.denied_permission,
+      })
+    })
+    .collect();
+
+  let reason = if !service.status {
+    "service_inactive"
+  } else if !denies.is_empty() {
+    "denied"
+  } else if granted_by_active {
+    "granted"
+  } else if granted_by_inactive {
+    "assignment_outside_window"
+  } else {
+    "no_grant"
+  };
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "person": person,
+      "service": {
+        "id": service.id,
+        "name": service.name,
+        "active": service.status,
+      },
+      "permission": permission,
+      "allowed": reason == "granted",
+      "reason": reason,
+      "assignments": assignments,
+      "denies": denies,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
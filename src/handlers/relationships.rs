@@ -0,0 +1,592 @@
+use httpageboy::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+  FlexibleId, RELATIONSHIPS_WRITE, TenantResource, error_response, require_admin_permission,
+  require_tenant_access, require_token_with_renew, resolve_service_id, token_tenant_id,
+  token_user_id, unauthorized_response,
+};
+
+/// Inheritance steps a relation check may follow before giving up.
+const MAX_RELATION_DEPTH: i32 = 8;
+
+/// Object types and relation names: lowercase letters, digits and underscores, starting with a
+/// letter.
+fn is_valid_relation_name(name: &str) -> bool {
+  let mut chars = name.chars();
+  chars.next().is_some_and(|c| c.is_ascii_lowercase())
+    && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// `type:id`, the notation objects and plain subjects are written in.
+struct ObjectRef {
+  object_type: String,
+  object_id: String,
+}
+
+impl ObjectRef {
+  fn parse(raw: &str) -> Option<Self> {
+    let (object_type, object_id) = raw.trim().split_once(':')?;
+    let valid_id = !object_id.is_empty()
+      && !object_id.contains('#')
+      && !object_id.chars().any(char::is_whitespace);
+    if !is_valid_relation_name(object_type) || !valid_id {
+      return None;
+    }
+    Some(Self {
+      object_type: object_type.to_string(),
+      object_id: object_id.to_string(),
+    })
+  }
+}
+
+/// `type:id` or `type:id#relation`; the latter stands for everyone holding that relation on the
+/// object (a userset).
+struct SubjectRef {
+  object: ObjectRef,
+  relation: Option<String>,
+}
+
+impl SubjectRef {
+  fn parse(raw: &str) -> Option<Self> {
+    let (object, relation) = match raw.trim().split_once('#') {
+      Some((object, relation)) if is_valid_relation_name(relation) => {
+        (object, Some(relation.to_string()))
+      }
+      Some(_) => return None,
+      None => (raw, None),
+    };
+    Some(Self {
+      object: ObjectRef::parse(object)?,
+      relation,
+    })
+  }
+}
+
+/// Reads the service from `identifier` and checks the caller's tenant may use it, or change its
+/// relations when `write` is set (shared services are read-only).
+async fn relation_service(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  identifier: &FlexibleId,
+  write: bool,
+) -> Result<i32, Response> {
+  let service_id = resolve_service_id(db, tenant_id, identifier, false).await?;
+  if write {
+    require_tenant_access(db, tenant_id, TenantResource::Service, service_id, true).await?;
+  }
+  Ok(service_id)
+}
+
+async fn require_relation_defined(
+  db: &crate::database::DB,
+  service_id: i32,
+  object_type: &str,
+  relation: &str,
+) -> Result<(), Response> {
+  match sqlx::query_scalar::<_, bool>("SELECT auth.relation_defined($1, $2, $3)")
+    .bind(service_id)
+    .bind(object_type)
+    .bind(relation)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(true) => Ok(()),
+    Ok(false) => Err(error_response(StatusCode::BadRequest, "unknown_relation")),
+    Err(_) => Err(error_response(
+      StatusCode::InternalServerError,
+      "load_relation_failed",
+    )),
+  }
+}
+
+/// `user` subjects are people, so they must belong to the caller's tenant.
+async fn require_subject_access(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  subject: &ObjectRef,
+) -> Result<(), Response> {
+  if subject.object_type != "user" {
+    return Ok(());
+  }
+  let person_id = subject
+    .object_id
+    .parse::<i32>()
+    .map_err(|_| error_response(StatusCode::BadRequest, "invalid_subject"))?;
+  require_tenant_access(db, tenant_id, TenantResource::Person, person_id, false).await
+}
+
+#[derive(Deserialize)]
+pub struct RelationRulePayload {
+  relation: String,
+  via: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DefineRelationPayload {
+  object_type: String,
+  relation: String,
+  #[serde(default)]
+  inherits: Vec<RelationRulePayload>,
+}
+
+#[derive(sqlx::FromRow)]
+struct RelationDefinitionRow {
+  object_type: String,
+  relation: String,
+  implied_by: Option<String>,
+  via_relation: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RelationRule {
+  relation: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  via: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RelationDefinition {
+  object_type: String,
+  relation: String,
+  inherits: Vec<RelationRule>,
+}
+
+fn service_from_path(req: &Request) -> Result<FlexibleId, Response> {
+  req
+    .params
+    .get("id")
+    .map(|id| FlexibleId::from(id.clone()))
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "invalid_service_id"))
+}
+
+pub async fn list_relation_definitions(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let identifier = match service_from_path(req) {
+    Ok(identifier) => identifier,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let service_id = match relation_service(&db, tenant_id, &identifier, false).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let rows = match sqlx::query_as::<_, RelationDefinitionRow>(
+    "SELECT * FROM auth.list_relation_definitions($1)",
+  )
+  .bind(service_id)
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(rows) => rows,
+    Err(_) => {
+      return error_response(
+        StatusCode::InternalServerError,
+        "list_relation_definitions_failed",
+      );
+    }
+  };
+
+  // Rows come ordered by relation, one per rule.
+  let mut definitions: Vec<RelationDefinition> = Vec::new();
+  for row in rows {
+    let same = definitions
+      .last()
+      .is_some_and(|last| last.object_type == row.object_type && last.relation == row.relation);
+    if !same {
+      definitions.push(RelationDefinition {
+        object_type: row.object_type,
+        relation: row.relation,
+        inherits: Vec::new(),
+      });
+    }
+    if let (Some(implied_by), Some(definition)) = (row.implied_by, definitions.last_mut()) {
+      definition.inherits.push(RelationRule {
+        relation: implied_by,
+        via: row.via_relation,
+      });
+    }
+  }
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: serde_json::to_vec(&definitions).unwrap(),
+  }
+}
+
+pub async fn define_relation(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONSHIPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let identifier = match service_from_path(req) {
+    Ok(identifier) => identifier,
+    Err(response) => return response,
+  };
+  let payload: DefineRelationPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let names_valid = is_valid_relation_name(&payload.object_type)
+    && is_valid_relation_name(&payload.relation)
+    && payload.inherits.iter().all(|rule| {
+      is_valid_relation_name(&rule.relation)
+        && rule.via.as_deref().is_none_or(is_valid_relation_name)
+    });
+  if !names_valid {
+    return error_response(StatusCode::BadRequest, "invalid_relation_name");
+  }
+  let tenant_id = token_tenant_id(&validation);
+  let service_id = match relation_service(&db, tenant_id, &identifier, true).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  let implied_by: Vec<&str> = payload
+    .inherits
+    .iter()
+    .map(|rule| rule.relation.as_str())
+    .collect();
+  let via: Vec<Option<&str>> = payload
+    .inherits
+    .iter()
+    .map(|rule| rule.via.as_deref())
+    .collect();
+  match sqlx::query_scalar::<_, i32>("SELECT auth.define_relation($1, $2, $3, $4, $5)")
+    .bind(service_id)
+    .bind(&payload.object_type)
+    .bind(&payload.relation)
+    .bind(&implied_by)
+    .bind(&via)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(_) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "status": "relation_defined",
+        "service_id": service_id,
+        "object_type": payload.object_type,
+        "relation": payload.relation,
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] define_relation: {}", err);
+      error_response(StatusCode::InternalServerError, "define_relation_failed")
+    }
+  }
+}
+
+pub async fn delete_relation_definition(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONSHIPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let identifier = match service_from_path(req) {
+    Ok(identifier) => identifier,
+    Err(response) => return response,
+  };
+  let (object_type, relation) = match (req.params.get("object_type"), req.params.get("relation")) {
+    (Some(object_type), Some(relation)) => (object_type.clone(), relation.clone()),
+    _ => return error_response(StatusCode::BadRequest, "invalid_relation_name"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let service_id = match relation_service(&db, tenant_id, &identifier, true).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query_scalar::<_, bool>("SELECT auth.delete_relation_definition($1, $2, $3)")
+    .bind(service_id)
+    .bind(&object_type)
+    .bind(&relation)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(true) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "status": "relation_deleted",
+        "service_id": service_id,
+        "object_type": object_type,
+        "relation": relation,
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Ok(false) => error_response(StatusCode::NotFound, "relation_not_found"),
+    Err(_) => error_response(
+      StatusCode::InternalServerError,
+      "delete_relation_definition_failed",
+    ),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct RelationTuplePayload {
+  service_id: FlexibleId,
+  object: String,
+  relation: String,
+  subject: String,
+}
+
+/// A validated tuple: the relation is defined on the object type and, for usersets, on the
+/// subject type too.
+struct RelationTuple {
+  service_id: i32,
+  object: ObjectRef,
+  relation: String,
+  subject: SubjectRef,
+}
+
+async fn resolve_relation_tuple(
+  db: &crate::database::DB,
+  tenant_id: i32,
+  payload: RelationTuplePayload,
+) -> Result<RelationTuple, Response> {
+  let object = ObjectRef::parse(&payload.object)
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "invalid_object"))?;
+  let subject = SubjectRef::parse(&payload.subject)
+    .ok_or_else(|| error_response(StatusCode::BadRequest, "invalid_subject"))?;
+  let service_id = relation_service(db, tenant_id, &payload.service_id, true).await?;
+  require_relation_defined(db, service_id, &object.object_type, &payload.relation).await?;
+  if let Some(subject_relation) = &subject.relation {
+    require_relation_defined(
+      db,
+      service_id,
+      &subject.object.object_type,
+      subject_relation,
+    )
+    .await?;
+  }
+  require_subject_access(db, tenant_id, &subject.object).await?;
+  Ok(RelationTuple {
+    service_id,
+    object,
+    relation: payload.relation,
+    subject,
+  })
+}
+
+async fn change_relation_tuple(req: &Request, procedure: &str, status: &str) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, RELATIONSHIPS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let payload: RelationTuplePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let tuple = match resolve_relation_tuple(&db, token_tenant_id(&validation), payload).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let query = format!("CALL auth.{}($1, $2, $3, $4, $5, $6, $7)", procedure);
+  match sqlx::query(&query)
+    .bind(tuple.service_id)
+    .bind(&tuple.object.object_type)
+    .bind(&tuple.object.object_id)
+    .bind(&tuple.relation)
+    .bind(&tuple.subject.object.object_type)
+    .bind(&tuple.subject.object.object_id)
+    .bind(&tuple.subject.relation)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "status": status,
+        "service_id": tuple.service_id,
+        "object": format!("{}:{}", tuple.object.object_type, tuple.object.object_id),
+        "relation": tuple.relation,
+        "subject": format_subject(
+          &tuple.subject.object.object_type,
+          &tuple.subject.object.object_id,
+          tuple.subject.relation.as_deref(),
+        ),
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] {}: {}", procedure, err);
+      error_response(
+        StatusCode::InternalServerError,
+        &format!("{}_failed", procedure),
+      )
+    }
+  }
+}
+
+fn format_subject(subject_type: &str, subject_id: &str, relation: Option<&str>) -> String {
+  match relation {
+    Some(relation) => format!("{}:{}#{}", subject_type, subject_id, relation),
+    None => format!("{}:{}", subject_type, subject_id),
+  }
+}
+
+pub async fn write_relation_tuple(req: &Request) -> Response {
+  change_relation_tuple(req, "write_relation_tuple", "relation_tuple_written").await
+}
+
+pub async fn delete_relation_tuple(req: &Request) -> Response {
+  change_relation_tuple(req, "delete_relation_tuple", "relation_tuple_deleted").await
+}
+
+#[derive(sqlx::FromRow)]
+struct RelationTupleRow {
+  object_type: String,
+  object_id: String,
+  relation: String,
+  subject_type: String,
+  subject_id: String,
+  subject_relation: Option<String>,
+}
+
+/// `?service_id=` is required; `&object=type` or `&object=type:id` narrows the listing.
+pub async fn list_relation_tuples(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let identifier = match req.params.get("service_id") {
+    Some(id) => FlexibleId::from(id.clone()),
+    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
+  };
+  let (object_type, object_id) = match req.params.get("object").map(|raw| raw.trim()) {
+    None | Some("") => (None, None),
+    Some(raw) if is_valid_relation_name(raw) => (Some(raw.to_string()), None),
+    Some(raw) => match ObjectRef::parse(raw) {
+      Some(object) => (Some(object.object_type), Some(object.object_id)),
+      None => return error_response(StatusCode::BadRequest, "invalid_object"),
+    },
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let service_id = match relation_service(&db, tenant_id, &identifier, false).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, RelationTupleRow>("SELECT * FROM auth.list_relation_tuples($1, $2, $3)")
+    .bind(service_id)
+    .bind(object_type)
+    .bind(object_id)
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(rows) => {
+      let tuples: Vec<_> = rows
+        .iter()
+        .map(|row| {
+          json!({
+            "object": format!("{}:{}", row.object_type, row.object_id),
+            "relation": row.relation,
+            "subject": format_subject(
+              &row.subject_type,
+              &row.subject_id,
+              row.subject_relation.as_deref(),
+            ),
+          })
+        })
+        .collect();
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: serde_json::to_vec(&tuples).unwrap(),
+      }
+    }
+    Err(_) => error_response(
+      StatusCode::InternalServerError,
+      "list_relation_tuples_failed",
+    ),
+  }
+}
+
+#[derive(Deserialize)]
+pub struct CheckRelationPayload {
+  service_id: FlexibleId,
+  object: String,
+  relation: String,
+  /// Defaults to the caller (`user:<id>`); checking someone else needs `relations.write`.
+  subject: Option<String>,
+}
+
+pub async fn check_relation(req: &Request) -> Response {
+  let payload: CheckRelationPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let auth = match payload.subject {
+    Some(_) => require_admin_permission(req, RELATIONSHIPS_WRITE).await,
+    None => require_token_with_renew(req).await,
+  };
+  let (db, validation, _) = match auth {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let tenant_id = token_tenant_id(&validation);
+  let subject = match &payload.subject {
+    Some(raw) => raw.trim().to_string(),
+    None => match token_user_id(&validation) {
+      Some(user_id) => format!("user:{}", user_id),
+      None => return unauthorized_response("invalid_token"),
+    },
+  };
+  let object = match ObjectRef::parse(&payload.object) {
+    Some(object) => object,
+    None => return error_response(StatusCode::BadRequest, "invalid_object"),
+  };
+  // Usersets are something to grant, not someone to check.
+  let subject_ref = match ObjectRef::parse(&subject) {
+    Some(subject) => subject,
+    None => return error_response(StatusCode::BadRequest, "invalid_subject"),
+  };
+  let service_id = match relation_service(&db, tenant_id, &payload.service_id, false).await {
+    Ok(id) => id,
+    Err(response) => return response,
+  };
+  if let Err(response) =
+    require_relation_defined(&db, service_id, &object.object_type, &payload.relation).await
+  {
+    return response;
+  }
+  if let Err(response) = require_subject_access(&db, tenant_id, &subject_ref).await {
+    return response;
+  }
+  match sqlx::query_scalar::<_, bool>("SELECT auth.check_relation($1, $2, $3, $4, $5, $6, $7)")
+    .bind(service_id)
+    .bind(&object.object_type)
+    .bind(&object.object_id)
+    .bind(&payload.relation)
+    .bind(&subject_ref.object_type)
+    .bind(&subject_ref.object_id)
+    .bind(MAX_RELATION_DEPTH)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(allowed) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "allowed": allowed,
+        "service_id": service_id,
+        "object": payload.object.trim(),
+        "relation": payload.relation,
+        "subject": subject,
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] check_relation: {}", err);
+      error_response(StatusCode::InternalServerError, "check_relation_failed")
+    }
+  }
+}
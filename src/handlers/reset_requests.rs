@@ -0,0 +1,127 @@
+use httpageboy::{Response, StatusCode};
+use serde_json::json;
+use std::env;
+
+use super::{current_epoch, error_response};
+
+const USERNAME_SCOPE: &str = "username";
+const IP_SCOPE: &str = "ip";
+
+/// Password reset request limits, read from the environment on each request like the lockout
+/// settings.
+#[derive(Debug, Clone)]
+struct ResetRequestConfig {
+  /// Requests per username within a window.
+  username_limit: i32,
+  /// Requests per client IP within a window; higher, since one IP may serve many people.
+  ip_limit: i32,
+  window_seconds: i64,
+}
+
+impl ResetRequestConfig {
+  const DEFAULT_USERNAME_LIMIT: i64 = 3;
+  const DEFAULT_IP_LIMIT: i64 = 20;
+  const DEFAULT_WINDOW_SECONDS: i64 = 60 * 60;
+
+  fn load_env(key: &str, fallback: i64) -> i64 {
+    env::var(key)
+      .ok()
+      .and_then(|v| v.parse::<i64>().ok())
+      .filter(|v| *v > 0)
+      .unwrap_or(fallback)
+  }
+
+  fn load() -> Self {
+    Self {
+      username_limit: Self::load_env(
+        "PASSWORD_RESET_USERNAME_LIMIT",
+        Self::DEFAULT_USERNAME_LIMIT,
+      ) as i32,
+      ip_limit: Self::load_env("PASSWORD_RESET_IP_LIMIT", Self::DEFAULT_IP_LIMIT) as i32,
+      window_seconds: Self::load_env(
+        "PASSWORD_RESET_WINDOW_SECONDS",
+        Self::DEFAULT_WINDOW_SECONDS,
+      ),
+    }
+  }
+}
+
+/// Counts one request against `(scope, key)` and returns when its window ends if the count is
+/// now over `limit`. A window starts with the first request after the previous one ended.
+async fn count_request(
+  db: &crate::database::DB,
+  config: &ResetRequestConfig,
+  scope: &str,
+  key: &str,
+  limit: i32,
+  now: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+  let (requests, window_start) = sqlx::query_as::<_, (i32, i64)>(
+    "INSERT INTO auth.password_reset_requests AS r (scope, key, requests, window_start)
+      VALUES ($1, $2, 1, $3)
+      ON CONFLICT (scope, key) DO UPDATE
+        SET requests = CASE WHEN r.window_start <= $3 - $4 THEN 1 ELSE r.requests + 1 END,
+          window_start = CASE WHEN r.window_start <= $3 - $4 THEN $3 ELSE r.window_start END
+      RETURNING requests, window_start",
+  )
+  .bind(scope)
+  .bind(key)
+  .bind(now)
+  .bind(config.window_seconds)
+  .fetch_one(db.pool())
+  .await?;
+  Ok((requests > limit).then_some(window_start + config.window_seconds))
+}
+
+/// Counts a reset request against the username (known or not, so the limit reveals nothing) and
+/// the client IP when one is known (see `client_ip` for when it can be trusted);
+/// `429 too_many_reset_requests` with the seconds left once either is over its limit.
+pub(super) async fn throttle_reset_request(
+  db: &crate::database::DB,
+  username: &str,
+  ip: Option<&str>,
+) -> Result<(), Response> {
+  let config = ResetRequestConfig::load();
+  let now = current_epoch();
+  let mut blocked_until = None;
+  let mut scopes = vec![(USERNAME_SCOPE, username, config.username_limit)];
+  if let Some(ip) = ip {
+    scopes.push((IP_SCOPE, ip, config.ip_limit));
+  }
+  for (scope, key, limit) in scopes {
+    match count_request(db, &config, scope, key, limit, now).await {
+      Ok(until) => blocked_until = blocked_until.max(until),
+      Err(_) => {
+        return Err(error_response(
+          StatusCode::InternalServerError,
+          "password_reset_failed",
+        ));
+      }
+    }
+  }
+  match blocked_until {
+    Some(blocked_until) => Err(Response {
+      status: StatusCode::TooManyRequests.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "error": "too_many_reset_requests",
+        "retry_after": blocked_until - now,
+      })
+      .to_string()
+      .into_bytes(),
+    }),
+    None => Ok(()),
+  }
+}
+
+/// Drops counters whose window has ended.
+pub async fn prune_reset_requests(db: &crate::database::DB) -> Result<u64, sqlx::Error> {
+  let config = ResetRequestConfig::load();
+  let cutoff = current_epoch() - config.window_seconds;
+  let rows = sqlx::query("DELETE FROM auth.password_reset_requests WHERE window_start <= $1")
+    .bind(cutoff)
+    .execute(db.pool())
+    .await?
+    .rows_affected();
+  Ok(rows)
+}
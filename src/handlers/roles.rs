@@ -1,8 +1,13 @@
+use crate::auth::TokenManager;
 use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{error_response, require_token_with_renew};
+use super::permissions::Permission;
+use super::{
+  error_response, require_admin_permission, require_tenant_access, require_token_with_renew,
+  token_tenant_id, TenantResource, ROLES_WRITE,
+};
 
 #[derive(Serialize, sqlx::FromRow)]
 pub struct Role {
@@ -16,7 +21,7 @@ pub struct CreateRolePayload {
 }
 
 pub async fn create_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -24,8 +29,9 @@ pub async fn create_role(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  match sqlx::query_as::<_, Role>("SELECT * FROM auth.create_role($1)")
+  match sqlx::query_as::<_, Role>("SELECT * FROM auth.create_role($1, $2)")
     .bind(payload.name)
+    .bind(token_tenant_id(&validation))
     .fetch_one(db.pool())
     .await
   {
@@ -39,11 +45,12 @@ pub async fn create_role(req: &Request) -> Response {
 }
 
 pub async fn list_roles(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
-  match sqlx::query_as::<_, Role>("SELECT * FROM auth.list_roles()")
+  match sqlx::query_as::<_, Role>("SELECT * FROM auth.list_roles($1)")
+    .bind(token_tenant_id(&validation))
     .fetch_all(db.pool())
     .await
   {
@@ -57,7 +64,7 @@ pub async fn list_roles(req: &Request) -> Response {
 }
 
 pub async fn get_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -65,6 +72,12 @@ pub async fn get_role(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, false).await
+  {
+    return response;
+  }
   match sqlx::query_as::<_, Role>("SELECT * FROM auth.get_role($1)")
     .bind(id)
     .fetch_optional(db.pool())
@@ -86,7 +99,7 @@ pub struct UpdateRolePayload {
 }
 
 pub async fn update_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -94,6 +107,12 @@ pub async fn update_role(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, true).await
+  {
+    return response;
+  }
   let payload: UpdateRolePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
@@ -117,7 +136,7 @@ pub async fn update_role(req: &Request) -> Response {
 }
 
 pub async fn delete_role(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -125,6 +144,12 @@ pub async fn delete_role(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, true).await
+  {
+    return response;
+  }
   match sqlx::query("CALL auth.delete_role($1)")
     .bind(id)
     .execute(db.pool())
@@ -140,3 +165,178 @@ pub async fn delete_role(req: &Request) -> Response {
     Err(_) => error_response(StatusCode::InternalServerError, "delete_role_failed"),
   }
 }
+
+#[derive(Deserialize)]
+pub struct RoleParentPayload {
+  parent_role_id: i32,
+}
+
+pub async fn add_role_parent(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, true).await
+  {
+    return response;
+  }
+  let payload: RoleParentPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  // Shared roles may be inherited from, but not roles of another tenant.
+  let parent_id = payload.parent_role_id;
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, parent_id, false).await
+  {
+    return response;
+  }
+  let outcome = match sqlx::query_scalar::<_, String>("SELECT auth.add_role_parent($1, $2)")
+    .bind(id)
+    .bind(payload.parent_role_id)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(outcome) => outcome,
+    Err(_) => return error_response(StatusCode::InternalServerError, "add_role_parent_failed"),
+  };
+  match outcome.as_str() {
+    "linked" => {}
+    "role_not_found" => return error_response(StatusCode::NotFound, "role_not_found"),
+    _ => return error_response(StatusCode::Conflict, "role_hierarchy_cycle"),
+  }
+  let manager = TokenManager::new(db.pool());
+  if manager.clear_access_cache().await.is_err() {
+    return error_response(
+      StatusCode::InternalServerError,
+      "invalidate_access_cache_failed",
+    );
+  }
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "status": "role_parent_added",
+      "role_id": id,
+      "parent_role_id": payload.parent_role_id,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+pub async fn remove_role_parent(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, ROLES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, true).await
+  {
+    return response;
+  }
+  let parent_id: i32 = match req.params.get("parent_id").and_then(|s| s.parse().ok()) {
+    Some(parent_id) => parent_id,
+    None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
+  };
+  match sqlx::query("CALL auth.remove_role_parent($1, $2)")
+    .bind(id)
+    .bind(parent_id)
+    .execute(db.pool())
+    .await
+  {
+    Ok(_) => {
+      let manager = TokenManager::new(db.pool());
+      if manager.clear_access_cache().await.is_err() {
+        return error_response(
+          StatusCode::InternalServerError,
+          "invalidate_access_cache_failed",
+        );
+      }
+      Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "status": "role_parent_removed",
+          "role_id": id,
+          "parent_role_id": parent_id,
+        })
+        .to_string()
+        .into_bytes(),
+      }
+    }
+    Err(_) => error_response(StatusCode::InternalServerError, "remove_role_parent_failed"),
+  }
+}
+
+pub async fn list_role_parents(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, false).await
+  {
+    return response;
+  }
+  match sqlx::query_as::<_, Role>("SELECT * FROM auth.list_role_parents($1)")
+    .bind(id)
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(roles) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&roles).unwrap(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "list_role_parents_failed"),
+  }
+}
+
+pub async fn list_role_effective_permissions(req: &Request) -> Response {
+  let (db, validation, _) = match require_token_with_renew(req).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_role_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Role, id, false).await
+  {
+    return response;
+  }
+  match sqlx::query_as::<_, Permission>("SELECT * FROM auth.list_role_effective_permissions($1)")
+    .bind(id)
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(permissions) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&permissions).unwrap(),
+    },
+    Err(_) => error_response(
+      StatusCode::InternalServerError,
+      "list_role_effective_permissions_failed",
+    ),
+  }
+}
@@ -3,64 +3,54 @@ use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use super::{error_response, require_token_with_renew};
+use super::{
+  error_response, require_admin_permission, require_tenant_access, require_token_with_renew,
+  token_tenant_id, TenantResource, AUTH_SERVICE_NAME, SERVICES_WRITE,
+};
 
 #[derive(Serialize, sqlx::FromRow)]
 pub struct Service {
   id: i32,
   name: String,
   description: Option<String>,
+  strict_roles: Option<bool>,
 }
 
 #[derive(Deserialize)]
 pub struct CreateServicePayload {
   name: String,
   description: Option<String>,
+  strict_roles: Option<bool>,
 }
 
-async fn require_service_registration(req: &Request) -> Result<crate::database::DB, Response> {
-  let (db, validation, _) = match require_token_with_renew(req).await {
-    Ok(tuple) => tuple,
-    Err(response) => return Err(response),
-  };
-  let user_id = match validation
-    .record
-    .payload
-    .get("user_id")
-    .and_then(|value| value.as_i64())
-  {
-    Some(id) => id as i32,
-    None => return Err(error_response(StatusCode::Unauthorized, "invalid_token")),
-  };
-  match sqlx::query_scalar::<_, bool>(
-    "SELECT can_register_services FROM auth.person WHERE id = $1 AND removed_at IS NULL",
-  )
-  .bind(user_id)
-  .fetch_optional(db.pool())
-  .await
+/// The reserved `auth` service backs admin checks by name, so it cannot be renamed or disabled.
+async fn reject_reserved_service(db: &crate::database::DB, id: i32) -> Result<(), Response> {
+  match sqlx::query_scalar::<_, bool>("SELECT name = $2 FROM auth.services WHERE id = $1")
+    .bind(id)
+    .bind(AUTH_SERVICE_NAME)
+    .fetch_optional(db.pool())
+    .await
   {
-    Ok(Some(true)) => Ok(db),
-    Ok(Some(false)) => Err(error_response(StatusCode::Forbidden, "insufficient_permissions")),
-    Ok(None) => Err(error_response(StatusCode::Unauthorized, "invalid_token")),
-    Err(_) => Err(error_response(
-      StatusCode::InternalServerError,
-      "service_permission_check_failed",
-    )),
+    Ok(Some(true)) => Err(error_response(StatusCode::Forbidden, "reserved_service")),
+    Ok(_) => Ok(()),
+    Err(_) => Err(error_response(StatusCode::InternalServerError, "load_service_failed")),
   }
 }
 
 pub async fn create_service(req: &Request) -> Response {
-  let db = match require_service_registration(req).await {
-    Ok(db) => db,
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
     Err(response) => return response,
   };
   let payload: CreateServicePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  match sqlx::query_as::<_, Service>("SELECT * FROM auth.create_service($1, $2)")
+  match sqlx::query_as::<_, Service>("SELECT * FROM auth.create_service($1, $2, $3, $4)")
     .bind(payload.name)
     .bind(payload.description)
+    .bind(payload.strict_roles)
+    .bind(token_tenant_id(&validation))
     .fetch_one(db.pool())
     .await
   {
@@ -74,11 +64,12 @@ pub async fn create_service(req: &Request) -> Response {
 }
 
 pub async fn list_services(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
-  match sqlx::query_as::<_, Service>("SELECT * FROM auth.list_services()")
+  match sqlx::query_as::<_, Service>("SELECT * FROM auth.list_services($1)")
+    .bind(token_tenant_id(&validation))
     .fetch_all(db.pool())
     .await
   {
@@ -95,25 +86,36 @@ pub async fn list_services(req: &Request) -> Response {
 pub struct UpdateServicePayload {
   name: Option<String>,
   description: Option<String>,
+  strict_roles: Option<bool>,
 }
 
 pub async fn update_service(req: &Request) -> Response {
-  let db = match require_service_registration(req).await {
-    Ok(db) => db,
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
     Err(response) => return response,
   };
   let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
   };
+  if let Err(response) = reject_reserved_service(&db, id).await {
+    return response;
+  }
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Service, id, true).await
+  {
+    return response;
+  }
   let payload: UpdateServicePayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
-  match sqlx::query("CALL auth.update_service($1, $2, $3)")
+  match sqlx::query("CALL auth.update_service($1, $2, $3, $4)")
     .bind(id)
     .bind(payload.name)
     .bind(payload.description)
+    .bind(payload.strict_roles)
     .execute(db.pool())
     .await
   {
@@ -127,14 +129,23 @@ pub async fn update_service(req: &Request) -> Response {
 }
 
 pub async fn delete_service(req: &Request) -> Response {
-  let db = match require_service_registration(req).await {
-    Ok(db) => db,
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
     Err(response) => return response,
   };
   let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
   };
+  if let Err(response) = reject_reserved_service(&db, id).await {
+    return response;
+  }
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Service, id, true).await
+  {
+    return response;
+  }
   match sqlx::query("CALL auth.delete_service($1)")
     .bind(id)
     .execute(db.pool())
@@ -158,32 +169,52 @@ struct ServiceTokenData {
   status: bool,
 }
 
-pub async fn issue_service_token(req: &Request) -> Response {
-  let db = match require_service_registration(req).await {
-    Ok(db) => db,
-    Err(response) => return response,
-  };
-  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
-    Some(id) => id,
-    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
-  };
-
-  let service = match sqlx::query_as::<_, ServiceTokenData>(
+async fn load_service(db: &crate::database::DB, id: i32) -> Result<ServiceTokenData, Response> {
+  match sqlx::query_as::<_, ServiceTokenData>(
     "SELECT id, name, status FROM auth.services WHERE id = $1",
   )
   .bind(id)
   .fetch_optional(db.pool())
   .await
   {
-    Ok(Some(service)) => service,
-    Ok(None) => return error_response(StatusCode::NotFound, "service_not_found"),
-    Err(_) => return error_response(StatusCode::InternalServerError, "load_service_failed"),
-  };
+    Ok(Some(service)) => Ok(service),
+    Ok(None) => Err(error_response(StatusCode::NotFound, "service_not_found")),
+    Err(_) => Err(error_response(StatusCode::InternalServerError, "load_service_failed")),
+  }
+}
 
+async fn load_active_service(
+  db: &crate::database::DB,
+  id: i32,
+) -> Result<ServiceTokenData, Response> {
+  let service = load_service(db, id).await?;
   if !service.status {
-    return error_response(StatusCode::Forbidden, "service_inactive");
+    return Err(error_response(StatusCode::Forbidden, "service_inactive"));
+  }
+  Ok(service)
+}
+
+pub async fn issue_service_token(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Service, id, true).await
+  {
+    return response;
   }
 
+  let service = match load_active_service(&db, id).await {
+    Ok(service) => service,
+    Err(response) => return response,
+  };
+
   let manager = TokenManager::new(db.pool());
   let issued = match manager.issue_service_token(service.id, &service.name).await {
     Ok(issue) => issue,
@@ -201,8 +232,151 @@ pub async fn issue_service_token(req: &Request) -> Response {
     content: json!({
       "service_id": service.id,
       "service_name": service.name,
-      "service_token": issued.token,
-      "expires_at": issued.expires_at,
+      "token_id": issued.id,
+      "service_token": issued.token.token,
+      "expires_at": issued.token.expires_at,
+    })
+    .to_string()
+    .into_bytes(),
+  }
+}
+
+pub async fn list_service_tokens(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Service, id, true).await
+  {
+    return response;
+  }
+  if let Err(response) = load_service(&db, id).await {
+    return response;
+  }
+
+  let manager = TokenManager::new(db.pool());
+  match manager.list_service_tokens(id).await {
+    Ok(tokens) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({ "service_id": id, "tokens": tokens })
+        .to_string()
+        .into_bytes(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "list_service_tokens_failed"),
+  }
+}
+
+pub async fn revoke_service_token(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Service, id, true).await
+  {
+    return response;
+  }
+  let token_id: i32 = match req.params.get("token_id").and_then(|s| s.parse().ok()) {
+    Some(token_id) => token_id,
+    None => return error_response(StatusCode::BadRequest, "invalid_token_id"),
+  };
+
+  let manager = TokenManager::new(db.pool());
+  match manager.revoke_service_token(id, token_id).await {
+    Ok(true) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "status": "service_token_revoked",
+        "service_id": id,
+        "token_id": token_id,
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Ok(false) => error_response(StatusCode::NotFound, "service_token_not_found"),
+    Err(_) => error_response(StatusCode::InternalServerError, "revoke_service_token_failed"),
+  }
+}
+
+#[derive(Deserialize, Default)]
+pub struct RotateServiceTokenPayload {
+  grace_seconds: Option<i64>,
+}
+
+pub async fn rotate_service_token(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, SERVICES_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Service, id, true).await
+  {
+    return response;
+  }
+  let payload: RotateServiceTokenPayload = if req.body.trim().is_empty() {
+    RotateServiceTokenPayload::default()
+  } else {
+    match serde_json::from_slice(req.body.as_bytes()) {
+      Ok(p) => p,
+      Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+    }
+  };
+
+  let service = match load_active_service(&db, id).await {
+    Ok(service) => service,
+    Err(response) => return response,
+  };
+
+  let manager = TokenManager::new(db.pool());
+  let grace_seconds = payload
+    .grace_seconds
+    .unwrap_or_else(|| manager.service_rotation_grace());
+  // Old tokens may not outlast a fresh one.
+  if grace_seconds < 0 || manager.service_ttl().is_some_and(|ttl| grace_seconds > ttl) {
+    return error_response(StatusCode::BadRequest, "invalid_grace_seconds");
+  }
+  let rotation = match manager
+    .rotate_service_token(service.id, &service.name, grace_seconds)
+    .await
+  {
+    Ok(rotation) => rotation,
+    Err(_) => {
+      return error_response(
+        StatusCode::InternalServerError,
+        "rotate_service_token_failed",
+      );
+    }
+  };
+
+  Response {
+    status: StatusCode::Ok.to_string(),
+    content_type: "application/json".to_string(),
+    content: json!({
+      "service_id": service.id,
+      "service_name": service.name,
+      "token_id": rotation.issued.id,
+      "service_token": rotation.issued.token.token,
+      "expires_at": rotation.issued.token.expires_at,
+      "retired_tokens": rotation.retired_tokens,
+      "grace_expires_at": rotation.grace_expires_at,
     })
     .to_string()
     .into_bytes(),
@@ -210,7 +384,7 @@ pub async fn issue_service_token(req: &Request) -> Response {
 }
 
 pub async fn list_services_of_person(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -218,8 +392,15 @@ pub async fn list_services_of_person(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_person_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::Person, person_id, false).await
+  {
+    return response;
+  }
   match sqlx::query_as::<_, Service>(
-    "SELECT id, name, NULL as description FROM auth.list_services_of_person($1)",
+    "SELECT id, name, NULL as description, NULL::BOOLEAN as strict_roles
+      FROM auth.list_services_of_person($1)",
   )
   .bind(person_id)
   .fetch_all(db.pool())
@@ -0,0 +1,111 @@
+use httpageboy::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::users::{auth_types, hash_password};
+use super::{
+  error_response, require_admin_permission, token_tenant_id, DEFAULT_TENANT_ID, TENANTS_WRITE,
+};
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct Tenant {
+  id: i32,
+  name: String,
+  owner_person_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct TenantOwnerPayload {
+  username: String,
+  password_hash: String,
+  name: String,
+  document_type: String, // DNI, CE, or RUC
+  document_number: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateTenantPayload {
+  name: String,
+  owner: TenantOwnerPayload,
+}
+
+/// Tenants are managed from the default tenant; `tenants.write` held elsewhere grants nothing.
+async fn require_tenant_admin(req: &Request) -> Result<crate::database::DB, Response> {
+  let (db, validation, _) = require_admin_permission(req, TENANTS_WRITE).await?;
+  if token_tenant_id(&validation) != DEFAULT_TENANT_ID {
+    return Err(error_response(
+      StatusCode::Forbidden,
+      "insufficient_permissions",
+    ));
+  }
+  Ok(db)
+}
+
+pub async fn create_tenant(req: &Request) -> Response {
+  let db = match require_tenant_admin(req).await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  let payload: CreateTenantPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+
+  let owner = &payload.owner;
+  let is_blank = |value: &str| value.trim().is_empty();
+  if is_blank(&payload.name)
+    || is_blank(&owner.username)
+    || is_blank(&owner.password_hash)
+    || is_blank(&owner.name)
+    || is_blank(&owner.document_number)
+  {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+  let document_type: auth_types::DocumentType =
+    match serde_json::from_str(&format!("\"{}\"", owner.document_type.trim())) {
+      Ok(value) => value,
+      Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+    };
+  let password_hash = match hash_password(&owner.password_hash) {
+    Ok(hashed) => hashed,
+    Err(resp) => return resp,
+  };
+
+  match sqlx::query_as::<_, Tenant>("SELECT * FROM auth.create_tenant($1, $2, $3, $4, $5, $6)")
+    .bind(payload.name.trim())
+    .bind(&owner.username)
+    .bind(password_hash)
+    .bind(&owner.name)
+    .bind(document_type)
+    .bind(&owner.document_number)
+    .fetch_one(db.pool())
+    .await
+  {
+    Ok(tenant) => Response {
+      status: StatusCode::Created.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&tenant).unwrap(),
+    },
+    Err(err) => {
+      eprintln!("[handler-error] create_tenant: {}", err);
+      error_response(StatusCode::InternalServerError, "create_tenant_failed")
+    }
+  }
+}
+
+pub async fn list_tenants(req: &Request) -> Response {
+  let db = match require_tenant_admin(req).await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+  match sqlx::query_as::<_, Tenant>("SELECT * FROM auth.list_tenants()")
+    .fetch_all(db.pool())
+    .await
+  {
+    Ok(tenants) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: serde_json::to_vec(&tenants).unwrap(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "list_tenants_failed"),
+  }
+}
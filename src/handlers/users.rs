@@ -1,15 +1,20 @@
-use crate::auth::TokenManager;
+use crate::auth::{RefreshError, SessionIssue, TokenManager};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use httpageboy::{Request, Response, StatusCode};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 
+use super::login_attempts::{check_login_lock, clear_login_failures, record_login_failure};
+use super::mfa::remaining_recovery_codes;
 use super::{
-  FlexibleId, error_response, extract_service_token, get_db_connection,
-  load_roles_and_permissions, log_access, require_token_with_renew,
-  require_token_with_renew_no_log, unauthorized_response, with_auth, with_auth_no_renew,
+  ConditionContextOverride, FlexibleId, PermissionMode, TenantResource, condition_context,
+  decide_permissions, error_response, extract_service_token, get_db_connection,
+  load_access_snapshot, log_access, normalize_scopes, permission_matches, require_admin_permission,
+  require_tenant_access, require_token_with_renew, require_token_with_renew_no_log,
+  session_metadata, token_scopes, token_tenant_id, token_user_id, unauthorized_response, with_auth,
+  with_auth_no_renew, USERS_WRITE,
 };
-use std::time::{SystemTime, UNIX_EPOCH};
 
 // Basic endpoints
 pub async fn home(_req: &Request) -> Response {
@@ -24,6 +29,7 @@ pub async fn home(_req: &Request) -> Response {
 pub struct LoginPayload {
   username: String,
   password: String,
+  scopes: Option<Vec<String>>,
 }
 
 #[derive(sqlx::FromRow)]
@@ -32,9 +38,11 @@ struct AuthUser {
   username: String,
   password_hash: String,
   name: String,
+  tenant_id: i32,
+  mfa_enabled: bool,
 }
 
-fn hash_password(password: &str) -> Result<String, Response> {
+pub(super) fn hash_password(password: &str) -> Result<String, Response> {
   hash(password, DEFAULT_COST)
     .map_err(|_| error_response(StatusCode::InternalServerError, "hash_password_failed"))
 }
@@ -44,21 +52,30 @@ pub async fn login(req: &Request) -> Response {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
   };
+  let scopes = match payload.scopes.as_deref().map(normalize_scopes).transpose() {
+    Ok(scopes) => scopes,
+    Err(response) => return response,
+  };
 
   let db = match get_db_connection().await {
     Ok(db) => db,
     Err(response) => return response,
   };
+  let metadata = session_metadata(req);
+  if let Err(response) = check_login_lock(&db, &payload.username, metadata.ip.as_deref()).await {
+    return response;
+  }
 
   let user = match sqlx::query_as::<_, AuthUser>(
-    "SELECT id, username, password_hash, name FROM auth.person WHERE username = $1 AND removed_at IS NULL",
+    "SELECT id, username, password_hash, name, tenant_id, mfa_enabled
+      FROM auth.person WHERE username = $1 AND removed_at IS NULL",
   )
   .bind(&payload.username)
   .fetch_optional(db.pool())
   .await
   {
     Ok(Some(user)) => user,
-    Ok(None) => return unauthorized_response("invalid_credentials"),
+    Ok(None) => return reject_login(&db, &payload.username, metadata.ip.as_deref()).await,
     Err(_) => {
       return error_response(
         StatusCode::InternalServerError,
@@ -69,55 +86,47 @@ pub async fn login(req: &Request) -> Response {
 
   match verify(&payload.password, &user.password_hash) {
     Ok(true) => {}
-    _ => return unauthorized_response("invalid_credentials"),
+    _ => return reject_login(&db, &payload.username, metadata.ip.as_deref()).await,
   }
 
-  let user_payload = json!({
+  let mut user_payload = json!({
     "user_id": user.id,
     "username": user.username,
     "name": user.name,
+    "tenant_id": user.tenant_id,
   });
+  if let Some(scopes) = scopes {
+    user_payload["scopes"] = json!(scopes);
+  }
 
+  // Only token hashes are stored, so an existing token cannot be handed back; each login issues a new one.
   let manager = TokenManager::new(db.pool());
-  let now = SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .unwrap_or_default()
-    .as_secs() as i64;
-  let existing_token = match sqlx::query_as::<_, (String, i64)>(
-    "SELECT token, expires_at FROM auth.tokens_cache
-      WHERE payload ->> 'user_id' = $1 AND expires_at > $2
-      ORDER BY expires_at DESC
-      LIMIT 1",
-  )
-  .bind(user.id.to_string())
-  .bind(now)
-  .fetch_optional(db.pool())
-  .await
-  {
-    Ok(token) => token,
-    Err(_) => {
-      return error_response(
-        StatusCode::InternalServerError,
-        "login_lookup_failed",
-      );
-    }
-  };
-
-  if let Some((token, expires_at)) = existing_token {
-    log_access(req, false);
-    return Response {
-      status: StatusCode::Ok.to_string(),
-      content_type: "application/json".to_string(),
-      content: json!({
-        "user_token": token,
-        "expires_at": expires_at,
-        "payload": user_payload,
-      })
-      .to_string()
-      .into_bytes(),
+  if user.mfa_enabled {
+    // The password alone only earns a challenge; /auth/mfa/verify trades it and a code
+    // for a session. Failures are kept until that code checks out, so re-logging in does
+    // not wipe the count of wrong codes.
+    return match manager.issue_mfa_challenge(user.id, user_payload).await {
+      Ok(challenge) => Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "mfa_required": true,
+          "mfa_token": challenge.token,
+          "expires_at": challenge.expires_at,
+        })
+        .to_string()
+        .into_bytes(),
+      },
+      Err(_) => error_response(StatusCode::InternalServerError, "login_issue_failed"),
     };
   }
-  let issued = match manager.issue_token(user_payload.clone()).await {
+  if clear_login_failures(&db, &user.username).await.is_err() {
+    return error_response(StatusCode::InternalServerError, "login_lookup_failed");
+  }
+  let issued = match manager
+    .issue_user_session(user.id, user_payload, &metadata)
+    .await
+  {
     Ok(issue) => issue,
     Err(_) => {
       return error_response(StatusCode::InternalServerError, "login_issue_failed");
@@ -126,23 +135,131 @@ pub async fn login(req: &Request) -> Response {
 
   log_access(req, false);
 
+  session_response(issued)
+}
+
+/// Counts the failure towards lockout, then answers like any other bad credential.
+async fn reject_login(db: &crate::database::DB, username: &str, ip: Option<&str>) -> Response {
+  if let Err(err) = record_login_failure(db, username, ip).await {
+    eprintln!("[handler-error] record_login_failure: {}", err);
+  }
+  unauthorized_response("invalid_credentials")
+}
+
+pub(super) fn session_response(issued: SessionIssue) -> Response {
   Response {
     status: StatusCode::Ok.to_string(),
     content_type: "application/json".to_string(),
     content: json!({
-      "user_token": issued.token,
-      "expires_at": issued.expires_at,
-      "payload": user_payload,
+      "user_token": issued.token.token,
+      "expires_at": issued.token.expires_at,
+      "refresh_token": issued.refresh.token,
+      "refresh_expires_at": issued.refresh.expires_at,
+      "session_id": issued.session_id,
+      "payload": issued.payload,
     })
     .to_string()
     .into_bytes(),
   }
 }
 
+#[derive(Deserialize)]
+pub struct RefreshPayload {
+  refresh_token: String,
+}
+
+pub async fn refresh(req: &Request) -> Response {
+  let payload: RefreshPayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  if payload.refresh_token.trim().is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+
+  let db = match get_db_connection().await {
+    Ok(db) => db,
+    Err(response) => return response,
+  };
+
+  let manager = TokenManager::new(db.pool());
+  let issued = match manager.refresh_user_session(payload.refresh_token.trim()).await {
+    Ok(issue) => issue,
+    Err(RefreshError::NotFound) => return unauthorized_response("invalid_refresh_token"),
+    Err(RefreshError::Expired) => return unauthorized_response("expired_refresh_token"),
+    Err(RefreshError::Reused) => return unauthorized_response("refresh_token_reused"),
+    Err(RefreshError::Database(_)) => {
+      return error_response(StatusCode::InternalServerError, "refresh_failed");
+    }
+  };
+
+  log_access(req, false);
+
+  session_response(issued)
+}
+
+#[derive(Deserialize)]
+pub struct ExchangePayload {
+  scopes: Vec<String>,
+}
+
+/// Trades the presented user token for a narrower one in the same session. A scoped token can
+/// only be exchanged for scopes it already covers.
+pub async fn exchange_token(req: &Request) -> Response {
+  let payload: ExchangePayload = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(p) => p,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  let scopes = match normalize_scopes(&payload.scopes) {
+    Ok(scopes) => scopes,
+    Err(response) => return response,
+  };
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    if token_user_id(&validation).is_none() {
+      return unauthorized_response("invalid_token");
+    }
+    let current = token_scopes(&validation.record.payload);
+    let exceeds = scopes.iter().any(|scope| {
+      !current.is_empty() && !current.iter().any(|held| permission_matches(held, scope))
+    });
+    if exceeds {
+      return error_response(StatusCode::Forbidden, "scope_not_granted");
+    }
+
+    let mut exchanged_payload = validation.record.payload.clone();
+    exchanged_payload["scopes"] = json!(scopes);
+    let manager = TokenManager::new(db.pool());
+    match manager
+      .issue_derived_token(&validation.record, exchanged_payload.clone())
+      .await
+    {
+      Ok(issued) => Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({
+          "user_token": issued.token,
+          "expires_at": issued.expires_at,
+          "session_id": validation.record.session_id,
+          "scopes": scopes,
+          "payload": exchanged_payload,
+        })
+        .to_string()
+        .into_bytes(),
+      },
+      Err(_) => error_response(StatusCode::InternalServerError, "exchange_token_failed"),
+    }
+  })
+  .await
+}
+
 pub async fn logout(req: &Request) -> Response {
-  with_auth_no_renew(req, |_req, db, _, token| async move {
+  with_auth_no_renew(req, |_req, db, validation, token| async move {
     let manager = TokenManager::new(db.pool());
-    match manager.delete_token(&token).await {
+    let revoked = match (validation.record.session_id, token_user_id(&validation)) {
+      (Some(session_id), Some(user_id)) => manager.revoke_session(user_id, session_id).await,
+      _ => manager.delete_token(&token).await,
+    };
+    match revoked {
       Ok(_) => Response {
         status: StatusCode::Ok.to_string(),
         content_type: "application/json".to_string(),
@@ -154,16 +271,108 @@ pub async fn logout(req: &Request) -> Response {
   .await
 }
 
+pub async fn logout_all(req: &Request) -> Response {
+  with_auth_no_renew(req, |_req, db, validation, _token| async move {
+    let user_id = match token_user_id(&validation) {
+      Some(id) => id,
+      None => return unauthorized_response("invalid_token"),
+    };
+    let manager = TokenManager::new(db.pool());
+    match manager.revoke_sessions_for_user(user_id, None).await {
+      Ok(revoked) => Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({ "status": "logged_out_all", "revoked_sessions": revoked })
+          .to_string()
+          .into_bytes(),
+      },
+      Err(_) => error_response(StatusCode::InternalServerError, "logout_failed"),
+    }
+  })
+  .await
+}
+
+pub async fn list_sessions(req: &Request) -> Response {
+  with_auth(req, true, |_req, db, validation, _token| async move {
+    let user_id = match token_user_id(&validation) {
+      Some(id) => id,
+      None => return unauthorized_response("invalid_token"),
+    };
+    let manager = TokenManager::new(db.pool());
+    match manager.list_sessions(user_id).await {
+      Ok(sessions) => {
+        let sessions: Vec<_> = sessions
+          .into_iter()
+          .map(|session| {
+            json!({
+              "id": session.id,
+              "user_agent": session.user_agent,
+              "ip": session.ip,
+              "created_at": session.created_at,
+              "last_seen": session.last_seen,
+              "current": validation.record.session_id == Some(session.id),
+            })
+          })
+          .collect();
+        Response {
+          status: StatusCode::Ok.to_string(),
+          content_type: "application/json".to_string(),
+          content: serde_json::to_vec(&sessions).unwrap(),
+        }
+      }
+      Err(_) => error_response(StatusCode::InternalServerError, "list_sessions_failed"),
+    }
+  })
+  .await
+}
+
+pub async fn revoke_session(req: &Request) -> Response {
+  let session_id: Option<i32> = req.params.get("id").and_then(|s| s.parse().ok());
+  with_auth(req, true, |_req, db, validation, _token| async move {
+    let user_id = match token_user_id(&validation) {
+      Some(id) => id,
+      None => return unauthorized_response("invalid_token"),
+    };
+    let session_id = match session_id {
+      Some(id) => id,
+      None => return error_response(StatusCode::BadRequest, "invalid_session_id"),
+    };
+    let manager = TokenManager::new(db.pool());
+    match manager.revoke_session(user_id, session_id).await {
+      Ok(true) => Response {
+        status: StatusCode::Ok.to_string(),
+        content_type: "application/json".to_string(),
+        content: json!({ "status": "session_revoked", "session_id": session_id })
+          .to_string()
+          .into_bytes(),
+      },
+      Ok(false) => error_response(StatusCode::NotFound, "session_not_found"),
+      Err(_) => error_response(StatusCode::InternalServerError, "revoke_session_failed"),
+    }
+  })
+  .await
+}
+
 pub async fn profile(req: &Request) -> Response {
-  with_auth(req, true, |_req, _db, validation, _token| async move {
+  with_auth(req, true, |_req, db, validation, _token| async move {
     let payload = validation.record.payload.clone();
+    let recovery_codes_remaining = match token_user_id(&validation) {
+      Some(person_id) => match remaining_recovery_codes(&db, person_id).await {
+        Ok(count) => Some(count),
+        Err(_) => return error_response(StatusCode::InternalServerError, "profile_lookup_failed"),
+      },
+      None => None,
+    };
     Response {
       status: StatusCode::Ok.to_string(),
       content_type: "application/json".to_string(),
       content: json!({
         "payload": payload,
+        "session_id": validation.record.session_id,
         "renewed": validation.renewed,
         "expires_at": validation.expires_at,
+        "absolute_expires_at": validation.absolute_expires_at,
+        "recovery_codes_remaining": recovery_codes_remaining,
       })
       .to_string()
       .into_bytes(),
@@ -176,6 +385,11 @@ pub async fn check_permission(req: &Request) -> Response {
   #[derive(Deserialize, Default)]
   struct CheckPermissionRequest {
     service_id: Option<FlexibleId>,
+    permission: Option<String>,
+    permissions: Option<Vec<String>>,
+    #[serde(default)]
+    mode: PermissionMode,
+    context: Option<ConditionContextOverride>,
   }
 
   let payload: CheckPermissionRequest = if req.body.trim().is_empty() {
@@ -186,12 +400,27 @@ pub async fn check_permission(req: &Request) -> Response {
       Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
     }
   };
+  if payload.permissions.as_ref().is_some_and(|list| list.is_empty()) {
+    return error_response(StatusCode::BadRequest, "invalid_permission");
+  }
+  let requested: Vec<String> = payload
+    .permission
+    .iter()
+    .chain(payload.permissions.iter().flatten())
+    .map(|name| name.trim().to_string())
+    .collect();
+  if requested.iter().any(|name| name.is_empty()) {
+    return error_response(StatusCode::BadRequest, "invalid_permission");
+  }
+  let mode = payload.mode;
+  let context_override = payload.context;
 
   let (db, validation, token) = match require_token_with_renew_no_log(req).await {
     Ok(values) => values,
     Err(response) => return response,
   };
 
+  let tenant_id = token_tenant_id(&validation);
   let service_token = extract_service_token(req);
   let service_id = payload
     .service_id
@@ -203,6 +432,10 @@ pub async fn check_permission(req: &Request) -> Response {
   if service_token.is_some() == service_id.is_some() {
     return error_response(StatusCode::BadRequest, "invalid_request_body");
   }
+  // Frontend callers could claim any IP or header, so only backends may relay request context.
+  if context_override.is_some() && service_token.is_none() {
+    return error_response(StatusCode::BadRequest, "context_requires_service_token");
+  }
 
   let service_id = if let Some(service_token) = service_token {
     let manager = TokenManager::new(db.pool());
@@ -211,7 +444,9 @@ pub async fn check_permission(req: &Request) -> Response {
       Err(crate::auth::TokenError::NotFound) => {
         return unauthorized_response("invalid_service_token");
       }
-      Err(crate::auth::TokenError::Expired) => return unauthorized_response("expired_token"),
+      Err(crate::auth::TokenError::Expired) => {
+        return unauthorized_response("expired_service_token");
+      }
       Err(crate::auth::TokenError::Database(_)) => {
         return error_response(
           StatusCode::InternalServerError,
@@ -229,13 +464,18 @@ pub async fn check_permission(req: &Request) -> Response {
       Some(service_id) => service_id,
       None => return unauthorized_response("invalid_service_token"),
     };
-    match sqlx::query_scalar::<_, bool>("SELECT status FROM auth.services WHERE id = $1")
-      .bind(service_id)
-      .fetch_optional(db.pool())
-      .await
+    match sqlx::query_as::<_, (bool, Option<i32>)>(
+      "SELECT status, tenant_id FROM auth.services WHERE id = $1",
+    )
+    .bind(service_id)
+    .fetch_optional(db.pool())
+    .await
     {
-      Ok(Some(true)) => service_id,
-      Ok(Some(false)) => return unauthorized_response("service_inactive"),
+      Ok(Some((_, Some(owner)))) if owner != tenant_id => {
+        return error_response(StatusCode::Forbidden, "tenant_mismatch");
+      }
+      Ok(Some((true, _))) => service_id,
+      Ok(Some((false, _))) => return unauthorized_response("service_inactive"),
       Ok(None) => return unauthorized_response("invalid_service_token"),
       Err(_) => {
         return error_response(StatusCode::InternalServerError, "service_lookup_failed");
@@ -246,10 +486,13 @@ pub async fn check_permission(req: &Request) -> Response {
       Some(id) => id,
       None => return error_response(StatusCode::BadRequest, "invalid_service_id"),
     };
-    match sqlx::query_scalar::<_, bool>("SELECT status FROM auth.services WHERE id = $1")
-      .bind(service_id)
-      .fetch_optional(db.pool())
-      .await
+    match sqlx::query_scalar::<_, bool>(
+      "SELECT status FROM auth.services WHERE id = $1 AND (tenant_id = $2 OR tenant_id IS NULL)",
+    )
+    .bind(service_id)
+    .bind(tenant_id)
+    .fetch_optional(db.pool())
+    .await
     {
       Ok(Some(true)) => service_id,
       Ok(Some(false)) => return unauthorized_response("service_inactive"),
@@ -270,46 +513,155 @@ pub async fn check_permission(req: &Request) -> Response {
     None => return unauthorized_response("invalid_token"),
   };
 
-  let now = SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .unwrap_or_default()
-    .as_secs() as i64;
-
-  let manager = TokenManager::new(db.pool());
-  let cached_access = match manager.load_access_cache(&token, service_id).await {
-    Ok(Some(cache)) if cache.expires_at > now => Some(cache.access_json),
-    Ok(_) => None,
-    Err(_) => {
-      return error_response(StatusCode::InternalServerError, "load_access_cache_failed");
-    }
-  };
-  let used_cache = cached_access.is_some();
-
-  let access_json = if let Some(access) = cached_access {
-    access
-  } else {
-    let (roles, permissions) = match load_roles_and_permissions(&db, user_id, service_id).await {
+  let scopes = token_scopes(&payload);
+  let (access_json, used_cache) =
+    match load_access_snapshot(&db, &token, user_id, service_id, &scopes).await {
       Ok(result) => result,
       Err(response) => return response,
     };
-    let expires_at = now + manager.ttl();
-    let access = json!({
-      "user_id": user_id,
-      "service_id": service_id,
-      "roles": roles,
-      "permissions": permissions,
-      "scopes": [],
-      "expires_at": expires_at,
-    });
-    if let Err(_) = manager
-      .store_access_cache(&token, service_id, &access, expires_at)
-      .await
-    {
-      return error_response(StatusCode::InternalServerError, "store_access_cache_failed");
+
+  log_access(req, used_cache);
+
+  let mut body = json!({
+    "valid": true,
+    "access": access_json,
+    "renewed": validation.renewed,
+    "expires_at": validation.expires_at,
+    "absolute_expires_at": validation.absolute_expires_at,
+    "scopes": scopes,
+  });
+  let mut status = StatusCode::Ok;
+  if !requested.is_empty() {
+    let mut context = condition_context(req, &payload);
+    if let Some(Err(response)) = context_override.map(|value| value.apply(&mut context)) {
+      return response;
+    }
+    let decision = decide_permissions(&body["access"], &requested, mode, &context);
+    let allowed = decision.allowed;
+    body["allowed"] = json!(allowed);
+    body["mode"] = json!(mode.as_str());
+    body["requested"] = json!(requested);
+    body["missing"] = json!(decision.missing);
+    body["unmet_conditions"] = json!(decision.unmet_conditions);
+    if !allowed {
+      body["error"] = json!("permission_denied");
+      status = StatusCode::Forbidden;
     }
-    access
+  }
+
+  Response {
+    status: status.to_string(),
+    content_type: "application/json".to_string(),
+    content: body.to_string().into_bytes(),
+  }
+}
+
+const MAX_BATCH_ITEMS: usize = 100;
+
+pub async fn check_permissions_batch(req: &Request) -> Response {
+  #[derive(Deserialize)]
+  struct BatchItem {
+    service_id: FlexibleId,
+    permission: String,
+  }
+
+  #[derive(Deserialize)]
+  struct BatchRequest {
+    items: Vec<BatchItem>,
+  }
+
+  let payload: BatchRequest = match serde_json::from_slice(req.body.as_bytes()) {
+    Ok(payload) => payload,
+    Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
+  };
+  if payload.items.is_empty() {
+    return error_response(StatusCode::BadRequest, "invalid_request_body");
+  }
+  if payload.items.len() > MAX_BATCH_ITEMS {
+    return error_response(StatusCode::BadRequest, "too_many_items");
+  }
+
+  let (db, validation, token) = match require_token_with_renew_no_log(req).await {
+    Ok(values) => values,
+    Err(response) => return response,
+  };
+  let user_id = match token_user_id(&validation) {
+    Some(user_id) => user_id,
+    None => return unauthorized_response("invalid_token"),
   };
 
+  let mut service_ids: Vec<i32> = payload
+    .items
+    .iter()
+    .filter_map(|item| item.service_id.parse_int())
+    .collect();
+  service_ids.sort_unstable();
+  service_ids.dedup();
+
+  let statuses: HashMap<i32, bool> = match sqlx::query_as::<_, (i32, bool)>(
+    "SELECT id, status FROM auth.services
+      WHERE id = ANY($1) AND (tenant_id = $2 OR tenant_id IS NULL)",
+  )
+  .bind(&service_ids)
+  .bind(token_tenant_id(&validation))
+  .fetch_all(db.pool())
+  .await
+  {
+    Ok(rows) => rows.into_iter().collect(),
+    Err(_) => return error_response(StatusCode::InternalServerError, "service_lookup_failed"),
+  };
+
+  // One snapshot per distinct active service; items then only read from memory.
+  let scopes = token_scopes(&validation.record.payload);
+  let mut snapshots: HashMap<i32, Value> = HashMap::new();
+  let mut used_cache = true;
+  for service_id in &service_ids {
+    if statuses.get(service_id) != Some(&true) {
+      continue;
+    }
+    let (access, cached) =
+      match load_access_snapshot(&db, &token, user_id, *service_id, &scopes).await {
+        Ok(result) => result,
+        Err(response) => return response,
+      };
+    used_cache &= cached;
+    snapshots.insert(*service_id, access);
+  }
+
+  let context = condition_context(req, &validation.record.payload);
+  let results: Vec<Value> = payload
+    .items
+    .iter()
+    .enumerate()
+    .map(|(index, item)| {
+      let service_id = item.service_id.parse_int();
+      let permission = item.permission.trim();
+      let error = match service_id.map(|id| statuses.get(&id)) {
+        None | Some(None) => Some("invalid_service_id"),
+        Some(Some(false)) => Some("service_inactive"),
+        Some(Some(true)) if permission.is_empty() => Some("invalid_permission"),
+        Some(Some(true)) => None,
+      };
+      let allowed = match (error, service_id.and_then(|id| snapshots.get(&id))) {
+        (None, Some(access)) => {
+          let requested = [permission.to_string()];
+          decide_permissions(access, &requested, PermissionMode::All, &context).allowed
+        }
+        _ => false,
+      };
+      let mut result = json!({
+        "index": index,
+        "service_id": service_id,
+        "permission": permission,
+        "allowed": allowed,
+      });
+      if let Some(error) = error {
+        result["error"] = json!(error);
+      }
+      result
+    })
+    .collect();
+
   log_access(req, used_cache);
 
   Response {
@@ -317,9 +669,10 @@ pub async fn check_permission(req: &Request) -> Response {
     content_type: "application/json".to_string(),
     content: json!({
       "valid": true,
-      "access": access_json,
+      "results": results,
       "renewed": validation.renewed,
       "expires_at": validation.expires_at,
+      "absolute_expires_at": validation.absolute_expires_at,
     })
     .to_string()
     .into_bytes(),
@@ -345,7 +698,7 @@ pub struct CreateUserPayload {
 }
 
 pub async fn create_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, USERS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -383,7 +736,7 @@ pub async fn create_user(req: &Request) -> Response {
   };
 
   match sqlx::query_as::<_, User>(
-    "SELECT id, username, name FROM auth.create_person($1, $2, $3, $4, $5, $6)",
+    "SELECT id, username, name FROM auth.create_person($1, $2, $3, $4, $5, $6, $7)",
   )
   .bind(payload.username)
   .bind(password_hash)
@@ -391,6 +744,7 @@ pub async fn create_user(req: &Request) -> Response {
   .bind(person_type)
   .bind(document_type)
   .bind(payload.document_number)
+  .bind(token_tenant_id(&validation))
   .fetch_one(db.pool())
   .await
   {
@@ -404,11 +758,12 @@ pub async fn create_user(req: &Request) -> Response {
 }
 
 pub async fn list_people(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
-  match sqlx::query_as::<_, User>("SELECT id, username, name FROM auth.list_people()")
+  match sqlx::query_as::<_, User>("SELECT id, username, name FROM auth.list_people($1)")
+    .bind(token_tenant_id(&validation))
     .fetch_all(db.pool())
     .await
   {
@@ -422,7 +777,7 @@ pub async fn list_people(req: &Request) -> Response {
 }
 
 pub async fn get_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_token_with_renew(req).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -430,6 +785,12 @@ pub async fn get_user(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_user_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::User, id, false).await
+  {
+    return response;
+  }
   match sqlx::query_as::<_, User>("SELECT id, username, name FROM auth.get_person($1)")
     .bind(id)
     .fetch_optional(db.pool())
@@ -453,7 +814,7 @@ pub struct UpdateUserPayload {
 }
 
 pub async fn update_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, USERS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -461,6 +822,12 @@ pub async fn update_user(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_user_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::User, id, true).await
+  {
+    return response;
+  }
   let payload: UpdateUserPayload = match serde_json::from_slice(req.body.as_bytes()) {
     Ok(p) => p,
     Err(_) => return error_response(StatusCode::BadRequest, "invalid_request_body"),
@@ -492,7 +859,7 @@ pub async fn update_user(req: &Request) -> Response {
 }
 
 pub async fn delete_user(req: &Request) -> Response {
-  let (db, _, _) = match require_token_with_renew(req).await {
+  let (db, validation, _) = match require_admin_permission(req, USERS_WRITE).await {
     Ok(tuple) => tuple,
     Err(response) => return response,
   };
@@ -500,6 +867,12 @@ pub async fn delete_user(req: &Request) -> Response {
     Some(id) => id,
     None => return error_response(StatusCode::BadRequest, "invalid_user_id"),
   };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::User, id, true).await
+  {
+    return response;
+  }
   let manager = TokenManager::new(db.pool());
   match sqlx::query("CALL auth.delete_person($1)")
     .bind(id)
@@ -524,8 +897,51 @@ pub async fn delete_user(req: &Request) -> Response {
   }
 }
 
+/// Lifts a login lockout early and forgets the username's failed attempts.
+pub async fn unlock_user(req: &Request) -> Response {
+  let (db, validation, _) = match require_admin_permission(req, USERS_WRITE).await {
+    Ok(tuple) => tuple,
+    Err(response) => return response,
+  };
+  let id: i32 = match req.params.get("id").and_then(|s| s.parse().ok()) {
+    Some(id) => id,
+    None => return error_response(StatusCode::BadRequest, "invalid_user_id"),
+  };
+  let tenant_id = token_tenant_id(&validation);
+  if let Err(response) =
+    require_tenant_access(&db, tenant_id, TenantResource::User, id, true).await
+  {
+    return response;
+  }
+  let username = match sqlx::query_scalar::<_, String>(
+    "SELECT username FROM auth.person WHERE id = $1 AND removed_at IS NULL",
+  )
+  .bind(id)
+  .fetch_optional(db.pool())
+  .await
+  {
+    Ok(Some(username)) => username,
+    Ok(None) => return error_response(StatusCode::NotFound, "user_not_found"),
+    Err(_) => return error_response(StatusCode::InternalServerError, "unlock_user_failed"),
+  };
+  match clear_login_failures(&db, &username).await {
+    Ok(cleared) => Response {
+      status: StatusCode::Ok.to_string(),
+      content_type: "application/json".to_string(),
+      content: json!({
+        "status": "user_unlocked",
+        "user_id": id,
+        "had_failures": cleared,
+      })
+      .to_string()
+      .into_bytes(),
+    },
+    Err(_) => error_response(StatusCode::InternalServerError, "unlock_user_failed"),
+  }
+}
+
 // These are needed for the create_person handler to deserialize the enums
-mod auth_types {
+pub(super) mod auth_types {
   use serde::Deserialize;
   #[derive(Debug, Deserialize, sqlx::Type)]
   #[sqlx(type_name = "person_type", rename_all = "UPPERCASE")]
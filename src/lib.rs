@@ -2,8 +2,11 @@ use crate::auth::TokenManager;
 use crate::database::DB;
 use crate::handlers::*;
 pub mod auth;
+pub mod conditions;
 mod database;
 mod handlers;
+pub mod mfa;
+pub mod notifier;
 pub use httpageboy::{Request, Response, Rt, Server, StatusCode, handler};
 use std::sync::OnceLock;
 use tokio::time::{self, Duration};
@@ -65,6 +68,15 @@ fn spawn_cleanup_job() {
           if let Err(err) = manager.cleanup_expired().await {
             eprintln!("[cleanup] token cleanup failed: {}", err);
           }
+          if let Err(err) = manager.expire_role_assignments().await {
+            eprintln!("[cleanup] role assignment expiry failed: {}", err);
+          }
+          if let Err(err) = prune_login_attempts(&db).await {
+            eprintln!("[cleanup] login attempt pruning failed: {}", err);
+          }
+          if let Err(err) = prune_reset_requests(&db).await {
+            eprintln!("[cleanup] reset request pruning failed: {}", err);
+          }
         }
         Err(err) => eprintln!("[cleanup] db unavailable: {}", err),
       }
@@ -78,15 +90,45 @@ pub async fn create_server(server_url: &str) -> Server {
     .expect("Failed to create server");
 
   server.set_cors(build_cors_policy());
+  if let Err(err) = notifier::install_from_env() {
+    eprintln!("[startup] password reset notifications disabled: {}", err);
+  }
   spawn_cleanup_job();
 
   server.add_route("/", Rt::GET, handler!(home));
 
   // Auth
   server.add_route("/auth/login", Rt::POST, handler!(login));
+  server.add_route("/auth/refresh", Rt::POST, handler!(refresh));
+  server.add_route("/auth/token/exchange", Rt::POST, handler!(exchange_token));
   server.add_route("/auth/logout", Rt::POST, handler!(logout));
+  server.add_route("/auth/logout-all", Rt::POST, handler!(logout_all));
+  server.add_route("/auth/sessions", Rt::GET, handler!(list_sessions));
+  server.add_route("/auth/sessions/{id}", Rt::DELETE, handler!(revoke_session));
   server.add_route("/auth/profile", Rt::GET, handler!(profile));
+  server.add_route("/auth/mfa/enroll", Rt::POST, handler!(enroll_mfa));
+  server.add_route("/auth/mfa/confirm", Rt::POST, handler!(confirm_mfa));
+  server.add_route("/auth/mfa/disable", Rt::POST, handler!(disable_mfa));
+  server.add_route(
+    "/auth/mfa/recovery-codes",
+    Rt::POST,
+    handler!(regenerate_recovery_codes),
+  );
+  server.add_route("/auth/mfa/verify", Rt::POST, handler!(verify_mfa));
+  server.add_route("/auth/password/forgot", Rt::POST, handler!(forgot_password));
+  server.add_route("/auth/password/reset", Rt::POST, handler!(reset_password));
+  server.add_route("/auth/password/change", Rt::POST, handler!(change_password));
   server.add_route("/check-permission", Rt::POST, handler!(check_permission));
+  server.add_route(
+    "/check-permission/explain",
+    Rt::POST,
+    handler!(explain_permission),
+  );
+  server.add_route(
+    "/check-permissions/batch",
+    Rt::POST,
+    handler!(check_permissions_batch),
+  );
 
   // Users
   server.add_route("/users", Rt::GET, handler!(list_people));
@@ -94,20 +136,63 @@ pub async fn create_server(server_url: &str) -> Server {
   server.add_route("/users/{id}", Rt::GET, handler!(get_user));
   server.add_route("/users/{id}", Rt::PUT, handler!(update_user));
   server.add_route("/users/{id}", Rt::DELETE, handler!(delete_user));
+  server.add_route("/users/{id}/unlock", Rt::POST, handler!(unlock_user));
 
   // Services
   server.add_route("/services", Rt::GET, handler!(list_services));
   server.add_route("/services", Rt::POST, handler!(create_service));
   server.add_route("/services/{id}/token", Rt::POST, handler!(issue_service_token));
+  server.add_route("/services/{id}/tokens", Rt::GET, handler!(list_service_tokens));
+  server.add_route(
+    "/services/{id}/tokens/rotate",
+    Rt::POST,
+    handler!(rotate_service_token),
+  );
+  server.add_route(
+    "/services/{id}/tokens/{token_id}",
+    Rt::DELETE,
+    handler!(revoke_service_token),
+  );
   server.add_route("/services/{id}", Rt::PUT, handler!(update_service));
   server.add_route("/services/{id}", Rt::DELETE, handler!(delete_service));
 
+  // Tenants
+  server.add_route("/tenants", Rt::GET, handler!(list_tenants));
+  server.add_route("/tenants", Rt::POST, handler!(create_tenant));
+
+  // Groups
+  server.add_route("/groups", Rt::GET, handler!(list_groups));
+  server.add_route("/groups", Rt::POST, handler!(create_group));
+  server.add_route("/groups/{id}", Rt::GET, handler!(get_group));
+  server.add_route("/groups/{id}", Rt::PUT, handler!(update_group));
+  server.add_route("/groups/{id}", Rt::DELETE, handler!(delete_group));
+  server.add_route("/groups/{id}/members", Rt::GET, handler!(list_group_members));
+  server.add_route("/groups/{id}/members", Rt::POST, handler!(add_group_member));
+  server.add_route(
+    "/groups/{id}/members/{person_id}",
+    Rt::DELETE,
+    handler!(remove_group_member),
+  );
+  server.add_route("/groups/{id}/roles", Rt::GET, handler!(list_group_roles));
+
   // Roles
   server.add_route("/roles", Rt::GET, handler!(list_roles));
   server.add_route("/roles", Rt::POST, handler!(create_role));
   server.add_route("/roles/{id}", Rt::GET, handler!(get_role));
   server.add_route("/roles/{id}", Rt::PUT, handler!(update_role));
   server.add_route("/roles/{id}", Rt::DELETE, handler!(delete_role));
+  server.add_route("/roles/{id}/parents", Rt::GET, handler!(list_role_parents));
+  server.add_route("/roles/{id}/parents", Rt::POST, handler!(add_role_parent));
+  server.add_route(
+    "/roles/{id}/parents/{parent_id}",
+    Rt::DELETE,
+    handler!(remove_role_parent),
+  );
+  server.add_route(
+    "/roles/{id}/effective-permissions",
+    Rt::GET,
+    handler!(list_role_effective_permissions),
+  );
 
   // Permissions
   server.add_route("/permissions", Rt::GET, handler!(list_permissions));
@@ -131,6 +216,21 @@ pub async fn create_server(server_url: &str) -> Server {
     Rt::GET,
     handler!(list_role_permissions),
   );
+  server.add_route(
+    "/role-permission-denies",
+    Rt::POST,
+    handler!(deny_permission_to_role),
+  );
+  server.add_route(
+    "/role-permission-denies",
+    Rt::DELETE,
+    handler!(remove_permission_deny_from_role),
+  );
+  server.add_route(
+    "/roles/{id}/denied-permissions",
+    Rt::GET,
+    handler!(list_role_denied_permissions),
+  );
 
   // Service-Roles
   server.add_route("/service-roles", Rt::POST, handler!(assign_role_to_service));
@@ -145,6 +245,18 @@ pub async fn create_server(server_url: &str) -> Server {
     handler!(list_service_roles),
   );
 
+  // Group-Service-Roles
+  server.add_route(
+    "/group-service-roles",
+    Rt::POST,
+    handler!(assign_role_to_group_in_service),
+  );
+  server.add_route(
+    "/group-service-roles",
+    Rt::DELETE,
+    handler!(remove_role_from_group_in_service),
+  );
+
   // Person-Service-Roles
   server.add_route(
     "/person-service-roles",
@@ -161,6 +273,21 @@ pub async fn create_server(server_url: &str) -> Server {
     Rt::POST,
     handler!(grant_permission_to_person_in_service),
   );
+  server.add_route(
+    "/person-service-denies",
+    Rt::POST,
+    handler!(deny_permission_to_person_in_service),
+  );
+  server.add_route(
+    "/person-service-denies",
+    Rt::DELETE,
+    handler!(remove_permission_deny_from_person_in_service),
+  );
+  server.add_route(
+    "/people/{person_id}/services/{service_id}/denied-permissions",
+    Rt::GET,
+    handler!(list_person_denied_permissions_in_service),
+  );
   server.add_route(
     "/people/{person_id}/services/{service_id}/roles",
     Rt::GET,
@@ -182,5 +309,22 @@ pub async fn create_server(server_url: &str) -> Server {
     handler!(get_person_service_info),
   );
 
+  // Relationships
+  server.add_route(
+    "/services/{id}/relations",
+    Rt::GET,
+    handler!(list_relation_definitions),
+  );
+  server.add_route("/services/{id}/relations", Rt::POST, handler!(define_relation));
+  server.add_route(
+    "/services/{id}/relations/{object_type}/{relation}",
+    Rt::DELETE,
+    handler!(delete_relation_definition),
+  );
+  server.add_route("/relation-tuples", Rt::GET, handler!(list_relation_tuples));
+  server.add_route("/relation-tuples", Rt::POST, handler!(write_relation_tuple));
+  server.add_route("/relation-tuples", Rt::DELETE, handler!(delete_relation_tuple));
+  server.add_route("/check-relation", Rt::POST, handler!(check_relation));
+
   server
 }
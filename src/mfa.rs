@@ -0,0 +1,216 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use data_encoding::{BASE32_NOPAD, BASE64};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Digits in a code.
+pub const CODE_DIGITS: u32 = 6;
+/// Seconds each code stays current.
+pub const STEP_SECONDS: i64 = 30;
+/// Steps accepted on either side of the current one, to absorb clock drift.
+pub const ALLOWED_SKEW_STEPS: i64 = 1;
+/// Secret length in bytes; 160 bits as recommended for HMAC-SHA1.
+const SECRET_BYTES: usize = 20;
+const NONCE_BYTES: usize = 12;
+/// Recovery codes handed out at a time.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+/// Lowercase letters and digits without the look-alikes `l`, `o`, `0` and `1`; 32 symbols, so
+/// each random byte maps to one without bias.
+const RECOVERY_CODE_ALPHABET: &[u8; 32] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MfaError(String);
+
+impl fmt::Display for MfaError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for MfaError {}
+
+fn error(message: impl Into<String>) -> MfaError {
+  MfaError(message.into())
+}
+
+pub fn generate_secret() -> Vec<u8> {
+  let mut secret = vec![0u8; SECRET_BYTES];
+  OsRng.fill_bytes(&mut secret);
+  secret
+}
+
+/// Base32 without padding, the form authenticator apps expect.
+pub fn encode_secret(secret: &[u8]) -> String {
+  BASE32_NOPAD.encode(secret)
+}
+
+/// `otpauth://` URI for QR codes; issuer and account are percent-encoded into the label.
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+  let issuer = percent_encode(issuer);
+  format!(
+    "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+    issuer,
+    percent_encode(account),
+    encode_secret(secret),
+    issuer,
+    CODE_DIGITS,
+    STEP_SECONDS,
+  )
+}
+
+fn percent_encode(value: &str) -> String {
+  let mut encoded = String::with_capacity(value.len());
+  for byte in value.bytes() {
+    if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+      encoded.push(byte as char);
+    } else {
+      encoded.push_str(&format!("%{:02X}", byte));
+    }
+  }
+  encoded
+}
+
+/// Time step an epoch falls in.
+pub fn time_step(epoch: i64) -> i64 {
+  epoch.div_euclid(STEP_SECONDS)
+}
+
+/// RFC 6238 code (HMAC-SHA1, dynamic truncation) for one time step.
+pub fn code_for_step(secret: &[u8], step: i64) -> String {
+  let mut mac = <HmacSha1 as Mac>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+  mac.update(&(step as u64).to_be_bytes());
+  let digest = mac.finalize().into_bytes();
+  let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+  let binary = u32::from_be_bytes([
+    digest[offset] & 0x7f,
+    digest[offset + 1],
+    digest[offset + 2],
+    digest[offset + 3],
+  ]);
+  format!(
+    "{:0width$}",
+    binary % 10u32.pow(CODE_DIGITS),
+    width = CODE_DIGITS as usize
+  )
+}
+
+/// Checks a code against the steps around `now` and returns the step it matched.
+/// Steps at or before `last_used_step` are skipped so a code cannot be replayed.
+pub fn verify_code(
+  secret: &[u8],
+  code: &str,
+  now: i64,
+  last_used_step: Option<i64>,
+) -> Option<i64> {
+  let code = code.trim();
+  if code.len() != CODE_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+    return None;
+  }
+  let current = time_step(now);
+  (current - ALLOWED_SKEW_STEPS..=current + ALLOWED_SKEW_STEPS)
+    .filter(|step| last_used_step.is_none_or(|last| *step > last))
+    .find(|step| constant_time_eq(code_for_step(secret, *step).as_bytes(), code.as_bytes()))
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+  left.len() == right.len()
+    && left
+      .iter()
+      .zip(right)
+      .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+      == 0
+}
+
+/// Fresh single-use recovery codes, shown as `xxxxx-xxxxx`.
+pub fn generate_recovery_codes() -> Vec<String> {
+  (0..RECOVERY_CODE_COUNT)
+    .map(|_| {
+      let mut random = [0u8; RECOVERY_CODE_LENGTH];
+      OsRng.fill_bytes(&mut random);
+      let symbols: String = random
+        .iter()
+        .map(|byte| RECOVERY_CODE_ALPHABET[(byte % 32) as usize] as char)
+        .collect();
+      format!("{}-{}", &symbols[..5], &symbols[5..])
+    })
+    .collect()
+}
+
+/// Canonical form a recovery code is hashed in: dashes and spaces dropped, lowercase.
+/// `None` when the input cannot be a recovery code.
+pub fn normalize_recovery_code(code: &str) -> Option<String> {
+  let normalized: String = code
+    .chars()
+    .filter(|c| *c != '-' && !c.is_whitespace())
+    .map(|c| c.to_ascii_lowercase())
+    .collect();
+  let valid = normalized.len() == RECOVERY_CODE_LENGTH
+    && normalized
+      .bytes()
+      .all(|b| RECOVERY_CODE_ALPHABET.contains(&b));
+  valid.then_some(normalized)
+}
+
+/// AES-256 key from `MFA_ENCRYPTION_KEY`, falling back to `JWT_SECRET` like token hashing.
+fn encryption_key() -> [u8; 32] {
+  let material = env::var("MFA_ENCRYPTION_KEY")
+    .ok()
+    .filter(|value| !value.is_empty())
+    .or_else(|| env::var("JWT_SECRET").ok())
+    .unwrap_or_else(|| "local_secret".to_string());
+  let mut hasher = Sha256::new();
+  hasher.update(b"mfa-secret:");
+  hasher.update(material.as_bytes());
+  hasher.finalize().into()
+}
+
+/// Encrypts a secret for storage as base64 `nonce || ciphertext`. The owner's id is bound as
+/// associated data, so a stored secret cannot be moved to another person.
+pub fn encrypt_secret(secret: &[u8], person_id: i32) -> Result<String, MfaError> {
+  let cipher = Aes256Gcm::new(&encryption_key().into());
+  let mut nonce = [0u8; NONCE_BYTES];
+  OsRng.fill_bytes(&mut nonce);
+  let aad = person_id.to_be_bytes();
+  let ciphertext = cipher
+    .encrypt(
+      Nonce::from_slice(&nonce),
+      Payload {
+        msg: secret,
+        aad: &aad,
+      },
+    )
+    .map_err(|_| error("secret encryption failed"))?;
+  let mut stored = nonce.to_vec();
+  stored.extend_from_slice(&ciphertext);
+  Ok(BASE64.encode(&stored))
+}
+
+pub fn decrypt_secret(stored: &str, person_id: i32) -> Result<Vec<u8>, MfaError> {
+  let raw = BASE64
+    .decode(stored.as_bytes())
+    .map_err(|_| error("stored secret is not base64"))?;
+  if raw.len() <= NONCE_BYTES {
+    return Err(error("stored secret is truncated"));
+  }
+  let (nonce, ciphertext) = raw.split_at(NONCE_BYTES);
+  let cipher = Aes256Gcm::new(&encryption_key().into());
+  let aad = person_id.to_be_bytes();
+  cipher
+    .decrypt(
+      Nonce::from_slice(nonce),
+      Payload {
+        msg: ciphertext,
+        aad: &aad,
+      },
+    )
+    .map_err(|_| error("stored secret cannot be decrypted"))
+}
@@ -0,0 +1,144 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// A message for a person, such as a password reset link. People have no address on record
+/// yet, so `recipient` is the username; each notifier decides how that reaches them.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+  pub kind: String,
+  pub person_id: i32,
+  pub recipient: String,
+  pub subject: String,
+  pub body: String,
+  /// Machine-readable values behind the message (for example the reset token).
+  pub data: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NotifierError(String);
+
+impl fmt::Display for NotifierError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for NotifierError {}
+
+/// Outbound delivery. `send` may block (files, SMTP, HTTP); handlers go through `deliver`, which
+/// runs it on the blocking thread pool rather than on the async workers.
+pub trait Notifier: Send + Sync {
+  fn send(&self, notification: &Notification) -> Result<(), NotifierError>;
+}
+
+/// Prints each notification as one JSON line, reset tokens included; for local use only.
+#[derive(Debug, Default)]
+pub struct StdoutNotifier;
+
+impl Notifier for StdoutNotifier {
+  fn send(&self, notification: &Notification) -> Result<(), NotifierError> {
+    let line = serde_json::to_string(notification).map_err(|err| NotifierError(err.to_string()))?;
+    println!("[notify] {}", line);
+    Ok(())
+  }
+}
+
+/// Appends each notification as one JSON line to a file, so tests and local tools can read them.
+#[derive(Debug)]
+pub struct FileNotifier {
+  path: PathBuf,
+  lock: Mutex<()>,
+}
+
+impl FileNotifier {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into(),
+      lock: Mutex::new(()),
+    }
+  }
+
+  pub fn path(&self) -> &PathBuf {
+    &self.path
+  }
+}
+
+impl Notifier for FileNotifier {
+  fn send(&self, notification: &Notification) -> Result<(), NotifierError> {
+    let mut line =
+      serde_json::to_string(notification).map_err(|err| NotifierError(err.to_string()))?;
+    line.push('\n');
+    let _guard = self
+      .lock
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .and_then(|mut file| file.write_all(line.as_bytes()))
+      .map_err(|err| NotifierError(format!("{}: {}", self.path.display(), err)))
+  }
+}
+
+static NOTIFIER: OnceLock<Arc<dyn Notifier>> = OnceLock::new();
+
+/// Picks the notifier `NOTIFIER` names: `stdout` or `file`, which writes to `NOTIFIER_FILE`
+/// (default `notifications.jsonl`). There is no default: notifications carry live tokens, so
+/// where they go has to be a deliberate choice.
+pub fn notifier_from_env() -> Result<Arc<dyn Notifier>, NotifierError> {
+  let name = env::var("NOTIFIER").unwrap_or_default();
+  match name.trim() {
+    "file" => {
+      let path = env::var("NOTIFIER_FILE").unwrap_or_else(|_| "notifications.jsonl".to_string());
+      Ok(Arc::new(FileNotifier::new(path)))
+    }
+    "stdout" => Ok(Arc::new(StdoutNotifier)),
+    "" => Err(NotifierError(
+      "NOTIFIER is not set; choose stdout or file".to_string(),
+    )),
+    other => Err(NotifierError(format!(
+      "unknown NOTIFIER {:?}; choose stdout or file",
+      other
+    ))),
+  }
+}
+
+/// Installs the notifier the server uses, before the first notification is sent.
+/// Returns `false` when one is already in place.
+pub fn install(notifier: Arc<dyn Notifier>) -> bool {
+  NOTIFIER.set(notifier).is_ok()
+}
+
+/// Installs the notifier from `NOTIFIER` unless one is already in place. The server calls this at
+/// startup and only warns on error: without a notifier, reset requests are answered but nothing
+/// is sent.
+pub fn install_from_env() -> Result<(), NotifierError> {
+  if NOTIFIER.get().is_none() {
+    install(notifier_from_env()?);
+  }
+  Ok(())
+}
+
+/// The installed notifier; an error if none was installed.
+pub fn current() -> Result<Arc<dyn Notifier>, NotifierError> {
+  NOTIFIER
+    .get()
+    .cloned()
+    .ok_or_else(|| NotifierError("no notifier installed".to_string()))
+}
+
+/// Sends through the current notifier on the blocking thread pool, so slow delivery never
+/// stalls the async workers serving other requests.
+pub async fn deliver(notification: Notification) -> Result<(), NotifierError> {
+  let notifier = current()?;
+  tokio::task::spawn_blocking(move || notifier.send(&notification))
+    .await
+    .map_err(|err| NotifierError(err.to_string()))?
+}
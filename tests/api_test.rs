@@ -1,14 +1,25 @@
 use eqeqo_api_auth::{
   Server, create_server,
+  notifier::{self, FileNotifier},
   test_utils::{run_test, setup_test_server},
 };
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use tokio::sync::OnceCell;
 
 const SERVER_URL: &str = "127.0.0.1:48080";
 static TEST_SERVER: OnceCell<()> = OnceCell::const_new();
 
+fn notifications_path() -> std::path::PathBuf {
+  std::env::temp_dir().join(format!("api-auth-notifications-{}.jsonl", std::process::id()))
+}
+
 async fn test_auth_server() -> Server {
   let _ = dotenvy::dotenv();
+  // The test client plays the proxy on localhost, with 10.0.0.0/24 as an inner hop.
+  unsafe { std::env::set_var("TRUSTED_PROXIES", "127.0.0.1, 10.0.0.0/24") };
+  let _ = std::fs::remove_file(notifications_path());
+  notifier::install(Arc::new(FileNotifier::new(notifications_path())));
   create_server(SERVER_URL).await
 }
 
@@ -29,6 +40,77 @@ fn extract_token_value(response: &str, key: &str) -> String {
     .to_string()
 }
 
+fn extract_id_value(response: &str, key: &str) -> String {
+  response
+    .split(&format!("\"{}\":", key))
+    .nth(1)
+    .and_then(|segment| segment.split([',', '}']).next())
+    .expect("id value")
+    .trim()
+    .to_string()
+}
+
+/// Digits no other call in this test run returns; used for names and documents.
+fn unique_suffix() -> String {
+  static COUNTER: AtomicU32 = AtomicU32::new(0);
+  let nanos = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  format!("{}{:03}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed) % 1000)
+}
+
+fn login_request(username: &str, password: &str) -> String {
+  format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  )
+}
+
+/// Logs in with a password that must be accepted and returns the whole response.
+async fn login(username: &str, password: &str) -> String {
+  let request = login_request(username, password);
+  run_test(request.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await
+}
+
+async fn login_token(username: &str, password: &str) -> String {
+  extract_token_value(&login(username, password).await, "user_token")
+}
+
+async fn login_admin() -> String {
+  login_token("adm1", "adm1-hash").await
+}
+
+/// Direct database access, for the few checks that need to move time forward.
+async fn test_db() -> sqlx::PgPool {
+  sqlx::PgPool::connect(&std::env::var("AUTH_DATABASE_URL").expect("database url"))
+    .await
+    .expect("database")
+}
+
+struct TestUser {
+  id: String,
+  username: String,
+  password: String,
+}
+
+/// Creates a person named after `prefix` with `token` (an admin of their tenant).
+async fn create_user(token: &str, prefix: &str) -> TestUser {
+  let suffix = unique_suffix();
+  let username = format!("{}_{}", prefix, suffix);
+  let password = format!("pass_{}", suffix);
+  let request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"{}\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}\"}}",
+    token, username, password, prefix, suffix
+  );
+  let response = run_test(request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  TestUser {
+    id: extract_id_value(&response, "id"),
+    username,
+    password,
+  }
+}
+
 #[tokio::test]
 async fn test_home_success() {
   boot_server().await;
@@ -61,6 +143,25 @@ async fn test_login_invalid_body() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn test_login_issues_new_token_each_time() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let first_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let first_token = extract_token_value(&first_response, "user_token");
+  let second_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let second_token = extract_token_value(&second_response, "user_token");
+  assert_ne!(first_token, second_token);
+
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    first_token
+  );
+  let expected = b"\"username\":\"usr1\"";
+  run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_logout_success() {
   boot_server().await;
@@ -115,6 +216,305 @@ async fn test_logout_revokes_token() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn test_refresh_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr2\",\"password\":\"usr2-hash\"}";
+  let expected = b"\"refresh_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let refresh_token = extract_token_value(&login_response, "refresh_token");
+
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  let expected = b"\"refresh_token\"";
+  let refresh_response = run_test(refresh_request.as_bytes(), expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&refresh_response, "user_token");
+  let rotated_refresh_token = extract_token_value(&refresh_response, "refresh_token");
+  assert_ne!(refresh_token, rotated_refresh_token);
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"\"username\":\"usr2\"";
+  run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_refresh_reuse_revokes_family() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr2\",\"password\":\"usr2-hash\"}";
+  let expected = b"\"refresh_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let refresh_token = extract_token_value(&login_response, "refresh_token");
+
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  let expected = b"\"refresh_token\"";
+  let refresh_response = run_test(refresh_request.as_bytes(), expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&refresh_response, "user_token");
+  let rotated_refresh_token = extract_token_value(&refresh_response, "refresh_token");
+
+  let expected = b"refresh_token_reused";
+  run_test(refresh_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let rotated_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    rotated_refresh_token
+  );
+  let expected = b"invalid_refresh_token";
+  run_test(rotated_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"invalid_token";
+  run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_refresh_after_logout() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr2\",\"password\":\"usr2-hash\"}";
+  let expected = b"\"refresh_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+  let refresh_token = extract_token_value(&login_response, "refresh_token");
+
+  let logout_request = format!("POST /auth/logout HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"\"status\":\"logged_out\"";
+  run_test(logout_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  let expected = b"invalid_refresh_token";
+  run_test(refresh_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_refresh_past_absolute_lifetime_is_rejected() {
+  boot_server().await;
+  let admin_token = login_admin().await;
+  let TestUser { username, password, .. } = create_user(&admin_token, "longlived").await;
+  let login_response = login(&username, &password).await;
+  let token = extract_token_value(&login_response, "user_token");
+  let refresh_token = extract_token_value(&login_response, "refresh_token");
+  let session_id = extract_id_value(&login_response, "session_id");
+  let number_after = |response: &str, key: &str| -> i64 {
+    response
+      .split(&format!("\"{}\":", key))
+      .nth(1)
+      .and_then(|segment| segment.split([',', '}']).next())
+      .and_then(|value| value.parse().ok())
+      .expect("number")
+  };
+
+  // The refresh token never outlives the session it belongs to.
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let profile_response =
+    run_test(profile_request.as_bytes(), b"\"absolute_expires_at\":", Some(SERVER_URL)).await;
+  assert!(
+    number_after(&login_response, "refresh_expires_at")
+      <= number_after(&profile_response, "absolute_expires_at")
+  );
+
+  // Age the session past any lifetime; refreshing issues new tokens but cannot restart it.
+  sqlx::query("UPDATE auth.sessions SET created_at = created_at - 400 * 86400 WHERE id = $1")
+    .bind(session_id.parse::<i32>().unwrap())
+    .execute(&test_db().await)
+    .await
+    .expect("age session");
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    refresh_token
+  );
+  run_test(refresh_request.as_bytes(), b"expired_refresh_token", Some(SERVER_URL)).await;
+  run_test(profile_request.as_bytes(), b"expired_token", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_refresh_invalid_token() {
+  boot_server().await;
+  let request = b"POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"refresh_token\":\"invalid\"}";
+  let expected = b"invalid_refresh_token";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_refresh_invalid_body() {
+  boot_server().await;
+  let request = b"POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\nnot-json";
+  let expected = b"invalid_request_body";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_sessions_list_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\nUser-Agent: session-list-agent\r\nX-Forwarded-For: 10.1.2.3, 10.0.0.1\r\n\r\n{\"username\":\"usr3\",\"password\":\"usr3-hash\"}";
+  let expected = b"\"session_id\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let list_request = format!("GET /auth/sessions HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"\"ip\":\"10.1.2.3\",\"last_seen\"";
+  let list_response = run_test(list_request.as_bytes(), expected, Some(SERVER_URL)).await;
+  assert!(list_response.contains("\"current\":true"));
+  assert!(list_response.contains("\"user_agent\":\"session-list-agent\""));
+}
+
+#[tokio::test]
+async fn test_sessions_are_independent() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr3\",\"password\":\"usr3-hash\"}";
+  let expected = b"\"user_token\"";
+  let laptop_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let laptop_token = extract_token_value(&laptop_response, "user_token");
+  let phone_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let phone_token = extract_token_value(&phone_response, "user_token");
+
+  let logout_request = format!(
+    "POST /auth/logout HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    laptop_token
+  );
+  let expected = b"\"status\":\"logged_out\"";
+  run_test(logout_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    phone_token
+  );
+  let expected = b"\"username\":\"usr3\"";
+  run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_session_revoke_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr3\",\"password\":\"usr3-hash\"}";
+  let expected = b"\"session_id\"";
+  let laptop_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let laptop_token = extract_token_value(&laptop_response, "user_token");
+  let phone_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let phone_token = extract_token_value(&phone_response, "user_token");
+  let phone_session_id = phone_response
+    .split("\"session_id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("session id")
+    .trim()
+    .to_string();
+
+  let revoke_request = format!(
+    "DELETE /auth/sessions/{} HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    phone_session_id, laptop_token
+  );
+  let expected = b"\"status\":\"session_revoked\"";
+  run_test(revoke_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    phone_token
+  );
+  let expected = b"invalid_token";
+  run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_session_revoke_not_found() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr3\",\"password\":\"usr3-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let revoke_request = format!(
+    "DELETE /auth/sessions/999999 HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let expected = b"session_not_found";
+  run_test(revoke_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_session_revoke_invalid_id() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr3\",\"password\":\"usr3-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let revoke_request = format!(
+    "DELETE /auth/sessions/abc HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let expected = b"invalid_session_id";
+  run_test(revoke_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_sessions_missing_token() {
+  boot_server().await;
+  let request = b"GET /auth/sessions HTTP/1.1\r\n\r\n";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_logout_all_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let admin_token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("logout_all_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Logout All\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}21\"}}",
+    admin_token, username, password, suffix
+  );
+  let expected = b"\"id\"";
+  run_test(create_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let expected = b"\"user_token\"";
+  let first_response = run_test(user_login.as_bytes(), expected, Some(SERVER_URL)).await;
+  let first_token = extract_token_value(&first_response, "user_token");
+  let second_response = run_test(user_login.as_bytes(), expected, Some(SERVER_URL)).await;
+  let second_token = extract_token_value(&second_response, "user_token");
+  let second_refresh = extract_token_value(&second_response, "refresh_token");
+
+  let logout_request = format!(
+    "POST /auth/logout-all HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    first_token
+  );
+  let expected = b"\"revoked_sessions\":2";
+  run_test(logout_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let profile_request = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    second_token
+  );
+  let expected = b"invalid_token";
+  run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let refresh_request = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    second_refresh
+  );
+  let expected = b"invalid_refresh_token";
+  run_test(refresh_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_profile_success() {
   boot_server().await;
@@ -134,6 +534,32 @@ async fn test_profile_success() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn test_profile_reports_absolute_expiry() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let profile_request = format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"\"absolute_expires_at\":";
+  let profile_response = run_test(profile_request.as_bytes(), expected, Some(SERVER_URL)).await;
+  let absolute_expires_at: i64 = profile_response
+    .split("\"absolute_expires_at\":")
+    .nth(1)
+    .and_then(|segment| segment.split([',', '}']).next())
+    .and_then(|value| value.parse().ok())
+    .expect("absolute expiry");
+  let expires_at: i64 = profile_response
+    .split("\"expires_at\":")
+    .nth(1)
+    .and_then(|segment| segment.split([',', '}']).next())
+    .and_then(|value| value.parse().ok())
+    .expect("sliding expiry");
+  assert!(expires_at <= absolute_expires_at);
+}
+
 #[tokio::test]
 async fn test_profile_missing_token() {
   boot_server().await;
@@ -174,6 +600,22 @@ async fn test_check_permission_success() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn test_check_permission_reports_absolute_expiry() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, "{\"service_id\":1}"
+  );
+  let expected = b"\"absolute_expires_at\":";
+  run_test(check_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_check_permission_invalid_token() {
   boot_server().await;
@@ -239,7 +681,7 @@ async fn test_check_permission_service_id_success() {
 }
 
 #[tokio::test]
-async fn test_check_permission_invalid_service_token() {
+async fn test_check_permission_specific_allowed() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
@@ -247,81 +689,280 @@ async fn test_check_permission_invalid_service_token() {
   let token = extract_token_value(&login_response, "user_token");
 
   let request = format!(
-    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: invalid\r\nContent-Type: application/json\r\n\r\n{}",
-    token, "{}"
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"read\"}}",
+    token
   );
-  let expected = b"invalid_service_token";
+  let expected = b"\"allowed\":true";
   run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
 }
 
 #[tokio::test]
-async fn test_check_permission_invalid_body() {
+async fn test_check_permission_specific_denied() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
   let token = extract_token_value(&login_response, "user_token");
 
-  let service_request =
-    format!("POST /services/1/token HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
-  let service_expected = b"\"service_token\"";
-  let service_response = run_test(service_request.as_bytes(), service_expected, Some(SERVER_URL))
-    .await;
-  let service_token = extract_token_value(&service_response, "service_token");
-
-  let check_request = format!(
-    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1}}",
-    token, service_token
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"share\"}}",
+    token
   );
-  let request = check_request.as_bytes();
-  let expected = b"invalid_request_body";
-  run_test(request, expected, Some(SERVER_URL)).await;
+  let expected = b"403 Forbidden";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
 }
 
-// Users
-
 #[tokio::test]
-async fn test_users_list_success() {
+async fn test_check_permission_any_mode() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
-    .split("\"user_token\":\"")
-    .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
-    .to_string();
+  let token = extract_token_value(&login_response, "user_token");
 
-  let list_request = format!("GET /users HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
-  let request = list_request.as_bytes();
-  let expected = b"\"username\":\"adm1\"";
-  run_test(request, expected, Some(SERVER_URL)).await;
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permissions\":[\"share\",\"read\"],\"mode\":\"any\"}}",
+    token
+  );
+  let expected = b"\"allowed\":true";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
 }
 
 #[tokio::test]
-async fn test_users_list_missing_token() {
+async fn test_check_permission_all_mode_reports_missing() {
   boot_server().await;
-  let request = b"GET /users HTTP/1.1\r\n\r\n";
-  let expected = b"missing_token_header";
-  run_test(request, expected, Some(SERVER_URL)).await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permissions\":[\"share\",\"read\"]}}",
+    token
+  );
+  let expected = b"\"missing\":[\"share\"]";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
 }
 
 #[tokio::test]
-async fn test_user_create_success() {
+async fn test_check_permission_scoped_token() {
   boot_server().await;
-  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
-  let expected = b"\"user_token\"";
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\",\"scopes\":[\"read\"]}";
+  let expected = b"\"scopes\":[\"read\"]";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
-    .split("\"user_token\":\"")
-    .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
-    .to_string();
+  let token = extract_token_value(&login_response, "user_token");
 
-  let suffix = std::time::SystemTime::now()
-    .duration_since(std::time::UNIX_EPOCH)
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"read\"}}",
+    token
+  );
+  let response = run_test(request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  assert!(response.contains("\"permissions\":[\"read\"]"));
+
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"write\"}}",
+    token
+  );
+  let expected = b"permission_denied";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_login_invalid_scopes() {
+  boot_server().await;
+  for scopes in ["[]", "[\"stock.*.read\"]", "[\" \"]"] {
+    let request = format!(
+      "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"adm1\",\"password\":\"adm1-hash\",\"scopes\":{}}}",
+      scopes
+    );
+    let expected = b"invalid_scope";
+    run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+  }
+}
+
+#[tokio::test]
+async fn test_token_exchange_narrows_scopes() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /auth/token/exchange HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"scopes\":[\"read\",\"update\"]}}",
+    token
+  );
+  let response = run_test(request.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let scoped_token = extract_token_value(&response, "user_token");
+  assert_ne!(scoped_token, token);
+
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permissions\":[\"read\",\"update\",\"write\"]}}",
+    scoped_token
+  );
+  let expected = b"\"missing\":[\"write\"]";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let request = format!(
+    "POST /auth/token/exchange HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"scopes\":[\"write\"]}}",
+    scoped_token
+  );
+  let expected = b"scope_not_granted";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let request = format!(
+    "POST /auth/token/exchange HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"scopes\":[\"read\"]}}",
+    scoped_token
+  );
+  let expected = b"\"scopes\":[\"read\"]";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_check_permission_empty_permission_list() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permissions\":[]}}",
+    token
+  );
+  let expected = b"invalid_permission";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_check_permissions_batch_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let items = "[{\"service_id\":1,\"permission\":\"read\"},{\"service_id\":1,\"permission\":\"share\"},{\"service_id\":999999,\"permission\":\"read\"},{\"service_id\":2,\"permission\":\"read\"}]";
+  let request = format!(
+    "POST /check-permissions/batch HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"items\":{}}}",
+    token, items
+  );
+  let expected = b"\"results\"";
+  let response = run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+  assert!(response.contains("\"allowed\":true,\"index\":0"));
+  assert!(response.contains("\"allowed\":false,\"index\":1"));
+  assert!(response.contains("\"allowed\":false,\"error\":\"invalid_service_id\",\"index\":2"));
+  assert!(response.contains("\"allowed\":false,\"index\":3"));
+}
+
+#[tokio::test]
+async fn test_check_permissions_batch_empty_items() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permissions/batch HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"items\":[]}}",
+    token
+  );
+  let expected = b"invalid_request_body";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_check_permissions_batch_missing_token() {
+  boot_server().await;
+  let request = b"POST /check-permissions/batch HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"items\":[{\"service_id\":1,\"permission\":\"read\"}]}";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_check_permission_invalid_service_token() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: invalid\r\nContent-Type: application/json\r\n\r\n{}",
+    token, "{}"
+  );
+  let expected = b"invalid_service_token";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_check_permission_invalid_body() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let service_request =
+    format!("POST /services/1/token HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let service_expected = b"\"service_token\"";
+  let service_response = run_test(service_request.as_bytes(), service_expected, Some(SERVER_URL))
+    .await;
+  let service_token = extract_token_value(&service_response, "service_token");
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1}}",
+    token, service_token
+  );
+  let request = check_request.as_bytes();
+  let expected = b"invalid_request_body";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+// Users
+
+#[tokio::test]
+async fn test_users_list_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
+    .split("\"user_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let list_request = format!("GET /users HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let request = list_request.as_bytes();
+  let expected = b"\"username\":\"adm1\"";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_users_list_missing_token() {
+  boot_server().await;
+  let request = b"GET /users HTTP/1.1\r\n\r\n";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_user_create_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
+    .split("\"user_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
     .unwrap()
     .as_nanos();
   let username = format!("user_{}", suffix);
@@ -374,6 +1015,35 @@ async fn test_user_create_invalid_body() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn user_create_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"no_perm_user\",\"password_hash\":\"secret\",\"name\":\"No Perm\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"999\"}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(create_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn user_delete_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let delete_request = format!("DELETE /users/2 HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"insufficient_permissions";
+  run_test(delete_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_user_update_success() {
   boot_server().await;
@@ -948,6 +1618,36 @@ async fn test_service_update_invalid_body() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn test_service_update_reserved_service() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let list_request = format!("GET /services HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let list_response = run_test(list_request.as_bytes(), b"\"name\":\"auth\"", Some(SERVER_URL)).await;
+  let service_id = list_response
+    .split("\"name\":\"auth\"")
+    .next()
+    .and_then(|segment| segment.rsplit("\"id\":").next())
+    .map(|segment| segment.trim_end_matches(',').trim().to_string())
+    .expect("auth service id");
+
+  let update_request = format!(
+    "PUT /services/{} HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"renamed\"}}",
+    service_id, token
+  );
+  let expected = b"reserved_service";
+  run_test(update_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let delete_request =
+    format!("DELETE /services/{} HTTP/1.1\r\nuser-token: {}\r\n\r\n", service_id, token);
+  let expected = b"reserved_service";
+  run_test(delete_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_service_delete_success() {
   boot_server().await;
@@ -1064,69 +1764,314 @@ async fn service_token_permission_behaves_as_expected() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
-// Roles
-
 #[tokio::test]
-async fn test_roles_list_success() {
+async fn test_service_tokens_list_redacted() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
-    .split("\"user_token\":\"")
-    .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
-    .to_string();
+  let token = extract_token_value(&login_response, "user_token");
 
-  let list_request = format!("GET /roles HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
-  let request = list_request.as_bytes();
-  let expected = b"\"name\":\"Admin\"";
-  run_test(request, expected, Some(SERVER_URL)).await;
+  let issue_request =
+    format!("POST /services/1/token HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let issue_response = run_test(issue_request.as_bytes(), b"\"token_id\"", Some(SERVER_URL)).await;
+  let service_token = extract_token_value(&issue_response, "service_token");
+
+  let list_request =
+    format!("GET /services/1/tokens HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = format!("\"token_hint\":\"{}\"", &service_token[service_token.len() - 4..]);
+  let list_response = run_test(list_request.as_bytes(), expected.as_bytes(), Some(SERVER_URL)).await;
+  assert!(!list_response.contains(&service_token));
 }
 
 #[tokio::test]
-async fn test_roles_list_missing_token() {
+async fn test_service_token_as_user_token_is_rejected() {
   boot_server().await;
-  let request = b"GET /roles HTTP/1.1\r\n\r\n";
-  let expected = b"missing_token_header";
-  run_test(request, expected, Some(SERVER_URL)).await;
-}
+  let token = login_admin().await;
 
-#[tokio::test]
-async fn test_role_get_missing_token() {
-  boot_server().await;
-  let request = b"GET /roles/1 HTTP/1.1\r\n\r\n";
-  let expected = b"missing_token_header";
-  run_test(request, expected, Some(SERVER_URL)).await;
+  let issue_request =
+    format!("POST /services/1/token HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let issue_response = run_test(issue_request.as_bytes(), b"\"token_id\"", Some(SERVER_URL)).await;
+  let service_token = extract_token_value(&issue_response, "service_token");
+  let token_id = extract_id_value(&issue_response, "token_id");
+
+  // Older than any user token may live; the user path must not expire (and delete) it.
+  sqlx::query(
+    "UPDATE auth.tokens_cache SET created_at = created_at - 400 * 86400
+      WHERE token_hash = (SELECT token_hash FROM auth.service_tokens WHERE id = $1)",
+  )
+  .bind(token_id.parse::<i32>().unwrap())
+  .execute(&test_db().await)
+  .await
+  .expect("age service token");
+  let profile_request =
+    format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", service_token);
+  run_test(profile_request.as_bytes(), b"invalid_token", Some(SERVER_URL)).await;
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, service_token, "{}"
+  );
+  run_test(check_request.as_bytes(), b"\"valid\":true", Some(SERVER_URL)).await;
 }
 
 #[tokio::test]
-async fn test_role_get_invalid_id() {
+async fn test_service_token_revoke_success() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
-    .split("\"user_token\":\"")
+  let token = extract_token_value(&login_response, "user_token");
+
+  let issue_request =
+    format!("POST /services/1/token HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let issue_response = run_test(issue_request.as_bytes(), b"\"token_id\"", Some(SERVER_URL)).await;
+  let service_token = extract_token_value(&issue_response, "service_token");
+  let token_id = issue_response
+    .split("\"token_id\":")
     .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("token id segment")
+    .trim()
     .to_string();
 
-  let get_request = format!(
-    "GET /roles/invalid HTTP/1.1\r\nuser-token: {token}\r\n\r\n",
-    token = token
+  let revoke_request = format!(
+    "DELETE /services/1/tokens/{} HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token_id, token
   );
-  let request = get_request.as_bytes();
-  let expected = b"invalid_role_id";
-  run_test(request, expected, Some(SERVER_URL)).await;
-}
+  let expected = b"\"status\":\"service_token_revoked\"";
+  run_test(revoke_request.as_bytes(), expected, Some(SERVER_URL)).await;
 
-#[tokio::test]
-async fn test_role_get_success() {
-  boot_server().await;
-  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, service_token, "{}"
+  );
+  let expected = b"invalid_service_token";
+  run_test(check_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let expected = b"service_token_not_found";
+  run_test(revoke_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_service_token_revoke_invalid_id() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let revoke_request =
+    format!("DELETE /services/1/tokens/abc HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"invalid_token_id";
+  run_test(revoke_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_service_token_rotate_keeps_old_token_during_grace() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"svc_rotate_{}\",\"description\":null}}",
+    token, suffix
+  );
+  let create_response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = create_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+
+  let issue_request = format!(
+    "POST /services/{}/token HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  let issue_response = run_test(issue_request.as_bytes(), b"\"service_token\"", Some(SERVER_URL)).await;
+  let old_token = extract_token_value(&issue_response, "service_token");
+
+  let rotate_request = format!(
+    "POST /services/{}/tokens/rotate HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"grace_seconds\":600}}",
+    service_id, token
+  );
+  let rotate_response = run_test(rotate_request.as_bytes(), b"\"retired_tokens\":1", Some(SERVER_URL)).await;
+  let new_token = extract_token_value(&rotate_response, "service_token");
+
+  for service_token in [&old_token, &new_token] {
+    let check_request = format!(
+      "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+      token, service_token, "{}"
+    );
+    let expected = b"\"valid\":true";
+    run_test(check_request.as_bytes(), expected, Some(SERVER_URL)).await;
+  }
+
+  let list_request = format!(
+    "GET /services/{}/tokens HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  let expected = format!("\"token_hint\":\"{}\"", &old_token[old_token.len() - 4..]);
+  run_test(list_request.as_bytes(), expected.as_bytes(), Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_service_token_rotate_without_grace_retires_old_token() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"svc_rotate_{}\",\"description\":null}}",
+    token, suffix
+  );
+  let create_response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = create_response
+    .split("\"id\":")
+    .nth(1)
+    .and_then(|segment| segment.split(|c| c == ',' || c == '}').next())
+    .expect("service id segment")
+    .trim()
+    .to_string();
+
+  let issue_request = format!(
+    "POST /services/{}/token HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  let issue_response = run_test(issue_request.as_bytes(), b"\"service_token\"", Some(SERVER_URL)).await;
+  let old_token = extract_token_value(&issue_response, "service_token");
+
+  let rotate_request = format!(
+    "POST /services/{}/tokens/rotate HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"grace_seconds\":0}}",
+    service_id, token
+  );
+  let rotate_response = run_test(rotate_request.as_bytes(), b"\"grace_expires_at\"", Some(SERVER_URL)).await;
+  let new_token = extract_token_value(&rotate_response, "service_token");
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, old_token, "{}"
+  );
+  let expected = b"expired_service_token";
+  run_test(check_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nservice-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, new_token, "{}"
+  );
+  let expected = b"\"valid\":true";
+  run_test(check_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_service_token_rotate_invalid_grace() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let rotate_request = format!(
+    "POST /services/1/tokens/rotate HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"grace_seconds\":-5}}",
+    token
+  );
+  let expected = b"invalid_grace_seconds";
+  run_test(rotate_request.as_bytes(), expected, Some(SERVER_URL)).await;
+
+  // Longer than a fresh service token lives (and far past what epoch arithmetic can hold).
+  let rotate_request = format!(
+    "POST /services/1/tokens/rotate HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"grace_seconds\":{}}}",
+    token,
+    i64::MAX
+  );
+  run_test(rotate_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_service_tokens_list_missing_token() {
+  boot_server().await;
+  let request = b"GET /services/1/tokens HTTP/1.1\r\n\r\n";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+// Roles
+
+#[tokio::test]
+async fn test_roles_list_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
+    .split("\"user_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let list_request = format!("GET /roles HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let request = list_request.as_bytes();
+  let expected = b"\"name\":\"Admin\"";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_roles_list_missing_token() {
+  boot_server().await;
+  let request = b"GET /roles HTTP/1.1\r\n\r\n";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_role_get_missing_token() {
+  boot_server().await;
+  let request = b"GET /roles/1 HTTP/1.1\r\n\r\n";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_role_get_invalid_id() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
+    .split("\"user_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let get_request = format!(
+    "GET /roles/invalid HTTP/1.1\r\nuser-token: {token}\r\n\r\n",
+    token = token
+  );
+  let request = get_request.as_bytes();
+  let expected = b"invalid_role_id";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_role_get_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
   let token = login_response
@@ -1280,6 +2225,22 @@ async fn test_role_create_invalid_body() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn role_create_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let create_request = format!(
+    "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"no_perm_role\"}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(create_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_role_update_success() {
   boot_server().await;
@@ -1453,76 +2414,222 @@ async fn test_role_delete_invalid_id() {
 // Permissions
 
 #[tokio::test]
-async fn test_permissions_list_success() {
-  boot_server().await;
-  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
-  let expected = b"\"user_token\"";
-  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
-    .split("\"user_token\":\"")
-    .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
-    .to_string();
-
-  let list_request = format!("GET /permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
-  let request = list_request.as_bytes();
-  let expected = b"\"name\":\"read\"";
-  run_test(request, expected, Some(SERVER_URL)).await;
-}
-
-#[tokio::test]
-async fn test_permissions_list_missing_token() {
-  boot_server().await;
-  let request = b"GET /permissions HTTP/1.1\r\n\r\n";
-  let expected = b"missing_token_header";
-  run_test(request, expected, Some(SERVER_URL)).await;
-}
-
-#[tokio::test]
-async fn test_permission_create_success() {
+async fn test_role_parent_inheritance_flow() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
-    .split("\"user_token\":\"")
-    .nth(1)
-    .and_then(|segment| segment.split('"').next())
-    .expect("token value")
-    .to_string();
+  let token = extract_token_value(&login_response, "user_token");
 
   let suffix = std::time::SystemTime::now()
     .duration_since(std::time::UNIX_EPOCH)
     .unwrap()
     .as_nanos();
-  let permission_name = format!("permission_{}", suffix);
-  let create_body = format!("{{\"name\":\"{}\"}}", permission_name);
+  let mut role_ids = Vec::new();
+  for prefix in ["child", "parent"] {
+    let create_request = format!(
+      "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"{}_{}\"}}",
+      token, prefix, suffix
+    );
+    let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+    role_ids.push(extract_id_value(&response, "id"));
+  }
+  let (child_id, parent_id) = (&role_ids[0], &role_ids[1]);
+
+  let permission_name = format!("inherited_{}", suffix);
   let create_request = format!(
-    "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
-    token, create_body
+    "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"{}\"}}",
+    token, permission_name
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let permission_id = extract_id_value(&response, "id");
+
+  let assign_request = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":{}}}",
+    token, parent_id, permission_id
+  );
+  run_test(assign_request.as_bytes(), b"\"status\":\"success\"", Some(SERVER_URL)).await;
+
+  let link_request = format!(
+    "POST /roles/{}/parents HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"parent_role_id\":{}}}",
+    child_id, token, parent_id
+  );
+  run_test(link_request.as_bytes(), b"role_parent_added", Some(SERVER_URL)).await;
+
+  let effective_request = format!(
+    "GET /roles/{}/effective-permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    child_id, token
   );
   let expected = format!("\"name\":\"{}\"", permission_name);
-  let request = create_request.as_bytes();
-  let expected = expected.as_bytes();
-  run_test(request, expected, Some(SERVER_URL)).await;
+  run_test(effective_request.as_bytes(), expected.as_bytes(), Some(SERVER_URL)).await;
+
+  let cycle_request = format!(
+    "POST /roles/{}/parents HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"parent_role_id\":{}}}",
+    parent_id, token, child_id
+  );
+  run_test(cycle_request.as_bytes(), b"role_hierarchy_cycle", Some(SERVER_URL)).await;
+
+  let username = format!("inherit_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Inherit\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}31\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+
+  let assign_request = format!(
+    "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":1,\"role_id\":{}}}",
+    token, person_id, child_id
+  );
+  run_test(assign_request.as_bytes(), b"\"status\"", Some(SERVER_URL)).await;
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"{}\"}}",
+    user_token, permission_name
+  );
+  run_test(check_request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
 }
 
 #[tokio::test]
-async fn test_permission_create_missing_token() {
+async fn test_role_parent_self_link_rejected() {
   boot_server().await;
-  let request = b"POST /permissions HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"name\":\"perm_missing\"}";
-  let expected = b"missing_token_header";
-  run_test(request, expected, Some(SERVER_URL)).await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let link_request = format!(
+    "POST /roles/2/parents HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"parent_role_id\":2}}",
+    token
+  );
+  let expected = b"role_hierarchy_cycle";
+  run_test(link_request.as_bytes(), expected, Some(SERVER_URL)).await;
 }
 
 #[tokio::test]
-async fn test_permission_create_invalid_body() {
+async fn test_role_parent_unknown_role() {
   boot_server().await;
   let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
   let expected = b"\"user_token\"";
   let login_response = run_test(request, expected, Some(SERVER_URL)).await;
-  let token = login_response
+  let token = extract_token_value(&login_response, "user_token");
+
+  let link_request = format!(
+    "POST /roles/2/parents HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"parent_role_id\":999999}}",
+    token
+  );
+  let expected = b"role_not_found";
+  run_test(link_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_role_parents_list_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let list_request = format!("GET /roles/1/parents HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let expected = b"\"name\":\"Editor\"";
+  run_test(list_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn role_parent_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let link_request = format!(
+    "POST /roles/4/parents HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"parent_role_id\":1}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(link_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_permissions_list_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
+    .split("\"user_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let list_request = format!("GET /permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  let request = list_request.as_bytes();
+  let expected = b"\"name\":\"read\"";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_permissions_list_missing_token() {
+  boot_server().await;
+  let request = b"GET /permissions HTTP/1.1\r\n\r\n";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_permission_create_success() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
+    .split("\"user_token\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("token value")
+    .to_string();
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let permission_name = format!("permission_{}", suffix);
+  let create_body = format!("{{\"name\":\"{}\"}}", permission_name);
+  let create_request = format!(
+    "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, create_body
+  );
+  let expected = format!("\"name\":\"{}\"", permission_name);
+  let request = create_request.as_bytes();
+  let expected = expected.as_bytes();
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_permission_create_missing_token() {
+  boot_server().await;
+  let request = b"POST /permissions HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"name\":\"perm_missing\"}";
+  let expected = b"missing_token_header";
+  run_test(request, expected, Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_permission_create_invalid_body() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = login_response
     .split("\"user_token\":\"")
     .nth(1)
     .and_then(|segment| segment.split('"').next())
@@ -1538,6 +2645,125 @@ async fn test_permission_create_invalid_body() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn test_permission_create_invalid_name() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  for name in ["stock.*.read", "stock..read", "stock.items*"] {
+    let create_request = format!(
+      "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"{}\"}}",
+      token, name
+    );
+    let expected = b"invalid_permission_name";
+    run_test(create_request.as_bytes(), expected, Some(SERVER_URL)).await;
+  }
+}
+
+#[tokio::test]
+async fn test_permission_wildcard_grant_flow() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"wildcard_{}\"}}",
+    token, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let role_id = extract_id_value(&response, "id");
+
+  let namespace = format!("wild{}", suffix);
+  let mut permission_ids = Vec::new();
+  for name in [format!("{}.*", namespace), format!("{}.items.read", namespace)] {
+    let create_request = format!(
+      "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"{}\"}}",
+      token, name
+    );
+    let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+    permission_ids.push(extract_id_value(&response, "id"));
+  }
+
+  let assign_request = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":{}}}",
+    token, role_id, permission_ids[0]
+  );
+  run_test(assign_request.as_bytes(), b"\"status\":\"success\"", Some(SERVER_URL)).await;
+
+  let effective_request = format!(
+    "GET /roles/{}/effective-permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    role_id, token
+  );
+  let expected = format!("\"name\":\"{}.items.read\"", namespace);
+  run_test(effective_request.as_bytes(), expected.as_bytes(), Some(SERVER_URL)).await;
+
+  let username = format!("wild_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Wild\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}41\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+
+  let assign_request = format!(
+    "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":1,\"role_id\":{}}}",
+    token, person_id, role_id
+  );
+  run_test(assign_request.as_bytes(), b"\"status\"", Some(SERVER_URL)).await;
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"{}.items.read\"}}",
+    user_token, namespace
+  );
+  let response = run_test(check_request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  assert!(response.contains(&format!("\"{}.items.read\"", namespace)));
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"{}.orders.write\"}}",
+    user_token, namespace
+  );
+  run_test(check_request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"other{}.items.read\"}}",
+    user_token, suffix
+  );
+  run_test(check_request.as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn permission_create_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let create_request = format!(
+    "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"no_perm_permission\"}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(create_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_permission_update_success() {
   boot_server().await;
@@ -2121,6 +3347,22 @@ async fn test_service_roles_assign_invalid_body() {
   run_test(request, expected, Some(SERVER_URL)).await;
 }
 
+#[tokio::test]
+async fn service_roles_assign_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let assign_request = format!(
+    "POST /service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"role_id\":4}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(assign_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
 #[tokio::test]
 async fn test_service_roles_list_success() {
   boot_server().await;
@@ -3466,3 +4708,1568 @@ async fn test_person_service_info_empty_permissions() {
   let expected = b"\"permissions\":[]";
   run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
 }
+
+// Deny rules
+
+#[tokio::test]
+async fn test_person_service_deny_overrides_grant() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("denied_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Denied\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}51\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+
+  let assign_request = format!(
+    "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":1,\"role_id\":4}}",
+    token, person_id
+  );
+  run_test(assign_request.as_bytes(), b"\"status\"", Some(SERVER_URL)).await;
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"read\"}}",
+    user_token
+  );
+  run_test(check_request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+
+  let deny_body = format!(
+    "{{\"person_id\":{},\"service_id\":1,\"permission_name\":\"read\"}}",
+    person_id
+  );
+  let deny_request = format!(
+    "POST /person-service-denies HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, deny_body
+  );
+  run_test(deny_request.as_bytes(), b"permission_denied_to_person", Some(SERVER_URL)).await;
+
+  let response = run_test(check_request.as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+  assert!(response.contains("\"denied\":[\"read\"]"));
+
+  let list_request = format!(
+    "GET /people/{}/services/1/denied-permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    person_id, token
+  );
+  run_test(list_request.as_bytes(), b"\"name\":\"read\"", Some(SERVER_URL)).await;
+
+  let remove_request = format!(
+    "DELETE /person-service-denies HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, deny_body
+  );
+  run_test(remove_request.as_bytes(), b"permission_deny_removed", Some(SERVER_URL)).await;
+  run_test(check_request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_role_deny_overrides_inherited_grant() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"restricted_{}\"}}",
+    token, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let role_id = extract_id_value(&response, "id");
+
+  let link_request = format!(
+    "POST /roles/{}/parents HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"parent_role_id\":3}}",
+    role_id, token
+  );
+  run_test(link_request.as_bytes(), b"role_parent_added", Some(SERVER_URL)).await;
+
+  let deny_body = format!("{{\"role_id\":{},\"permission_id\":3}}", role_id);
+  let deny_request = format!(
+    "POST /role-permission-denies HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, deny_body
+  );
+  run_test(deny_request.as_bytes(), b"permission_denied_to_role", Some(SERVER_URL)).await;
+
+  let denied_request = format!(
+    "GET /roles/{}/denied-permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    role_id, token
+  );
+  run_test(denied_request.as_bytes(), b"\"name\":\"update\"", Some(SERVER_URL)).await;
+
+  let effective_request = format!(
+    "GET /roles/{}/effective-permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    role_id, token
+  );
+  let response = run_test(effective_request.as_bytes(), b"\"name\":\"read\"", Some(SERVER_URL)).await;
+  assert!(!response.contains("\"name\":\"update\""));
+
+  let remove_request = format!(
+    "DELETE /role-permission-denies HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, deny_body
+  );
+  run_test(remove_request.as_bytes(), b"permission_deny_removed", Some(SERVER_URL)).await;
+  run_test(effective_request.as_bytes(), b"\"name\":\"update\"", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn person_service_deny_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let deny_request = format!(
+    "POST /person-service-denies HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":2,\"service_id\":1,\"permission_id\":1}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(deny_request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+// Time-bound role assignments
+
+#[tokio::test]
+async fn test_person_service_role_validity_window() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let now = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap();
+  let suffix = now.as_nanos();
+  let now = now.as_secs();
+  let username = format!("window_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Window\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}61\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+
+  let assign = |window: String| {
+    format!(
+      "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":1,\"role_id\":4{}}}",
+      token, person_id, window
+    )
+  };
+  let expired = assign(format!(",\"valid_until\":{}", now - 60));
+  run_test(expired.as_bytes(), b"invalid_validity_window", Some(SERVER_URL)).await;
+  let inverted = assign(format!(",\"valid_from\":{},\"valid_until\":{}", now + 120, now + 60));
+  run_test(inverted.as_bytes(), b"invalid_validity_window", Some(SERVER_URL)).await;
+
+  let future = assign(format!(",\"valid_from\":{}", now + 3600));
+  run_test(future.as_bytes(), b"\"status\":\"success\"", Some(SERVER_URL)).await;
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+  let check_request = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":1,\"permission\":\"read\"}}",
+    user_token
+  );
+  let response = run_test(check_request.as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+  assert!(response.contains("\"roles\":[]"));
+  // Listings leave out assignments outside their window too.
+  let roles_request = format!(
+    "GET /people/{}/services/1/roles HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    person_id, token
+  );
+  run_test(roles_request.as_bytes(), b"[]", Some(SERVER_URL)).await;
+  let holders_request = format!(
+    "GET /services/1/roles/4/people HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let holders = run_test(holders_request.as_bytes(), b"[", Some(SERVER_URL)).await;
+  assert!(!holders.contains(&format!("\"username\":\"{}\"", username)));
+
+  let current = assign(format!(",\"valid_from\":{},\"valid_until\":{}", now - 60, now + 3600));
+  run_test(current.as_bytes(), b"\"status\":\"success\"", Some(SERVER_URL)).await;
+  run_test(check_request.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  run_test(roles_request.as_bytes(), b"\"id\":4", Some(SERVER_URL)).await;
+  let holder = format!("\"username\":\"{}\"", username);
+  run_test(holders_request.as_bytes(), holder.as_bytes(), Some(SERVER_URL)).await;
+}
+
+// Permission explanation
+
+#[tokio::test]
+async fn test_explain_permission_granted_through_inheritance() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permission/explain HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":1,\"service_id\":1,\"permission\":\"read\"}}",
+    token
+  );
+  let response = run_test(request.as_bytes(), b"\"reason\":\"granted\"", Some(SERVER_URL)).await;
+  assert!(response.contains("\"allowed\":true"));
+  assert!(response.contains("\"inherited\":true"));
+  assert!(response.contains("\"role_name\":\"Admin\""));
+}
+
+#[tokio::test]
+async fn test_explain_permission_without_grant() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permission/explain HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":\"usr1\",\"service_id\":\"Service A\",\"permission\":\"share\"}}",
+    token
+  );
+  let response = run_test(request.as_bytes(), b"\"reason\":\"no_grant\"", Some(SERVER_URL)).await;
+  assert!(response.contains("\"allowed\":false"));
+}
+
+#[tokio::test]
+async fn test_explain_permission_reports_direct_role() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"explain_{}\",\"password_hash\":\"pass_{}\",\"name\":\"Explain\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}71\"}}",
+    token, suffix, suffix, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+
+  let grant_request = format!(
+    "POST /person-service-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":2,\"permission_name\":\"share\"}}",
+    token, person_id
+  );
+  run_test(grant_request.as_bytes(), b"permission_granted", Some(SERVER_URL)).await;
+
+  let request = format!(
+    "POST /check-permission/explain HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":2,\"permission\":\"share\"}}",
+    token, person_id
+  );
+  let response = run_test(request.as_bytes(), b"\"reason\":\"granted\"", Some(SERVER_URL)).await;
+  assert!(response.contains("\"direct\":true"));
+  assert!(response.contains(&format!("\"role_name\":\"direct:{}:2\"", person_id)));
+}
+
+#[tokio::test]
+async fn explain_permission_permission_behaves_as_expected() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let request = format!(
+    "POST /check-permission/explain HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":2,\"service_id\":1,\"permission\":\"read\"}}",
+    token
+  );
+  let expected = b"insufficient_permissions";
+  run_test(request.as_bytes(), expected, Some(SERVER_URL)).await;
+}
+
+// Strict service roles
+
+#[tokio::test]
+async fn test_strict_service_requires_service_role_link() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"strict_{}\",\"description\":\"Strict\",\"strict_roles\":true}}",
+    token, suffix
+  );
+  let response = run_test(create_service.as_bytes(), b"\"strict_roles\":true", Some(SERVER_URL)).await;
+  let service_id = extract_id_value(&response, "id");
+
+  let assign_request = format!(
+    "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":2,\"service_id\":{},\"role_id\":4}}",
+    token, service_id
+  );
+  run_test(
+    assign_request.as_bytes(),
+    b"role_not_linked_to_service",
+    Some(SERVER_URL),
+  )
+  .await;
+
+  let link_request = format!(
+    "POST /service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"role_id\":4}}",
+    token, service_id
+  );
+  run_test(link_request.as_bytes(), b"success", Some(SERVER_URL)).await;
+  run_test(assign_request.as_bytes(), b"success", Some(SERVER_URL)).await;
+
+  let unlink_request = format!(
+    "DELETE /service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"role_id\":4}}",
+    token, service_id
+  );
+  let response = run_test(unlink_request.as_bytes(), b"service_role_in_use", Some(SERVER_URL)).await;
+  assert!(response.contains("\"assignments\":1"));
+
+  let cascade_request = format!(
+    "DELETE /service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"role_id\":4,\"cascade\":true}}",
+    token, service_id
+  );
+  run_test(
+    cascade_request.as_bytes(),
+    b"role_removed_from_service",
+    Some(SERVER_URL),
+  )
+  .await;
+
+  let roles_request = format!(
+    "GET /people/2/services/{}/roles HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  run_test(roles_request.as_bytes(), b"[]", Some(SERVER_URL)).await;
+}
+
+// Per-service permission catalogs
+
+#[tokio::test]
+async fn test_service_permission_only_applies_in_its_service() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"ledger_{}\",\"description\":\"Ledger\"}}",
+    token, suffix
+  );
+  let response = run_test(create_service.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = extract_id_value(&response, "id");
+
+  let permission_name = format!("ledger_{}.close", suffix);
+  let create_permission = format!(
+    "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"{}\",\"service_id\":{}}}",
+    token, permission_name, service_id
+  );
+  let response = run_test(
+    create_permission.as_bytes(),
+    format!("\"service_id\":{}", service_id).as_bytes(),
+    Some(SERVER_URL),
+  )
+  .await;
+  let permission_id = extract_id_value(&response, "id");
+  let response = run_test(create_permission.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  assert_eq!(extract_id_value(&response, "id"), permission_id);
+
+  let list_request = format!(
+    "GET /permissions?service_id={} HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  run_test(list_request.as_bytes(), permission_name.as_bytes(), Some(SERVER_URL)).await;
+  let list_request = format!(
+    "GET /permissions?service_id=1 HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let response = run_test(list_request.as_bytes(), b"\"read\"", Some(SERVER_URL)).await;
+  assert!(!response.contains(&permission_name));
+
+  let create_role = format!(
+    "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"ledger_role_{}\"}}",
+    token, suffix
+  );
+  let response = run_test(create_role.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let role_id = extract_id_value(&response, "id");
+  let role_permission = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":{}}}",
+    token, role_id, permission_id
+  );
+  run_test(role_permission.as_bytes(), b"success", Some(SERVER_URL)).await;
+
+  let username = format!("ledger_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Ledger\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}81\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+  for target_service in [service_id.as_str(), "1"] {
+    let assign_request = format!(
+      "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":{},\"role_id\":{}}}",
+      token, person_id, target_service, role_id
+    );
+    run_test(assign_request.as_bytes(), b"success", Some(SERVER_URL)).await;
+  }
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+  let check = |target_service: &str| {
+    format!(
+      "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"permission\":\"{}\"}}",
+      user_token, target_service, permission_name
+    )
+  };
+  run_test(check(&service_id).as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  run_test(check("1").as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+
+  let grant_by_id = format!(
+    "POST /person-service-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":1,\"permission_id\":{}}}",
+    token, person_id, permission_id
+  );
+  run_test(grant_by_id.as_bytes(), b"permission_not_in_service", Some(SERVER_URL)).await;
+  let grant_by_name = format!(
+    "POST /person-service-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":1,\"permission_name\":\"{}\"}}",
+    token, person_id, permission_name
+  );
+  run_test(grant_by_name.as_bytes(), b"permission_not_found", Some(SERVER_URL)).await;
+}
+
+// Groups
+#[tokio::test]
+async fn test_group_members_inherit_group_roles() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"crew_{}\",\"description\":\"Crew\"}}",
+    token, suffix
+  );
+  let response = run_test(create_service.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = extract_id_value(&response, "id");
+  let create_role = format!(
+    "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"crew_role_{}\"}}",
+    token, suffix
+  );
+  let response = run_test(create_role.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let role_id = extract_id_value(&response, "id");
+  let role_permission = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":1}}",
+    token, role_id
+  );
+  run_test(role_permission.as_bytes(), b"success", Some(SERVER_URL)).await;
+
+  let create_group = format!(
+    "POST /groups HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"crew_{}\",\"description\":\"Crew\"}}",
+    token, suffix
+  );
+  let response = run_test(create_group.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let group_id = extract_id_value(&response, "id");
+  let group_role = format!(
+    "POST /group-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"group_id\":{},\"service_id\":{},\"role_id\":{}}}",
+    token, group_id, service_id, role_id
+  );
+  run_test(group_role.as_bytes(), b"success", Some(SERVER_URL)).await;
+  let list_roles = format!("GET /groups/{}/roles HTTP/1.1\r\nuser-token: {}\r\n\r\n", group_id, token);
+  run_test(
+    list_roles.as_bytes(),
+    format!("\"role_id\":{}", role_id).as_bytes(),
+    Some(SERVER_URL),
+  )
+  .await;
+
+  let username = format!("crew_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Crew\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}94\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+  let check = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"permission\":\"read\"}}",
+    user_token, service_id
+  );
+  run_test(check.as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+
+  let add_member = format!(
+    "POST /groups/{}/members HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{}}}",
+    group_id, token, person_id
+  );
+  run_test(add_member.as_bytes(), b"group_member_added", Some(SERVER_URL)).await;
+  let members = format!("GET /groups/{}/members HTTP/1.1\r\nuser-token: {}\r\n\r\n", group_id, token);
+  run_test(members.as_bytes(), username.as_bytes(), Some(SERVER_URL)).await;
+  // The snapshot cached by the first check is dropped when membership changes.
+  let response = run_test(check.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  assert!(response.contains(&format!("crew_role_{}", suffix)));
+
+  let remove_member = format!(
+    "DELETE /groups/{}/members/{} HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    group_id, person_id, token
+  );
+  run_test(remove_member.as_bytes(), b"group_member_removed", Some(SERVER_URL)).await;
+  run_test(check.as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_create_group_requires_permission() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+  let create_group = format!(
+    "POST /groups HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"blocked\"}}",
+    token
+  );
+  run_test(create_group.as_bytes(), b"insufficient_permissions", Some(SERVER_URL)).await;
+}
+
+// Tenants
+#[tokio::test]
+async fn test_tenant_isolates_people_and_services() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("acme_owner_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_tenant = format!(
+    "POST /tenants HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"acme_{}\",\"owner\":{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Acme\",\"document_type\":\"RUC\",\"document_number\":\"{}91\"}}}}",
+    token, suffix, username, password, suffix
+  );
+  let response = run_test(create_tenant.as_bytes(), b"\"owner_person_id\"", Some(SERVER_URL)).await;
+  let tenant_id = extract_id_value(&response, "id");
+  let owner_id = extract_id_value(&response, "owner_person_id");
+
+  let owner_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(
+    owner_login.as_bytes(),
+    format!("\"tenant_id\":{}", tenant_id).as_bytes(),
+    Some(SERVER_URL),
+  )
+  .await;
+  let owner_token = extract_token_value(&response, "user_token");
+
+  let list_services = format!("GET /services HTTP/1.1\r\nuser-token: {}\r\n\r\n", owner_token);
+  // The reserved auth service is shared with every tenant.
+  let response =
+    run_test(list_services.as_bytes(), b"\"name\":\"auth\"", Some(SERVER_URL)).await;
+  assert!(!response.contains("Service A"));
+  let other_user = format!("GET /users/1 HTTP/1.1\r\nuser-token: {}\r\n\r\n", owner_token);
+  run_test(other_user.as_bytes(), b"user_not_found", Some(SERVER_URL)).await;
+
+  // Names only need to be unique inside a tenant.
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"Service A\",\"description\":\"Acme catalog\"}}",
+    owner_token
+  );
+  run_test(create_service.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+
+  let nested_tenant = format!(
+    "POST /tenants HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"nested_{}\",\"owner\":{{\"username\":\"nested_{}\",\"password_hash\":\"x\",\"name\":\"Nested\",\"document_type\":\"RUC\",\"document_number\":\"{}92\"}}}}",
+    owner_token, suffix, suffix, suffix
+  );
+  run_test(nested_tenant.as_bytes(), b"insufficient_permissions", Some(SERVER_URL)).await;
+
+  let owner_lookup = format!("GET /users/{} HTTP/1.1\r\nuser-token: {}\r\n\r\n", owner_id, token);
+  run_test(owner_lookup.as_bytes(), b"user_not_found", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_create_tenant_requires_permission() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+  let create_tenant = format!(
+    "POST /tenants HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"blocked\",\"owner\":{{\"username\":\"blocked\",\"password_hash\":\"x\",\"name\":\"Blocked\",\"document_type\":\"RUC\",\"document_number\":\"000000093\"}}}}",
+    token
+  );
+  run_test(create_tenant.as_bytes(), b"insufficient_permissions", Some(SERVER_URL)).await;
+}
+
+// Relationships
+#[tokio::test]
+async fn test_check_relation_follows_inherited_relations() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"docs_{}\",\"description\":\"Docs\"}}",
+    token, suffix
+  );
+  let response = run_test(create_service.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = extract_id_value(&response, "id");
+  for definition in [
+    "{\"object_type\":\"folder\",\"relation\":\"viewer\"}",
+    "{\"object_type\":\"document\",\"relation\":\"parent\"}",
+    "{\"object_type\":\"document\",\"relation\":\"owner\"}",
+    "{\"object_type\":\"document\",\"relation\":\"editor\",\"inherits\":[{\"relation\":\"owner\"}]}",
+    "{\"object_type\":\"document\",\"relation\":\"viewer\",\"inherits\":[{\"relation\":\"editor\"},{\"relation\":\"viewer\",\"via\":\"parent\"}]}",
+  ] {
+    let define = format!(
+      "POST /services/{}/relations HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+      service_id, token, definition
+    );
+    run_test(define.as_bytes(), b"relation_defined", Some(SERVER_URL)).await;
+  }
+  let definitions = format!(
+    "GET /services/{}/relations HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  run_test(
+    definitions.as_bytes(),
+    b"{\"relation\":\"viewer\",\"via\":\"parent\"}",
+    Some(SERVER_URL),
+  )
+  .await;
+
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let login_response = run_test(request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&login_response, "user_token");
+  let check_editor = format!(
+    "POST /check-relation HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"object\":\"document:d1\",\"relation\":\"editor\"}}",
+    user_token, service_id
+  );
+  let response = run_test(check_editor.as_bytes(), b"\"allowed\":false", Some(SERVER_URL)).await;
+  let subject = response
+    .split("\"subject\":\"")
+    .nth(1)
+    .and_then(|segment| segment.split('"').next())
+    .expect("subject")
+    .to_string();
+
+  // Owners are editors too.
+  let owner_tuple = format!(
+    "{{\"service_id\":{},\"object\":\"document:d1\",\"relation\":\"owner\",\"subject\":\"{}\"}}",
+    service_id, subject
+  );
+  let write_owner = format!(
+    "POST /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, owner_tuple
+  );
+  run_test(write_owner.as_bytes(), b"relation_tuple_written", Some(SERVER_URL)).await;
+  run_test(check_editor.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+
+  // Folder viewers see the documents in the folder, without becoming editors.
+  for tuple in [
+    format!(
+      "{{\"service_id\":{},\"object\":\"document:d2\",\"relation\":\"parent\",\"subject\":\"folder:f1\"}}",
+      service_id
+    ),
+    format!(
+      "{{\"service_id\":{},\"object\":\"folder:f1\",\"relation\":\"viewer\",\"subject\":\"{}\"}}",
+      service_id, subject
+    ),
+  ] {
+    let write = format!(
+      "POST /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+      token, tuple
+    );
+    run_test(write.as_bytes(), b"relation_tuple_written", Some(SERVER_URL)).await;
+  }
+  let check_viewer = format!(
+    "POST /check-relation HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"object\":\"document:d2\",\"relation\":\"viewer\"}}",
+    user_token, service_id
+  );
+  run_test(check_viewer.as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  let check_other = format!(
+    "POST /check-relation HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"object\":\"document:d2\",\"relation\":\"viewer\",\"subject\":\"user:0\"}}",
+    token, service_id
+  );
+  run_test(check_other.as_bytes(), b"\"allowed\":false", Some(SERVER_URL)).await;
+  let check_undefined = format!(
+    "POST /check-relation HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"object\":\"document:d2\",\"relation\":\"deleter\"}}",
+    user_token, service_id
+  );
+  run_test(check_undefined.as_bytes(), b"unknown_relation", Some(SERVER_URL)).await;
+
+  let tuples = format!(
+    "GET /relation-tuples?service_id={}&object=document:d1 HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    service_id, token
+  );
+  let response = run_test(tuples.as_bytes(), b"\"relation\":\"owner\"", Some(SERVER_URL)).await;
+  assert!(!response.contains("folder:f1"));
+  let delete_owner = format!(
+    "DELETE /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    token, owner_tuple
+  );
+  run_test(delete_owner.as_bytes(), b"relation_tuple_deleted", Some(SERVER_URL)).await;
+  run_test(check_editor.as_bytes(), b"\"allowed\":false", Some(SERVER_URL)).await;
+
+  // Writing tuples, or checking someone else, takes relationships.write.
+  let blocked = format!(
+    "POST /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    user_token, owner_tuple
+  );
+  run_test(blocked.as_bytes(), b"insufficient_permissions", Some(SERVER_URL)).await;
+
+  // relations.write covers role assignments only, not tuples.
+  let assigner = create_user(&token, "rel_assigner").await;
+  let grant = |permission: &str| {
+    format!(
+      "POST /person-service-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":\"auth\",\"permission_name\":\"{}\"}}",
+      token, assigner.id, permission
+    )
+  };
+  let granted = b"\"status\":\"permission_granted\"";
+  run_test(grant("relations.write").as_bytes(), granted, Some(SERVER_URL)).await;
+  let assigner_write = format!(
+    "POST /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    login_token(&assigner.username, &assigner.password).await,
+    owner_tuple
+  );
+  run_test(assigner_write.as_bytes(), b"insufficient_permissions", Some(SERVER_URL)).await;
+  run_test(grant("relationships.write").as_bytes(), granted, Some(SERVER_URL)).await;
+  let assigner_write = format!(
+    "POST /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+    login_token(&assigner.username, &assigner.password).await,
+    owner_tuple
+  );
+  run_test(assigner_write.as_bytes(), b"relation_tuple_written", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_check_relation_stops_on_cycles() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"teams_{}\",\"description\":\"Teams\"}}",
+    token, suffix
+  );
+  let response = run_test(create_service.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = extract_id_value(&response, "id");
+  for definition in [
+    "{\"object_type\":\"team\",\"relation\":\"parent\"}",
+    "{\"object_type\":\"team\",\"relation\":\"member\",\"inherits\":[{\"relation\":\"member\",\"via\":\"parent\"}]}",
+  ] {
+    let define = format!(
+      "POST /services/{}/relations HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{}",
+      service_id, token, definition
+    );
+    run_test(define.as_bytes(), b"relation_defined", Some(SERVER_URL)).await;
+  }
+  // team:a and team:b are each other's parent, and team:b's members include team:a's.
+  for (object, relation, subject) in [
+    ("team:a", "parent", "team:b"),
+    ("team:b", "parent", "team:a"),
+    ("team:b", "member", "team:a#member"),
+  ] {
+    let write = format!(
+      "POST /relation-tuples HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"object\":\"{}\",\"relation\":\"{}\",\"subject\":\"{}\"}}",
+      token, service_id, object, relation, subject
+    );
+    run_test(write.as_bytes(), b"relation_tuple_written", Some(SERVER_URL)).await;
+  }
+  let check = format!(
+    "POST /check-relation HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"object\":\"team:a\",\"relation\":\"member\",\"subject\":\"user:0\"}}",
+    token, service_id
+  );
+  run_test(check.as_bytes(), b"\"allowed\":false", Some(SERVER_URL)).await;
+}
+
+// Conditions
+#[tokio::test]
+async fn test_conditional_grants_follow_request_context() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let expected = b"\"user_token\"";
+  let login_response = run_test(request, expected, Some(SERVER_URL)).await;
+  let token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let create_service = format!(
+    "POST /services HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"kiosk_{}\",\"description\":\"Kiosk\"}}",
+    token, suffix
+  );
+  let response = run_test(create_service.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let service_id = extract_id_value(&response, "id");
+  let create_permission = format!(
+    "POST /permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"kiosk.refund\",\"service_id\":{}}}",
+    token, service_id
+  );
+  let response = run_test(create_permission.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let refund_id = extract_id_value(&response, "id");
+  let create_role = format!(
+    "POST /roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"name\":\"kiosk_role_{}\"}}",
+    token, suffix
+  );
+  let response = run_test(create_role.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let role_id = extract_id_value(&response, "id");
+  let read_grant = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":1}}",
+    token, role_id
+  );
+  run_test(read_grant.as_bytes(), b"success", Some(SERVER_URL)).await;
+  let invalid_grant = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":{},\"condition\":\"time.second == 1\"}}",
+    token, role_id, refund_id
+  );
+  run_test(invalid_grant.as_bytes(), b"invalid_condition", Some(SERVER_URL)).await;
+  let refund_grant = format!(
+    "POST /role-permissions HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"role_id\":{},\"permission_id\":{},\"condition\":\"ip in \\\"10.0.0.0/8\\\"\"}}",
+    token, role_id, refund_id
+  );
+  run_test(refund_grant.as_bytes(), b"success", Some(SERVER_URL)).await;
+  let role_permissions = format!(
+    "GET /roles/{}/permissions HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    role_id, token
+  );
+  run_test(
+    role_permissions.as_bytes(),
+    b"\"condition\":\"ip in \\\"10.0.0.0/8\\\"\"",
+    Some(SERVER_URL),
+  )
+  .await;
+
+  let username = format!("kiosk_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Kiosk\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}95\"}}",
+    token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+  let assign = format!(
+    "POST /person-service-roles HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"person_id\":{},\"service_id\":{},\"role_id\":{},\"condition\":\"header.x-client-id == \\\"servcli1\\\"\"}}",
+    token, person_id, service_id, role_id
+  );
+  run_test(assign.as_bytes(), b"success", Some(SERVER_URL)).await;
+
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&response, "user_token");
+  let check_read = |client_id: &str| {
+    format!(
+      "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nx-client-id: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"permission\":\"read\"}}",
+      user_token, client_id, service_id
+    )
+  };
+  let response = run_test(check_read("other").as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+  assert!(response.contains("\"unmet_conditions\":[{"));
+  assert!(response.contains("x-client-id"));
+  run_test(check_read("servcli1").as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+
+  // Both the assignment and the refund grant carry a condition.
+  let check_refund = |ip: &str| {
+    format!(
+      "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nx-client-id: servcli1\r\nx-forwarded-for: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"permission\":\"kiosk.refund\"}}",
+      user_token, ip, service_id
+    )
+  };
+  run_test(check_refund("10.4.4.4").as_bytes(), b"\"allowed\":true", Some(SERVER_URL)).await;
+  run_test(check_refund("172.16.0.1").as_bytes(), b"permission_denied", Some(SERVER_URL)).await;
+
+  let relayed = format!(
+    "POST /check-permission HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"service_id\":{},\"permission\":\"read\",\"context\":{{\"headers\":{{\"x-client-id\":\"servcli1\"}}}}}}",
+    user_token, service_id
+  );
+  run_test(relayed.as_bytes(), b"context_requires_service_token", Some(SERVER_URL)).await;
+}
+
+// MFA
+fn mfa_code(secret: &str, step: i64) -> String {
+  let secret = data_encoding::BASE32_NOPAD
+    .decode(secret.as_bytes())
+    .expect("base32 secret");
+  eqeqo_api_auth::mfa::code_for_step(&secret, step)
+}
+
+#[tokio::test]
+async fn test_mfa_enrollment_and_two_step_login() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let login_response = run_test(request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let admin_token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("mfa_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Mfa\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}96\"}}",
+    admin_token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let token = extract_token_value(&response, "user_token");
+
+  let with_code = |path: &str, code: &str| {
+    format!(
+      "POST {} HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"code\":\"{}\"}}",
+      path, token, code
+    )
+  };
+  run_test(
+    with_code("/auth/mfa/confirm", "123456").as_bytes(),
+    b"mfa_not_enrolled",
+    Some(SERVER_URL),
+  )
+  .await;
+  let enroll = format!(
+    "POST /auth/mfa/enroll HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let response = run_test(enroll.as_bytes(), b"\"otpauth_uri\":\"otpauth://totp/", Some(SERVER_URL)).await;
+  assert!(response.contains(&format!(":{}?secret=", username)));
+  let secret = extract_token_value(&response, "secret");
+
+  // Steps before and after the current one are accepted; stay clear of a step boundary so
+  // the three codes below remain inside the window.
+  let now = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_secs() as i64;
+  if now % 30 > 25 {
+    tokio::time::sleep(std::time::Duration::from_secs((31 - now % 30) as u64)).await;
+  }
+  let step = eqeqo_api_auth::mfa::time_step(
+    std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .unwrap()
+      .as_secs() as i64,
+  );
+  run_test(
+    with_code("/auth/mfa/confirm", &mfa_code(&secret, step + 5)).as_bytes(),
+    b"invalid_mfa_code",
+    Some(SERVER_URL),
+  )
+  .await;
+  run_test(
+    with_code("/auth/mfa/confirm", &mfa_code(&secret, step - 1)).as_bytes(),
+    b"mfa_enabled",
+    Some(SERVER_URL),
+  )
+  .await;
+  run_test(enroll.as_bytes(), b"mfa_already_enabled", Some(SERVER_URL)).await;
+
+  // The password alone now only yields a challenge.
+  let response = run_test(user_login.as_bytes(), b"\"mfa_required\":true", Some(SERVER_URL)).await;
+  assert!(!response.contains("user_token"));
+  let mfa_token = extract_token_value(&response, "mfa_token");
+  let verify = |code: &str| {
+    format!(
+      "POST /auth/mfa/verify HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"mfa_token\":\"{}\",\"code\":\"{}\"}}",
+      mfa_token, code
+    )
+  };
+  let challenge_as_token = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    mfa_token
+  );
+  run_test(challenge_as_token.as_bytes(), b"invalid_token", Some(SERVER_URL)).await;
+  // The confirming code was already used.
+  run_test(verify(&mfa_code(&secret, step - 1)).as_bytes(), b"invalid_mfa_code", Some(SERVER_URL)).await;
+  let response = run_test(verify(&mfa_code(&secret, step)).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  assert!(response.contains(&format!("\"username\":\"{}\"", username)));
+  let mfa_user_token = extract_token_value(&response, "user_token");
+  run_test(verify(&mfa_code(&secret, step + 1)).as_bytes(), b"invalid_mfa_token", Some(SERVER_URL)).await;
+  let profile = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    mfa_user_token
+  );
+  run_test(profile.as_bytes(), b"\"user_id\"", Some(SERVER_URL)).await;
+
+  // Wrong codes use up the challenge.
+  let response = run_test(user_login.as_bytes(), b"\"mfa_token\"", Some(SERVER_URL)).await;
+  let mfa_token = extract_token_value(&response, "mfa_token");
+  let verify_again = |code: &str| {
+    format!(
+      "POST /auth/mfa/verify HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"mfa_token\":\"{}\",\"code\":\"{}\"}}",
+      mfa_token, code
+    )
+  };
+  for _ in 0..5 {
+    run_test(verify_again("000000").as_bytes(), b"invalid_mfa_code", Some(SERVER_URL)).await;
+  }
+  run_test(
+    verify_again(&mfa_code(&secret, step + 1)).as_bytes(),
+    b"invalid_mfa_token",
+    Some(SERVER_URL),
+  )
+  .await;
+
+  run_test(
+    with_code("/auth/mfa/disable", "000000").as_bytes(),
+    b"invalid_mfa_code",
+    Some(SERVER_URL),
+  )
+  .await;
+  run_test(
+    with_code("/auth/mfa/disable", &mfa_code(&secret, step + 1)).as_bytes(),
+    b"mfa_disabled",
+    Some(SERVER_URL),
+  )
+  .await;
+  // The wrong codes above also count towards the login lockout.
+  run_test(user_login.as_bytes(), b"account_locked", Some(SERVER_URL)).await;
+  let unlock = format!(
+    "POST /users/{}/unlock HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    person_id, admin_token
+  );
+  run_test(unlock.as_bytes(), b"user_unlocked", Some(SERVER_URL)).await;
+  run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_mfa_recovery_codes_replace_lost_authenticator() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let login_response = run_test(request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let admin_token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("recovery_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Recovery\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}97\"}}",
+    admin_token, username, password, suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let user_login = format!(
+    "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+    username, password
+  );
+  let response = run_test(user_login.as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let token = extract_token_value(&response, "user_token");
+  let profile = |token: &str| format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+
+  let enroll = format!(
+    "POST /auth/mfa/enroll HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let response = run_test(enroll.as_bytes(), b"\"recovery_codes\":[", Some(SERVER_URL)).await;
+  let secret = extract_token_value(&response, "secret");
+  let codes: Vec<String> = serde_json::from_str::<serde_json::Value>(
+    &response[response.find('{').unwrap()..],
+  )
+  .unwrap()["recovery_codes"]
+    .as_array()
+    .unwrap()
+    .iter()
+    .map(|code| code.as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(codes.len(), 10);
+  // Codes only count once MFA is on, and they cannot confirm it.
+  run_test(profile(&token).as_bytes(), b"\"recovery_codes_remaining\":0", Some(SERVER_URL)).await;
+  let with_code = |path: &str, code: &str| {
+    format!(
+      "POST {} HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"code\":\"{}\"}}",
+      path, token, code
+    )
+  };
+  run_test(
+    with_code("/auth/mfa/confirm", &codes[0]).as_bytes(),
+    b"invalid_mfa_code",
+    Some(SERVER_URL),
+  )
+  .await;
+  let step = eqeqo_api_auth::mfa::time_step(
+    std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .unwrap()
+      .as_secs() as i64,
+  );
+  run_test(
+    with_code("/auth/mfa/confirm", &mfa_code(&secret, step)).as_bytes(),
+    b"mfa_enabled",
+    Some(SERVER_URL),
+  )
+  .await;
+  run_test(profile(&token).as_bytes(), b"\"recovery_codes_remaining\":10", Some(SERVER_URL)).await;
+
+  // A recovery code stands in for the authenticator, in any case and without the dash, once.
+  let verify = |mfa_token: &str, code: &str| {
+    format!(
+      "POST /auth/mfa/verify HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"mfa_token\":\"{}\",\"code\":\"{}\"}}",
+      mfa_token, code
+    )
+  };
+  let response = run_test(user_login.as_bytes(), b"\"mfa_token\"", Some(SERVER_URL)).await;
+  let mfa_token = extract_token_value(&response, "mfa_token");
+  let typed = codes[3].replace('-', "").to_uppercase();
+  let response = run_test(verify(&mfa_token, &typed).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let recovered_token = extract_token_value(&response, "user_token");
+  run_test(
+    profile(&recovered_token).as_bytes(),
+    b"\"recovery_codes_remaining\":9",
+    Some(SERVER_URL),
+  )
+  .await;
+  let response = run_test(user_login.as_bytes(), b"\"mfa_token\"", Some(SERVER_URL)).await;
+  let mfa_token = extract_token_value(&response, "mfa_token");
+  run_test(verify(&mfa_token, &codes[3]).as_bytes(), b"invalid_mfa_code", Some(SERVER_URL)).await;
+
+  // Regenerating needs a second factor and invalidates the previous set.
+  run_test(
+    with_code("/auth/mfa/recovery-codes", "aaaaa-aaaaa").as_bytes(),
+    b"invalid_mfa_code",
+    Some(SERVER_URL),
+  )
+  .await;
+  let response = run_test(
+    with_code("/auth/mfa/recovery-codes", &codes[4]).as_bytes(),
+    b"\"recovery_codes\":[",
+    Some(SERVER_URL),
+  )
+  .await;
+  assert!(!codes.iter().any(|code| response.contains(code.as_str())));
+  run_test(profile(&token).as_bytes(), b"\"recovery_codes_remaining\":10", Some(SERVER_URL)).await;
+  run_test(verify(&mfa_token, &codes[5]).as_bytes(), b"invalid_mfa_code", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_mfa_code_guesses_across_logins_lock_the_account() {
+  boot_server().await;
+  let admin_token = login_admin().await;
+
+  let TestUser { username, password, .. } = create_user(&admin_token, "guesser").await;
+  let user_login = login_request(&username, &password);
+  let token = login_token(&username, &password).await;
+  let enroll = format!(
+    "POST /auth/mfa/enroll HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    token
+  );
+  let response = run_test(enroll.as_bytes(), b"\"secret\"", Some(SERVER_URL)).await;
+  let secret = extract_token_value(&response, "secret");
+  let step = eqeqo_api_auth::mfa::time_step(
+    std::time::SystemTime::now()
+      .duration_since(std::time::UNIX_EPOCH)
+      .unwrap()
+      .as_secs() as i64,
+  );
+  let confirm = format!(
+    "POST /auth/mfa/confirm HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"code\":\"{}\"}}",
+    token,
+    mfa_code(&secret, step)
+  );
+  run_test(confirm.as_bytes(), b"mfa_enabled", Some(SERVER_URL)).await;
+
+  // The right password earns a fresh challenge each time, but must not reset the wrong codes.
+  for _ in 0..5 {
+    let response = run_test(user_login.as_bytes(), b"\"mfa_token\"", Some(SERVER_URL)).await;
+    let verify = format!(
+      "POST /auth/mfa/verify HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"mfa_token\":\"{}\",\"code\":\"000000\"}}",
+      extract_token_value(&response, "mfa_token")
+    );
+    run_test(verify.as_bytes(), b"invalid_mfa_code", Some(SERVER_URL)).await;
+  }
+  run_test(user_login.as_bytes(), b"account_locked", Some(SERVER_URL)).await;
+}
+
+// Login lockout
+#[tokio::test]
+async fn test_login_lockout_and_admin_unlock() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let login_response = run_test(request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let admin_token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("locked_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Locked\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}98\"}}",
+    admin_token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+  let login_as = |username: &str, password: &str| {
+    format!(
+      "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+      username, password
+    )
+  };
+
+  // A success resets the count, so only five failures in a row lock the account.
+  for _ in 0..4 {
+    run_test(login_as(&username, "wrong").as_bytes(), b"invalid_credentials", Some(SERVER_URL)).await;
+  }
+  run_test(login_as(&username, &password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  for _ in 0..5 {
+    run_test(login_as(&username, "wrong").as_bytes(), b"invalid_credentials", Some(SERVER_URL)).await;
+  }
+  let response = run_test(
+    login_as(&username, &password).as_bytes(),
+    b"429 Too Many Requests",
+    Some(SERVER_URL),
+  )
+  .await;
+  assert!(response.contains("\"error\":\"account_locked\""));
+  let retry_after: i64 = extract_id_value(&response, "retry_after").parse().unwrap();
+  assert!(retry_after > 0 && retry_after <= 30);
+
+  // Unknown usernames lock the same way, so lockout does not reveal which accounts exist.
+  let ghost = format!("ghost_{}", suffix);
+  for _ in 0..5 {
+    run_test(login_as(&ghost, "wrong").as_bytes(), b"invalid_credentials", Some(SERVER_URL)).await;
+  }
+  run_test(login_as(&ghost, "wrong").as_bytes(), b"account_locked", Some(SERVER_URL)).await;
+
+  let user_request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"usr1\",\"password\":\"usr1-hash\"}";
+  let user_response = run_test(user_request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let user_token = extract_token_value(&user_response, "user_token");
+  let unlock = |token: &str| {
+    format!(
+      "POST /users/{}/unlock HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+      person_id, token
+    )
+  };
+  run_test(unlock(&user_token).as_bytes(), b"insufficient_permissions", Some(SERVER_URL)).await;
+  run_test(unlock(&admin_token).as_bytes(), b"\"had_failures\":true", Some(SERVER_URL)).await;
+  run_test(login_as(&username, &password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  run_test(unlock(&admin_token).as_bytes(), b"\"had_failures\":false", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_login_lockout_per_client_ip() {
+  boot_server().await;
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let ip = format!("10.{}.{}.{}", suffix % 251, (suffix / 251) % 251, (suffix / 63_001) % 251);
+  let login_from = |ip: Option<&str>, username: &str, password: &str| {
+    format!(
+      "POST /auth/login HTTP/1.1\r\n{}Content-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+      ip.map(|ip| format!("x-forwarded-for: {}\r\n", ip)).unwrap_or_default(),
+      username,
+      password
+    )
+  };
+  // Spread over many usernames, so only the per-IP count reaches its threshold.
+  for attempt in 0..20 {
+    let username = format!("spray_{}_{}", suffix, attempt);
+    run_test(
+      login_from(Some(&ip), &username, "guess").as_bytes(),
+      b"invalid_credentials",
+      Some(SERVER_URL),
+    )
+    .await;
+  }
+  run_test(
+    login_from(Some(&ip), "usr1", "usr1-hash").as_bytes(),
+    b"account_locked",
+    Some(SERVER_URL),
+  )
+  .await;
+  // The proxy appends the real address, so a client-supplied entry in front changes nothing.
+  run_test(
+    login_from(Some(&format!("192.0.2.7, {}", ip)), "usr1", "usr1-hash").as_bytes(),
+    b"account_locked",
+    Some(SERVER_URL),
+  )
+  .await;
+  run_test(
+    login_from(None, "usr1", "usr1-hash").as_bytes(),
+    b"\"user_token\"",
+    Some(SERVER_URL),
+  )
+  .await;
+}
+
+// Password reset
+fn latest_reset_token(username: &str) -> Option<String> {
+  let notifications = std::fs::read_to_string(notifications_path()).unwrap_or_default();
+  notifications
+    .lines()
+    .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+    .filter(|entry| entry["kind"] == "password_reset" && entry["recipient"] == username)
+    .last()
+    .map(|entry| entry["data"]["token"].as_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn test_password_reset_flow() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let login_response = run_test(request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let admin_token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("forgetful_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Forgetful\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}99\"}}",
+    admin_token, username, password, suffix
+  );
+  let response = run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let person_id = extract_id_value(&response, "id");
+  let login_as = |password: &str| {
+    format!(
+      "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+      username, password
+    )
+  };
+  let response = run_test(login_as(&password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let old_token = extract_token_value(&response, "user_token");
+  let old_refresh = extract_token_value(&response, "refresh_token");
+
+  let forgot = |username: &str| {
+    format!(
+      "POST /auth/password/forgot HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\"}}",
+      username
+    )
+  };
+  // Unknown usernames get the same answer and no notification.
+  let ghost = format!("ghost_{}", suffix);
+  run_test(forgot(&ghost).as_bytes(), b"password_reset_requested", Some(SERVER_URL)).await;
+  assert!(latest_reset_token(&ghost).is_none());
+  run_test(forgot(&username).as_bytes(), b"202 Accepted", Some(SERVER_URL)).await;
+  let first_token = latest_reset_token(&username).expect("reset notification");
+  // Asking again right away sends nothing and keeps the token just sent.
+  run_test(forgot(&username).as_bytes(), b"password_reset_requested", Some(SERVER_URL)).await;
+  assert_eq!(latest_reset_token(&username).as_deref(), Some(first_token.as_str()));
+  // Past the resend interval a new request replaces it.
+  sqlx::query("UPDATE auth.password_reset_tokens SET created_at = created_at - 3600 WHERE person_id = $1")
+    .bind(person_id.parse::<i32>().unwrap())
+    .execute(&test_db().await)
+    .await
+    .expect("age reset token");
+  run_test(forgot(&username).as_bytes(), b"password_reset_requested", Some(SERVER_URL)).await;
+  let reset_token = latest_reset_token(&username).expect("reset notification");
+  assert_ne!(first_token, reset_token);
+  // Three requests per username and window.
+  run_test(forgot(&username).as_bytes(), b"too_many_reset_requests", Some(SERVER_URL)).await;
+
+  let new_password = format!("new_{}", suffix);
+  let reset = |token: &str| {
+    format!(
+      "POST /auth/password/reset HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"token\":\"{}\",\"password\":\"{}\"}}",
+      token, new_password
+    )
+  };
+  run_test(reset("not-a-token").as_bytes(), b"invalid_reset_token", Some(SERVER_URL)).await;
+  // A newer request replaces the earlier token.
+  run_test(reset(&first_token).as_bytes(), b"invalid_reset_token", Some(SERVER_URL)).await;
+  let blank = format!(
+    "POST /auth/password/reset HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"token\":\"{}\",\"password\":\" \"}}",
+    reset_token
+  );
+  run_test(blank.as_bytes(), b"invalid_request_body", Some(SERVER_URL)).await;
+  // A refused password leaves the token usable for another try.
+  let weak = format!(
+    "POST /auth/password/reset HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"token\":\"{}\",\"password\":\"short1\"}}",
+    reset_token
+  );
+  run_test(weak.as_bytes(), b"weak_password", Some(SERVER_URL)).await;
+  run_test(reset(&reset_token).as_bytes(), b"\"status\":\"password_reset\"", Some(SERVER_URL)).await;
+  run_test(reset(&reset_token).as_bytes(), b"invalid_reset_token", Some(SERVER_URL)).await;
+
+  // Every session of the old password is gone.
+  let profile = format!(
+    "GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n",
+    old_token
+  );
+  run_test(profile.as_bytes(), b"invalid_token", Some(SERVER_URL)).await;
+  let refresh = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    old_refresh
+  );
+  run_test(refresh.as_bytes(), b"invalid_refresh_token", Some(SERVER_URL)).await;
+  run_test(login_as(&password).as_bytes(), b"invalid_credentials", Some(SERVER_URL)).await;
+  run_test(login_as(&new_password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+}
+
+#[tokio::test]
+async fn test_password_reset_requests_limited_per_client_ip() {
+  boot_server().await;
+  let suffix: u128 = unique_suffix().parse().unwrap();
+  let ip = format!("10.{}.{}.{}", suffix % 251, (suffix / 251) % 251, (suffix / 63_001) % 251);
+  let forgot_from = |ip: Option<&str>, username: &str| {
+    format!(
+      "POST /auth/password/forgot HTTP/1.1\r\n{}Content-Type: application/json\r\n\r\n{{\"username\":\"{}\"}}",
+      ip.map(|ip| format!("x-forwarded-for: {}\r\n", ip)).unwrap_or_default(),
+      username
+    )
+  };
+  // Spread over many usernames, so only the per-IP count reaches its limit.
+  for attempt in 0..20 {
+    let username = format!("asker_{}_{}", suffix, attempt);
+    run_test(
+      forgot_from(Some(&ip), &username).as_bytes(),
+      b"password_reset_requested",
+      Some(SERVER_URL),
+    )
+    .await;
+  }
+  let username = format!("asker_{}_last", suffix);
+  run_test(
+    forgot_from(Some(&ip), &username).as_bytes(),
+    b"too_many_reset_requests",
+    Some(SERVER_URL),
+  )
+  .await;
+  run_test(
+    forgot_from(None, &username).as_bytes(),
+    b"password_reset_requested",
+    Some(SERVER_URL),
+  )
+  .await;
+}
+
+#[tokio::test]
+async fn test_change_password_keeps_only_current_session() {
+  boot_server().await;
+  let request = b"POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"username\":\"adm1\",\"password\":\"adm1-hash\"}";
+  let login_response = run_test(request, b"\"user_token\"", Some(SERVER_URL)).await;
+  let admin_token = extract_token_value(&login_response, "user_token");
+
+  let suffix = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap()
+    .as_nanos();
+  let username = format!("changer_{}", suffix);
+  let password = format!("pass_{}", suffix);
+  let create_request = format!(
+    "POST /users HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password_hash\":\"{}\",\"name\":\"Changer\",\"person_type\":\"N\",\"document_type\":\"DNI\",\"document_number\":\"{}96\"}}",
+    admin_token, username, password, suffix
+  );
+  run_test(create_request.as_bytes(), b"\"id\"", Some(SERVER_URL)).await;
+  let login_as = |password: &str| {
+    format!(
+      "POST /auth/login HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"username\":\"{}\",\"password\":\"{}\"}}",
+      username, password
+    )
+  };
+  let laptop = run_test(login_as(&password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let laptop_token = extract_token_value(&laptop, "user_token");
+  let laptop_session = extract_id_value(&laptop, "session_id");
+  let phone = run_test(login_as(&password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+  let phone_token = extract_token_value(&phone, "user_token");
+  let phone_refresh = extract_token_value(&phone, "refresh_token");
+
+  let change = |token: &str, current: &str, new: &str| {
+    format!(
+      "POST /auth/password/change HTTP/1.1\r\nuser-token: {}\r\nContent-Type: application/json\r\n\r\n{{\"current_password\":\"{}\",\"new_password\":\"{}\"}}",
+      token, current, new
+    )
+  };
+  let new_password = format!("changed-{}", suffix);
+  let request = "POST /auth/password/change HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{}";
+  run_test(request.as_bytes(), b"missing_token_header", Some(SERVER_URL)).await;
+  let wrong = change(&laptop_token, "not-the-password", &new_password);
+  run_test(wrong.as_bytes(), b"invalid_current_password", Some(SERVER_URL)).await;
+  for weak in ["short1", "onlyletters", &password, &format!("x1{}", username)] {
+    let request = change(&laptop_token, &password, weak);
+    run_test(request.as_bytes(), b"weak_password", Some(SERVER_URL)).await;
+  }
+
+  let request = change(&laptop_token, &password, &new_password);
+  let changed = run_test(request.as_bytes(), b"\"refresh_token\"", Some(SERVER_URL)).await;
+  let new_token = extract_token_value(&changed, "user_token");
+  assert_ne!(new_token, laptop_token);
+  assert_eq!(extract_id_value(&changed, "session_id"), laptop_session);
+
+  let profile = |token: &str| format!("GET /auth/profile HTTP/1.1\r\nuser-token: {}\r\n\r\n", token);
+  run_test(profile(&new_token).as_bytes(), b"\"payload\"", Some(SERVER_URL)).await;
+  run_test(profile(&laptop_token).as_bytes(), b"invalid_token", Some(SERVER_URL)).await;
+  run_test(profile(&phone_token).as_bytes(), b"invalid_token", Some(SERVER_URL)).await;
+  let refresh = format!(
+    "POST /auth/refresh HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{{\"refresh_token\":\"{}\"}}",
+    phone_refresh
+  );
+  run_test(refresh.as_bytes(), b"invalid_refresh_token", Some(SERVER_URL)).await;
+  let sessions = format!("GET /auth/sessions HTTP/1.1\r\nuser-token: {}\r\n\r\n", new_token);
+  let listed = run_test(sessions.as_bytes(), b"\"current\":true", Some(SERVER_URL)).await;
+  assert_eq!(listed.matches("\"current\"").count(), 1);
+
+  run_test(login_as(&password).as_bytes(), b"invalid_credentials", Some(SERVER_URL)).await;
+  run_test(login_as(&new_password).as_bytes(), b"\"user_token\"", Some(SERVER_URL)).await;
+}
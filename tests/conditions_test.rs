@@ -0,0 +1,111 @@
+use eqeqo_api_auth::conditions::{Condition, ConditionContext};
+use serde_json::json;
+
+// Monday 2024-01-01 10:30:00 UTC
+const MONDAY_MORNING: i64 = 1_704_105_000;
+
+fn context() -> ConditionContext {
+  let mut context = ConditionContext {
+    now: MONDAY_MORNING,
+    ip: Some("10.1.2.3".parse().unwrap()),
+    ..Default::default()
+  };
+  context
+    .headers
+    .insert("x-client-id".to_string(), "servcli1".to_string());
+  context.user.insert("id".to_string(), json!(7));
+  context.user.insert("username".to_string(), json!("adm1"));
+  context
+}
+
+fn holds(source: &str, context: &ConditionContext) -> bool {
+  Condition::parse(source)
+    .unwrap_or_else(|err| panic!("{}: {}", source, err))
+    .evaluate(context)
+}
+
+#[test]
+fn test_condition_time_fields() {
+  let context = context();
+  assert!(holds("time.hour == 10 && time.minute == 30", &context));
+  assert!(holds("time.weekday == 1", &context));
+  assert!(holds("time.hour >= 9 && time.hour < 18 && time.weekday in [1, 2, 3, 4, 5]", &context));
+  assert!(holds(&format!("time.epoch == {}", MONDAY_MORNING), &context));
+
+  let sunday_night = ConditionContext {
+    now: MONDAY_MORNING - 12 * 3_600,
+    ..context
+  };
+  assert!(holds("time.weekday == 7 && time.hour == 22", &sunday_night));
+  assert!(!holds("time.hour >= 9 && time.hour < 18", &sunday_night));
+}
+
+#[test]
+fn test_condition_ip_ranges() {
+  let context = context();
+  assert!(holds("ip in \"10.0.0.0/8\"", &context));
+  assert!(holds("ip in [\"192.168.0.0/16\", \"10.1.2.0/24\"]", &context));
+  assert!(holds("ip in [\"10.1.2.3\"]", &context));
+  assert!(!holds("ip in \"10.1.3.0/24\"", &context));
+  assert!(!holds("ip in \"::1/128\"", &context));
+
+  let v6 = ConditionContext {
+    ip: Some("2001:db8::1".parse().unwrap()),
+    ..context
+  };
+  assert!(holds("ip in \"2001:db8::/32\"", &v6));
+}
+
+#[test]
+fn test_condition_headers_and_user_attributes() {
+  let context = context();
+  assert!(holds("header.x-client-id == \"servcli1\"", &context));
+  assert!(holds("header.X-Client-Id != \"other\"", &context));
+  assert!(holds("user.username == \"adm1\" && user.id == 7", &context));
+  assert!(holds("!(user.id in [1, 2, 3])", &context));
+  assert!(holds("(user.id == 1 || user.id == 7) && true", &context));
+}
+
+#[test]
+fn test_condition_missing_values_fail_closed() {
+  let context = ConditionContext::default();
+  assert!(!holds("ip in \"0.0.0.0/0\"", &context));
+  assert!(!holds("header.x-client-id == \"servcli1\"", &context));
+  assert!(!holds("header.x-client-id != \"servcli1\"", &context));
+  assert!(!holds("user.id > 0", &context));
+  assert!(!holds("user.username == 7", &context));
+  assert!(!holds("\"a\" < \"b\"", &context));
+  assert!(!holds("user.flag", &context));
+}
+
+#[test]
+fn test_condition_rejects_invalid_expressions() {
+  for source in [
+    "",
+    "time.hour >=",
+    "time.second == 1",
+    "location == \"home\"",
+    "header. == \"x\"",
+    "time.hour == 9 &&",
+    "(time.hour == 9",
+    "ip in [\"10.0.0.0/8\"",
+    "\"unterminated",
+    "time.hour = 9",
+    "time.hour == 9 time.minute == 0",
+    "[1, [2]] == 1",
+    "99999999999999999999 == 1",
+  ] {
+    assert!(Condition::parse(source).is_err(), "accepted {:?}", source);
+  }
+}
+
+#[test]
+fn test_condition_limits_size_and_nesting() {
+  let long = format!("user.username == \"{}\"", "a".repeat(600));
+  assert!(Condition::parse(&long).is_err());
+  let nested = format!("{}true{}", "(".repeat(20), ")".repeat(20));
+  assert!(Condition::parse(&nested).is_err());
+  let negated = format!("{}true", "!".repeat(20));
+  assert!(Condition::parse(&negated).is_err());
+  assert!(holds("((!!true))", &context()));
+}
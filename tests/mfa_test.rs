@@ -0,0 +1,115 @@
+use eqeqo_api_auth::mfa::{
+  RECOVERY_CODE_COUNT, code_for_step, decrypt_secret, encode_secret, encrypt_secret,
+  generate_recovery_codes, normalize_recovery_code, otpauth_uri, time_step, verify_code,
+};
+
+// RFC 6238 appendix B seed for HMAC-SHA1.
+const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+#[test]
+fn test_mfa_codes_match_rfc_6238_vectors() {
+  // The RFC lists 8-digit codes; 6-digit codes are their last six digits.
+  for (epoch, expected) in [
+    (59, "287082"),
+    (1_111_111_109, "081804"),
+    (1_111_111_111, "050471"),
+    (1_234_567_890, "005924"),
+    (2_000_000_000, "279037"),
+    (20_000_000_000, "353130"),
+  ] {
+    assert_eq!(
+      code_for_step(RFC_SECRET, time_step(epoch)),
+      expected,
+      "epoch {}",
+      epoch
+    );
+  }
+}
+
+#[test]
+fn test_mfa_verify_allows_one_step_of_drift() {
+  let now = 1_234_567_890;
+  let step = time_step(now);
+  for drift in [-1, 0, 1] {
+    let code = code_for_step(RFC_SECRET, step + drift);
+    assert_eq!(
+      verify_code(RFC_SECRET, &code, now, None),
+      Some(step + drift)
+    );
+  }
+  for drift in [-2, 2] {
+    let code = code_for_step(RFC_SECRET, step + drift);
+    assert_eq!(verify_code(RFC_SECRET, &code, now, None), None);
+  }
+  assert_eq!(verify_code(RFC_SECRET, " 005924 ", now, None), Some(step));
+  for malformed in ["", "00592", "0059244", "00592a", "-05924"] {
+    assert_eq!(verify_code(RFC_SECRET, malformed, now, None), None);
+  }
+}
+
+#[test]
+fn test_mfa_verify_rejects_used_steps() {
+  let now = 1_234_567_890;
+  let step = time_step(now);
+  let code = code_for_step(RFC_SECRET, step);
+  assert_eq!(verify_code(RFC_SECRET, &code, now, Some(step)), None);
+  assert_eq!(
+    verify_code(RFC_SECRET, &code, now, Some(step - 1)),
+    Some(step)
+  );
+  let next = code_for_step(RFC_SECRET, step + 1);
+  assert_eq!(
+    verify_code(RFC_SECRET, &next, now, Some(step)),
+    Some(step + 1)
+  );
+}
+
+#[test]
+fn test_mfa_otpauth_uri() {
+  assert_eq!(
+    encode_secret(RFC_SECRET),
+    "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"
+  );
+  assert_eq!(
+    otpauth_uri("eqeqo auth", "ana@example.com", RFC_SECRET),
+    "otpauth://totp/eqeqo%20auth:ana%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+     &issuer=eqeqo%20auth&algorithm=SHA1&digits=6&period=30"
+  );
+}
+
+#[test]
+fn test_mfa_secret_encryption_is_bound_to_person() {
+  let stored = encrypt_secret(RFC_SECRET, 7).unwrap();
+  assert!(!stored.contains("GEZDGNBV"));
+  assert_ne!(stored, encrypt_secret(RFC_SECRET, 7).unwrap());
+  assert_eq!(decrypt_secret(&stored, 7).unwrap(), RFC_SECRET);
+  assert!(decrypt_secret(&stored, 8).is_err());
+
+  let mut tampered = stored.into_bytes();
+  let last = tampered.len() - 3;
+  tampered[last] = if tampered[last] == b'A' { b'B' } else { b'A' };
+  assert!(decrypt_secret(&String::from_utf8(tampered).unwrap(), 7).is_err());
+  assert!(decrypt_secret("not base64!", 7).is_err());
+}
+
+#[test]
+fn test_mfa_recovery_codes_are_distinct_and_normalize() {
+  let codes = generate_recovery_codes();
+  assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+  for code in &codes {
+    assert_eq!(code.len(), 11);
+    assert_eq!(&code[5..6], "-");
+    let normalized = normalize_recovery_code(code).unwrap();
+    assert_eq!(normalized, code.replace('-', ""));
+    assert_eq!(normalize_recovery_code(&code.to_uppercase()), Some(normalized.clone()));
+    assert_eq!(normalize_recovery_code(&format!(" {} ", normalized)), Some(normalized));
+  }
+  let mut unique = codes.clone();
+  unique.sort();
+  unique.dedup();
+  assert_eq!(unique.len(), codes.len());
+
+  for invalid in ["", "123456", "abcde-fghi", "abcde-fghijk", "abcde-fgh1j", "abcde_fghij"] {
+    assert_eq!(normalize_recovery_code(invalid), None, "{:?}", invalid);
+  }
+}
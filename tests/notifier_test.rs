@@ -0,0 +1,82 @@
+use eqeqo_api_auth::notifier::{self, FileNotifier, Notification, Notifier};
+use serde_json::{Value, json};
+use std::sync::Arc;
+
+fn notification(person_id: i32, token: &str) -> Notification {
+  Notification {
+    kind: "password_reset".to_string(),
+    person_id,
+    recipient: format!("user{}", person_id),
+    subject: "Password reset".to_string(),
+    body: format!("token {}", token),
+    data: json!({ "token": token }),
+  }
+}
+
+fn read_lines(path: &std::path::Path) -> Vec<Value> {
+  std::fs::read_to_string(path)
+    .unwrap()
+    .lines()
+    .map(|line| serde_json::from_str(line).unwrap())
+    .collect()
+}
+
+#[test]
+fn test_file_notifier_appends_json_lines() {
+  let path = std::env::temp_dir().join(format!("notifier-append-{}.jsonl", std::process::id()));
+  let _ = std::fs::remove_file(&path);
+  let file_notifier = FileNotifier::new(&path);
+  file_notifier.send(&notification(1, "first")).unwrap();
+  file_notifier.send(&notification(2, "second")).unwrap();
+
+  let lines = read_lines(&path);
+  assert_eq!(lines.len(), 2);
+  assert_eq!(lines[0]["recipient"], "user1");
+  assert_eq!(lines[0]["data"]["token"], "first");
+  assert_eq!(lines[1]["kind"], "password_reset");
+  assert_eq!(lines[1]["body"], "token second");
+  let _ = std::fs::remove_file(&path);
+
+  let missing_dir = std::env::temp_dir()
+    .join("notifier-missing-dir")
+    .join("out.jsonl");
+  assert!(
+    FileNotifier::new(missing_dir)
+      .send(&notification(3, "x"))
+      .is_err()
+  );
+}
+
+#[tokio::test]
+async fn test_installed_notifier_is_used() {
+  let path = std::env::temp_dir().join(format!("notifier-installed-{}.jsonl", std::process::id()));
+  let _ = std::fs::remove_file(&path);
+  assert!(notifier::install(Arc::new(FileNotifier::new(&path))));
+  assert!(!notifier::install(Arc::new(FileNotifier::new(
+    "ignored.jsonl"
+  ))));
+
+  notifier::current()
+    .unwrap()
+    .send(&notification(4, "installed"))
+    .unwrap();
+  notifier::deliver(notification(5, "delivered")).await.unwrap();
+  let lines = read_lines(&path);
+  assert_eq!(lines.len(), 2);
+  assert_eq!(lines[0]["data"]["token"], "installed");
+  assert_eq!(lines[1]["data"]["token"], "delivered");
+  let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_notifier_must_be_chosen() {
+  // The only test in this binary touching the environment.
+  unsafe { std::env::remove_var("NOTIFIER") };
+  let missing = notifier::notifier_from_env().err().expect("missing NOTIFIER");
+  assert!(missing.to_string().contains("NOTIFIER is not set"));
+  unsafe { std::env::set_var("NOTIFIER", "smtp") };
+  let unknown = notifier::notifier_from_env().err().expect("unknown NOTIFIER");
+  assert!(unknown.to_string().contains("unknown NOTIFIER \"smtp\""));
+  unsafe { std::env::set_var("NOTIFIER", "stdout") };
+  assert!(notifier::notifier_from_env().is_ok());
+}